@@ -0,0 +1,259 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Error, Result, LOG_CATEGORY};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use instant_acme::{
+    Account, AccountCredentials, ExternalAccountKey, LetsEncrypt, NewAccount,
+    ZeroSsl,
+};
+use openssl::rsa::Rsa;
+use pingap_certificate::rcgen;
+use pingap_config::{CertificateConf, ConfigStorage};
+use pingora::tls::hash::{hash, MessageDigest};
+use pingora::tls::pkey::PKey;
+use tracing::{error, info};
+
+const GOOGLE_PRODUCTION_URL: &str =
+    "https://dv.acme-v02.api.pki.goog/directory";
+const GOOGLE_STAGING_URL: &str =
+    "https://dv.acme-v02.test-api.pki.goog/directory";
+
+/// Options of the ACME server and the certificate key
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcmeOptions {
+    /// Challenge of acme, `lets_encrypt` or the dns provider url
+    pub challenge: String,
+    /// Directory url or alias of the ACME server
+    pub directory: String,
+    /// Key identifier of external account binding
+    pub eab_kid: String,
+    /// Base64url encoded HMAC key of external account binding
+    pub eab_hmac_key: String,
+    /// Key type of the certificate
+    pub key_type: String,
+}
+
+impl From<&CertificateConf> for AcmeOptions {
+    fn from(value: &CertificateConf) -> Self {
+        Self {
+            challenge: value.acme.clone().unwrap_or_default(),
+            directory: value.acme_directory.clone().unwrap_or_default(),
+            eab_kid: value.acme_eab_kid.clone().unwrap_or_default(),
+            eab_hmac_key: value.acme_eab_hmac_key.clone().unwrap_or_default(),
+            key_type: value.acme_key_type.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Returns the directory url of ACME server, the alias is converted to url:
+/// `lets_encrypt`(default), `lets_encrypt_staging`, `zerossl`, `google`
+/// and `google_staging`.
+pub fn get_directory_url(directory: &str) -> String {
+    match directory.trim() {
+        "" | "lets_encrypt" => LetsEncrypt::Production.url().to_string(),
+        "lets_encrypt_staging" => LetsEncrypt::Staging.url().to_string(),
+        "zerossl" => ZeroSsl::Production.url().to_string(),
+        "google" => GOOGLE_PRODUCTION_URL.to_string(),
+        "google_staging" => GOOGLE_STAGING_URL.to_string(),
+        url => url.to_string(),
+    }
+}
+
+/// Returns the storage path of the account credentials, the account
+/// is shared by the certificates of the same ACME server and eab key.
+fn get_account_path(directory_url: &str, eab_kid: &str) -> Result<String> {
+    let digest = hash(
+        MessageDigest::sha256(),
+        format!("{directory_url}#{eab_kid}").as_bytes(),
+    )
+    .map_err(|e| Error::Fail {
+        category: "account_path".to_string(),
+        message: e.to_string(),
+    })?;
+    let key: String = digest[..16].iter().map(|v| format!("{v:02x}")).collect();
+    Ok(format!("pingap-acme-accounts/{key}.json"))
+}
+
+/// Gets the ACME account from the config storage, a new account is
+/// created and saved to the storage if it does not exist, so every
+/// node shares the same account.
+pub async fn get_account(
+    storage: &'static (dyn ConfigStorage + Sync + Send),
+    options: &AcmeOptions,
+) -> Result<Account> {
+    let directory_url = get_directory_url(&options.directory);
+    let path = get_account_path(&directory_url, &options.eab_kid)?;
+    if let Ok(data) = storage.load(&path).await {
+        match serde_json::from_slice::<AccountCredentials>(&data) {
+            Ok(credentials) => {
+                return Account::from_credentials(credentials).await.map_err(
+                    |e| Error::Instant {
+                        category: "account_from_credentials".to_string(),
+                        source: e,
+                    },
+                );
+            },
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    path,
+                    "parse acme account credentials fail"
+                );
+            },
+        }
+    }
+
+    let external_account = if options.eab_kid.is_empty() {
+        None
+    } else {
+        let key = URL_SAFE_NO_PAD
+            .decode(options.eab_hmac_key.trim_end_matches('='))
+            .map_err(|e| Error::Fail {
+                category: "eab_hmac_key".to_string(),
+                message: e.to_string(),
+            })?;
+        Some(ExternalAccountKey::new(options.eab_kid.clone(), &key))
+    };
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &[],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &directory_url,
+        external_account.as_ref(),
+    )
+    .await
+    .map_err(|e| Error::Instant {
+        category: "create_account".to_string(),
+        source: e,
+    })?;
+    let data = serde_json::to_vec(&credentials).map_err(|e| Error::Fail {
+        category: "account_credentials".to_string(),
+        message: e.to_string(),
+    })?;
+    storage.save(&path, &data).await.map_err(|e| Error::Fail {
+        category: "save_account".to_string(),
+        message: e.to_string(),
+    })?;
+    info!(
+        category = LOG_CATEGORY,
+        directory_url, "create acme account success"
+    );
+    Ok(account)
+}
+
+/// Generates the private key of certificate, the supported key types:
+/// `ecdsa_p256`(default), `ecdsa_p384`, `rsa2048`, `rsa3072` and `rsa4096`.
+pub fn new_key_pair(key_type: &str) -> Result<rcgen::KeyPair> {
+    let map_rcgen_err = |e: rcgen::Error| Error::Rcgen {
+        category: "generate_key_pair".to_string(),
+        source: e,
+    };
+    let bits = match key_type.trim().to_lowercase().as_str() {
+        "" | "ecdsa_p256" => {
+            return rcgen::KeyPair::generate_for(
+                &rcgen::PKCS_ECDSA_P256_SHA256,
+            )
+            .map_err(map_rcgen_err);
+        },
+        "ecdsa_p384" => {
+            return rcgen::KeyPair::generate_for(
+                &rcgen::PKCS_ECDSA_P384_SHA384,
+            )
+            .map_err(map_rcgen_err);
+        },
+        "rsa2048" => 2048,
+        "rsa3072" => 3072,
+        "rsa4096" => 4096,
+        _ => {
+            return Err(Error::Fail {
+                category: "key_type".to_string(),
+                message: format!("key type {key_type} is not supported"),
+            });
+        },
+    };
+    // rsa key is generated by openssl, and signed by rcgen
+    let map_err = |e: pingora::tls::error::ErrorStack| Error::Fail {
+        category: "generate_rsa_key".to_string(),
+        message: e.to_string(),
+    };
+    let rsa = Rsa::generate(bits).map_err(map_err)?;
+    let pem = PKey::from_rsa(rsa)
+        .map_err(map_err)?
+        .private_key_to_pem_pkcs8()
+        .map_err(map_err)?;
+    rcgen::KeyPair::from_pem(&String::from_utf8_lossy(&pem))
+        .map_err(map_rcgen_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_get_directory_url() {
+        assert_eq!(
+            "https://acme-v02.api.letsencrypt.org/directory",
+            get_directory_url("")
+        );
+        assert_eq!(
+            "https://acme-staging-v02.api.letsencrypt.org/directory",
+            get_directory_url("lets_encrypt_staging")
+        );
+        assert_eq!(
+            "https://acme.zerossl.com/v2/DV90",
+            get_directory_url("zerossl")
+        );
+        assert_eq!(GOOGLE_PRODUCTION_URL, get_directory_url("google"));
+        assert_eq!(
+            "https://127.0.0.1:14000/dir",
+            get_directory_url("https://127.0.0.1:14000/dir")
+        );
+    }
+
+    #[test]
+    fn test_get_account_path() {
+        let path = get_account_path(&get_directory_url(""), "").unwrap();
+        assert_eq!(true, path.starts_with("pingap-acme-accounts/"));
+        assert_eq!(path, get_account_path(&get_directory_url(""), "").unwrap());
+        assert_ne!(
+            path,
+            get_account_path(&get_directory_url(""), "kid").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_new_key_pair() {
+        assert_eq!(
+            &rcgen::PKCS_ECDSA_P256_SHA256,
+            new_key_pair("").unwrap().algorithm()
+        );
+        assert_eq!(
+            &rcgen::PKCS_ECDSA_P384_SHA384,
+            new_key_pair("ecdsa_p384").unwrap().algorithm()
+        );
+        assert_eq!(
+            &rcgen::PKCS_RSA_SHA256,
+            new_key_pair("rsa2048").unwrap().algorithm()
+        );
+        assert_eq!(
+            "Let's Encrypt operation failed: key type ed25519 is not supported, category: key_type",
+            new_key_pair("ed25519").err().unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::rfc2136::Rfc2136Provider;
+use super::{Error, Result, LOG_CATEGORY};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+use url::Url;
+
+const DNS_CHALLENGE_PREFIX: &str = "_acme-challenge";
+const WEBHOOK_SCHEMA_PREFIX: &str = "webhook+";
+const DEFAULT_PROPAGATION_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Provider for creating and removing the TXT record of DNS-01 challenge
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Creates the TXT record of the challenge
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()>;
+    /// Removes the TXT record of the challenge
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()>;
+}
+
+/// DNS-01 challenge with the provider
+pub struct DnsChallenge {
+    pub provider: Box<dyn DnsProvider>,
+    /// Waiting time for the record to propagate before validation
+    pub propagation_delay: Duration,
+}
+
+/// Returns the name of TXT record for the domain's DNS-01 challenge,
+/// the wildcard domain uses the same record as its base domain.
+pub fn get_dns_challenge_name(domain: &str) -> String {
+    let domain = domain.trim_start_matches("*.").trim_end_matches('.');
+    format!("{DNS_CHALLENGE_PREFIX}.{domain}.")
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookDnsParams<'a> {
+    action: &'a str,
+    fqdn: &'a str,
+    value: &'a str,
+}
+
+/// Dns provider which calls the http webhook to update the TXT record,
+/// the request is post with json body: `{"action": "present", "fqdn": "", "value": ""}`,
+/// the action is `present` or `cleanup`.
+///
+/// e.g. `webhook+https://dns.pingap.io/acme?token=123`
+#[derive(Debug)]
+pub struct WebhookDnsProvider {
+    url: String,
+    timeout: Duration,
+}
+
+impl TryFrom<&Url> for WebhookDnsProvider {
+    type Error = Error;
+    fn try_from(value: &Url) -> Result<Self> {
+        let mut url = value.clone();
+        let mut timeout = DEFAULT_WEBHOOK_TIMEOUT;
+        let mut query_list = vec![];
+        for (key, value) in value.query_pairs().into_iter() {
+            match key.as_ref() {
+                "timeout" => {
+                    if let Ok(d) = humantime::parse_duration(value.as_ref()) {
+                        timeout = d;
+                    }
+                },
+                // common parameter of dns challenge
+                "propagation_delay" => {},
+                _ => query_list.push((key.to_string(), value.to_string())),
+            }
+        }
+        url.set_query(None);
+        if !query_list.is_empty() {
+            url.query_pairs_mut().extend_pairs(query_list);
+        }
+        let url = url.to_string();
+        Ok(Self {
+            url: url[WEBHOOK_SCHEMA_PREFIX.len()..].to_string(),
+            timeout,
+        })
+    }
+}
+
+impl WebhookDnsProvider {
+    async fn call(&self, action: &str, fqdn: &str, value: &str) -> Result<()> {
+        let map_err = |e: reqwest::Error| Error::Fail {
+            category: "dns_webhook".to_string(),
+            message: e.to_string(),
+        };
+        let resp = reqwest::Client::new()
+            .post(&self.url)
+            .timeout(self.timeout)
+            .json(&WebhookDnsParams {
+                action,
+                fqdn,
+                value,
+            })
+            .send()
+            .await
+            .map_err(map_err)?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(Error::Fail {
+                category: "dns_webhook".to_string(),
+                message: format!(
+                    "{action} dns record fail, status: {status}, body: {body}"
+                ),
+            });
+        }
+        info!(
+            category = LOG_CATEGORY,
+            action, fqdn, "webhook dns update success"
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsProvider for WebhookDnsProvider {
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.call("present", fqdn, value).await
+    }
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.call("cleanup", fqdn, value).await
+    }
+}
+
+/// Creates the DNS-01 challenge from the acme config, returns `None`
+/// if the acme uses HTTP-01 challenge(e.g. `lets_encrypt`).
+///
+/// The common query parameter `propagation_delay` is the waiting time
+/// before validation, the supported providers:
+/// * `rfc2136://127.0.0.1:53?zone=pingap.io&key_name=acme&key_secret=base64`
+/// * `webhook+https://dns.pingap.io/acme?timeout=30s`
+pub fn new_dns_challenge(acme: &str) -> Result<Option<DnsChallenge>> {
+    if !acme.contains("://") {
+        return Ok(None);
+    }
+    let url = Url::parse(acme).map_err(|e| Error::Fail {
+        category: "parse_acme".to_string(),
+        message: e.to_string(),
+    })?;
+    let mut propagation_delay = DEFAULT_PROPAGATION_DELAY;
+    for (key, value) in url.query_pairs().into_iter() {
+        if key == "propagation_delay" {
+            if let Ok(d) = humantime::parse_duration(value.as_ref()) {
+                propagation_delay = d;
+            }
+        }
+    }
+    let provider: Box<dyn DnsProvider> = match url.scheme() {
+        "rfc2136" => Box::new(Rfc2136Provider::try_from(&url)?),
+        schema if schema.starts_with(WEBHOOK_SCHEMA_PREFIX) => {
+            Box::new(WebhookDnsProvider::try_from(&url)?)
+        },
+        schema => {
+            return Err(Error::Fail {
+                category: "parse_acme".to_string(),
+                message: format!("dns provider {schema} is not supported"),
+            });
+        },
+    };
+    Ok(Some(DnsChallenge {
+        provider,
+        propagation_delay,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_get_dns_challenge_name() {
+        assert_eq!(
+            "_acme-challenge.pingap.io.",
+            get_dns_challenge_name("pingap.io")
+        );
+        assert_eq!(
+            "_acme-challenge.pingap.io.",
+            get_dns_challenge_name("*.pingap.io")
+        );
+    }
+
+    #[test]
+    fn test_new_dns_challenge() {
+        assert_eq!(true, new_dns_challenge("lets_encrypt").unwrap().is_none());
+
+        let challenge = new_dns_challenge(
+            "rfc2136://127.0.0.1:53?zone=pingap.io&propagation_delay=5s",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(Duration::from_secs(5), challenge.propagation_delay);
+
+        let challenge = new_dns_challenge("webhook+https://dns.pingap.io/acme")
+            .unwrap()
+            .unwrap();
+        assert_eq!(DEFAULT_PROPAGATION_DELAY, challenge.propagation_delay);
+
+        assert_eq!(
+            "Let's Encrypt operation failed: dns provider route53 is not supported, category: parse_acme",
+            new_dns_challenge("route53://pingap.io")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_new_webhook_dns_provider() {
+        let url = Url::parse(
+            "webhook+https://dns.pingap.io/acme?token=123&timeout=3s&propagation_delay=1m",
+        )
+        .unwrap();
+        let provider = WebhookDnsProvider::try_from(&url).unwrap();
+        assert_eq!("https://dns.pingap.io/acme?token=123", provider.url);
+        assert_eq!(Duration::from_secs(3), provider.timeout);
+
+        let url = Url::parse("webhook+http://127.0.0.1:3000/acme").unwrap();
+        let provider = WebhookDnsProvider::try_from(&url).unwrap();
+        assert_eq!("http://127.0.0.1:3000/acme", provider.url);
+        assert_eq!(DEFAULT_WEBHOOK_TIMEOUT, provider.timeout);
+    }
+}
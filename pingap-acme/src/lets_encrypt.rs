@@ -12,13 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{get_token_path, Error, Result, LOG_CATEGORY};
-use instant_acme::{
-    Account, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder,
-    OrderStatus,
+use super::account::{get_account, new_key_pair, AcmeOptions};
+use super::dns_provider::{
+    get_dns_challenge_name, new_dns_challenge, DnsChallenge,
 };
+use super::{get_token_path, Error, Result, LOG_CATEGORY};
+use instant_acme::{ChallengeType, Identifier, NewOrder, Order, OrderStatus};
 use pingap_certificate::rcgen;
-use pingap_certificate::{try_update_certificates, Certificate};
+use pingap_certificate::{
+    get_tls_alpn_challenge_path, try_update_certificates, Certificate,
+};
 use pingap_config::{
     get_current_config, set_current_config, ConfigStorage, LoadConfigOptions,
     PingapConf, CATEGORY_CERTIFICATE,
@@ -37,6 +40,8 @@ use substring::Substring;
 use tracing::{error, info};
 
 static WELL_KNOWN_PATH_PREFIX: &str = "/.well-known/acme-challenge/";
+/// Acme challenge of TLS-ALPN-01
+static TLS_ALPN_01: &str = "tls_alpn_01";
 
 /// Updates the certificate for the given name and domains using Let's Encrypt.
 /// This function will:
@@ -47,9 +52,10 @@ async fn update_certificate_lets_encrypt(
     storage: &'static (dyn ConfigStorage + Sync + Send),
     name: &str,
     domains: &[String],
+    acme: &AcmeOptions,
 ) -> Result<PingapConf> {
     // get new certificate from lets encrypt
-    let (pem, key) = new_lets_encrypt(storage, domains, true).await?;
+    let (pem, key) = new_lets_encrypt(storage, domains, acme).await?;
     let mut conf = storage
         .load_config(LoadConfigOptions {
             ..Default::default()
@@ -80,6 +86,7 @@ async fn update_certificate_lets_encrypt(
 struct UpdateCertificateParams {
     name: String,
     domains: Vec<String>,
+    acme: AcmeOptions,
     buffer_days: u16,
 }
 
@@ -141,8 +148,14 @@ async fn do_update_certificates(
             continue;
         }
 
-        if let Err(e) =
-            renew_certificate(storage, name, domains, sender.clone()).await
+        if let Err(e) = renew_certificate(
+            storage,
+            name,
+            domains,
+            &item.acme,
+            sender.clone(),
+        )
+        .await
         {
             error!(
                 category = LOG_CATEGORY,
@@ -160,9 +173,11 @@ async fn renew_certificate(
     storage: &'static (dyn ConfigStorage + Sync + Send),
     name: &str,
     domains: &[String],
+    acme: &AcmeOptions,
     sender: Option<Arc<NotificationSender>>,
 ) -> Result<()> {
-    let conf = update_certificate_lets_encrypt(storage, name, domains).await?;
+    let conf =
+        update_certificate_lets_encrypt(storage, name, domains, acme).await?;
     set_current_config(&conf);
     handle_successful_renewal(domains, &conf, sender).await;
     Ok(())
@@ -231,6 +246,7 @@ pub fn new_lets_encrypt_service(
                     }
                     params.push(UpdateCertificateParams {
                         name: name.to_string(),
+                        acme: AcmeOptions::from(certificate),
                         buffer_days: certificate
                             .buffer_days
                             .unwrap_or_default(),
@@ -312,82 +328,59 @@ pub async fn handle_lets_encrypt(
     Ok(false)
 }
 
-/// Generates a new certificate from Let's Encrypt for the given domains.
-/// The ACME protocol flow:
-/// 1. Creates/retrieves an ACME account with Let's Encrypt
-/// 2. Creates a new order for the domains to be certified
-/// 3. For each domain:
-///    - Gets the HTTP-01 challenge details
-///    - Stores the challenge token response
-///    - Notifies Let's Encrypt that the challenge is ready
-/// 4. Waits for Let's Encrypt to verify domain ownership
-/// 5. Generates a CSR (Certificate Signing Request)
-/// 6. Submits the CSR and retrieves the signed certificate
+/// Generates the self-signed certificate of TLS-ALPN-01 challenge(RFC 8737),
+/// it contains the critical acmeIdentifier extension with the sha256 digest
+/// of key authorization.
 ///
-/// Returns a tuple of (certificate_chain_pem, private_key_pem)
-async fn new_lets_encrypt(
-    storage: &'static (dyn ConfigStorage + Sync + Send),
-    domains: &[String],
-    production: bool,
+/// Returns a tuple of (certificate_pem, private_key_pem)
+fn new_tls_alpn_challenge_certificate(
+    domain: &str,
+    digest: &[u8],
 ) -> Result<(String, String)> {
-    let mut domains: Vec<String> = domains.to_vec();
-    // sort domain for comparing later
-    domains.sort();
-    info!(
-        category = LOG_CATEGORY,
-        domains = domains.join(","),
-        "acme from let's encrypt"
-    );
-    let url = if production {
-        LetsEncrypt::Production.url()
-    } else {
-        LetsEncrypt::Staging.url()
-    };
-    let (account, _) = Account::create(
-        &NewAccount {
-            contact: &[],
-            terms_of_service_agreed: true,
-            only_return_existing: false,
-        },
-        url,
-        None,
-    )
-    .await
-    .map_err(|e| Error::Instant {
-        category: "create_account".to_string(),
-        source: e,
-    })?;
-
-    let mut order = account
-        .new_order(&NewOrder {
-            identifiers: &domains
-                .iter()
-                .map(|item| Identifier::Dns(item.to_owned()))
-                .collect::<Vec<Identifier>>(),
-        })
-        .await
-        .map_err(|e| Error::Instant {
-            category: "new_order".to_string(),
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])
+        .map_err(|e| Error::Rcgen {
+            category: "new_params".to_string(),
             source: e,
         })?;
+    params.custom_extensions =
+        vec![rcgen::CustomExtension::new_acme_identifier(digest)];
+    let key = rcgen::KeyPair::generate().map_err(|e| Error::Rcgen {
+        category: "generate_key_pair".to_string(),
+        source: e,
+    })?;
+    let cert = params.self_signed(&key).map_err(|e| Error::Rcgen {
+        category: "self_signed".to_string(),
+        source: e,
+    })?;
+    Ok((cert.pem(), key.serialize_pem()))
+}
 
-    let state = order.state();
-    if !matches!(state.status, OrderStatus::Pending) {
-        return Err(Error::Fail {
-            message: format!(
-                "order is not pending, status: {:?}",
-                state.status
-            ),
-            category: "order_status".to_string(),
-        });
-    }
-
+/// Completes the challenges of the order and waits for the order to be ready.
+/// The HTTP-01 token responses and the TLS-ALPN-01 certificates are stored
+/// in storage, so any node can serve them, and the DNS-01 records
+/// are created by the dns provider. The created records and certificates are
+/// appended to `dns_records` and `tls_alpn_domains` for cleanup.
+async fn validate_order(
+    storage: &'static (dyn ConfigStorage + Sync + Send),
+    order: &mut Order,
+    acme: &AcmeOptions,
+    dns_challenge: Option<&DnsChallenge>,
+    dns_records: &mut Vec<(String, String)>,
+    tls_alpn_domains: &mut Vec<String>,
+) -> Result<()> {
     let authorizations =
         order.authorizations().await.map_err(|e| Error::Instant {
             category: "authorizations".to_string(),
             source: e,
         })?;
     let mut challenges = Vec::with_capacity(authorizations.len());
+    let challenge_type = if dns_challenge.is_some() {
+        ChallengeType::Dns01
+    } else if acme.challenge == TLS_ALPN_01 {
+        ChallengeType::TlsAlpn01
+    } else {
+        ChallengeType::Http01
+    };
 
     for authz in &authorizations {
         info!(
@@ -404,14 +397,47 @@ async fn new_lets_encrypt(
         let challenge = authz
             .challenges
             .iter()
-            .find(|c| c.r#type == ChallengeType::Http01)
+            .find(|c| c.r#type == challenge_type)
             .ok_or_else(|| Error::NotFound {
-                message: "Http01 challenge not found".to_string(),
+                message: format!("{challenge_type:?} challenge not found"),
             })?;
 
         let instant_acme::Identifier::Dns(identifier) = &authz.identifier;
 
         let key_auth = order.key_authorization(challenge);
+        if let Some(dns_challenge) = dns_challenge {
+            let fqdn = get_dns_challenge_name(identifier);
+            let value = key_auth.dns_value();
+            dns_challenge.provider.present(&fqdn, &value).await?;
+            info!(category = LOG_CATEGORY, fqdn, "let's encrypt dns-01 record");
+            dns_records.push((fqdn, value));
+            challenges.push(&challenge.url);
+            continue;
+        }
+        if challenge_type == ChallengeType::TlsAlpn01 {
+            let (cert, key) = new_tls_alpn_challenge_certificate(
+                identifier,
+                key_auth.digest().as_ref(),
+            )?;
+            storage
+                .save(
+                    &get_tls_alpn_challenge_path(identifier),
+                    format!("{cert}{key}").as_bytes(),
+                )
+                .await
+                .map_err(|e| Error::Fail {
+                    category: "save_tls_alpn_certificate".to_string(),
+                    message: e.to_string(),
+                })?;
+            info!(
+                category = LOG_CATEGORY,
+                domain = identifier,
+                "let's encrypt tls-alpn-01 certificate"
+            );
+            tls_alpn_domains.push(identifier.to_string());
+            challenges.push(&challenge.url);
+            continue;
+        }
         storage
             .save(
                 &get_token_path(&challenge.token),
@@ -429,10 +455,16 @@ async fn new_lets_encrypt(
             "let's encrypt well known path",
         );
 
-        challenges.push((identifier, &challenge.url));
+        challenges.push(&challenge.url);
+    }
+    // wait for the dns records to propagate
+    if let Some(dns_challenge) = dns_challenge {
+        if !dns_records.is_empty() {
+            tokio::time::sleep(dns_challenge.propagation_delay).await;
+        }
     }
     // set challenge ready for verification
-    for (_, url) in &challenges {
+    for url in &challenges {
         order
             .set_challenge_ready(url)
             .await
@@ -488,24 +520,113 @@ async fn new_lets_encrypt(
             message: format!("order is invalid, detail url: {detail_url:?}"),
         });
     }
+    Ok(())
+}
+
+/// Generates a new certificate from Let's Encrypt for the given domains.
+/// The ACME protocol flow:
+/// 1. Creates/retrieves an ACME account with Let's Encrypt
+/// 2. Creates a new order for the domains to be certified
+/// 3. For each domain:
+///    - Gets the HTTP-01, TLS-ALPN-01 or DNS-01 challenge details
+///    - Stores the challenge token response, installs the challenge
+///      certificate or creates the TXT record
+///    - Notifies Let's Encrypt that the challenge is ready
+/// 4. Waits for Let's Encrypt to verify domain ownership
+/// 5. Removes the TXT records of DNS-01 or the certificates of TLS-ALPN-01
+/// 6. Generates a CSR (Certificate Signing Request)
+/// 7. Submits the CSR and retrieves the signed certificate
+///
+/// Returns a tuple of (certificate_chain_pem, private_key_pem)
+async fn new_lets_encrypt(
+    storage: &'static (dyn ConfigStorage + Sync + Send),
+    domains: &[String],
+    acme: &AcmeOptions,
+) -> Result<(String, String)> {
+    let dns_challenge = new_dns_challenge(&acme.challenge)?;
+    // generate the key first to check the key type
+    let private_key = new_key_pair(&acme.key_type)?;
+    let mut domains: Vec<String> = domains.to_vec();
+    // sort domain for comparing later
+    domains.sort();
+    info!(
+        category = LOG_CATEGORY,
+        domains = domains.join(","),
+        directory = acme.directory,
+        "acme from let's encrypt"
+    );
+    let account = get_account(storage, acme).await?;
+
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &domains
+                .iter()
+                .map(|item| Identifier::Dns(item.to_owned()))
+                .collect::<Vec<Identifier>>(),
+        })
+        .await
+        .map_err(|e| Error::Instant {
+            category: "new_order".to_string(),
+            source: e,
+        })?;
 
-    // generate certificate
-    let mut names = Vec::with_capacity(challenges.len());
-    for (identifier, _) in challenges {
-        names.push(identifier.to_owned());
+    let state = order.state();
+    if !matches!(state.status, OrderStatus::Pending) {
+        return Err(Error::Fail {
+            message: format!(
+                "order is not pending, status: {:?}",
+                state.status
+            ),
+            category: "order_status".to_string(),
+        });
     }
+
+    let mut dns_records = vec![];
+    let mut tls_alpn_domains = vec![];
+    let result = validate_order(
+        storage,
+        &mut order,
+        acme,
+        dns_challenge.as_ref(),
+        &mut dns_records,
+        &mut tls_alpn_domains,
+    )
+    .await;
+    // the storage has no delete, so the certificate is cleared
+    for domain in tls_alpn_domains.iter() {
+        let key = get_tls_alpn_challenge_path(domain);
+        if let Err(e) = storage.save(&key, b"").await {
+            error!(
+                category = LOG_CATEGORY,
+                error = %e,
+                domain,
+                "cleanup tls-alpn-01 certificate fail"
+            );
+        }
+    }
+    if let Some(dns_challenge) = &dns_challenge {
+        for (fqdn, value) in dns_records.iter() {
+            if let Err(e) = dns_challenge.provider.cleanup(fqdn, value).await {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    fqdn,
+                    "cleanup dns-01 record fail"
+                );
+            }
+        }
+    }
+    result?;
+
+    // generate certificate, the wildcard domain is kept
     let mut params =
-        rcgen::CertificateParams::new(names.clone()).map_err(|e| {
+        rcgen::CertificateParams::new(domains.clone()).map_err(|e| {
             Error::Rcgen {
                 category: "new_params".to_string(),
                 source: e,
             }
         })?;
     params.distinguished_name = rcgen::DistinguishedName::new();
-    let private_key = rcgen::KeyPair::generate().map_err(|e| Error::Rcgen {
-        category: "generate_key_pair".to_string(),
-        source: e,
-    })?;
     let csr =
         params
             .serialize_request(&private_key)
@@ -540,14 +661,44 @@ mod tests {
     use pretty_assertions::assert_eq;
     use tempfile::TempDir;
 
+    #[test]
+    fn test_new_tls_alpn_challenge_certificate() {
+        let (cert, key) =
+            new_tls_alpn_challenge_certificate("pingap.io", &[1; 32]).unwrap();
+        let certificate = Certificate::new(&cert, &key).unwrap();
+        assert_eq!(vec!["pingap.io".to_string()], certificate.domains);
+
+        // the critical acmeIdentifier extension contains the digest
+        let (_, pem) =
+            x509_parser::pem::parse_x509_pem(cert.as_bytes()).unwrap();
+        let x509 = pem.parse_x509().unwrap();
+        let extension = x509
+            .extensions()
+            .iter()
+            .find(|item| item.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert_eq!(true, extension.critical);
+        let mut value = vec![0x04, 0x20];
+        value.extend_from_slice(&[1; 32]);
+        assert_eq!(value, extension.value.to_vec());
+    }
+
     #[tokio::test]
     async fn test_new_lets_encrypt() {
         let tmp = TempDir::new().unwrap();
         let path = tmp.path().to_string_lossy().to_string();
         // Create storage and leak it to extend its lifetime to 'static
         let storage = Box::leak(Box::new(FileStorage::new(&path).unwrap()));
-        let result =
-            new_lets_encrypt(storage, &["pingap.io".to_string()], false).await;
+        let result = new_lets_encrypt(
+            storage,
+            &["pingap.io".to_string()],
+            &AcmeOptions {
+                challenge: "lets_encrypt".to_string(),
+                directory: "lets_encrypt_staging".to_string(),
+                ..Default::default()
+            },
+        )
+        .await;
 
         assert_eq!(true, result.is_err());
 
@@ -66,6 +66,11 @@ pub fn get_token_path(key: &str) -> String {
     format!("pingap-acme-tokens/{key}")
 }
 
+mod account;
+mod dns_provider;
 mod lets_encrypt;
+mod rfc2136;
 
+pub use account::{get_directory_url, AcmeOptions};
+pub use dns_provider::{new_dns_challenge, DnsChallenge, DnsProvider};
 pub use lets_encrypt::{handle_lets_encrypt, new_lets_encrypt_service};
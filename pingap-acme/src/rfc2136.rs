@@ -0,0 +1,798 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::dns_provider::DnsProvider;
+use super::{Error, Result, LOG_CATEGORY};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::memcmp;
+use openssl::sign::Signer;
+use pingora::tls::hash::MessageDigest;
+use pingora::tls::pkey::PKey;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::info;
+use url::Url;
+
+const DEFAULT_TTL: u32 = 60;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Allowed time difference(seconds) between the signer and the dns server
+const TSIG_FUDGE: u16 = 300;
+
+const OPCODE_UPDATE: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+fn new_rfc2136_error(message: String) -> Error {
+    Error::Fail {
+        category: "rfc2136".to_string(),
+        message,
+    }
+}
+
+/// TSIG key for signing the dns update message
+#[derive(Debug, Clone)]
+struct TsigKey {
+    name: String,
+    algorithm: String,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    fn digest(&self) -> Result<MessageDigest> {
+        match self.algorithm.as_str() {
+            "hmac-sha1" => Ok(MessageDigest::sha1()),
+            "hmac-sha256" => Ok(MessageDigest::sha256()),
+            "hmac-sha384" => Ok(MessageDigest::sha384()),
+            "hmac-sha512" => Ok(MessageDigest::sha512()),
+            _ => Err(new_rfc2136_error(format!(
+                "tsig algorithm {} is not supported",
+                self.algorithm
+            ))),
+        }
+    }
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let map_err = |e: pingora::tls::error::ErrorStack| {
+            new_rfc2136_error(e.to_string())
+        };
+        let key = PKey::hmac(&self.secret).map_err(map_err)?;
+        let mut signer = Signer::new(self.digest()?, &key).map_err(map_err)?;
+        signer.update(data).map_err(map_err)?;
+        signer.sign_to_vec().map_err(map_err)
+    }
+}
+
+/// Dns provider which updates the TXT record through
+/// dynamic dns update(RFC 2136), the update is signed with TSIG(RFC 8945)
+/// and the TSIG of the response is verified. If the zone is not set, it's
+/// found by querying the SOA record of the name and its parents(the query
+/// is not signed).
+///
+/// e.g. `rfc2136://127.0.0.1:53?zone=pingap.io&key_name=acme&key_secret=base64&algorithm=hmac-sha256`
+#[derive(Debug)]
+pub struct Rfc2136Provider {
+    server: String,
+    zone: Option<String>,
+    ttl: u32,
+    timeout: Duration,
+    key: Option<TsigKey>,
+}
+
+impl TryFrom<&Url> for Rfc2136Provider {
+    type Error = Error;
+    fn try_from(url: &Url) -> Result<Self> {
+        let host = url.host_str().unwrap_or_default();
+        if host.is_empty() {
+            return Err(new_rfc2136_error(
+                "dns server of rfc2136 is empty".to_string(),
+            ));
+        }
+        let server = format!("{host}:{}", url.port().unwrap_or(53));
+        let mut zone = None;
+        let mut ttl = DEFAULT_TTL;
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut key_name = "".to_string();
+        let mut key_secret = "".to_string();
+        let mut algorithm = "hmac-sha256".to_string();
+        for (key, value) in url.query_pairs().into_iter() {
+            match key.as_ref() {
+                "zone" => zone = Some(value.trim_matches('.').to_string()),
+                "ttl" => {
+                    if let Ok(v) = value.parse::<u32>() {
+                        ttl = v;
+                    }
+                },
+                "timeout" => {
+                    if let Ok(d) = humantime::parse_duration(value.as_ref()) {
+                        timeout = d;
+                    }
+                },
+                "key_name" => key_name = value.to_string(),
+                "key_secret" => key_secret = value.to_string(),
+                "algorithm" => algorithm = value.to_lowercase(),
+                _ => {},
+            }
+        }
+        let key = if key_name.is_empty() {
+            None
+        } else {
+            let secret = STANDARD
+                .decode(key_secret.as_bytes())
+                .map_err(|e| new_rfc2136_error(e.to_string()))?;
+            let key = TsigKey {
+                name: key_name,
+                algorithm,
+                secret,
+            };
+            // check the algorithm is supported
+            key.digest()?;
+            Some(key)
+        };
+        Ok(Self {
+            server,
+            zone,
+            ttl,
+            timeout,
+            key,
+        })
+    }
+}
+
+/// Writes the domain name in wire format(uncompressed and lowercase)
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|item| !item.is_empty()) {
+        let label = label.to_lowercase();
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Writes the resource record of TXT
+fn write_txt_record(
+    buf: &mut Vec<u8>,
+    name: &str,
+    class: u16,
+    ttl: u32,
+    value: &str,
+) {
+    write_name(buf, name);
+    buf.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Returns the data signed by TSIG: the mac of request(only for response),
+/// the message and the TSIG variables.
+fn new_tsig_data(
+    message: &[u8],
+    key: &TsigKey,
+    request_mac: &[u8],
+    time_signed: u64,
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut data = vec![];
+    if !request_mac.is_empty() {
+        data.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(request_mac);
+    }
+    data.extend_from_slice(message);
+    write_name(&mut data, &key.name);
+    data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    write_name(&mut data, &key.algorithm);
+    data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    data.extend_from_slice(&fudge.to_be_bytes());
+    data.extend_from_slice(&error.to_be_bytes());
+    data.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    data.extend_from_slice(other);
+    data
+}
+
+/// Appends the TSIG record to the message and increases the additional count,
+/// the request mac should be set if the message is a response.
+/// Returns the mac of the message.
+fn sign_message(
+    message: &mut Vec<u8>,
+    key: &TsigKey,
+    time_signed: u64,
+    request_mac: &[u8],
+) -> Result<Vec<u8>> {
+    let id = [message[0], message[1]];
+    let data = new_tsig_data(
+        message,
+        key,
+        request_mac,
+        time_signed,
+        TSIG_FUDGE,
+        0,
+        &[],
+    );
+    let mac = key.sign(&data)?;
+
+    let mut rdata = vec![];
+    write_name(&mut rdata, &key.algorithm);
+    rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    rdata.extend_from_slice(&id);
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+
+    write_name(message, &key.name);
+    message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+    message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+
+    let count = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&count.to_be_bytes());
+    Ok(mac)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let data = buf.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// Returns the offset after the domain name, the compressed name is supported
+fn skip_name(buf: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let size = *buf.get(offset)? as usize;
+        if size == 0 {
+            return Some(offset + 1);
+        }
+        // compression pointer
+        if size & 0xc0 == 0xc0 {
+            buf.get(offset + 1)?;
+            return Some(offset + 2);
+        }
+        offset += 1 + size;
+    }
+}
+
+/// Returns the offset after the question(zone) section
+fn skip_questions(buf: &[u8]) -> Option<usize> {
+    let mut offset = 12;
+    for _ in 0..read_u16(buf, 4)? {
+        offset = skip_name(buf, offset)? + 4;
+    }
+    Some(offset)
+}
+
+/// Returns the offset after the resource record
+fn skip_record(buf: &[u8], offset: usize) -> Option<usize> {
+    let offset = skip_name(buf, offset)?;
+    let size = read_u16(buf, offset + 8)? as usize;
+    Some(offset + 10 + size)
+}
+
+/// TSIG record of the message
+#[derive(Debug)]
+struct TsigRecord {
+    /// Offset of the record in the message
+    offset: usize,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+/// Parses the TSIG record, it should be the last additional record
+fn parse_tsig(buf: &[u8]) -> Option<TsigRecord> {
+    let additional = read_u16(buf, 10)? as usize;
+    if additional == 0 {
+        return None;
+    }
+    let records =
+        read_u16(buf, 6)? as usize + read_u16(buf, 8)? as usize + additional
+            - 1;
+    let mut offset = skip_questions(buf)?;
+    for _ in 0..records {
+        offset = skip_record(buf, offset)?;
+    }
+    let start = offset;
+    offset = skip_name(buf, offset)?;
+    if read_u16(buf, offset)? != TYPE_TSIG {
+        return None;
+    }
+    // type, class, ttl and rdata length
+    offset = skip_name(buf, offset + 10)?;
+    let time_signed = buf
+        .get(offset..offset + 6)?
+        .iter()
+        .fold(0u64, |acc, v| (acc << 8) | *v as u64);
+    let fudge = read_u16(buf, offset + 6)?;
+    let mac_size = read_u16(buf, offset + 8)? as usize;
+    offset += 10;
+    let mac = buf.get(offset..offset + mac_size)?.to_vec();
+    offset += mac_size;
+    let original_id = read_u16(buf, offset)?;
+    let error = read_u16(buf, offset + 2)?;
+    let other_size = read_u16(buf, offset + 4)? as usize;
+    let other = buf.get(offset + 6..offset + 6 + other_size)?.to_vec();
+    Some(TsigRecord {
+        offset: start,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+    })
+}
+
+/// Verifies the TSIG of the response with the mac of request,
+/// so the forged response of update can't be accepted.
+fn verify_response(
+    response: &[u8],
+    request_mac: &[u8],
+    key: &TsigKey,
+) -> Result<()> {
+    let tsig = parse_tsig(response).ok_or_else(|| {
+        new_rfc2136_error("tsig of dns update response is missing".to_string())
+    })?;
+    if tsig.error != 0 {
+        return Err(new_rfc2136_error(format!(
+            "tsig of dns update response is invalid, error: {}",
+            tsig.error
+        )));
+    }
+    if pingap_util::now_sec().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(new_rfc2136_error(
+            "tsig time of dns update response is out of range".to_string(),
+        ));
+    }
+    // the mac is calculated with the original id and without tsig record
+    let mut message = response[..tsig.offset].to_vec();
+    message[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let count = read_u16(&message, 10).unwrap_or_default().saturating_sub(1);
+    message[10..12].copy_from_slice(&count.to_be_bytes());
+    let data = new_tsig_data(
+        &message,
+        key,
+        request_mac,
+        tsig.time_signed,
+        tsig.fudge,
+        tsig.error,
+        &tsig.other,
+    );
+    let mac = key.sign(&data)?;
+    if mac.len() != tsig.mac.len() || !memcmp::eq(&mac, &tsig.mac) {
+        return Err(new_rfc2136_error(
+            "tsig mac of dns update response is invalid".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns whether the first answer of the response is SOA record
+fn has_soa_answer(response: &[u8]) -> bool {
+    let rcode = response.get(3).map(|v| v & 0x0f);
+    if rcode != Some(0) || read_u16(response, 6).unwrap_or_default() == 0 {
+        return false;
+    }
+    skip_questions(response)
+        .and_then(|offset| skip_name(response, offset))
+        .and_then(|offset| read_u16(response, offset))
+        == Some(TYPE_SOA)
+}
+
+fn new_message_id() -> u16 {
+    (pingap_util::now_ms() & 0xffff) as u16
+}
+
+/// Returns the name of response code
+fn get_rcode_name(rcode: u8) -> String {
+    match rcode {
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        _ => format!("RCODE{rcode}"),
+    }
+}
+
+impl Rfc2136Provider {
+    /// Returns the zone of the record, if the zone is not set, the name
+    /// and its parents are queried until the SOA record is found.
+    async fn find_zone(&self, fqdn: &str) -> Result<String> {
+        if let Some(zone) = &self.zone {
+            return Ok(zone.clone());
+        }
+        let mut name = fqdn.trim_end_matches('.');
+        loop {
+            let id = new_message_id();
+            let mut message = vec![];
+            message.extend_from_slice(&id.to_be_bytes());
+            // standard query with recursion desired
+            message.extend_from_slice(&0x0100u16.to_be_bytes());
+            for count in [1u16, 0, 0, 0] {
+                message.extend_from_slice(&count.to_be_bytes());
+            }
+            write_name(&mut message, name);
+            message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            let response = self.exchange(id, &message).await?;
+            if has_soa_answer(&response) {
+                return Ok(name.to_string());
+            }
+            let Some((_, parent)) = name.split_once('.') else {
+                break;
+            };
+            name = parent;
+        }
+        Err(new_rfc2136_error(format!("zone of {fqdn} is not found")))
+    }
+    /// Builds the update message, the record is added or deleted.
+    /// Returns the message and its mac(empty if the key is not set).
+    fn new_update_message(
+        &self,
+        id: u16,
+        zone: &str,
+        fqdn: &str,
+        value: &str,
+        add: bool,
+        time_signed: u64,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut message = vec![];
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+        // zone count, prerequisite count, update count, additional count
+        for count in [1u16, 0, 1, 0] {
+            message.extend_from_slice(&count.to_be_bytes());
+        }
+        write_name(&mut message, zone);
+        message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        if add {
+            write_txt_record(&mut message, fqdn, CLASS_IN, self.ttl, value);
+        } else {
+            write_txt_record(&mut message, fqdn, CLASS_NONE, 0, value);
+        }
+        let mac = if let Some(key) = &self.key {
+            sign_message(&mut message, key, time_signed, &[])?
+        } else {
+            vec![]
+        };
+        Ok((message, mac))
+    }
+    /// Sends the message to the dns server and returns the response
+    async fn exchange(&self, id: u16, message: &[u8]) -> Result<Vec<u8>> {
+        let map_err = |e: std::io::Error| new_rfc2136_error(e.to_string());
+        let bind_addr = if self.server.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await.map_err(map_err)?;
+        socket.connect(&self.server).await.map_err(map_err)?;
+        socket.send(message).await.map_err(map_err)?;
+
+        let mut buf = [0; 512];
+        let size = tokio::time::timeout(self.timeout, socket.recv(&mut buf))
+            .await
+            .map_err(|_| {
+                new_rfc2136_error(format!(
+                    "dns request of {} is timeout",
+                    self.server
+                ))
+            })?
+            .map_err(map_err)?;
+        if size < 12 || buf[0..2] != id.to_be_bytes() {
+            return Err(new_rfc2136_error(
+                "dns response is invalid".to_string(),
+            ));
+        }
+        Ok(buf[..size].to_vec())
+    }
+    async fn update(&self, fqdn: &str, value: &str, add: bool) -> Result<()> {
+        let zone = self.find_zone(fqdn).await?;
+        let id = new_message_id();
+        let (message, mac) = self.new_update_message(
+            id,
+            &zone,
+            fqdn,
+            value,
+            add,
+            pingap_util::now_sec(),
+        )?;
+        let response = self.exchange(id, &message).await?;
+        let rcode = response[3] & 0x0f;
+        if rcode != 0 {
+            return Err(new_rfc2136_error(format!(
+                "dns update of {fqdn} fail, rcode: {}",
+                get_rcode_name(rcode)
+            )));
+        }
+        if let Some(key) = &self.key {
+            verify_response(&response, &mac, key)?;
+        }
+        info!(
+            category = LOG_CATEGORY,
+            fqdn,
+            add,
+            server = self.server,
+            "rfc2136 dns update success"
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Rfc2136Provider {
+    async fn present(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.update(fqdn, value, true).await
+    }
+    async fn cleanup(&self, fqdn: &str, value: &str) -> Result<()> {
+        self.update(fqdn, value, false).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn new_provider(server: &str) -> Rfc2136Provider {
+        let url = Url::parse(&format!(
+            "rfc2136://{server}?zone=pingap.io.&key_name=acme&key_secret={}&ttl=120&timeout=1s",
+            STANDARD.encode(b"pingap")
+        ))
+        .unwrap();
+        Rfc2136Provider::try_from(&url).unwrap()
+    }
+
+    #[test]
+    fn test_new_rfc2136_provider() {
+        let provider = new_provider("127.0.0.1:5353");
+        assert_eq!("127.0.0.1:5353", provider.server);
+        assert_eq!(Some("pingap.io".to_string()), provider.zone);
+        assert_eq!(120, provider.ttl);
+        assert_eq!(Duration::from_secs(1), provider.timeout);
+        let key = provider.key.unwrap();
+        assert_eq!("acme", key.name);
+        assert_eq!("hmac-sha256", key.algorithm);
+        assert_eq!(b"pingap".to_vec(), key.secret);
+
+        let url = Url::parse("rfc2136://127.0.0.1?algorithm=hmac-md5&key_name=acme&key_secret=cGluZ2Fw").unwrap();
+        assert_eq!(
+            "Let's Encrypt operation failed: tsig algorithm hmac-md5 is not supported, category: rfc2136",
+            Rfc2136Provider::try_from(&url).unwrap_err().to_string()
+        );
+
+        let url = Url::parse("rfc2136://127.0.0.1").unwrap();
+        let provider = Rfc2136Provider::try_from(&url).unwrap();
+        assert_eq!("127.0.0.1:53", provider.server);
+        assert_eq!(true, provider.key.is_none());
+        assert_eq!(true, provider.zone.is_none());
+    }
+
+    #[test]
+    fn test_new_update_message() {
+        let provider = new_provider("127.0.0.1:53");
+        let (message, request_mac) = provider
+            .new_update_message(
+                1,
+                "pingap.io",
+                "_acme-challenge.pingap.io.",
+                "abc",
+                true,
+                1735689600,
+            )
+            .unwrap();
+        // header: id, flags, zone count, prerequisite count, update count, additional count
+        assert_eq!(
+            vec![0, 1, 40, 0, 0, 1, 0, 0, 0, 1, 0, 1],
+            message[..12].to_vec()
+        );
+        // zone
+        assert_eq!(
+            b"\x06pingap\x02io\x00\x00\x06\x00\x01".to_vec(),
+            message[12..27].to_vec()
+        );
+        // txt record
+        assert_eq!(
+            b"\x0f_acme-challenge\x06pingap\x02io\x00\x00\x10\x00\x01\x00\x00\x00\x78\x00\x04\x03abc".to_vec(),
+            message[27..68].to_vec()
+        );
+        // tsig record
+        assert_eq!(
+            b"\x04acme\x00\x00\xfa\x00\xff\x00\x00\x00\x00".to_vec(),
+            message[68..82].to_vec()
+        );
+
+        // verify the mac of message
+        let key = provider.key.as_ref().unwrap();
+        let mut data = message[..68].to_vec();
+        data[11] = 0;
+        data.extend_from_slice(b"\x04acme\x00\x00\xff\x00\x00\x00\x00");
+        data.extend_from_slice(b"\x0bhmac-sha256\x00");
+        data.extend_from_slice(&1735689600u64.to_be_bytes()[2..]);
+        data.extend_from_slice(&[1, 44, 0, 0, 0, 0]);
+        let mac = key.sign(&data).unwrap();
+        let rdata = &message[84..];
+        let mac_offset = 13 + 6 + 2 + 2;
+        assert_eq!(32, u16::from_be_bytes([rdata[21], rdata[22]]));
+        assert_eq!(mac, rdata[mac_offset..mac_offset + 32].to_vec());
+        assert_eq!(mac, request_mac);
+        assert_eq!(vec![0, 1, 0, 0, 0, 0], rdata[mac_offset + 32..].to_vec());
+
+        let (message, _) = provider
+            .new_update_message(
+                1,
+                "pingap.io",
+                "_acme-challenge.pingap.io",
+                "abc",
+                false,
+                1735689600,
+            )
+            .unwrap();
+        // the record is deleted with class none and ttl 0
+        assert_eq!(
+            b"\x00\x10\x00\xfe\x00\x00\x00\x00".to_vec(),
+            message[54..62].to_vec()
+        );
+    }
+
+    // Returns the signed response of the signed request
+    fn new_signed_response(
+        request: &[u8],
+        key: &TsigKey,
+        rcode: u8,
+    ) -> Vec<u8> {
+        let request_mac = parse_tsig(request).unwrap().mac;
+        let mut response = request[..12].to_vec();
+        response[2] |= 0x80;
+        response[3] = rcode;
+        response[4..12].fill(0);
+        sign_message(&mut response, key, pingap_util::now_sec(), &request_mac)
+            .unwrap();
+        response
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let provider = new_provider("127.0.0.1:53");
+        let key = provider.key.as_ref().unwrap();
+        let (request, request_mac) = provider
+            .new_update_message(
+                1,
+                "pingap.io",
+                "_acme-challenge.pingap.io",
+                "abc",
+                true,
+                pingap_util::now_sec(),
+            )
+            .unwrap();
+        let tsig = parse_tsig(&request).unwrap();
+        assert_eq!(68, tsig.offset);
+        assert_eq!(request_mac, tsig.mac);
+        assert_eq!(1, tsig.original_id);
+
+        let mut response = new_signed_response(&request, key, 0);
+        assert_eq!(true, verify_response(&response, &request_mac, key).is_ok());
+
+        // the mac of other request
+        assert_eq!(
+            "Let's Encrypt operation failed: tsig mac of dns update response is invalid, category: rfc2136",
+            verify_response(&response, &[0; 32], key)
+                .unwrap_err()
+                .to_string()
+        );
+        // the response is modified
+        response[3] = 5;
+        assert_eq!(
+            true,
+            verify_response(&response, &request_mac, key).is_err()
+        );
+        // the response is not signed
+        assert_eq!(
+            "Let's Encrypt operation failed: tsig of dns update response is missing, category: rfc2136",
+            verify_response(&response[..12], &request_mac, key)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_zone() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "rfc2136://{}?timeout=1s",
+            server.local_addr().unwrap()
+        ))
+        .unwrap();
+        let provider = Rfc2136Provider::try_from(&url).unwrap();
+        tokio::spawn(async move {
+            let mut zone = vec![];
+            write_name(&mut zone, "pingap.io");
+            let mut buf = [0; 512];
+            while let Ok((size, addr)) = server.recv_from(&mut buf).await {
+                let mut resp = buf[..size].to_vec();
+                resp[2] |= 0x80;
+                if resp[12..size - 4] == zone {
+                    resp[7] = 1;
+                    // answer of SOA with compressed name
+                    resp.extend_from_slice(&[0xc0, 0x0c, 0, 6, 0, 1]);
+                    resp.extend_from_slice(&[0, 0, 0, 60, 0, 0]);
+                } else {
+                    // nxdomain
+                    resp[3] = 3;
+                }
+                server.send_to(&resp, addr).await.unwrap();
+            }
+        });
+        assert_eq!(
+            "pingap.io",
+            provider
+                .find_zone("_acme-challenge.www.pingap.io.")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "Let's Encrypt operation failed: zone of _acme-challenge.pingap.dev is not found, category: rfc2136",
+            provider
+                .find_zone("_acme-challenge.pingap.dev")
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rfc2136_update() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let provider = new_provider(&server.local_addr().unwrap().to_string());
+        let key = provider.key.clone().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            for rcode in [0u8, 5] {
+                let (size, addr) = server.recv_from(&mut buf).await.unwrap();
+                let resp = new_signed_response(&buf[..size], &key, rcode);
+                server.send_to(&resp, addr).await.unwrap();
+            }
+        });
+        provider
+            .present("_acme-challenge.pingap.io", "abc")
+            .await
+            .unwrap();
+        assert_eq!(
+            "Let's Encrypt operation failed: dns update of _acme-challenge.pingap.io fail, rcode: REFUSED, category: rfc2136",
+            provider
+                .cleanup("_acme-challenge.pingap.io", "abc")
+                .await
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
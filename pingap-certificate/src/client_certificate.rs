@@ -0,0 +1,165 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Error;
+use pingora::listeners::tls::TlsSettings;
+use pingora::tls::ssl::SslVerifyMode;
+use pingora::tls::x509::store::X509StoreBuilder;
+use pingora::tls::x509::{X509Ref, X509};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Identity of the verified client certificate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientCertificateInfo {
+    /// Subject of the certificate, e.g. "CN=client,O=pingap"
+    pub subject: String,
+    /// Subject alternative names, e.g. "DNS:client.pingap.io,IP:127.0.0.1"
+    pub san: String,
+}
+
+fn get_subject(cert: &X509Ref) -> String {
+    cert.subject_name()
+        .entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or_default();
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn get_san(cert: &X509Ref) -> String {
+    let Some(names) = cert.subject_alt_names() else {
+        return "".to_string();
+    };
+    names
+        .iter()
+        .filter_map(|name| {
+            if let Some(value) = name.dnsname() {
+                return Some(format!("DNS:{value}"));
+            }
+            if let Some(value) = name.ipaddress() {
+                let ip = super::parse_ip_addr(value).ok()?;
+                return Some(format!("IP:{ip}"));
+            }
+            if let Some(value) = name.email() {
+                return Some(format!("email:{value}"));
+            }
+            name.uri().map(|value| format!("URI:{value}"))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Returns the identity of the client certificate,
+/// it should be the peer certificate of the verified connection.
+pub fn get_client_certificate_info(cert: &X509Ref) -> ClientCertificateInfo {
+    ClientCertificateInfo {
+        subject: get_subject(cert),
+        san: get_san(cert),
+    }
+}
+
+/// Enables the verification of client certificate against the ca certificates,
+/// the handshake fails without client certificate if it's required.
+pub(crate) fn set_client_verify(
+    tls_settings: &mut TlsSettings,
+    server_name: &str,
+    client_ca: &[u8],
+    required: bool,
+) -> Result<()> {
+    let new_error = |message: String| Error::Invalid {
+        category: "client_ca".to_string(),
+        message,
+    };
+    let certs = X509::stack_from_pem(client_ca)
+        .map_err(|e| new_error(e.to_string()))?;
+    if certs.is_empty() {
+        return Err(new_error("client ca is empty".to_string()));
+    }
+    let mut store =
+        X509StoreBuilder::new().map_err(|e| new_error(e.to_string()))?;
+    for cert in certs.iter() {
+        store
+            .add_cert(cert.clone())
+            .map_err(|e| new_error(e.to_string()))?;
+        // the ca names are sent to client for selecting certificate
+        tls_settings
+            .add_client_ca(cert)
+            .map_err(|e| new_error(e.to_string()))?;
+    }
+    tls_settings
+        .set_verify_cert_store(store.build())
+        .map_err(|e| new_error(e.to_string()))?;
+    // session resumption fails without session id context if verify peer
+    tls_settings
+        .set_session_id_context(server_name.as_bytes())
+        .map_err(|e| new_error(e.to_string()))?;
+    let mut mode = SslVerifyMode::PEER;
+    if required {
+        mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+    }
+    tls_settings.set_verify(mode);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_client_certificate_info() {
+        let certified = rcgen::generate_simple_self_signed(vec![
+            "client.pingap.io".to_string(),
+            "127.0.0.1".to_string(),
+        ])
+        .unwrap();
+        let cert = X509::from_pem(certified.cert.pem().as_bytes()).unwrap();
+        let info = get_client_certificate_info(&cert);
+        assert_eq!("CN=rcgen self signed cert", info.subject);
+        assert_eq!("DNS:client.pingap.io,IP:127.0.0.1", info.san);
+    }
+
+    #[test]
+    fn test_set_client_verify() {
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["pingap.io".to_string()])
+                .unwrap();
+        let mut params = crate::TlsSettingParams {
+            server_name: "pingap".to_string(),
+            enabled_h2: false,
+            cipher_list: None,
+            cipher_suites: None,
+            tls_min_version: None,
+            tls_max_version: None,
+            client_ca: Some(certified.cert.pem().into_bytes()),
+            client_auth_required: true,
+        };
+        let dynamic = crate::GlobalCertificate::default();
+        assert_eq!(true, dynamic.new_tls_settings(&params).is_ok());
+
+        params.client_ca = Some(b"pingap".to_vec());
+        assert_eq!(
+            "Invalid error, category: client_ca, client ca is empty",
+            dynamic.new_tls_settings(&params).err().unwrap().to_string()
+        );
+    }
+}
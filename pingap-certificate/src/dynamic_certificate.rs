@@ -12,6 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::client_certificate::set_client_verify;
+use super::ocsp::get_ocsp_response;
+use super::tls_alpn_challenge::{
+    load_tls_alpn_challenge_certificate, on_client_hello, select_alpn,
+    set_tls_alpn_challenge_installed,
+};
 use super::{Certificate, Error, TlsCertificate, LOG_CATEGORY};
 use ahash::AHashMap;
 use arc_swap::ArcSwap;
@@ -162,6 +168,8 @@ pub struct TlsSettingParams {
     pub cipher_suites: Option<String>, // Modern cipher suites
     pub tls_min_version: Option<String>, // Minimum TLS version
     pub tls_max_version: Option<String>, // Maximum TLS version
+    pub client_ca: Option<Vec<u8>>,  // CA certificates to verify client
+    pub client_auth_required: bool,  // Client certificate is required
 }
 
 /// Applies certificate, private key and chain certificate to an SSL context
@@ -237,6 +245,15 @@ impl GlobalCertificate {
         if params.enabled_h2 {
             tls_settings.enable_h2();
         }
+        // the alpn callback is replaced to support acme-tls/1 challenge,
+        // the offer of acme-tls/1 is marked in client hello as the
+        // certificate callback is called before the alpn is selected
+        tls_settings
+            .set_client_hello_callback(|ssl, _| Ok(on_client_hello(ssl)));
+        let enabled_h2 = params.enabled_h2;
+        tls_settings.set_alpn_select_callback(move |ssl, client| {
+            select_alpn(ssl, client, enabled_h2)
+        });
         if let Some(cipher_list) = &params.cipher_list {
             if let Err(e) = tls_settings.set_cipher_list(cipher_list) {
                 error!(category = LOG_CATEGORY, error = %e, name, "set cipher list fail");
@@ -263,6 +280,28 @@ impl GlobalCertificate {
             error!(category = LOG_CATEGORY, error = %e, name, "set tls max proto version fail");
         }
 
+        // the stapled OCSP response is set in certificate callback
+        if let Err(e) = tls_settings
+            .set_status_callback(|ssl| Ok(ssl.ocsp_status().is_some()))
+        {
+            error!(category = LOG_CATEGORY, error = %e, name, "set ocsp status callback fail");
+        }
+
+        if let Some(client_ca) = &params.client_ca {
+            set_client_verify(
+                &mut tls_settings,
+                &name,
+                client_ca,
+                params.client_auth_required,
+            )?;
+            info!(
+                category = LOG_CATEGORY,
+                name,
+                required = params.client_auth_required,
+                "client certificate verification is enabled"
+            );
+        }
+
         // tls_settings.set_min_proto_version(version)
         if let Some(min_version) = tls_settings.min_proto_version() {
             info!(
@@ -306,6 +345,17 @@ impl pingora::listeners::TlsAccept for GlobalCertificate {
             server_name = sni
         );
 
+        // tls-alpn-01 challenge of acme
+        if let Some(cert) = load_tls_alpn_challenge_certificate(ssl).await {
+            info!(
+                category = LOG_CATEGORY,
+                sni, "serve tls-alpn-01 challenge certificate"
+            );
+            ssl_certificate(ssl, &cert.x509, &cert.key, &None);
+            set_tls_alpn_challenge_installed(ssl);
+            return;
+        }
+
         let mut dynamic_certificate = None;
         let certs = DYNAMIC_CERTIFICATE_MAP.load();
         let wildcard_sni =
@@ -348,6 +398,13 @@ impl pingora::listeners::TlsAccept for GlobalCertificate {
         if let Some((cert, key)) = &d.certificate {
             ssl_certificate(ssl, cert, key, &d.chain_certificate);
         }
+        if let Some(ocsp_response) =
+            d.ocsp_key.as_deref().and_then(get_ocsp_response)
+        {
+            if let Err(e) = ssl.set_ocsp_status(&ocsp_response) {
+                error!(category = LOG_CATEGORY, error = %e, "ssl set ocsp status fail");
+            }
+        }
     }
 }
 
@@ -451,6 +508,8 @@ aqcrKJfS+xaKWxXPiNlpBMG5
                 ),
                 tls_min_version: Some("tlsv1.1".to_string()),
                 tls_max_version: Some("tlsv1.3".to_string()),
+                client_ca: None,
+                client_auth_required: false,
             })
             .unwrap();
         assert_eq!(true, tls_settings.min_proto_version().is_some());
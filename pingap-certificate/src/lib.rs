@@ -17,8 +17,11 @@ use snafu::Snafu;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 mod chain;
+mod client_certificate;
 mod dynamic_certificate;
+mod ocsp;
 mod self_signed;
+mod tls_alpn_challenge;
 mod tls_certificate;
 mod validity_checker;
 
@@ -186,11 +189,18 @@ impl Certificate {
     }
 }
 
+pub use client_certificate::{
+    get_client_certificate_info, ClientCertificateInfo,
+};
 pub use dynamic_certificate::{
     get_certificate_info_list, list_certificates, try_update_certificates,
     GlobalCertificate, TlsSettingParams,
 };
+pub use ocsp::new_ocsp_stapling_service;
 pub use self_signed::new_self_signed_certificate_validity_service;
+pub use tls_alpn_challenge::{
+    get_tls_alpn_challenge_path, ACME_TLS_ALPN_PROTOCOL,
+};
 pub use tls_certificate::TlsCertificate;
 pub use validity_checker::new_certificate_validity_service;
 
@@ -0,0 +1,444 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{list_certificates, Error, TlsCertificate, LOG_CATEGORY};
+use ahash::AHashMap;
+use once_cell::sync::Lazy;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse,
+    OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use pingap_core::Error as ServiceError;
+use pingap_core::{
+    NotificationData, NotificationLevel, NotificationSender,
+    SimpleServiceTaskFuture,
+};
+use pingora::tls::error::ErrorStack;
+use pingora::tls::hash::MessageDigest;
+use pingora::tls::x509::store::X509StoreBuilder;
+use pingora::tls::x509::verify::X509VerifyFlags;
+use pingora::tls::x509::{X509Ref, X509};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Check interval in minutes
+const CHECK_INTERVAL_MINUTES: u32 = 10;
+/// Refresh interval of the response if its next update is unknown
+const DEFAULT_REFRESH_SECONDS: i64 = 3600;
+/// Timeout of requesting the OCSP responder
+const OCSP_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Category of the ocsp error and notification
+const OCSP_CATEGORY: &str = "ocsp_stapling";
+
+/// OCSP response of the certificate for stapling
+#[derive(Debug, Clone)]
+struct OcspStaple {
+    /// DER encoded OCSP response
+    der: Vec<u8>,
+    /// Unix timestamp when the response should be refreshed
+    refresh_at: i64,
+    /// Unix timestamp when the response expires
+    next_update: i64,
+}
+
+// Http client of the OCSP responders, the connections are reused
+static OCSP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+// The OCSP responses of certificates, the key is the fingerprint of certificate
+static OCSP_STAPLES: Lazy<RwLock<AHashMap<String, Arc<OcspStaple>>>> =
+    Lazy::new(|| RwLock::new(AHashMap::new()));
+
+fn new_ocsp_error(message: String) -> Error {
+    Error::Invalid {
+        category: OCSP_CATEGORY.to_string(),
+        message,
+    }
+}
+
+/// Returns the fingerprint(sha256) of certificate, it's the key of OCSP response
+pub(crate) fn get_ocsp_key(cert: &X509Ref) -> Option<String> {
+    let digest = cert.digest(MessageDigest::sha256()).ok()?;
+    Some(digest.iter().map(|v| format!("{v:02x}")).collect())
+}
+
+/// Returns the unexpired OCSP response of the certificate for stapling
+pub(crate) fn get_ocsp_response(key: &str) -> Option<Vec<u8>> {
+    let staples = OCSP_STAPLES.read().ok()?;
+    let staple = staples.get(key)?;
+    if staple.next_update <= pingap_util::now_sec() as i64 {
+        return None;
+    }
+    Some(staple.der.clone())
+}
+
+fn set_ocsp_staple(key: &str, staple: OcspStaple) {
+    if let Ok(mut staples) = OCSP_STAPLES.write() {
+        staples.insert(key.to_string(), Arc::new(staple));
+    }
+}
+
+fn get_ocsp_staple(key: &str) -> Option<Arc<OcspStaple>> {
+    OCSP_STAPLES.read().ok()?.get(key).cloned()
+}
+
+// Converts the asn1 generalized time(e.g. "Jun  1 12:00:00 2025 GMT") to unix timestamp
+fn parse_asn1_time(value: &str) -> Option<i64> {
+    let format = time::macros::format_description!(
+        "[month repr:short] [day padding:space] [hour]:[minute]:[second] [year] GMT"
+    );
+    time::PrimitiveDateTime::parse(value, &format)
+        .ok()
+        .map(|value| value.assume_utc().unix_timestamp())
+}
+
+// Returns the issuer of certificate, it's the chain certificate or
+// the second certificate of the pem
+fn get_issuer(cert: &TlsCertificate) -> Option<X509> {
+    if let Some(chain) = &cert.chain_certificate {
+        return Some(chain.clone());
+    }
+    let info = cert.info.as_ref()?;
+    X509::stack_from_pem(&info.get_cert())
+        .ok()?
+        .into_iter()
+        .nth(1)
+}
+
+/// Parses and verifies the OCSP response of the certificate, the response
+/// should be signed by the issuer or the responder certificate issued by it.
+/// The issuer is trusted directly, so the response of the certificate issued
+/// by the intermediate certificate can be verified without the root.
+fn parse_ocsp_response(
+    der: &[u8],
+    cert: &X509Ref,
+    issuer: &X509Ref,
+) -> Result<OcspStaple> {
+    let map_err = |e: ErrorStack| new_ocsp_error(e.to_string());
+    let response = OcspResponse::from_der(der).map_err(map_err)?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(new_ocsp_error(format!(
+            "ocsp response status is {}",
+            response.status().as_raw()
+        )));
+    }
+    let basic = response.basic().map_err(map_err)?;
+    let mut certs = Stack::new().map_err(map_err)?;
+    certs.push(issuer.to_owned()).map_err(map_err)?;
+    let mut store = X509StoreBuilder::new().map_err(map_err)?;
+    store.add_cert(issuer.to_owned()).map_err(map_err)?;
+    // the chain of delegated responder ends with the issuer
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(map_err)?;
+    basic
+        .verify(&certs, &store.build(), OcspFlag::TRUST_OTHER)
+        .map_err(map_err)?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)
+        .map_err(map_err)?;
+    let status = basic.find_status(&id).ok_or_else(|| {
+        new_ocsp_error("ocsp status is not found".to_string())
+    })?;
+    if status.status != OcspCertStatus::GOOD {
+        return Err(new_ocsp_error(format!(
+            "certificate status is {}",
+            status.status.as_raw()
+        )));
+    }
+    status.check_validity(300, None).map_err(map_err)?;
+
+    let this_update = parse_asn1_time(&status.this_update.to_string())
+        .unwrap_or_else(|| pingap_util::now_sec() as i64);
+    let (next_update, refresh_at) = if let Some(next_update) =
+        parse_asn1_time(&status.next_update.to_string())
+    {
+        // refresh at the half of the validity period
+        (next_update, this_update + (next_update - this_update) / 2)
+    } else {
+        (i64::MAX, this_update + DEFAULT_REFRESH_SECONDS)
+    };
+    Ok(OcspStaple {
+        der: der.to_vec(),
+        refresh_at,
+        next_update,
+    })
+}
+
+/// Fetches the OCSP response from the responder of the certificate
+async fn fetch_ocsp_response(
+    cert: &X509Ref,
+    issuer: &X509Ref,
+) -> Result<Vec<u8>> {
+    let map_err = |e: ErrorStack| new_ocsp_error(e.to_string());
+    let responders = cert.ocsp_responders().map_err(map_err)?;
+    let Some(url) = responders.iter().next().map(|item| item.to_string())
+    else {
+        return Err(new_ocsp_error(
+            "ocsp responder of certificate is not found".to_string(),
+        ));
+    };
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)
+        .map_err(map_err)?;
+    let mut request = OcspRequest::new().map_err(map_err)?;
+    request.add_id(id).map_err(map_err)?;
+    let body = request.to_der().map_err(map_err)?;
+
+    let resp = OCSP_CLIENT
+        .post(&url)
+        .header("Content-Type", "application/ocsp-request")
+        .timeout(OCSP_REQUEST_TIMEOUT)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| new_ocsp_error(e.to_string()))?;
+    if !resp.status().is_success() {
+        return Err(new_ocsp_error(format!(
+            "ocsp responder({url}) response status is {}",
+            resp.status()
+        )));
+    }
+    let data = resp
+        .bytes()
+        .await
+        .map_err(|e| new_ocsp_error(e.to_string()))?;
+    Ok(data.to_vec())
+}
+
+/// Returns the OCSP response of certificate, it's loaded from the cache
+/// directory if valid, otherwise it's fetched from the responder.
+async fn update_ocsp_staple(
+    cache_dir: &PathBuf,
+    key: &str,
+    cert: &TlsCertificate,
+) -> Result<bool> {
+    let now = pingap_util::now_sec() as i64;
+    if get_ocsp_staple(key).is_some_and(|staple| staple.refresh_at > now) {
+        return Ok(false);
+    }
+    let Some((leaf, _)) = &cert.certificate else {
+        return Ok(false);
+    };
+    let issuer = get_issuer(cert).ok_or_else(|| {
+        new_ocsp_error("issuer certificate is not found".to_string())
+    })?;
+    let file = cache_dir.join(format!("{key}.der"));
+    // the cached response is used after restart
+    if let Ok(der) = tokio::fs::read(&file).await {
+        if let Ok(staple) = parse_ocsp_response(&der, leaf, &issuer) {
+            if staple.refresh_at > now {
+                set_ocsp_staple(key, staple);
+                return Ok(true);
+            }
+        }
+    }
+    let der = fetch_ocsp_response(leaf, &issuer).await?;
+    let staple = parse_ocsp_response(&der, leaf, &issuer)?;
+    if let Err(e) = tokio::fs::create_dir_all(cache_dir).await {
+        error!(category = LOG_CATEGORY, error = %e, "create ocsp cache directory fail");
+    } else if let Err(e) = tokio::fs::write(&file, &der).await {
+        error!(category = LOG_CATEGORY, error = %e, "write ocsp response fail");
+    }
+    set_ocsp_staple(key, staple);
+    Ok(true)
+}
+
+/// Refreshes the OCSP responses of certificates with stapling enabled,
+/// and sends notification for the failures.
+async fn do_ocsp_update(
+    count: u32,
+    cache_dir: PathBuf,
+    sender: Option<Arc<NotificationSender>>,
+) -> Result<bool, ServiceError> {
+    if count % CHECK_INTERVAL_MINUTES != 0 {
+        return Ok(false);
+    }
+    let mut errors = vec![];
+    let mut updated = AHashMap::new();
+    for cert in list_certificates().values() {
+        let Some(key) = &cert.ocsp_key else {
+            continue;
+        };
+        // the certificate of multiple domains is only updated once
+        if updated.insert(key.clone(), ()).is_some() {
+            continue;
+        }
+        let name = cert.name.clone().unwrap_or_default();
+        match update_ocsp_staple(&cache_dir, key, cert).await {
+            Ok(true) => {
+                info!(
+                    category = LOG_CATEGORY,
+                    name, "ocsp response is updated"
+                );
+            },
+            Ok(false) => {},
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    name,
+                    "update ocsp response fail"
+                );
+                errors.push(format!("{name}: {e}"));
+            },
+        }
+    }
+    if !errors.is_empty() {
+        if let Some(sender) = &sender {
+            sender
+                .notify(NotificationData {
+                    level: NotificationLevel::Error,
+                    category: OCSP_CATEGORY.to_string(),
+                    message: format!(
+                        "fetch ocsp response fail, {}",
+                        errors.join("; ")
+                    ),
+                    ..Default::default()
+                })
+                .await;
+        }
+    }
+    Ok(true)
+}
+
+/// Creates a new background service for refreshing the OCSP responses of
+/// certificates, the responses are cached in memory and the cache directory.
+///
+/// # Returns
+///
+/// A tuple containing:
+/// * Service name as String
+/// * Service task future for executing OCSP updates
+pub fn new_ocsp_stapling_service(
+    cache_dir: Option<String>,
+    sender: Option<Arc<NotificationSender>>,
+) -> (String, SimpleServiceTaskFuture) {
+    let cache_dir = cache_dir
+        .filter(|value| !value.is_empty())
+        .map(|value| PathBuf::from(pingap_util::resolve_path(&value)))
+        .unwrap_or_else(|| std::env::temp_dir().join("pingap-ocsp"));
+    let task: SimpleServiceTaskFuture = Box::new(move |count: u32| {
+        Box::pin(do_ocsp_update(count, cache_dir.clone(), sender.clone()))
+    });
+    ("ocsp_stapling".to_string(), task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_asn1_time() {
+        assert_eq!(
+            Some(1748779200),
+            parse_asn1_time("Jun  1 12:00:00 2025 GMT")
+        );
+        assert_eq!(
+            Some(1765627200),
+            parse_asn1_time("Dec 13 12:00:00 2025 GMT")
+        );
+        assert_eq!(None, parse_asn1_time("2025-06-01"));
+    }
+
+    #[test]
+    fn test_ocsp_staple() {
+        set_ocsp_staple(
+            "pingap",
+            OcspStaple {
+                der: b"ocsp".to_vec(),
+                refresh_at: 0,
+                next_update: i64::MAX,
+            },
+        );
+        assert_eq!(Some(b"ocsp".to_vec()), get_ocsp_response("pingap"));
+
+        set_ocsp_staple(
+            "expired",
+            OcspStaple {
+                der: b"ocsp".to_vec(),
+                refresh_at: 0,
+                next_update: 0,
+            },
+        );
+        assert_eq!(None, get_ocsp_response("expired"));
+        assert_eq!(None, get_ocsp_response("unknown"));
+    }
+
+    #[test]
+    fn test_parse_ocsp_response() {
+        // leaf certificate issued by the intermediate certificate
+        let cert = X509::from_pem(
+            br###"-----BEGIN CERTIFICATE-----
+MIIBxjCCAWugAwIBAgICEAEwCgYIKoZIzj0EAwIwMjEPMA0GA1UECgwGUGluZ2Fw
+MR8wHQYDVQQDDBZQaW5nYXAgSW50ZXJtZWRpYXRlIENBMCAXDTI2MTAxODE1MDM1
+N1oYDzIxMjYwOTI0MTUwMzU3WjAUMRIwEAYDVQQDDAlwaW5nYXAuaW8wWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAQB1olQoo1PXGynPBcjrC3UvaWK4Z0tWDImTum6
+98hPIWNA+ciwMU7b2nhq657ckNYtLTW+X+rpEEM+gLrdbjW9o4GMMIGJMBQGA1Ud
+EQQNMAuCCXBpbmdhcC5pbzAxBggrBgEFBQcBAQQlMCMwIQYIKwYBBQUHMAGGFWh0
+dHA6Ly9vY3NwLnBpbmdhcC5pbzAdBgNVHQ4EFgQULHFWlMjSoHFqxxU9cxffz05t
+R/owHwYDVR0jBBgwFoAUQ1e/9C8ZJR+0z9OsDp3ol3QHkJIwCgYIKoZIzj0EAwID
+SQAwRgIhAOINciHXJ8AkejKt9bWhEqRrub1TVou3nRGexO7g0jObAiEAyyI4b9cz
+zNOk6tt1TS8BPMLpomL1epa27JCdMcpax+o=
+-----END CERTIFICATE-----"###,
+        )
+        .unwrap();
+        let issuer = X509::from_pem(
+            br###"-----BEGIN CERTIFICATE-----
+MIIBxjCCAWygAwIBAgIUf1lsJRSCzGVg6Ywmcnox8LmvEXQwCgYIKoZIzj0EAwIw
+KjEPMA0GA1UECgwGUGluZ2FwMRcwFQYDVQQDDA5QaW5nYXAgUm9vdCBDQTAgFw0y
+NjEwMTgxNTAzNTdaGA8yMTI2MDkyNDE1MDM1N1owMjEPMA0GA1UECgwGUGluZ2Fw
+MR8wHQYDVQQDDBZQaW5nYXAgSW50ZXJtZWRpYXRlIENBMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAE/255fgnQH0iQ7NBA+1BLiaZPnogxXOi2UEe2sAzICqJc9POs
+l+CSle3L+4afioAMvAEo37VPjDgIIsVuN5NAKqNmMGQwEgYDVR0TAQH/BAgwBgEB
+/wIBADAOBgNVHQ8BAf8EBAMCAYYwHQYDVR0OBBYEFENXv/QvGSUftM/TrA6d6Jd0
+B5CSMB8GA1UdIwQYMBaAFFE2ELdoiL2SicMIHdGWU65usEZSMAoGCCqGSM49BAMC
+A0gAMEUCIHxAoh7QoL4gtCXpbozNwk9n68SzWj4wJSt77SkuicufAiEAjNY1S3HG
+UrCAXOzI28M6E4GBIxfXjdFX169UGeZ47Ec=
+-----END CERTIFICATE-----"###,
+        )
+        .unwrap();
+        // good status signed by the intermediate certificate,
+        // next update: Sep 24 15:03:57 2126 GMT
+        let der = pingap_util::base64_decode(
+            "MIIBJQoBAKCCAR4wggEaBgkrBgEFBQcwAQEEggELMIIBBzCBrqE0MDIxDzANBgNVBAoMBlBpbmdhcDEfMB0GA1UEAwwWUGluZ2FwIEludGVybWVkaWF0ZSBDQRgPMjAyNjEwMTgxNTAzNTdaMGUwYzA7MAkGBSsOAwIaBQAEFKlCz2OlcU8eFh8N5f+qo9Q/QzfTBBRDV7/0LxklH7TP06wOneiXdAeQkgICEAGAABgPMjAyNjEwMTgxNTAzNTdaoBEYDzIxMjYwOTI0MTUwMzU3WjAKBggqhkjOPQQDAgNIADBFAiBvgE+cwR2IyguwEBpI7sDgIkkVWLjnrSfvJoqc2Uu9VQIhALyMJEc58zKi6RonoR11J51t0cs+lj5X/FRYQzsaFSG6",
+        )
+        .unwrap();
+
+        let staple = parse_ocsp_response(&der, &cert, &issuer).unwrap();
+        assert_eq!(der, staple.der);
+        assert_eq!(4945935837, staple.next_update);
+        assert_eq!(true, staple.refresh_at < staple.next_update);
+
+        // the response is not signed by the issuer
+        let other =
+            rcgen::generate_simple_self_signed(vec!["pingap.io".to_string()])
+                .unwrap();
+        let other = X509::from_pem(other.cert.pem().as_bytes()).unwrap();
+        assert_eq!(true, parse_ocsp_response(&der, &cert, &other).is_err());
+    }
+
+    #[test]
+    fn test_get_ocsp_key() {
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["pingap.io".to_string()])
+                .unwrap();
+        let cert = X509::from_pem(certified.cert.pem().as_bytes()).unwrap();
+        assert_eq!(64, get_ocsp_key(&cert).unwrap().len());
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Error, LOG_CATEGORY};
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::Lazy;
+use openssl::ex_data::Index;
+use pingap_config::get_config_storage;
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::ssl::{
+    select_next_proto, AlpnError, ClientHelloResponse, NameType, Ssl, SslRef,
+};
+use pingora::tls::ssl_sys;
+use pingora::tls::x509::X509;
+use tracing::{debug, error};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// ALPN protocol of ACME TLS-ALPN-01 challenge(RFC 8737)
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+const ACME_TLS_ALPN_WIRE: &[u8] = b"\x0aacme-tls/1";
+const ALPN_H2_H1_WIRE: &[u8] = b"\x02h2\x08http/1.1";
+// extension type of application layer protocol negotiation(RFC 7301)
+const TLSEXT_TYPE_ALPN: u32 = 16;
+
+/// Challenge certificate of TLS-ALPN-01
+#[derive(Debug)]
+pub struct TlsAlpnChallengeCertificate {
+    pub x509: X509,
+    pub key: PKey<Private>,
+}
+
+#[derive(Debug, PartialEq)]
+enum TlsAlpnChallengeState {
+    // the client offers `acme-tls/1`
+    Offered,
+    // the challenge certificate of the sni is installed
+    Installed,
+}
+
+// The tls-alpn-01 challenge state of the connection
+static TLS_ALPN_CHALLENGE_INDEX: Lazy<Index<Ssl, TlsAlpnChallengeState>> =
+    Lazy::new(|| {
+        Ssl::new_ex_index().expect("new ex index of tls-alpn-01 challenge")
+    });
+
+/// Returns the storage key of the TLS-ALPN-01 challenge certificate,
+/// the value is the certificate pem followed by the private key pem.
+#[must_use]
+pub fn get_tls_alpn_challenge_path(domain: &str) -> String {
+    format!("pingap-acme-tls-alpn/{domain}")
+}
+
+/// Parses the TLS-ALPN-01 challenge certificate saved in storage
+fn parse_tls_alpn_challenge_certificate(
+    data: &[u8],
+) -> Result<TlsAlpnChallengeCertificate> {
+    let x509 = X509::from_pem(data).map_err(|e| Error::Invalid {
+        category: "tls_alpn_challenge".to_string(),
+        message: e.to_string(),
+    })?;
+    let key = PKey::private_key_from_pem(data).map_err(|e| Error::Invalid {
+        category: "tls_alpn_challenge".to_string(),
+        message: e.to_string(),
+    })?;
+    Ok(TlsAlpnChallengeCertificate { x509, key })
+}
+
+/// Returns the protocol list of the ALPN extension in client hello,
+/// it can only be used inside of the client hello callback.
+fn client_hello_alpn(ssl: &SslRef) -> Option<&[u8]> {
+    let mut ptr = std::ptr::null();
+    let mut len = 0;
+    // SAFETY: the extension data is owned by the ssl and is only
+    // read inside of the client hello callback.
+    let data = unsafe {
+        if ssl_sys::SSL_client_hello_get0_ext(
+            ssl.as_ptr(),
+            TLSEXT_TYPE_ALPN,
+            &mut ptr,
+            &mut len,
+        ) != 1
+        {
+            return None;
+        }
+        std::slice::from_raw_parts(ptr, len)
+    };
+    // the protocol list is prefixed by its length of two bytes
+    data.get(2..)
+}
+
+/// Client hello callback which marks the connection if the client
+/// offers `acme-tls/1`, the challenge certificate is only loaded
+/// from storage for these connections.
+pub(crate) fn on_client_hello(ssl: &mut SslRef) -> ClientHelloResponse {
+    let offered = client_hello_alpn(ssl)
+        .and_then(|client| select_next_proto(ACME_TLS_ALPN_WIRE, client))
+        .is_some();
+    if offered {
+        ssl.set_ex_data(
+            *TLS_ALPN_CHALLENGE_INDEX,
+            TlsAlpnChallengeState::Offered,
+        );
+    }
+    ClientHelloResponse::SUCCESS
+}
+
+/// Loads the TLS-ALPN-01 challenge certificate of the sni from storage
+/// if the client offers `acme-tls/1`. The certificate is saved in the
+/// same storage as the HTTP-01 tokens, so any node can serve it.
+pub(crate) async fn load_tls_alpn_challenge_certificate(
+    ssl: &SslRef,
+) -> Option<TlsAlpnChallengeCertificate> {
+    if ssl.ex_data(*TLS_ALPN_CHALLENGE_INDEX)
+        != Some(&TlsAlpnChallengeState::Offered)
+    {
+        return None;
+    }
+    let sni = ssl.servername(NameType::HOST_NAME)?;
+    let storage = get_config_storage()?;
+    let data = match storage.load(&get_tls_alpn_challenge_path(sni)).await {
+        Ok(data) => data,
+        Err(e) => {
+            debug!(
+                category = LOG_CATEGORY,
+                error = %e,
+                sni,
+                "load tls-alpn-01 challenge certificate fail"
+            );
+            return None;
+        },
+    };
+    // the challenge certificate is cleared after the order is done
+    if data.is_empty() {
+        return None;
+    }
+    match parse_tls_alpn_challenge_certificate(&data) {
+        Ok(cert) => Some(cert),
+        Err(e) => {
+            error!(
+                category = LOG_CATEGORY,
+                error = %e,
+                sni,
+                "parse tls-alpn-01 challenge certificate fail"
+            );
+            None
+        },
+    }
+}
+
+/// Marks the challenge certificate is installed, then `acme-tls/1`
+/// is selected by the alpn callback
+pub(crate) fn set_tls_alpn_challenge_installed(ssl: &mut SslRef) {
+    ssl.set_ex_data(
+        *TLS_ALPN_CHALLENGE_INDEX,
+        TlsAlpnChallengeState::Installed,
+    );
+}
+
+/// Selects the ALPN protocol, `acme-tls/1` is selected if the challenge
+/// certificate of the sni is installed, otherwise h2 or http/1.1 is
+/// selected if h2 is enabled.
+pub(crate) fn select_alpn<'a>(
+    ssl: &SslRef,
+    client: &'a [u8],
+    enabled_h2: bool,
+) -> Result<&'a [u8], AlpnError> {
+    if ssl.ex_data(*TLS_ALPN_CHALLENGE_INDEX)
+        == Some(&TlsAlpnChallengeState::Installed)
+    {
+        let protocol = select_next_proto(ACME_TLS_ALPN_WIRE, client);
+        if let Some(protocol) = protocol {
+            return Ok(protocol);
+        }
+    }
+    if !enabled_h2 {
+        return Err(AlpnError::NOACK);
+    }
+    select_next_proto(ALPN_H2_H1_WIRE, client).ok_or(AlpnError::NOACK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_tls_alpn_challenge_certificate() {
+        assert_eq!(
+            "pingap-acme-tls-alpn/pingap.io",
+            get_tls_alpn_challenge_path("pingap.io")
+        );
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["pingap.io".to_string()])
+                .unwrap();
+        let data = format!(
+            "{}{}",
+            certified.cert.pem(),
+            certified.key_pair.serialize_pem()
+        );
+        let cert =
+            parse_tls_alpn_challenge_certificate(data.as_bytes()).unwrap();
+        assert_eq!(certified.cert.der().to_vec(), cert.x509.to_der().unwrap());
+
+        assert_eq!(
+            true,
+            parse_tls_alpn_challenge_certificate(b"cert").is_err()
+        );
+        assert_eq!(
+            true,
+            parse_tls_alpn_challenge_certificate(
+                certified.cert.pem().as_bytes()
+            )
+            .is_err()
+        );
+    }
+}
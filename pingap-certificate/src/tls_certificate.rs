@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use super::chain::get_lets_encrypt_chain_certificate;
+use super::ocsp::get_ocsp_key;
 use super::self_signed::{
     add_self_signed_certificate, get_self_signed_certificate,
     SelfSignedCertificate,
@@ -50,6 +51,8 @@ pub struct TlsCertificate {
     pub is_ca: bool,
     // Buffer days for certificate renewal
     pub buffer_days: u16,
+    // Key of the stapled OCSP response if ocsp stapling is enabled
+    pub ocsp_key: Option<String>,
 }
 
 impl TryFrom<&CertificateConf> for TlsCertificate {
@@ -105,8 +108,14 @@ impl TryFrom<&CertificateConf> for TlsCertificate {
                 message: e.to_string(),
             }
         })?;
+        let ocsp_key = if value.ocsp_stapling.unwrap_or_default() {
+            get_ocsp_key(&cert)
+        } else {
+            None
+        };
         Ok(TlsCertificate {
             hash_key,
+            ocsp_key,
             chain_certificate,
             domains: info.domains.clone(),
             certificate: Some((cert, key)),
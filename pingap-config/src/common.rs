@@ -17,14 +17,22 @@ use super::{Error, Result};
 // use crate::proxy::Parser;
 use arc_swap::ArcSwap;
 use bytesize::ByteSize;
-use http::{HeaderName, HeaderValue};
+use http::{HeaderName, HeaderValue, StatusCode};
+use ipnet::IpNet;
 use once_cell::sync::Lazy;
-use pingap_discovery::{is_static_discovery, DNS_DISCOVERY};
+use pingap_core::{
+    ProxyProtocolVersion, RETRY_ON_CONNECT_ERROR, RETRY_ON_ERROR,
+    RETRY_ON_TIMEOUT,
+};
+use pingap_discovery::{
+    is_static_discovery, is_unix_addr, parse_addr_options, DNS_DISCOVERY,
+    UNIX_ADDR_PREFIX,
+};
 use regex::Regex;
 use serde::{Deserialize, Serialize, Serializer};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::Cursor;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{collections::HashMap, str::FromStr};
@@ -42,6 +50,10 @@ pub const CATEGORY_PLUGIN: &str = "plugin";
 pub const CATEGORY_CERTIFICATE: &str = "certificate";
 pub const CATEGORY_STORAGE: &str = "storage";
 
+pub const SERVER_KIND_HTTP: &str = "http";
+pub const SERVER_KIND_STREAM: &str = "stream";
+pub const SERVER_KIND_UDP: &str = "udp";
+
 #[derive(PartialEq, Debug, Default, Clone, EnumString, strum::Display)]
 #[strum(serialize_all = "snake_case")]
 pub enum PluginCategory {
@@ -132,12 +144,32 @@ pub struct CertificateConf {
     pub is_ca: Option<bool>,
     /// ACME configuration for automated certificate management
     pub acme: Option<String>,
+    /// ACME directory url or alias of the CA, default is Let's Encrypt
+    pub acme_directory: Option<String>,
+    /// Key identifier of ACME external account binding
+    pub acme_eab_kid: Option<String>,
+    /// Base64url encoded HMAC key of ACME external account binding
+    pub acme_eab_hmac_key: Option<String>,
+    /// Key type of the ACME certificate (e.g. "ecdsa_p256", "rsa2048")
+    pub acme_key_type: Option<String>,
     /// Buffer days for certificate renewal
     pub buffer_days: Option<u16>,
+    /// Whether to staple the OCSP response fetched from the issuer's responder
+    pub ocsp_stapling: Option<bool>,
     /// Optional description/notes about this certificate
     pub remark: Option<String>,
 }
 
+/// Returns whether the retry condition is valid, it should be
+/// "connect_error", "timeout", "error" or a valid status code
+fn is_valid_retry_on(value: &str) -> bool {
+    let value = value.trim();
+    [RETRY_ON_CONNECT_ERROR, RETRY_ON_TIMEOUT, RETRY_ON_ERROR].contains(&value)
+        || value
+            .parse::<u16>()
+            .is_ok_and(|code| (100..600).contains(&code))
+}
+
 /// Validates a certificate in PEM format or base64 encoded
 fn validate_cert(value: &str) -> Result<()> {
     // Convert from PEM/base64 to binary
@@ -211,7 +243,7 @@ impl CertificateConf {
 /// Configuration for an upstream service that handles proxied requests
 #[derive(Debug, Default, Deserialize, Clone, Serialize, Hash)]
 pub struct UpstreamConf {
-    /// List of upstream server addresses in format "host:port" or "host:port weight"
+    /// List of upstream server addresses in format "host:port" or "host:port weight=5 backup"
     pub addrs: Vec<String>,
 
     /// Service discovery mechanism to use (e.g. "dns", "static")
@@ -222,7 +254,7 @@ pub struct UpstreamConf {
     #[serde(with = "humantime_serde")]
     pub update_frequency: Option<Duration>,
 
-    /// Load balancing algorithm (e.g. "round_robin", "hash:cookie")
+    /// Load balancing algorithm (e.g. "round_robin", "hash:cookie", "least_conn", "ewma")
     pub algo: Option<String>,
 
     /// Server Name Indication for TLS connections
@@ -243,6 +275,22 @@ pub struct UpstreamConf {
     /// Application Layer Protocol Negotiation for TLS
     pub alpn: Option<String>,
 
+    /// Name of the certificate used as the client certificate of mTLS
+    pub client_certificate: Option<String>,
+
+    /// Client certificate of mTLS in PEM format, base64 encoded or file path
+    pub client_tls_cert: Option<String>,
+
+    /// Private key of the client certificate in PEM format, base64 encoded or file path
+    pub client_tls_key: Option<String>,
+
+    /// Trusted CA certificates to verify upstream, the name of certificate
+    /// or the certificates in PEM format, base64 encoded or file path
+    pub tls_ca: Option<String>,
+
+    /// Version of PROXY protocol header sent to upstream, "v1" or "v2"
+    pub proxy_protocol: Option<String>,
+
     /// Timeout for establishing new connections
     #[serde(default)]
     #[serde(with = "humantime_serde")]
@@ -287,6 +335,83 @@ pub struct UpstreamConf {
     /// Enable TCP Fast Open
     pub tcp_fast_open: Option<bool>,
 
+    /// Number of consecutive 5xx responses or connection failures
+    /// before a backend is ejected, passive health check is disabled if not set
+    pub outlier_consecutive_failures: Option<u32>,
+
+    /// Base ejection time of the outlier backend,
+    /// it grows exponentially with the number of ejections
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub outlier_ejection_time: Option<Duration>,
+
+    /// Maximum ejection time of the outlier backend
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub outlier_max_ejection_time: Option<Duration>,
+
+    /// Maximum number of retries when the request to upstream fails
+    pub retry_attempts: Option<u32>,
+
+    /// Conditions to retry, "connect_error", "timeout", "error"
+    /// or status code of upstream response like "502"
+    pub retry_on: Option<Vec<String>>,
+
+    /// Only retry the idempotent requests, default is true
+    pub retry_idempotent_only: Option<bool>,
+
+    /// Maximum percentage of retries to requests in a time window,
+    /// default is 20, zero means unlimited
+    pub retry_budget: Option<u32>,
+
+    /// Error rate(percentage) of the rolling window to open the circuit,
+    /// circuit breaker is disabled if not set
+    pub circuit_breaker_error_rate: Option<u32>,
+
+    /// Minimum number of requests in the rolling window
+    /// before the error rate is checked
+    pub circuit_breaker_min_requests: Option<u32>,
+
+    /// Rolling window of the error rate
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub circuit_breaker_window: Option<Duration>,
+
+    /// Cool-down time before the open circuit becomes half-open
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub circuit_breaker_open_time: Option<Duration>,
+
+    /// Number of successful probe requests to close the half-open circuit
+    pub circuit_breaker_half_open_requests: Option<u32>,
+
+    /// Response status code when the circuit is open
+    pub circuit_breaker_status: Option<u16>,
+
+    /// Slow start window of the backend which becomes healthy or is newly
+    /// discovered, its effective weight ramps up linearly during the window
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub slow_start: Option<Duration>,
+
+    /// Name of the affinity cookie issued by proxy for sticky session
+    pub sticky_cookie: Option<String>,
+
+    /// Max-Age of the affinity cookie, it's a session cookie if not set
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub sticky_cookie_ttl: Option<Duration>,
+
+    /// Path of the affinity cookie, default is "/"
+    pub sticky_cookie_path: Option<String>,
+
+    /// Other attributes of the affinity cookie, e.g. "HttpOnly; Secure"
+    pub sticky_cookie_attributes: Option<String>,
+
+    /// Secret to encrypt the backend address of the affinity cookie,
+    /// the hash of the address is used if not set
+    pub sticky_cookie_secret: Option<String>,
+
     /// List of included configuration files
     pub includes: Option<Vec<String>>,
 
@@ -315,9 +440,14 @@ impl UpstreamConf {
 
         // Check if any address contains a hostname (non-IP)
         let has_hostname = self.addrs.iter().any(|addr| {
+            // Remove the weight and backup options
+            let addr = addr.split_whitespace().next().unwrap_or_default();
+            // The unix domain socket address isn't a hostname
+            if is_unix_addr(addr) {
+                return false;
+            }
             // Extract host portion before port
-            let host =
-                addr.split_once(':').map_or(addr.as_str(), |(host, _)| host);
+            let host = addr.split_once(':').map_or(addr, |(host, _)| host);
 
             // If host can't be parsed as IP, it's a hostname
             host.parse::<std::net::IpAddr>().is_err()
@@ -335,6 +465,10 @@ impl UpstreamConf {
     /// 2. For static discovery, addresses must be valid socket addresses
     /// 3. Health check URL must be valid if specified
     /// 4. TCP probe count must not exceed maximum (16)
+    /// 5. Retry conditions must be valid
+    /// 6. Circuit breaker error rate and status must be valid
+    /// 7. Sticky cookie name must be a valid cookie name
+    /// 8. Client certificate and key of mTLS must be valid
     pub fn validate(&self, name: &str) -> Result<()> {
         // Validate address list
         self.validate_addresses(name)?;
@@ -345,6 +479,91 @@ impl UpstreamConf {
         // Validate TCP probe count
         self.validate_tcp_probe_count()?;
 
+        // Validate retry conditions
+        if let Some(value) = self
+            .retry_on
+            .iter()
+            .flatten()
+            .find(|value| !is_valid_retry_on(value))
+        {
+            return Err(Error::Invalid {
+                message: format!(
+                    "retry on({value}) is invalid(upstream:{name})"
+                ),
+            });
+        }
+
+        // Validate circuit breaker
+        if let Some(value) = self.circuit_breaker_error_rate {
+            if value == 0 || value > 100 {
+                return Err(Error::Invalid {
+                    message: format!("circuit breaker error rate should be in 1-100(upstream:{name})"),
+                });
+            }
+        }
+        if let Some(value) = self.circuit_breaker_status {
+            if StatusCode::from_u16(value).is_err() {
+                return Err(Error::Invalid {
+                    message: format!("circuit breaker status({value}) is invalid(upstream:{name})"),
+                });
+            }
+        }
+
+        // Validate client certificate and key of mTLS
+        match (&self.client_tls_cert, &self.client_tls_key) {
+            (Some(cert), Some(key)) => {
+                validate_cert(cert)?;
+                let buf = pingap_util::convert_pem(key).map_err(|e| {
+                    Error::Invalid {
+                        message: format!("{e}(upstream:{name})"),
+                    }
+                })?;
+                let mut key = Cursor::new(buf);
+                if !matches!(rustls_pemfile::private_key(&mut key), Ok(Some(_)))
+                {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "client tls key is invalid(upstream:{name})"
+                        ),
+                    });
+                }
+            },
+            (None, None) => {},
+            _ => {
+                return Err(Error::Invalid {
+                    message: format!("client tls cert and key should be set together(upstream:{name})"),
+                });
+            },
+        }
+
+        // Validate proxy protocol version
+        if let Some(value) = &self.proxy_protocol {
+            if ProxyProtocolVersion::from_str(value).is_err() {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "proxy protocol({value}) is invalid(upstream:{name})"
+                    ),
+                });
+            }
+        }
+
+        // Validate sticky cookie name
+        if let Some(value) = &self.sticky_cookie {
+            if value.is_empty()
+                || value.chars().any(|c| {
+                    c.is_ascii_control()
+                        || c.is_whitespace()
+                        || "()<>@,;:\\\"/[]?={}".contains(c)
+                })
+            {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "sticky cookie({value}) is invalid(upstream:{name})"
+                    ),
+                });
+            }
+        }
+
         Ok(())
     }
 
@@ -355,6 +574,16 @@ impl UpstreamConf {
             });
         }
 
+        // Validate weight and backup options of addresses
+        for addr in &self.addrs {
+            let parts: Vec<_> = addr.split_whitespace().collect();
+            if let Some(options) = parts.get(1..) {
+                parse_addr_options(options).map_err(|e| Error::Invalid {
+                    message: format!("{e}(upstream:{name})"),
+                })?;
+            }
+        }
+
         // Only validate addresses for static discovery
         if !is_static_discovery(&self.guess_discovery()) {
             return Ok(());
@@ -363,6 +592,17 @@ impl UpstreamConf {
         for addr in &self.addrs {
             let parts: Vec<_> = addr.split_whitespace().collect();
             let host_port = parts[0].to_string();
+            // The socket file may be created after the upstream starts
+            if let Some(path) = host_port.strip_prefix(UNIX_ADDR_PREFIX) {
+                if path.is_empty() {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "unix socket path is empty(upstream:{name})"
+                        ),
+                    });
+                }
+                continue;
+            }
 
             // Add default port 80 if not specified
             let addr_to_check = if !host_port.contains(':') {
@@ -459,6 +699,54 @@ pub struct LocationConf {
     /// Whether to enable reverse proxy headers
     pub enable_reverse_proxy_headers: Option<bool>,
 
+    /// Maximum number of retries when the request to upstream fails
+    pub retry_attempts: Option<u32>,
+
+    /// Conditions to retry, "connect_error", "timeout", "error"
+    /// or status code of upstream response like "502"
+    pub retry_on: Option<Vec<String>>,
+
+    /// Only retry the idempotent requests, default is true
+    pub retry_idempotent_only: Option<bool>,
+
+    /// Maximum percentage of retries to requests in a time window,
+    /// default is 20, zero means unlimited
+    pub retry_budget: Option<u32>,
+
+    /// Weighted upstreams for canary release, e.g. "api-v1 95", "api-v2 5",
+    /// they take precedence over the upstream
+    pub upstreams: Option<Vec<String>>,
+
+    /// Rules to select the upstream of canary release,
+    /// e.g. "header:X-Canary=1 api-v2", "cookie:canary api-v2"
+    pub upstream_rules: Option<Vec<String>>,
+
+    /// Delay before sending the hedged request to another backend,
+    /// if the response header of upstream is not received
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub hedge_delay: Option<Duration>,
+
+    /// Percentile of the upstream response time as the hedge delay, e.g. 95
+    pub hedge_percentile: Option<u8>,
+
+    /// Maximum number of hedged requests of each request, default is 1
+    pub hedge_max_attempts: Option<u32>,
+
+    /// Maximum percentage of hedged requests to requests in a time window,
+    /// default is 10, zero means unlimited
+    pub hedge_budget: Option<u32>,
+
+    /// Upstream to send a copy of the requests to, its responses are discarded
+    pub mirror_upstream: Option<String>,
+
+    /// Percentage of the requests to mirror, default is 100
+    pub mirror_percentage: Option<u8>,
+
+    /// Maximum size of the request body to buffer for mirroring,
+    /// the request with larger body isn't mirrored, default is 64kb
+    pub mirror_body_limit: Option<ByteSize>,
+
     /// Optional description/notes about this location
     pub remark: Option<String>,
 }
@@ -477,6 +765,10 @@ impl LocationConf {
     /// 2. Validates header names and values are valid HTTP headers
     /// 3. Validates upstream exists if specified
     /// 4. Validates rewrite pattern is valid regex if specified
+    /// 5. Validates retry conditions if specified
+    /// 6. Validates mirror upstream exists and mirror percentage is valid
+    /// 7. Validates weighted upstreams and upstream rules
+    /// 8. Validates hedge percentile if specified
     fn validate(&self, name: &str, upstream_names: &[String]) -> Result<()> {
         // Helper function to validate HTTP headers
         let validate = |headers: &Option<Vec<String>>| -> Result<()> {
@@ -533,9 +825,90 @@ impl LocationConf {
                 Regex::new(arr[0]).map_err(|e| Error::Regex { source: e })?;
         }
 
+        // Validate retry conditions
+        if let Some(value) = self
+            .retry_on
+            .iter()
+            .flatten()
+            .find(|value| !is_valid_retry_on(value))
+        {
+            return Err(Error::Invalid {
+                message: format!(
+                    "retry on({value}) is invalid(location:{name})"
+                ),
+            });
+        }
+
+        // Validate upstream rules
+        if let Some(rule) = self.upstream_rules.iter().flatten().find(|rule| {
+            let arr: Vec<&str> = rule.split_whitespace().collect();
+            arr.len() != 2
+                || !["header:", "cookie:", "query:"]
+                    .iter()
+                    .any(|prefix| arr[0].starts_with(prefix))
+        }) {
+            return Err(Error::Invalid {
+                message: format!(
+                    "upstream rule({rule}) is invalid(location:{name})"
+                ),
+            });
+        }
+
+        // Validate weighted upstreams, rule upstreams and mirror upstream exist
+        if let Some(upstream) = self
+            .referenced_upstreams()
+            .into_iter()
+            .find(|upstream| !upstream_names.contains(upstream))
+        {
+            return Err(Error::Invalid {
+                message: format!(
+                    "upstream({upstream}) is not found(location:{name})"
+                ),
+            });
+        }
+        // Validate hedge percentile
+        if let Some(value) = self.hedge_percentile {
+            if value == 0 || value > 99 {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "hedge percentile should be in 1-99(location:{name})"
+                    ),
+                });
+            }
+        }
+        if let Some(value) = self.mirror_percentage {
+            if value == 0 || value > 100 {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "mirror percentage should be in 1-100(location:{name})"
+                    ),
+                });
+            }
+        }
+
         Ok(())
     }
 
+    /// Returns the upstreams referenced by the weighted upstreams,
+    /// upstream rules and mirror upstream
+    fn referenced_upstreams(&self) -> Vec<String> {
+        let weighted = self
+            .upstreams
+            .iter()
+            .flatten()
+            .filter_map(|item| item.split_whitespace().next());
+        let rules = self
+            .upstream_rules
+            .iter()
+            .flatten()
+            .filter_map(|item| item.split_whitespace().last());
+        weighted
+            .chain(rules)
+            .chain(self.mirror_upstream.as_deref())
+            .map(|item| item.to_string())
+            .collect()
+    }
+
     /// Calculates the matching priority weight for this location
     /// Higher weight = higher priority
     /// Weight is based on:
@@ -586,6 +959,27 @@ pub struct ServerConf {
     /// Address to listen on in format "host:port" or multiple addresses separated by commas
     pub addr: String,
 
+    /// Kind of server: "http"(default), "stream"(tcp proxy or tls passthrough)
+    /// or "udp"(udp proxy)
+    pub kind: Option<String>,
+
+    /// Default upstream of the stream or udp server
+    pub stream_upstream: Option<String>,
+
+    /// Upstreams of the stream server selected by the SNI of tls connection,
+    /// the format is "server_name upstream", e.g. "*.example.com upstream"
+    pub stream_sni_upstreams: Option<Vec<String>>,
+
+    /// Idle timeout of the udp session, the session is closed if no datagram
+    /// is forwarded in either direction
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub udp_idle_timeout: Option<Duration>,
+
+    /// Maximum number of the udp sessions, the datagrams of new client
+    /// are dropped if it's exceeded
+    pub udp_max_sessions: Option<usize>,
+
     /// Access log format string for request logging
     pub access_log: Option<String>,
 
@@ -610,6 +1004,12 @@ pub struct ServerConf {
     /// Whether to use global certificates instead of per-server certs
     pub global_certificates: Option<bool>,
 
+    /// CA certificate(the name of certificate with `is_ca`) to verify client certificate
+    pub tls_client_ca: Option<String>,
+
+    /// Client certificate verification mode: "required" or "optional"
+    pub tls_client_auth: Option<String>,
+
     /// Whether to enable HTTP/2 protocol support
     pub enabled_h2: Option<bool>,
 
@@ -644,22 +1044,188 @@ pub struct ServerConf {
     /// Whether to enable server-timing header
     pub enable_server_timing: Option<bool>,
 
+    /// Whether to accept PROXY protocol v1/v2 header on the listeners
+    pub proxy_protocol: Option<bool>,
+
+    /// Trusted source ips or cidrs of PROXY protocol header, it's required if PROXY protocol is enabled
+    pub proxy_protocol_trusted_cidrs: Option<Vec<String>>,
+
     /// Optional description/notes about this server
     pub remark: Option<String>,
 }
 
 impl ServerConf {
+    /// Returns whether it's a stream server
+    pub fn is_stream(&self) -> bool {
+        self.kind.as_deref() == Some(SERVER_KIND_STREAM)
+    }
+
+    /// Returns whether it's a udp server
+    pub fn is_udp(&self) -> bool {
+        self.kind.as_deref() == Some(SERVER_KIND_UDP)
+    }
+
+    /// Returns the (server name, upstream) list of the stream server
+    pub fn get_stream_sni_upstreams(&self) -> Vec<(String, String)> {
+        self.stream_sni_upstreams
+            .iter()
+            .flatten()
+            .filter_map(|item| {
+                let mut arr = item.split_whitespace();
+                let server_name = arr.next()?;
+                let upstream = arr.next()?;
+                Some((server_name.to_string(), upstream.to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the (listen address, path) of prometheus pull metrics of the
+    /// stream or udp server, e.g. "127.0.0.1:9100/metrics", the path is
+    /// "/metrics" if it's not set. It's none if the push gateway mode is used.
+    pub fn get_stream_prometheus_pull(&self) -> Option<(String, String)> {
+        let value = self.prometheus_metrics.as_deref()?;
+        if !(self.is_stream() || self.is_udp())
+            || value.is_empty()
+            || value.contains("://")
+        {
+            return None;
+        }
+        let (addr, path) = match value.find('/') {
+            Some(index) => value.split_at(index),
+            None => (value, "/metrics"),
+        };
+        if addr.is_empty() {
+            return None;
+        }
+        Some((addr.to_string(), path.to_string()))
+    }
+
+    /// Returns the upstreams referenced by the stream or udp server
+    fn referenced_upstreams(&self) -> Vec<String> {
+        if self.is_udp() {
+            return self.stream_upstream.iter().cloned().collect();
+        }
+        if !self.is_stream() {
+            return vec![];
+        }
+        self.get_stream_sni_upstreams()
+            .into_iter()
+            .map(|(_, upstream)| upstream)
+            .chain(self.stream_upstream.clone())
+            .collect()
+    }
     /// Validate the options of server config.
     /// 1. Parse listen addr to socket addr.
     /// 2. Check the locations are exists.
     /// 3. Parse access log layout success.
-    fn validate(&self, name: &str, location_names: &[String]) -> Result<()> {
+    /// 4. Parse trusted cidrs of proxy protocol.
+    /// 5. Check the client auth mode of tls.
+    /// 6. Check the upstreams of stream or udp server are exists.
+    fn validate(
+        &self,
+        name: &str,
+        location_names: &[String],
+        upstream_names: &[String],
+    ) -> Result<()> {
         for addr in self.addr.split(',') {
             let _ = addr.to_socket_addrs().map_err(|e| Error::Io {
                 source: e,
                 file: self.addr.clone(),
             })?;
         }
+        if self.is_stream() || self.is_udp() {
+            for item in self.stream_sni_upstreams.iter().flatten() {
+                if item.split_whitespace().count() != 2 {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "stream sni upstream({item}) is invalid(server:{name})"
+                        ),
+                    });
+                }
+            }
+            let upstreams = self.referenced_upstreams();
+            if upstreams.is_empty() {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "stream upstream is not set(server:{name})"
+                    ),
+                });
+            }
+            for upstream in upstreams.iter() {
+                if !upstream_names.contains(upstream) {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "upstream({upstream}) is not found(server:{name})"
+                        ),
+                    });
+                }
+            }
+            // the stream or udp server listens on another address
+            // for pull metrics
+            let metrics = self.prometheus_metrics.clone().unwrap_or_default();
+            if !metrics.is_empty() && !metrics.contains("://") {
+                let valid = self
+                    .get_stream_prometheus_pull()
+                    .is_some_and(|(addr, _)| addr.to_socket_addrs().is_ok());
+                if !valid {
+                    let kind = self.kind.as_deref().unwrap_or_default();
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "prometheus metrics({metrics}) of {kind} server should be a listen address with path(server:{name})"
+                        ),
+                    });
+                }
+            }
+        } else if let Some(kind) = &self.kind {
+            if kind != SERVER_KIND_HTTP {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "server kind({kind}) is invalid(server:{name})"
+                    ),
+                });
+            }
+        }
+        // the client certificate is only verified by tls server
+        if self.tls_client_ca.is_some()
+            && !self.global_certificates.unwrap_or_default()
+        {
+            return Err(Error::Invalid {
+                message: format!(
+                    "tls client ca is set but tls is not enabled(server:{name})"
+                ),
+            });
+        }
+        if let Some(value) = &self.tls_client_auth {
+            if !["required", "optional"].contains(&value.as_str()) {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "tls client auth({value}) is invalid(server:{name})"
+                    ),
+                });
+            }
+        }
+        if self.proxy_protocol.unwrap_or_default()
+            && self
+                .proxy_protocol_trusted_cidrs
+                .as_ref()
+                .is_none_or(|cidrs| cidrs.is_empty())
+        {
+            return Err(Error::Invalid {
+                message: format!(
+                    "proxy protocol trusted cidrs are not set(server:{name})"
+                ),
+            });
+        }
+        for item in self.proxy_protocol_trusted_cidrs.iter().flatten() {
+            if IpNet::from_str(item).is_err() && IpAddr::from_str(item).is_err()
+            {
+                return Err(Error::Invalid {
+                    message: format!(
+                        "proxy protocol trusted cidr({item}) is invalid(server:{name})"
+                    ),
+                });
+            }
+        }
         if let Some(locations) = &self.locations {
             for item in locations {
                 if !location_names.contains(item) {
@@ -741,6 +1307,8 @@ pub struct BasicConf {
     pub cache_directory: Option<String>,
     /// Maximum size of cache storage
     pub cache_max_size: Option<ByteSize>,
+    /// Directory to store the OCSP responses of certificates
+    pub ocsp_cache_directory: Option<String>,
 }
 
 impl BasicConf {
@@ -1014,6 +1582,21 @@ impl PingapConf {
         let mut upstream_names = vec![];
         for (name, upstream) in self.upstreams.iter() {
             upstream.validate(name)?;
+            // the certificates of mTLS may reference the certificate config
+            if let Some(cert) = &upstream.client_certificate {
+                if !self.certificates.contains_key(cert) {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "certificate({cert}) is not found(upstream:{name})"
+                        ),
+                    });
+                }
+            }
+            if let Some(ca) = &upstream.tls_ca {
+                if !self.certificates.contains_key(ca) {
+                    validate_cert(ca)?;
+                }
+            }
             upstream_names.push(name.to_string());
         }
         let mut location_names = vec![];
@@ -1031,7 +1614,41 @@ impl PingapConf {
                 }
                 listen_addr_list.push(addr.to_string());
             }
-            server.validate(name, &location_names)?;
+            server.validate(name, &location_names, &upstream_names)?;
+            // the tcp health check is not suitable for udp backends
+            if server.is_udp() {
+                let upstream =
+                    server.stream_upstream.clone().unwrap_or_default();
+                let is_udp_check = self
+                    .upstreams
+                    .get(&upstream)
+                    .and_then(|item| item.health_check.as_ref())
+                    .is_some_and(|value| value.starts_with("udp://"));
+                if !is_udp_check {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "health check of upstream({upstream}) should be udp://(server:{name})"
+                        ),
+                    });
+                }
+            }
+            // the client certificate is verified by the ca certificate
+            if let Some(ca) = &server.tls_client_ca {
+                let tls_cert = self
+                    .certificates
+                    .get(ca)
+                    .filter(|item| item.is_ca.unwrap_or_default())
+                    .and_then(|item| item.tls_cert.clone())
+                    .unwrap_or_default();
+                if tls_cert.is_empty() {
+                    return Err(Error::Invalid {
+                        message: format!(
+                            "ca certificate({ca}) is not found(server:{name})"
+                        ),
+                    });
+                }
+                validate_cert(&tls_cert)?;
+            }
         }
         // TODO: validate plugins
         // for (name, plugin) in self.plugins.iter() {
@@ -1065,7 +1682,9 @@ impl PingapConf {
         match category {
             CATEGORY_UPSTREAM => {
                 for (location_name, location) in self.locations.iter() {
-                    if let Some(upstream) = &location.upstream {
+                    let mut upstreams = location.referenced_upstreams();
+                    upstreams.extend(location.upstream.clone());
+                    for upstream in upstreams.iter() {
                         if upstream == name {
                             return Err(Error::Invalid {
                                 message: format!(
@@ -1075,6 +1694,16 @@ impl PingapConf {
                         }
                     }
                 }
+                for (server_name, server) in self.servers.iter() {
+                    if server.referenced_upstreams().contains(&name.to_string())
+                    {
+                        return Err(Error::Invalid {
+                            message: format!(
+                                "upstream({name}) is in used by server({server_name})",
+                            ),
+                        });
+                    }
+                }
                 self.upstreams.remove(name);
             },
             CATEGORY_LOCATION => {
@@ -1107,6 +1736,26 @@ impl PingapConf {
                 self.plugins.remove(name);
             },
             CATEGORY_CERTIFICATE => {
+                for (upstream_name, upstream) in self.upstreams.iter() {
+                    if upstream.client_certificate.as_deref() == Some(name)
+                        || upstream.tls_ca.as_deref() == Some(name)
+                    {
+                        return Err(Error::Invalid {
+                            message: format!(
+                                "certificate({name}) is in used by upstream({upstream_name})"
+                            ),
+                        });
+                    }
+                }
+                for (server_name, server) in self.servers.iter() {
+                    if server.tls_client_ca.as_deref() == Some(name) {
+                        return Err(Error::Invalid {
+                            message: format!(
+                                "certificate({name}) is in used by server({server_name})"
+                            ),
+                        });
+                    }
+                }
                 self.certificates.remove(name);
             },
             _ => {},
@@ -1361,6 +2010,36 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
         assert_eq!("B7B8046B", get_config_hash());
     }
 
+    #[test]
+    fn test_validate_udp_health_check() {
+        let mut conf = PingapConf::default();
+        conf.upstreams.insert(
+            "dns".to_string(),
+            UpstreamConf {
+                addrs: vec!["127.0.0.1:53".to_string()],
+                ..Default::default()
+            },
+        );
+        conf.servers.insert(
+            "udp".to_string(),
+            ServerConf {
+                addr: "127.0.0.1:5353".to_string(),
+                kind: Some("udp".to_string()),
+                stream_upstream: Some("dns".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            "Invalid error health check of upstream(dns) should be udp://(server:udp)",
+            conf.validate().expect_err("").to_string()
+        );
+
+        if let Some(upstream) = conf.upstreams.get_mut("dns") {
+            upstream.health_check = Some("udp://dns".to_string());
+        }
+        assert_eq!(true, conf.validate().is_ok());
+    }
+
     #[test]
     fn test_plugin_category_serde() {
         #[derive(Deserialize, Serialize)]
@@ -1388,6 +2067,72 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
             result.expect_err("").to_string()
         );
 
+        conf.addrs = vec!["127.0.0.1 weight=a".to_string()];
+        let result = conf.validate("test");
+        assert_eq!(true, result.is_err());
+        assert_eq!(
+            "Invalid error invalid weight: a(upstream:test)",
+            result.expect_err("").to_string()
+        );
+
+        conf.addrs = vec!["127.0.0.1:8080 weight 5 backup".to_string()];
+        assert_eq!(true, conf.validate("test").is_ok());
+
+        conf.retry_on = Some(vec!["timeout".to_string(), "5xx".to_string()]);
+        let result = conf.validate("test");
+        assert_eq!(
+            "Invalid error retry on(5xx) is invalid(upstream:test)",
+            result.expect_err("").to_string()
+        );
+        conf.retry_on =
+            Some(vec!["connect_error".to_string(), "502".to_string()]);
+        assert_eq!(true, conf.validate("test").is_ok());
+
+        conf.circuit_breaker_error_rate = Some(101);
+        let result = conf.validate("test");
+        assert_eq!(
+            "Invalid error circuit breaker error rate should be in 1-100(upstream:test)",
+            result.expect_err("").to_string()
+        );
+        conf.circuit_breaker_error_rate = Some(50);
+        conf.circuit_breaker_status = Some(1000);
+        let result = conf.validate("test");
+        assert_eq!(
+            "Invalid error circuit breaker status(1000) is invalid(upstream:test)",
+            result.expect_err("").to_string()
+        );
+        conf.circuit_breaker_status = Some(503);
+        assert_eq!(true, conf.validate("test").is_ok());
+
+        conf.sticky_cookie = Some("pingap sticky".to_string());
+        let result = conf.validate("test");
+        assert_eq!(
+            "Invalid error sticky cookie(pingap sticky) is invalid(upstream:test)",
+            result.expect_err("").to_string()
+        );
+        conf.sticky_cookie = Some("pingap_sticky".to_string());
+        assert_eq!(true, conf.validate("test").is_ok());
+
+        conf.proxy_protocol = Some("v3".to_string());
+        assert_eq!(
+            "Invalid error proxy protocol(v3) is invalid(upstream:test)",
+            conf.validate("test").expect_err("").to_string()
+        );
+        conf.proxy_protocol = Some("v2".to_string());
+        assert_eq!(true, conf.validate("test").is_ok());
+
+        conf.addrs = vec!["127.0.0.1 backup".to_string()];
+        assert_eq!("", conf.guess_discovery());
+
+        conf.addrs = vec!["unix:/run/app.sock weight=2".to_string()];
+        assert_eq!("", conf.guess_discovery());
+        assert_eq!(true, conf.validate("test").is_ok());
+        conf.addrs = vec!["unix:".to_string()];
+        assert_eq!(
+            "Invalid error unix socket path is empty(upstream:test)",
+            conf.validate("test").expect_err("").to_string()
+        );
+
         conf.addrs = vec!["127.0.0.1".to_string(), "github".to_string()];
         conf.discovery = Some("static".to_string());
         let result = conf.validate("test");
@@ -1462,6 +2207,59 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
         conf.rewrite = Some(r"^/api /".to_string());
         let result = conf.validate("lo", &upstream_names);
         assert_eq!(true, result.is_ok());
+
+        conf.retry_on = Some(vec!["600".to_string()]);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(true, result.is_err());
+        conf.retry_on = Some(vec!["error".to_string(), "503".to_string()]);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(true, result.is_ok());
+
+        conf.upstreams =
+            Some(vec!["upstream1 95".to_string(), "upstream3 5".to_string()]);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(
+            "Invalid error upstream(upstream3) is not found(location:lo)",
+            result.expect_err("").to_string()
+        );
+        conf.upstreams = Some(vec!["upstream1 95".to_string()]);
+        conf.upstream_rules = Some(vec!["ip:1.1.1.1 upstream1".to_string()]);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(
+            "Invalid error upstream rule(ip:1.1.1.1 upstream1) is invalid(location:lo)",
+            result.expect_err("").to_string()
+        );
+        conf.upstream_rules =
+            Some(vec!["header:X-Canary=1 upstream1".to_string()]);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(true, result.is_ok());
+
+        conf.hedge_percentile = Some(100);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(
+            "Invalid error hedge percentile should be in 1-99(location:lo)",
+            result.expect_err("").to_string()
+        );
+        conf.hedge_percentile = Some(95);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(true, result.is_ok());
+
+        conf.mirror_upstream = Some("upstream2".to_string());
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(
+            "Invalid error upstream(upstream2) is not found(location:lo)",
+            result.expect_err("").to_string()
+        );
+        conf.mirror_upstream = Some("upstream1".to_string());
+        conf.mirror_percentage = Some(101);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(
+            "Invalid error mirror percentage should be in 1-100(location:lo)",
+            result.expect_err("").to_string()
+        );
+        conf.mirror_percentage = Some(10);
+        let result = conf.validate("lo", &upstream_names);
+        assert_eq!(true, result.is_ok());
     }
 
     #[test]
@@ -1498,8 +2296,9 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
     fn test_server_conf() {
         let mut conf = ServerConf::default();
         let location_names = vec!["lo".to_string()];
+        let upstream_names = vec!["charts".to_string()];
 
-        let result = conf.validate("test", &location_names);
+        let result = conf.validate("test", &location_names, &upstream_names);
         assert_eq!(true, result.is_err());
         assert_eq!(
             "Io error invalid socket address, ",
@@ -1508,7 +2307,7 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
 
         conf.addr = "127.0.0.1:3001".to_string();
         conf.locations = Some(vec!["lo1".to_string()]);
-        let result = conf.validate("test", &location_names);
+        let result = conf.validate("test", &location_names, &upstream_names);
         assert_eq!(true, result.is_err());
         assert_eq!(
             "Invalid error location(lo1) is not found(server:test)",
@@ -1516,8 +2315,152 @@ EHjKf0Dweb4ppL4ddgeAKU5V0qn76K2fFaE=
         );
 
         conf.locations = Some(vec!["lo".to_string()]);
-        let result = conf.validate("test", &location_names);
+        let result = conf.validate("test", &location_names, &upstream_names);
         assert_eq!(true, result.is_ok());
+
+        conf.proxy_protocol = Some(true);
+        assert_eq!(
+            "Invalid error proxy protocol trusted cidrs are not set(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.proxy_protocol_trusted_cidrs =
+            Some(vec!["10.0.0.0/8".to_string(), "192.168.1.1".to_string()]);
+        let result = conf.validate("test", &location_names, &upstream_names);
+        assert_eq!(true, result.is_ok());
+
+        conf.proxy_protocol_trusted_cidrs =
+            Some(vec!["10.0.0.0/33".to_string()]);
+        assert_eq!(
+            "Invalid error proxy protocol trusted cidr(10.0.0.0/33) is invalid(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+
+        conf.proxy_protocol = None;
+        conf.proxy_protocol_trusted_cidrs = None;
+        conf.kind = Some("stream".to_string());
+        assert_eq!(
+            "Invalid error stream upstream is not set(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.stream_upstream = Some("charts".to_string());
+        conf.stream_sni_upstreams =
+            Some(vec!["*.example.com diving".to_string()]);
+        assert_eq!(
+            "Invalid error upstream(diving) is not found(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.stream_sni_upstreams = Some(vec!["charts".to_string()]);
+        assert_eq!(
+            "Invalid error stream sni upstream(charts) is invalid(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.stream_sni_upstreams =
+            Some(vec!["*.example.com charts".to_string()]);
+        assert_eq!(
+            true,
+            conf.validate("test", &location_names, &upstream_names)
+                .is_ok()
+        );
+        assert_eq!(
+            vec![("*.example.com".to_string(), "charts".to_string())],
+            conf.get_stream_sni_upstreams()
+        );
+        conf.prometheus_metrics = Some("/metrics".to_string());
+        assert_eq!(
+            "Invalid error prometheus metrics(/metrics) of stream server should be a listen address with path(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.prometheus_metrics = Some("127.0.0.1:9100".to_string());
+        assert_eq!(
+            Some(("127.0.0.1:9100".to_string(), "/metrics".to_string())),
+            conf.get_stream_prometheus_pull()
+        );
+        conf.prometheus_metrics = Some("127.0.0.1:9100/stats".to_string());
+        assert_eq!(
+            true,
+            conf.validate("test", &location_names, &upstream_names)
+                .is_ok()
+        );
+        assert_eq!(
+            Some(("127.0.0.1:9100".to_string(), "/stats".to_string())),
+            conf.get_stream_prometheus_pull()
+        );
+        conf.kind = Some("udp".to_string());
+        conf.prometheus_metrics = Some("/metrics".to_string());
+        assert_eq!(
+            "Invalid error prometheus metrics(/metrics) of udp server should be a listen address with path(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.prometheus_metrics = Some("127.0.0.1:9100/stats".to_string());
+        assert_eq!(
+            Some(("127.0.0.1:9100".to_string(), "/stats".to_string())),
+            conf.get_stream_prometheus_pull()
+        );
+        conf.prometheus_metrics = None;
+        conf.stream_upstream = None;
+        assert_eq!(
+            "Invalid error stream upstream is not set(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.stream_upstream = Some("charts".to_string());
+        assert_eq!(
+            true,
+            conf.validate("test", &location_names, &upstream_names)
+                .is_ok()
+        );
+        conf.kind = Some("quic".to_string());
+        assert_eq!(
+            "Invalid error server kind(quic) is invalid(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.kind = None;
+
+        conf.tls_client_auth = Some("optional".to_string());
+        assert_eq!(
+            true,
+            conf.validate("test", &location_names, &upstream_names)
+                .is_ok()
+        );
+        conf.tls_client_auth = Some("none".to_string());
+        assert_eq!(
+            "Invalid error tls client auth(none) is invalid(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.tls_client_auth = None;
+
+        conf.tls_client_ca = Some("ca".to_string());
+        assert_eq!(
+            "Invalid error tls client ca is set but tls is not enabled(server:test)",
+            conf.validate("test", &location_names, &upstream_names)
+                .expect_err("")
+                .to_string()
+        );
+        conf.global_certificates = Some(true);
+        assert_eq!(
+            true,
+            conf.validate("test", &location_names, &upstream_names)
+                .is_ok()
+        );
     }
 
     #[test]
@@ -1587,6 +2530,24 @@ ai02RHnemmqJaNepfmCdyec=
         let result = conf.validate();
         assert_eq!(true, result.is_ok());
 
-        assert_eq!("9c44d229a135d931", conf.hash_key());
+        assert_eq!("5bde584e8bf90e9a", conf.hash_key());
+
+        // client certificate of upstream mTLS
+        let mut upstream = UpstreamConf {
+            addrs: vec!["127.0.0.1:5000".to_string()],
+            client_tls_cert: Some(pem.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "Invalid error client tls cert and key should be set together(upstream:test)",
+            upstream.validate("test").expect_err("").to_string()
+        );
+        upstream.client_tls_key = Some(pem.to_string());
+        assert_eq!(
+            "Invalid error client tls key is invalid(upstream:test)",
+            upstream.validate("test").expect_err("").to_string()
+        );
+        upstream.client_tls_key = Some(key.to_string());
+        assert_eq!(true, upstream.validate("test").is_ok());
     }
 }
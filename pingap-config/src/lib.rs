@@ -62,7 +62,7 @@ pub enum Error {
     #[snafu(display("Regex error {source}"))]
     Regex { source: regex::Error },
     #[snafu(display("Etcd error {source}"))]
-    Etcd { source: etcd_client::Error },
+    Etcd { source: Box<etcd_client::Error> },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -81,10 +81,9 @@ impl Observer {
             tokio::time::sleep(sleep_time).await;
             return Ok(false);
         };
-        let resp = stream
-            .message()
-            .await
-            .map_err(|e| Error::Etcd { source: e })?;
+        let resp = stream.message().await.map_err(|e| Error::Etcd {
+            source: Box::new(e),
+        })?;
 
         Ok(resp.is_some())
     }
@@ -129,15 +128,15 @@ pub trait ConfigStorage {
 }
 
 // Global static storage for the configuration backend
-static CONFIG_STORAGE: OnceCell<Box<(dyn ConfigStorage + Sync + Send)>> =
+static CONFIG_STORAGE: OnceCell<Box<dyn ConfigStorage + Sync + Send>> =
     OnceCell::new();
 
 // Creates a new configuration storage based on the path
 // Supports both etcd:// and file:// protocols
 fn new_config_storage(
     path: &str,
-) -> Result<Box<(dyn ConfigStorage + Sync + Send)>> {
-    let s: Box<(dyn ConfigStorage + Sync + Send)> =
+) -> Result<Box<dyn ConfigStorage + Sync + Send>> {
+    let s: Box<dyn ConfigStorage + Sync + Send> =
         if path.starts_with(ETCD_PROTOCOL) {
             let storage = EtcdStorage::new(path)?;
             Box::new(storage)
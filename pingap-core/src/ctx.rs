@@ -130,6 +130,16 @@ pub struct Ctx {
     pub tls_cipher: Option<String>,
     /// Time taken for TLS handshake with client (in milliseconds)
     pub tls_handshake_time: Option<u64>,
+    /// Subject of the verified client certificate (e.g., "CN=client,O=pingap"),
+    /// it is not available for http2 connections
+    pub tls_client_subject: Option<String>,
+    /// Subject alternative names of the verified client certificate,
+    /// it is not available for http2 connections
+    pub tls_client_san: Option<String>,
+    /// Sha256 fingerprint(hex) of the verified client certificate
+    pub tls_client_fingerprint: Option<String>,
+    /// Server name indication of the tls connection proxied by the stream server
+    pub tls_server_name: Option<String>,
     /// HTTP status code of the response
     pub status: Option<StatusCode>,
     /// Total time the connection has been alive (in milliseconds)
@@ -173,6 +183,17 @@ pub struct Ctx {
     pub upstream_reused: bool,
     /// Number of requests processing by upstream
     pub upstream_processing: Option<i32>,
+    /// Number of retries to upstream
+    pub upstream_retries: u32,
+    /// Addresses of the upstream servers that failed and are excluded when retrying
+    pub upstream_failed_addresses: Option<Vec<String>>,
+    /// Number of hedged requests to upstream
+    pub upstream_hedged_requests: u32,
+    /// Whether the response is from the hedged request
+    pub upstream_hedge_won: bool,
+    /// Whether the circuit breaker of upstream has been acquired by the
+    /// hedged request, the normal proxy doesn't acquire it again
+    pub upstream_circuit_breaker_acquired: bool,
     /// Time taken to establish/reuse upstream connection (in milliseconds)
     pub upstream_connect_time: Option<u64>,
     /// Current number of active upstream connections
@@ -190,6 +211,10 @@ pub struct Ctx {
     pub upstream_connection_time: Option<u64>,
     /// Size of the request payload in bytes
     pub payload_size: usize,
+    /// Upstream of the mirror request, the request is mirrored after its body is received
+    pub mirror_upstream: Option<String>,
+    /// Buffered request body of the mirror request
+    pub mirror_body: Option<BytesMut>,
     /// Statistics about response compression
     pub compression_stat: Option<CompressionStat>,
     /// Handler for modifying response body
@@ -338,6 +363,7 @@ impl Ctx {
                     buf.extend(b"false");
                 }
             },
+            "upstream" => buf.extend(self.upstream.as_bytes()),
             "upstream_addr" => buf.extend(self.upstream_address.as_bytes()),
             "processing" => buf
                 .extend(itoa::Buffer::new().format(self.processing).as_bytes()),
@@ -406,10 +432,8 @@ impl Ctx {
                     buf = format_duration(buf, ms);
                 }
             },
-            "location" => {
-                if !self.location.is_empty() {
-                    buf.extend(self.location.as_bytes())
-                }
+            "location" if !self.location.is_empty() => {
+                buf.extend(self.location.as_bytes())
             },
             "connection_time" => {
                 buf.extend(
@@ -436,6 +460,26 @@ impl Ctx {
                     buf.extend(value.as_bytes());
                 }
             },
+            "tls_client_subject" => {
+                if let Some(value) = &self.tls_client_subject {
+                    buf.extend(value.as_bytes());
+                }
+            },
+            "tls_client_san" => {
+                if let Some(value) = &self.tls_client_san {
+                    buf.extend(value.as_bytes());
+                }
+            },
+            "tls_client_fingerprint" => {
+                if let Some(value) = &self.tls_client_fingerprint {
+                    buf.extend(value.as_bytes());
+                }
+            },
+            "tls_server_name" => {
+                if let Some(value) = &self.tls_server_name {
+                    buf.extend(value.as_bytes());
+                }
+            },
             "tls_handshake_time" => {
                 if let Some(ms) = self.tls_handshake_time {
                     buf.extend(itoa::Buffer::new().format(ms).as_bytes());
@@ -695,6 +739,12 @@ mod tests {
                 .as_ref()
         );
 
+        ctx.upstream = "charts".to_string();
+        assert_eq!(
+            b"charts",
+            ctx.append_value(BytesMut::new(), "upstream").as_ref()
+        );
+
         ctx.upstream_address = "192.168.1.1:80".to_string();
         assert_eq!(
             b"192.168.1.1:80",
@@ -835,6 +885,31 @@ mod tests {
             ctx.append_value(BytesMut::new(), "tls_cipher").as_ref()
         );
 
+        ctx.tls_client_subject = Some("CN=client,O=pingap".to_string());
+        assert_eq!(
+            b"CN=client,O=pingap",
+            ctx.append_value(BytesMut::new(), "tls_client_subject")
+                .as_ref()
+        );
+        ctx.tls_client_san = Some("DNS:client.pingap.io".to_string());
+        assert_eq!(
+            b"DNS:client.pingap.io",
+            ctx.append_value(BytesMut::new(), "tls_client_san").as_ref()
+        );
+        ctx.tls_client_fingerprint = Some("8f43".to_string());
+        assert_eq!(
+            b"8f43",
+            ctx.append_value(BytesMut::new(), "tls_client_fingerprint")
+                .as_ref()
+        );
+
+        ctx.tls_server_name = Some("pingap.io".to_string());
+        assert_eq!(
+            b"pingap.io",
+            ctx.append_value(BytesMut::new(), "tls_server_name")
+                .as_ref()
+        );
+
         ctx.tls_handshake_time = Some(101);
         assert_eq!(
             b"101",
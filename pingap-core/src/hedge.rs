@@ -0,0 +1,185 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::now_ms;
+use crate::retry::RetryBudget;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Number of the latest response times used to calculate the percentile
+const HEDGE_LATENCY_SAMPLES: usize = 512;
+// Minimum number of samples before the percentile delay is used
+const HEDGE_LATENCY_MIN_SAMPLES: usize = 20;
+// The percentile is recalculated after the number of new samples
+const HEDGE_LATENCY_RECALCULATE: u64 = 32;
+
+/// Default percentage of hedged requests to requests
+pub const DEFAULT_HEDGE_BUDGET: u32 = 10;
+
+#[derive(Debug, Default)]
+struct LatencySamples {
+    samples: Vec<u64>,
+    count: u64,
+    percentile: Option<u64>,
+}
+
+/// Hedge policy of the request to upstream, if the response header
+/// is not received within the delay, another request is sent to a
+/// different backend and the first response wins.
+#[derive(Debug)]
+pub struct HedgePolicy {
+    /// Fixed delay before sending the hedged request
+    delay: Option<Duration>,
+    /// Percentile of the response time as the delay, e.g. 95
+    percentile: Option<u8>,
+    /// Maximum number of hedged requests of each request
+    max_attempts: u32,
+    /// Hedge budget, none means unlimited
+    budget: Option<RetryBudget>,
+    /// The latest response times(ms) to calculate the percentile
+    latencies: Mutex<LatencySamples>,
+}
+
+impl HedgePolicy {
+    /// Creates a new hedge policy, returns None if neither the delay
+    /// nor the percentile is set.
+    ///
+    /// # Arguments
+    /// * `delay` - Fixed delay before sending the hedged request,
+    ///   it's also used before the percentile has enough samples
+    /// * `percentile` - Percentile of the response time as the delay
+    /// * `max_attempts` - Maximum number of hedged requests of each request
+    /// * `budget` - Maximum percentage of hedged requests to requests, zero means unlimited
+    pub fn new(
+        delay: Option<Duration>,
+        percentile: Option<u8>,
+        max_attempts: u32,
+        budget: u32,
+    ) -> Option<Self> {
+        let percentile = percentile.filter(|value| *value > 0 && *value < 100);
+        if delay.is_none() && percentile.is_none() {
+            return None;
+        }
+        Some(Self {
+            delay,
+            percentile,
+            max_attempts: max_attempts.max(1),
+            budget: (budget > 0).then(|| RetryBudget::new(budget)),
+            latencies: Mutex::new(LatencySamples::default()),
+        })
+    }
+    /// Returns the maximum number of hedged requests of each request
+    #[inline]
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+    /// Returns the delay before sending the hedged request, the percentile
+    /// of response time is used if it has enough samples,
+    /// otherwise the fixed delay. None means no hedged request.
+    pub fn delay(&self) -> Option<Duration> {
+        if self.percentile.is_some() {
+            if let Some(value) = self
+                .latencies
+                .lock()
+                .ok()
+                .and_then(|latencies| latencies.percentile)
+            {
+                return Some(Duration::from_millis(value.max(1)));
+            }
+        }
+        self.delay
+    }
+    /// Records a new request for the hedge budget
+    pub fn on_request(&self) {
+        if let Some(budget) = &self.budget {
+            budget.on_request(now_ms());
+        }
+    }
+    /// Acquires the permission of a hedged request from the hedge budget
+    pub fn acquire(&self) -> bool {
+        if let Some(budget) = &self.budget {
+            return budget.acquire(now_ms());
+        }
+        true
+    }
+    /// Records the response time(ms) of upstream,
+    /// it's used to calculate the percentile delay
+    pub fn observe(&self, latency: u64) {
+        let Some(percentile) = self.percentile else {
+            return;
+        };
+        let Ok(mut latencies) = self.latencies.lock() else {
+            return;
+        };
+        let index = (latencies.count % HEDGE_LATENCY_SAMPLES as u64) as usize;
+        if index < latencies.samples.len() {
+            latencies.samples[index] = latency;
+        } else {
+            latencies.samples.push(latency);
+        }
+        latencies.count += 1;
+        let size = latencies.samples.len();
+        if size < HEDGE_LATENCY_MIN_SAMPLES
+            || (latencies.percentile.is_some()
+                && latencies.count % HEDGE_LATENCY_RECALCULATE != 0)
+        {
+            return;
+        }
+        let mut samples = latencies.samples.clone();
+        samples.sort_unstable();
+        let index = (size * percentile as usize).div_ceil(100) - 1;
+        latencies.percentile = Some(samples[index.min(size - 1)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_hedge_policy() {
+        assert_eq!(true, HedgePolicy::new(None, None, 1, 10).is_none());
+        assert_eq!(true, HedgePolicy::new(None, Some(100), 1, 10).is_none());
+
+        let policy =
+            HedgePolicy::new(Some(Duration::from_millis(50)), None, 0, 0)
+                .unwrap();
+        assert_eq!(1, policy.max_attempts());
+        assert_eq!(Some(Duration::from_millis(50)), policy.delay());
+        policy.observe(10);
+        assert_eq!(Some(Duration::from_millis(50)), policy.delay());
+        // no budget
+        for _ in 0..100 {
+            assert_eq!(true, policy.acquire());
+        }
+
+        let policy = HedgePolicy::new(None, Some(95), 2, 10).unwrap();
+        assert_eq!(None, policy.delay());
+        for i in 0..100 {
+            policy.observe(if i % 25 == 0 { 1000 } else { 10 });
+            policy.on_request();
+        }
+        assert_eq!(Some(Duration::from_millis(10)), policy.delay());
+        for _ in 0..64 {
+            policy.observe(1000);
+        }
+        assert_eq!(Some(Duration::from_millis(1000)), policy.delay());
+        // at least 10 hedged requests in the window
+        for _ in 0..10 {
+            assert_eq!(true, policy.acquire());
+        }
+        assert_eq!(false, policy.acquire());
+    }
+}
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{get_hostname, Ctx};
+use super::{
+    get_hostname, get_proxy_protocol_connection, Ctx, ProxyProtocolAddrs,
+};
 use bytes::BytesMut;
 use http::header;
 use http::{HeaderName, HeaderValue};
@@ -288,12 +290,47 @@ fn handle_context_value(buf: &[u8], ctx: &Ctx) -> Option<HeaderValue> {
     }
 }
 
-/// Get remote addr from session
+/// Get remote addr from session, the original client address is used
+/// if the connection is relayed by the PROXY protocol listener.
 pub fn get_remote_addr(session: &Session) -> Option<(String, u16)> {
     session
         .client_addr()
         .and_then(|addr| addr.as_inet())
-        .map(|addr| (addr.ip().to_string(), addr.port()))
+        .map(|addr| {
+            let addr = get_proxy_protocol_connection(addr)
+                .map(|addrs| addrs.source)
+                .unwrap_or(*addr);
+            (addr.ip().to_string(), addr.port())
+        })
+}
+
+/// Get server addr from session, the address that the client connected to
+/// is used if the connection is relayed by the PROXY protocol listener.
+pub fn get_server_addr(session: &Session) -> Option<(String, u16)> {
+    let client_addr = session.client_addr().and_then(|addr| addr.as_inet());
+    session
+        .server_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| {
+            let addr = client_addr
+                .and_then(get_proxy_protocol_connection)
+                .map(|addrs| addrs.destination)
+                .unwrap_or(*addr);
+            (addr.ip().to_string(), addr.port())
+        })
+}
+
+/// Gets the original client and server addresses of the connection,
+/// the addresses of PROXY header are used if the connection is relayed.
+pub fn get_connection_addrs(session: &Session) -> Option<ProxyProtocolAddrs> {
+    let client_addr = session.client_addr()?.as_inet()?;
+    if let Some(addrs) = get_proxy_protocol_connection(client_addr) {
+        return Some(addrs);
+    }
+    Some(ProxyProtocolAddrs {
+        source: *client_addr,
+        destination: *session.server_addr()?.as_inet()?,
+    })
 }
 
 /// Gets client ip from X-Forwarded-For,
@@ -352,7 +389,7 @@ pub fn get_cookie_value<'a>(
     if let Some(cookie_value) = get_req_header_value(req_header, "Cookie") {
         for item in cookie_value.split(';') {
             if let Some((k, v)) = item.split_once('=') {
-                if k == cookie_name {
+                if k.trim() == cookie_name {
                     return Some(v.trim());
                 }
             }
@@ -805,6 +842,17 @@ mod tests {
             get_cookie_value(session.req_header(), "name"),
             Some("pingap")
         );
+
+        let headers = ["Cookie: uid=123; name=pingap"].join("\r\n");
+        let input_header =
+            format!("GET /vicanso/pingap?size=1 HTTP/1.1\r\n{headers}\r\n\r\n");
+        let mock_io = Builder::new().read(input_header.as_bytes()).build();
+        let mut session = Session::new_h1(Box::new(mock_io));
+        session.read_request().await.unwrap();
+        assert_eq!(
+            get_cookie_value(session.req_header(), "name"),
+            Some("pingap")
+        );
     }
 
     #[test]
@@ -34,21 +34,27 @@ pub fn new_internal_error(status: u16, message: String) -> pingora::BError {
 }
 
 mod ctx;
+mod hedge;
 mod http_header;
 mod http_response;
 mod notification;
 mod plugin;
+mod proxy_protocol;
+mod retry;
 mod service;
 mod ttl_lru_limit;
 mod util;
 
 pub use ctx::*;
+pub use hedge::*;
 pub use http_header::*;
 pub use http_response::*;
 pub use notification::*;
 pub use pingora_limits::inflight::*;
 pub use pingora_limits::rate::*;
 pub use plugin::*;
+pub use proxy_protocol::*;
+pub use retry::*;
 pub use service::*;
 pub use tinyufo::TinyUfo;
 pub use ttl_lru_limit::*;
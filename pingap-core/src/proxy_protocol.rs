@@ -0,0 +1,462 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Error;
+use ahash::AHashMap;
+use once_cell::sync::Lazy;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Signature of PROXY protocol v1
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+// Signature of PROXY protocol v2
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Max length of v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+// Length of v2 header before the addresses
+const V2_HEADER_LENGTH: usize = 16;
+
+/// Version of PROXY protocol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "v1" | "1" => Ok(Self::V1),
+            "v2" | "2" => Ok(Self::V2),
+            _ => Err(Error::Invalid {
+                message: format!("proxy protocol version({value}) is invalid"),
+            }),
+        }
+    }
+}
+
+/// Addresses of the original connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProxyProtocolAddrs {
+    /// Address of the client
+    pub source: SocketAddr,
+    /// Address that the client connected to
+    pub destination: SocketAddr,
+}
+
+/// Result of parsing the PROXY header
+#[derive(Debug, PartialEq)]
+pub enum ProxyProtocolHeader {
+    /// More data is needed to parse the header
+    Incomplete,
+    /// The data is not started with the PROXY header
+    Missing,
+    /// The header is parsed, the addresses are none for the LOCAL command
+    /// or the UNKNOWN protocol, `size` is the length of the header
+    Parsed {
+        addrs: Option<ProxyProtocolAddrs>,
+        size: usize,
+    },
+}
+
+fn new_invalid_error(message: &str) -> Error {
+    Error::Invalid {
+        message: format!("proxy protocol header is invalid, {message}"),
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<ProxyProtocolHeader> {
+    let limit = buf.len().min(V1_MAX_LENGTH);
+    let Some(end) = buf[..limit].windows(2).position(|item| item == b"\r\n")
+    else {
+        if buf.len() >= V1_MAX_LENGTH {
+            return Err(new_invalid_error("v1 header is too long"));
+        }
+        return Ok(ProxyProtocolHeader::Incomplete);
+    };
+    let size = end + 2;
+    let line = std::str::from_utf8(&buf[..end])
+        .map_err(|_| new_invalid_error("v1 header is not utf8"))?;
+    let arr: Vec<&str> = line.split(' ').collect();
+    if arr.get(1) == Some(&"UNKNOWN") {
+        return Ok(ProxyProtocolHeader::Parsed { addrs: None, size });
+    }
+    let [_, protocol, source_ip, destination_ip, source_port, destination_port] =
+        arr.as_slice()
+    else {
+        return Err(new_invalid_error("v1 header fields are invalid"));
+    };
+    let parse_addr = |ip: &str, port: &str| -> Result<SocketAddr> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| new_invalid_error("v1 ip is invalid"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| new_invalid_error("v1 port is invalid"))?;
+        let matched = match *protocol {
+            "TCP4" => ip.is_ipv4(),
+            "TCP6" => ip.is_ipv6(),
+            _ => false,
+        };
+        if !matched {
+            return Err(new_invalid_error("v1 protocol is invalid"));
+        }
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(ProxyProtocolHeader::Parsed {
+        addrs: Some(ProxyProtocolAddrs {
+            source: parse_addr(source_ip, source_port)?,
+            destination: parse_addr(destination_ip, destination_port)?,
+        }),
+        size,
+    })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<ProxyProtocolHeader> {
+    if buf.len() < V2_HEADER_LENGTH {
+        return Ok(ProxyProtocolHeader::Incomplete);
+    }
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(new_invalid_error("v2 version is invalid"));
+    }
+    let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let size = V2_HEADER_LENGTH + length;
+    if buf.len() < size {
+        return Ok(ProxyProtocolHeader::Incomplete);
+    }
+    let data = &buf[V2_HEADER_LENGTH..size];
+    let addrs = match (version_command & 0x0f, buf[13] >> 4) {
+        // LOCAL command, the connection is established by the proxy itself
+        (0, _) => None,
+        // PROXY command with AF_INET
+        (1, 1) if length >= 12 => {
+            let ip = |offset: usize| {
+                IpAddr::from(
+                    <[u8; 4]>::try_from(&data[offset..offset + 4])
+                        .unwrap_or_default(),
+                )
+            };
+            Some(ProxyProtocolAddrs {
+                source: SocketAddr::new(
+                    ip(0),
+                    u16::from_be_bytes([data[8], data[9]]),
+                ),
+                destination: SocketAddr::new(
+                    ip(4),
+                    u16::from_be_bytes([data[10], data[11]]),
+                ),
+            })
+        },
+        // PROXY command with AF_INET6
+        (1, 2) if length >= 36 => {
+            let ip = |offset: usize| {
+                IpAddr::from(
+                    <[u8; 16]>::try_from(&data[offset..offset + 16])
+                        .unwrap_or_default(),
+                )
+            };
+            Some(ProxyProtocolAddrs {
+                source: SocketAddr::new(
+                    ip(0),
+                    u16::from_be_bytes([data[32], data[33]]),
+                ),
+                destination: SocketAddr::new(
+                    ip(16),
+                    u16::from_be_bytes([data[34], data[35]]),
+                ),
+            })
+        },
+        // the addresses of unix socket or unspecified family are ignored
+        (1, 0 | 3) => None,
+        _ => return Err(new_invalid_error("v2 command or family is invalid")),
+    };
+    Ok(ProxyProtocolHeader::Parsed { addrs, size })
+}
+
+/// Parses the PROXY protocol v1 or v2 header from the beginning of the data.
+pub fn parse_proxy_protocol(buf: &[u8]) -> Result<ProxyProtocolHeader> {
+    for signature in [V1_SIGNATURE, V2_SIGNATURE] {
+        let size = buf.len().min(signature.len());
+        if buf[..size] != signature[..size] {
+            continue;
+        }
+        if size < signature.len() {
+            return Ok(ProxyProtocolHeader::Incomplete);
+        }
+        if signature == V1_SIGNATURE {
+            return parse_v1(buf);
+        }
+        return parse_v2(buf);
+    }
+    Ok(ProxyProtocolHeader::Missing)
+}
+
+/// Creates the PROXY protocol header, the LOCAL command(v2)
+/// or UNKNOWN protocol(v1) is used if the addresses are none.
+pub fn new_proxy_protocol_header(
+    version: ProxyProtocolVersion,
+    addrs: Option<&ProxyProtocolAddrs>,
+) -> Vec<u8> {
+    // the ipv4 address is mapped to ipv6 if the families are different
+    let addrs = addrs.map(|addrs| {
+        let mut source = addrs.source;
+        let mut destination = addrs.destination;
+        if source.is_ipv4() != destination.is_ipv4() {
+            for addr in [&mut source, &mut destination] {
+                if let IpAddr::V4(ip) = addr.ip() {
+                    addr.set_ip(IpAddr::V6(ip.to_ipv6_mapped()));
+                }
+            }
+        }
+        (source, destination)
+    });
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let Some((source, destination)) = addrs else {
+                return b"PROXY UNKNOWN\r\n".to_vec();
+            };
+            let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {protocol} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            let Some((source, destination)) = addrs else {
+                buf.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                return buf;
+            };
+            let mut data = vec![];
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    data.extend_from_slice(&source_ip.octets());
+                    data.extend_from_slice(&destination_ip.octets());
+                    0x11
+                },
+                (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                    data.extend_from_slice(&source_ip.octets());
+                    data.extend_from_slice(&destination_ip.octets());
+                    0x21
+                },
+                // the families are the same after mapping
+                _ => 0x00,
+            };
+            data.extend_from_slice(&source.port().to_be_bytes());
+            data.extend_from_slice(&destination.port().to_be_bytes());
+            buf.extend_from_slice(&[0x21, family]);
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&data);
+            buf
+        },
+    }
+}
+
+// Original addresses of the connections relayed by the PROXY protocol
+// listener, the key is the local address of the relayed connection
+static PROXY_PROTOCOL_CONNECTIONS: Lazy<
+    RwLock<AHashMap<SocketAddr, ProxyProtocolAddrs>>,
+> = Lazy::new(|| RwLock::new(AHashMap::new()));
+
+/// Adds the original addresses of the relayed connection
+pub fn add_proxy_protocol_connection(
+    relay_addr: SocketAddr,
+    addrs: ProxyProtocolAddrs,
+) {
+    if let Ok(mut connections) = PROXY_PROTOCOL_CONNECTIONS.write() {
+        connections.insert(relay_addr, addrs);
+    }
+}
+
+/// Removes the original addresses of the relayed connection
+pub fn remove_proxy_protocol_connection(relay_addr: &SocketAddr) {
+    if let Ok(mut connections) = PROXY_PROTOCOL_CONNECTIONS.write() {
+        connections.remove(relay_addr);
+    }
+}
+
+/// Gets the original addresses of the relayed connection
+pub fn get_proxy_protocol_connection(
+    relay_addr: &SocketAddr,
+) -> Option<ProxyProtocolAddrs> {
+    let connections = PROXY_PROTOCOL_CONNECTIONS.read().ok()?;
+    if connections.is_empty() {
+        return None;
+    }
+    connections.get(relay_addr).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_proxy_protocol_v1() {
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "10.0.0.1:443".parse().unwrap(),
+        };
+        let data = b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 443\r\nGET /";
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: Some(addrs),
+                size: 43,
+            },
+            parse_proxy_protocol(data).unwrap()
+        );
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: None,
+                size: 15,
+            },
+            parse_proxy_protocol(b"PROXY UNKNOWN\r\n").unwrap()
+        );
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: Some(ProxyProtocolAddrs {
+                    source: "[2001:db8::1]:8080".parse().unwrap(),
+                    destination: "[::1]:80".parse().unwrap(),
+                }),
+                size: 36,
+            },
+            parse_proxy_protocol(b"PROXY TCP6 2001:db8::1 ::1 8080 80\r\n\r\n")
+                .unwrap()
+        );
+
+        for data in [&b"PRO"[..], b"PROXY TCP4 192.168.1.1"] {
+            assert_eq!(
+                ProxyProtocolHeader::Incomplete,
+                parse_proxy_protocol(data).unwrap()
+            );
+        }
+        for data in [&b"GET / HTTP/1.1\r\n"[..], b"\x16\x03\x01"] {
+            assert_eq!(
+                ProxyProtocolHeader::Missing,
+                parse_proxy_protocol(data).unwrap()
+            );
+        }
+        assert_eq!(
+            "Invalid error: proxy protocol header is invalid, v1 protocol is invalid",
+            parse_proxy_protocol(b"PROXY TCP6 192.168.1.1 10.0.0.1 1 2\r\n")
+                .unwrap_err()
+                .to_string()
+        );
+        let mut data = V1_SIGNATURE.to_vec();
+        data.extend_from_slice(&[b'1'; V1_MAX_LENGTH]);
+        assert_eq!(
+            "Invalid error: proxy protocol header is invalid, v1 header is too long",
+            parse_proxy_protocol(&data).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2() {
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "10.0.0.1:443".parse().unwrap(),
+        };
+        let mut data =
+            new_proxy_protocol_header(ProxyProtocolVersion::V2, Some(&addrs));
+        assert_eq!(28, data.len());
+        assert_eq!(
+            ProxyProtocolHeader::Incomplete,
+            parse_proxy_protocol(&data[..20]).unwrap()
+        );
+        data.extend_from_slice(b"GET /");
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: Some(addrs),
+                size: 28,
+            },
+            parse_proxy_protocol(&data).unwrap()
+        );
+
+        // ipv4 is mapped to ipv6
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "[2001:db8::1]:443".parse().unwrap(),
+        };
+        let data =
+            new_proxy_protocol_header(ProxyProtocolVersion::V2, Some(&addrs));
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: Some(ProxyProtocolAddrs {
+                    source: "[::ffff:192.168.1.1]:56324".parse().unwrap(),
+                    destination: addrs.destination,
+                }),
+                size: 52,
+            },
+            parse_proxy_protocol(&data).unwrap()
+        );
+
+        let data = new_proxy_protocol_header(ProxyProtocolVersion::V2, None);
+        assert_eq!(
+            ProxyProtocolHeader::Parsed {
+                addrs: None,
+                size: 16,
+            },
+            parse_proxy_protocol(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_new_proxy_protocol_header_v1() {
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "10.0.0.1:443".parse().unwrap(),
+        };
+        assert_eq!(
+            b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 443\r\n".to_vec(),
+            new_proxy_protocol_header(ProxyProtocolVersion::V1, Some(&addrs))
+        );
+        assert_eq!(
+            b"PROXY UNKNOWN\r\n".to_vec(),
+            new_proxy_protocol_header(ProxyProtocolVersion::V1, None)
+        );
+        assert_eq!(
+            ProxyProtocolVersion::V2,
+            ProxyProtocolVersion::from_str("v2").unwrap()
+        );
+        assert_eq!(
+            "Invalid error: proxy protocol version(v3) is invalid",
+            ProxyProtocolVersion::from_str("v3")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_proxy_protocol_connection() {
+        let relay_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "10.0.0.1:443".parse().unwrap(),
+        };
+        assert_eq!(None, get_proxy_protocol_connection(&relay_addr));
+        add_proxy_protocol_connection(relay_addr, addrs);
+        assert_eq!(Some(addrs), get_proxy_protocol_connection(&relay_addr));
+        remove_proxy_protocol_connection(&relay_addr);
+        assert_eq!(None, get_proxy_protocol_connection(&relay_addr));
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::now_ms;
+use http::Method;
+use pingora::{Error, ErrorSource, ErrorType};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The window of retry budget, the counters are reset for each window
+const RETRY_BUDGET_WINDOW_MS: u64 = 10_000;
+// The minimum retries allowed in each window, so that the retry
+// is still available when the traffic is low
+const RETRY_BUDGET_MIN_RETRIES: u64 = 10;
+
+/// Default percentage of retries to requests
+pub const DEFAULT_RETRY_BUDGET: u32 = 20;
+
+pub const RETRY_ON_CONNECT_ERROR: &str = "connect_error";
+pub const RETRY_ON_TIMEOUT: &str = "timeout";
+pub const RETRY_ON_ERROR: &str = "error";
+
+/// The reason why the request to upstream fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryCondition {
+    /// Fail to connect to upstream
+    ConnectError,
+    /// Connect, read or write timeout
+    Timeout,
+    /// Other io errors after connected, e.g. connection reset
+    Error,
+    /// Upstream responds with the status code
+    Status(u16),
+}
+
+impl RetryCondition {
+    /// Gets the retry condition from the upstream error,
+    /// returns None if the error is not from upstream.
+    pub fn from_error(e: &Error, connecting: bool) -> Option<Self> {
+        if e.esource() != &ErrorSource::Upstream {
+            return None;
+        }
+        let condition = match e.etype() {
+            ErrorType::HTTPStatus(code) => Self::Status(*code),
+            ErrorType::ConnectTimedout
+            | ErrorType::ReadTimedout
+            | ErrorType::WriteTimedout => Self::Timeout,
+            _ if connecting => Self::ConnectError,
+            _ => Self::Error,
+        };
+        Some(condition)
+    }
+}
+
+/// Retry budget limits the ratio of retries to requests in a time window,
+/// so that the retries can't amplify an outage of upstream.
+/// It's also used to limit the hedged requests.
+#[derive(Debug, Default)]
+pub(crate) struct RetryBudget {
+    percent: u64,
+    window_start: AtomicU64,
+    requests: AtomicU64,
+    retries: AtomicU64,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(percent: u32) -> Self {
+        Self {
+            percent: percent as u64,
+            ..Default::default()
+        }
+    }
+    fn reset_if_expired(&self, now: u64) {
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now.saturating_sub(start) < RETRY_BUDGET_WINDOW_MS {
+            return;
+        }
+        if self
+            .window_start
+            .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.requests.store(0, Ordering::Relaxed);
+            self.retries.store(0, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn on_request(&self, now: u64) {
+        self.reset_if_expired(now);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn acquire(&self, now: u64) -> bool {
+        self.reset_if_expired(now);
+        let requests = self.requests.load(Ordering::Relaxed);
+        let limit =
+            (requests * self.percent / 100).max(RETRY_BUDGET_MIN_RETRIES);
+        self.retries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| {
+                (retries < limit).then_some(retries + 1)
+            })
+            .is_ok()
+    }
+}
+
+/// Retry policy of the request to upstream
+#[derive(Debug, Default)]
+pub struct RetryPolicy {
+    /// Maximum number of retries
+    max_retries: u32,
+    /// Retry on connect error
+    connect_error: bool,
+    /// Retry on connect, read or write timeout
+    timeout: bool,
+    /// Retry on other io errors
+    error: bool,
+    /// Retry on the status codes of upstream response
+    statuses: Vec<u16>,
+    /// Only retry the idempotent requests
+    idempotent_only: bool,
+    /// Retry budget, none means unlimited
+    budget: Option<RetryBudget>,
+}
+
+impl RetryPolicy {
+    /// Creates a new retry policy
+    ///
+    /// # Arguments
+    /// * `max_retries` - Maximum number of retries
+    /// * `retry_on` - Conditions to retry: "connect_error", "timeout", "error" or status code like "502",
+    ///   only retry on connect error if it's empty
+    /// * `idempotent_only` - Only retry the idempotent requests
+    /// * `budget` - Maximum percentage of retries to requests, zero means unlimited
+    pub fn new(
+        max_retries: u32,
+        retry_on: &[String],
+        idempotent_only: bool,
+        budget: u32,
+    ) -> Self {
+        let mut policy = Self {
+            max_retries,
+            idempotent_only,
+            ..Default::default()
+        };
+        if retry_on.is_empty() {
+            policy.connect_error = true;
+        }
+        for item in retry_on.iter() {
+            match item.trim() {
+                RETRY_ON_CONNECT_ERROR => policy.connect_error = true,
+                RETRY_ON_TIMEOUT => policy.timeout = true,
+                RETRY_ON_ERROR => policy.error = true,
+                value => {
+                    if let Ok(code) = value.parse::<u16>() {
+                        policy.statuses.push(code);
+                    }
+                },
+            }
+        }
+        if budget > 0 {
+            policy.budget = Some(RetryBudget::new(budget));
+        }
+        policy
+    }
+    /// Records a new request for the retry budget
+    pub fn on_request(&self) {
+        if let Some(budget) = &self.budget {
+            budget.on_request(now_ms());
+        }
+    }
+    /// Returns whether the status code of upstream response is retryable
+    #[inline]
+    pub fn is_retry_status(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+    /// Returns whether the request can be retried, the retry budget
+    /// is consumed if it can be retried.
+    ///
+    /// # Arguments
+    /// * `method` - Method of the request
+    /// * `retries` - Number of retries have been done
+    /// * `condition` - The reason why the request fails
+    pub fn can_retry(
+        &self,
+        method: &Method,
+        retries: u32,
+        condition: RetryCondition,
+    ) -> bool {
+        if retries >= self.max_retries {
+            return false;
+        }
+        if self.idempotent_only && !method.is_idempotent() {
+            return false;
+        }
+        let matched = match condition {
+            RetryCondition::ConnectError => self.connect_error,
+            RetryCondition::Timeout => self.timeout,
+            RetryCondition::Error => self.error,
+            RetryCondition::Status(code) => self.is_retry_status(code),
+        };
+        if !matched {
+            return false;
+        }
+        if let Some(budget) = &self.budget {
+            return budget.acquire(now_ms());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_retry_condition() {
+        let e = Error::explain(ErrorType::HTTPStatus(502), "bad gateway");
+        assert_eq!(None, RetryCondition::from_error(&e, false));
+        let e = Error::new_up(ErrorType::HTTPStatus(502));
+        assert_eq!(
+            Some(RetryCondition::Status(502)),
+            RetryCondition::from_error(&e, false)
+        );
+
+        let e = Error::new(ErrorType::ConnectRefused);
+        assert_eq!(None, RetryCondition::from_error(&e, true));
+        let e = Error::new_up(ErrorType::ConnectRefused);
+        assert_eq!(
+            Some(RetryCondition::ConnectError),
+            RetryCondition::from_error(&e, true)
+        );
+        assert_eq!(
+            Some(RetryCondition::Error),
+            RetryCondition::from_error(&e, false)
+        );
+
+        let e = Error::new_up(ErrorType::ReadTimedout);
+        assert_eq!(
+            Some(RetryCondition::Timeout),
+            RetryCondition::from_error(&e, false)
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(
+            2,
+            &["connect_error".to_string(), "502".to_string()],
+            true,
+            0,
+        );
+        assert_eq!(true, policy.is_retry_status(502));
+        assert_eq!(false, policy.is_retry_status(503));
+        assert_eq!(
+            true,
+            policy.can_retry(&Method::GET, 0, RetryCondition::ConnectError)
+        );
+        assert_eq!(
+            true,
+            policy.can_retry(&Method::GET, 1, RetryCondition::Status(502))
+        );
+        // max retries
+        assert_eq!(
+            false,
+            policy.can_retry(&Method::GET, 2, RetryCondition::ConnectError)
+        );
+        // not idempotent
+        assert_eq!(
+            false,
+            policy.can_retry(&Method::POST, 0, RetryCondition::ConnectError)
+        );
+        // not matched condition
+        assert_eq!(
+            false,
+            policy.can_retry(&Method::GET, 0, RetryCondition::Timeout)
+        );
+
+        let policy = RetryPolicy::new(
+            1,
+            &["timeout".to_string(), "error".to_string()],
+            false,
+            0,
+        );
+        assert_eq!(
+            true,
+            policy.can_retry(&Method::POST, 0, RetryCondition::Timeout)
+        );
+        assert_eq!(
+            true,
+            policy.can_retry(&Method::POST, 0, RetryCondition::Error)
+        );
+
+        // retry on connect error by default
+        let policy = RetryPolicy::new(1, &[], true, 0);
+        assert_eq!(
+            true,
+            policy.can_retry(&Method::GET, 0, RetryCondition::ConnectError)
+        );
+        assert_eq!(
+            false,
+            policy.can_retry(&Method::GET, 0, RetryCondition::Error)
+        );
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let policy =
+            RetryPolicy::new(1, &["connect_error".to_string()], true, 20);
+        for _ in 0..100 {
+            policy.on_request();
+        }
+        let mut count = 0;
+        for _ in 0..100 {
+            if policy.can_retry(&Method::GET, 0, RetryCondition::ConnectError) {
+                count += 1;
+            }
+        }
+        assert_eq!(20, count);
+
+        // min retries of the window
+        let policy =
+            RetryPolicy::new(1, &["connect_error".to_string()], true, 20);
+        policy.on_request();
+        let mut count = 0;
+        for _ in 0..100 {
+            if policy.can_retry(&Method::GET, 0, RetryCondition::ConnectError) {
+                count += 1;
+            }
+        }
+        assert_eq!(RETRY_BUDGET_MIN_RETRIES, count);
+    }
+}
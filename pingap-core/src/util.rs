@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use once_cell::sync::Lazy;
+use pingora::protocols::l4::socket::SocketAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // 2022-05-07: 1651852800
@@ -49,6 +50,16 @@ pub fn get_hostname() -> &'static str {
     HOST_NAME.as_str()
 }
 
+/// Returns the string of the socket address, it's the key of the backend,
+/// the unix domain socket is formatted as "unix:/run/app.sock".
+pub fn format_socket_addr(addr: &SocketAddr) -> String {
+    match addr {
+        #[cfg(unix)]
+        SocketAddr::Unix(_) => format!("unix:{addr}"),
+        _ => addr.to_string(),
+    }
+}
+
 #[inline]
 pub(crate) fn now_ms() -> u64 {
     SystemTime::now()
@@ -12,12 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{format_addrs, Error, Result};
+use super::{format_addrs, new_backend, Error, Result};
 use super::{Discovery, LOG_CATEGORY, STATIC_DISCOVERY};
-use http::Extensions;
 use pingora::lb::discovery;
 use pingora::lb::{Backend, Backends};
-use pingora::protocols::l4::socket::SocketAddr;
 use std::collections::BTreeSet;
 use std::net::ToSocketAddrs;
 use std::time::SystemTime;
@@ -43,12 +41,17 @@ pub fn is_static_discovery(value: &str) -> bool {
 pub fn new_static_discovery(discovery: &Discovery) -> Result<Backends> {
     let hosts = discovery.addr.join(",");
     let start_time = SystemTime::now();
-    let formatted_addrs = format_addrs(&discovery.addr, discovery.tls);
+    let formatted_addrs = format_addrs(&discovery.addr, discovery.tls)?;
 
     let mut backends: Vec<Backend> = vec![];
 
     // resolve ip and port to socket address
-    for (ip, port, weight) in formatted_addrs {
+    for (ip, port, weight, backup) in formatted_addrs {
+        #[cfg(unix)]
+        if let Some(path) = ip.strip_prefix(super::UNIX_ADDR_PREFIX) {
+            backends.push(super::new_unix_backend(path, weight, backup)?);
+            continue;
+        }
         let addr = format!("{ip}:{port}");
         addr.to_socket_addrs()
             .map_err(|e| Error::Io {
@@ -57,21 +60,13 @@ pub fn new_static_discovery(discovery: &Discovery) -> Result<Backends> {
             })?
             .filter(|socket_addr| !discovery.ipv4_only || socket_addr.is_ipv4())
             .for_each(|socket_addr| {
-                backends.push(Backend {
-                    addr: SocketAddr::Inet(socket_addr),
-                    weight,
-                    ext: Extensions::new(),
-                });
+                backends.push(new_backend(socket_addr, weight, backup));
             });
     }
 
     let resolved_addrs: Vec<String> = backends
         .iter()
-        .map(|b| match &b.addr {
-            SocketAddr::Inet(addr) => addr.to_string(),
-            _ => String::new(),
-        })
-        .filter(|addr| !addr.is_empty())
+        .map(|b| pingap_core::format_socket_addr(&b.addr))
         .collect();
 
     info!(
@@ -103,6 +98,7 @@ mod tests {
             tls: false,
             ipv4_only: false,
             sender: None,
+            update_callback: None,
         })
         .unwrap();
 
@@ -111,5 +107,25 @@ mod tests {
 
         backends.update(|_| {}).await.unwrap();
         assert_eq!(backends.get_backend().len(), 1);
+
+        let backends = new_static_discovery(&Discovery {
+            addr: vec![
+                "unix:/run/app.sock".to_string(),
+                "127.0.0.1:8080".to_string(),
+            ],
+            tls: false,
+            ipv4_only: true,
+            sender: None,
+            update_callback: None,
+        })
+        .unwrap();
+        backends.update(|_| {}).await.unwrap();
+        let mut addrs: Vec<_> = backends
+            .get_backend()
+            .iter()
+            .map(|item| pingap_core::format_socket_addr(&item.addr))
+            .collect();
+        addrs.sort();
+        assert_eq!(vec!["127.0.0.1:8080", "unix:/run/app.sock"], addrs);
     }
 }
@@ -0,0 +1,700 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    new_backend, new_shared_attrs_backends, parse_addr_options, Error, Result,
+};
+use super::{
+    Discovery, UpdateCallback, UpdateNotifier, CONSUL_DISCOVERY, LOG_CATEGORY,
+};
+use async_trait::async_trait;
+use pingap_core::{NotificationData, NotificationLevel, NotificationSender};
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+use url::Url;
+
+const CONSUL_TOKEN_HEADER: &str = "X-Consul-Token";
+const CONSUL_INDEX_HEADER: &str = "X-Consul-Index";
+const DEFAULT_WAIT: &str = "5m";
+const WEIGHT_META: &str = "weight";
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+// the blocking query returns immediately if nothing is changed(e.g. timeout),
+// so the next query is delayed to avoid a busy loop
+const WATCH_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn new_consul_error(message: String) -> Error {
+    Error::Invalid {
+        message: format!("consul discovery: {message}"),
+    }
+}
+
+/// Checks if the discovery type is consul
+pub fn is_consul_discovery(value: &str) -> bool {
+    value == CONSUL_DISCOVERY
+}
+
+/// The service of consul discovery.
+/// Format: "http://127.0.0.1:8500/service?tag=v1&dc=dc1 weight=5 backup",
+/// the scheme is `http` if omitted, the supported query parameters:
+/// * `tag` - only the instances with the tag are used, it can be repeated
+/// * `dc` - the datacenter of service, default is the agent's datacenter
+/// * `passing` - only the instances passing all checks are used, default `true`
+/// * `token` - the acl token of consul
+/// * `wait` - the max wait time of blocking query, default `5m`
+#[derive(Debug, Clone, PartialEq)]
+struct ConsulService {
+    server: String,
+    name: String,
+    tags: Vec<String>,
+    dc: String,
+    passing: bool,
+    token: String,
+    wait: String,
+    weight: usize,
+    backup: bool,
+}
+
+impl TryFrom<&str> for ConsulService {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let parts: Vec<_> = value.split_whitespace().collect();
+        let Some(addr) = parts.first() else {
+            return Err(new_consul_error("service is empty".to_string()));
+        };
+        let (weight, backup) = parse_addr_options(&parts[1..])?;
+        let addr = if addr.contains("://") {
+            addr.to_string()
+        } else {
+            format!("http://{addr}")
+        };
+        let url =
+            Url::parse(&addr).map_err(|e| new_consul_error(e.to_string()))?;
+        let name = url.path().trim_matches('/').to_string();
+        if name.is_empty() || name.contains('/') {
+            return Err(new_consul_error(format!("invalid service: {value}")));
+        }
+        let mut service = Self {
+            server: url[..url::Position::BeforePath].to_string(),
+            name,
+            tags: vec![],
+            dc: "".to_string(),
+            passing: true,
+            token: "".to_string(),
+            wait: DEFAULT_WAIT.to_string(),
+            weight,
+            backup,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "tag" => service.tags.push(value.to_string()),
+                "dc" => service.dc = value.to_string(),
+                "passing" => service.passing = value != "false",
+                "token" => service.token = value.to_string(),
+                "wait" => service.wait = value.to_string(),
+                _ => {},
+            }
+        }
+        Ok(service)
+    }
+}
+
+impl ConsulService {
+    /// Returns the url of health service api, the index is used for blocking query.
+    fn get_url(&self, index: u64) -> String {
+        let url = format!("{}/v1/health/service/{}", self.server, self.name);
+        let mut params = url::form_urlencoded::Serializer::new(String::new());
+        for tag in self.tags.iter() {
+            params.append_pair("tag", tag);
+        }
+        if !self.dc.is_empty() {
+            params.append_pair("dc", &self.dc);
+        }
+        if self.passing {
+            params.append_pair("passing", "true");
+        }
+        if index > 0 {
+            params.append_pair("index", &index.to_string());
+            params.append_pair("wait", &self.wait);
+        }
+        let query = params.finish();
+        if query.is_empty() {
+            return url;
+        }
+        format!("{url}?{query}")
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    #[serde(default)]
+    address: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulAgentService {
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    port: u16,
+    meta: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulServiceEntry {
+    #[serde(default)]
+    node: ConsulNode,
+    #[serde(default)]
+    service: ConsulAgentService,
+}
+
+/// Converts the service entries to backends, the address of service
+/// is used(fallback to the node address), the weight is read from
+/// the service meta `weight`(fallback to the weight of address).
+async fn to_backends(
+    entries: &[ConsulServiceEntry],
+    service: &ConsulService,
+    tls: bool,
+    ipv4_only: bool,
+) -> Vec<Backend> {
+    let mut backends = vec![];
+    for entry in entries.iter() {
+        let host = if entry.service.address.is_empty() {
+            &entry.node.address
+        } else {
+            &entry.service.address
+        };
+        let port = match entry.service.port {
+            0 if tls => 443,
+            0 => 80,
+            port => port,
+        };
+        let weight = entry
+            .service
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.get(WEIGHT_META))
+            .and_then(|weight| weight.parse::<usize>().ok())
+            .unwrap_or(service.weight);
+        let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            // the address may be a hostname
+            Err(_) => {
+                match tokio::net::lookup_host((host.as_str(), port)).await {
+                    Ok(addrs) => addrs.collect(),
+                    Err(e) => {
+                        error!(
+                            category = LOG_CATEGORY,
+                            error = %e,
+                            host,
+                            "consul lookup host fail"
+                        );
+                        vec![]
+                    },
+                }
+            },
+        };
+        for addr in addrs {
+            if ipv4_only && !addr.is_ipv4() {
+                continue;
+            }
+            backends.push(new_backend(addr, weight, service.backup));
+        }
+    }
+    backends
+}
+
+/// Queries the health service api, returns the service entries and consul index.
+async fn query_service(
+    client: &reqwest::Client,
+    service: &ConsulService,
+    index: u64,
+) -> Result<(Vec<ConsulServiceEntry>, u64)> {
+    let url = service.get_url(index);
+    let timeout = if index > 0 {
+        // the max wait time of consul is 10 minutes
+        let wait = humantime::parse_duration(&service.wait)
+            .unwrap_or(Duration::from_secs(600));
+        wait + wait / 16 + REQUEST_TIMEOUT
+    } else {
+        REQUEST_TIMEOUT
+    };
+    let mut req = client.get(&url).timeout(timeout);
+    if !service.token.is_empty() {
+        req = req.header(CONSUL_TOKEN_HEADER, &service.token);
+    }
+    let resp = req
+        .send()
+        .await
+        .map_err(|e| new_consul_error(e.to_string()))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(new_consul_error(format!(
+            "request {url} fail, status: {status}, body: {body}"
+        )));
+    }
+    let index = resp
+        .headers()
+        .get(CONSUL_INDEX_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or_default();
+    let entries = resp
+        .json::<Vec<ConsulServiceEntry>>()
+        .await
+        .map_err(|e| new_consul_error(e.to_string()))?;
+    Ok((entries, index))
+}
+
+fn get_addrs(backends: &[Backend]) -> BTreeSet<String> {
+    backends.iter().map(|item| item.addr.to_string()).collect()
+}
+
+/// Options of the consul watch task
+struct WatchOptions {
+    client: reqwest::Client,
+    tls: bool,
+    ipv4_only: bool,
+    sender: Option<Arc<NotificationSender>>,
+    notifier: UpdateNotifier,
+}
+
+/// Watches the service by blocking query until the discovery is dropped,
+/// the upstream is notified when the result of query is changed and
+/// the notification is sent when the members of service are changed.
+async fn watch_service(
+    services: Weak<RwLock<HashMap<usize, Vec<Backend>>>>,
+    options: WatchOptions,
+    index: usize,
+    service: ConsulService,
+    mut consul_index: u64,
+) {
+    loop {
+        let Some(current) = services
+            .upgrade()
+            .and_then(|services| services.read().ok()?.get(&index).cloned())
+        else {
+            info!(
+                category = LOG_CATEGORY,
+                service = service.name,
+                "consul discovery is dropped, stop watching"
+            );
+            return;
+        };
+        let start = SystemTime::now();
+        let (entries, new_index) = match query_service(
+            &options.client,
+            &service,
+            consul_index,
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    service = service.name,
+                    "consul watch fail"
+                );
+                consul_index = 0;
+                tokio::time::sleep(WATCH_RETRY_DELAY).await;
+                continue;
+            },
+        };
+        if new_index == consul_index {
+            let elapsed = start.elapsed().unwrap_or_default();
+            if elapsed < WATCH_MIN_INTERVAL {
+                tokio::time::sleep(WATCH_MIN_INTERVAL - elapsed).await;
+            }
+            continue;
+        }
+        // the index should be reset if it goes backwards
+        consul_index = if new_index < consul_index {
+            0
+        } else {
+            new_index
+        };
+        let backends =
+            to_backends(&entries, &service, options.tls, options.ipv4_only)
+                .await;
+        let previous_addrs = get_addrs(&current);
+        let addrs = get_addrs(&backends);
+        let Some(services) = services.upgrade() else {
+            continue;
+        };
+        if let Ok(mut services) = services.write() {
+            services.insert(index, backends);
+        }
+        // the weight or backup of the members may be changed
+        options.notifier.notify();
+        if previous_addrs == addrs {
+            continue;
+        }
+        let added: Vec<_> =
+            addrs.difference(&previous_addrs).cloned().collect();
+        let removed: Vec<_> =
+            previous_addrs.difference(&addrs).cloned().collect();
+        info!(
+            category = LOG_CATEGORY,
+            service = service.name,
+            added = added.join(","),
+            removed = removed.join(","),
+            "consul service members are changed"
+        );
+        if let Some(sender) = &options.sender {
+            sender
+                .notify(NotificationData {
+                    category: "service_discover_change".to_string(),
+                    level: NotificationLevel::Info,
+                    message: format!(
+                        "consul service {} is changed, added: {added:?}, removed: {removed:?}",
+                        service.name
+                    ),
+                    ..Default::default()
+                })
+                .await;
+        }
+    }
+}
+
+/// Consul service discovery implementation, the health service api
+/// is queried at first and then watched by blocking query.
+struct Consul {
+    services: Vec<ConsulService>,
+    client: reqwest::Client,
+    tls: bool,
+    ipv4_only: bool,
+    sender: Option<Arc<NotificationSender>>,
+    notifier: UpdateNotifier,
+    backends: Arc<RwLock<HashMap<usize, Vec<Backend>>>>,
+    watching: Vec<AtomicBool>,
+}
+
+impl Consul {
+    fn new(addrs: &[String], tls: bool, ipv4_only: bool) -> Result<Self> {
+        let services = addrs
+            .iter()
+            .map(|addr| ConsulService::try_from(addr.as_str()))
+            .collect::<Result<Vec<_>>>()?;
+        let watching =
+            services.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(Self {
+            services,
+            client: reqwest::Client::new(),
+            tls,
+            ipv4_only,
+            sender: None,
+            notifier: UpdateNotifier::default(),
+            backends: Arc::new(RwLock::new(HashMap::new())),
+            watching,
+        })
+    }
+    /// Sets the notification sender
+    pub fn with_sender(
+        mut self,
+        sender: Option<Arc<NotificationSender>>,
+    ) -> Self {
+        self.sender = sender;
+        self
+    }
+    /// Sets the callback which is called when the members of service
+    /// are changed
+    pub fn with_update_callback(
+        mut self,
+        callback: Option<UpdateCallback>,
+    ) -> Self {
+        self.notifier = UpdateNotifier::new(callback);
+        self
+    }
+    /// Queries the services which are not watched and starts watching them,
+    /// then returns the backends of all services.
+    async fn run_discover(&self) -> Result<(BTreeSet<Backend>, Vec<String>)> {
+        let mut failed_services = vec![];
+        for (index, service) in self.services.iter().enumerate() {
+            if self.watching[index].load(Ordering::Relaxed) {
+                continue;
+            }
+            match query_service(&self.client, service, 0).await {
+                Ok((entries, consul_index)) => {
+                    let backends = to_backends(
+                        &entries,
+                        service,
+                        self.tls,
+                        self.ipv4_only,
+                    )
+                    .await;
+                    if let Ok(mut services) = self.backends.write() {
+                        services.insert(index, backends);
+                    }
+                    self.watching[index].store(true, Ordering::Relaxed);
+                    tokio::spawn(watch_service(
+                        Arc::downgrade(&self.backends),
+                        WatchOptions {
+                            client: self.client.clone(),
+                            tls: self.tls,
+                            ipv4_only: self.ipv4_only,
+                            sender: self.sender.clone(),
+                            notifier: self.notifier.clone(),
+                        },
+                        index,
+                        service.clone(),
+                        consul_index,
+                    ));
+                },
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        service = service.name,
+                        "consul query service fail"
+                    );
+                    failed_services.push(format!("{}: {e}", service.name));
+                },
+            }
+        }
+        // the failed services are queried again after the retry delay
+        if !failed_services.is_empty() {
+            self.notifier.notify_after(WATCH_RETRY_DELAY);
+        }
+        if !self.services.is_empty()
+            && failed_services.len() == self.services.len()
+        {
+            return Err(new_consul_error(failed_services.join("; ")));
+        }
+        let upstreams = self
+            .backends
+            .read()
+            .map(|services| services.values().flatten().cloned().collect())
+            .unwrap_or_default();
+        Ok((upstreams, failed_services))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Consul {
+    async fn discover(
+        &self,
+    ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = SystemTime::now();
+        let services: Vec<String> =
+            self.services.iter().map(|item| item.name.clone()).collect();
+        let (upstreams, failed_services) = match self.run_discover().await {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    services = services.join(","),
+                    "consul discover fail"
+                );
+                (BTreeSet::new(), vec![e.to_string()])
+            },
+        };
+        if !failed_services.is_empty() {
+            if let Some(sender) = &self.sender {
+                sender
+                    .notify(NotificationData {
+                        category: "service_discover_fail".to_string(),
+                        level: NotificationLevel::Warn,
+                        message: format!(
+                            "consul discovery fail: {}",
+                            failed_services.join("; ")
+                        ),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+            if upstreams.is_empty() {
+                return Err(new_consul_error(failed_services.join("; ")).into());
+            }
+        }
+        let addrs: Vec<String> =
+            upstreams.iter().map(|item| item.addr.to_string()).collect();
+        debug!(
+            category = LOG_CATEGORY,
+            services = services.join(","),
+            addrs = addrs.join(","),
+            elapsed =
+                format!("{}ms", now.elapsed().unwrap_or_default().as_millis()),
+            "consul discover success"
+        );
+        Ok((upstreams, HashMap::new()))
+    }
+}
+
+/// Creates a new consul service discovery backend, the addresses are
+/// the services in the format "http://127.0.0.1:8500/service?tag=v1".
+///
+/// # Arguments
+/// * `discovery` - The discovery configuration
+///
+/// # Returns
+/// * `Result<Backends>` - Configured service discovery backend
+pub fn new_consul_discover_backends(discovery: &Discovery) -> Result<Backends> {
+    let consul =
+        Consul::new(&discovery.addr, discovery.tls, discovery.ipv4_only)?;
+    let backends = new_shared_attrs_backends(
+        consul
+            .with_sender(discovery.sender.clone())
+            .with_update_callback(discovery.update_callback.clone()),
+    );
+    Ok(backends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const SERVICE_ENTRIES: &str = r#"[{"Node":{"Node":"node1","Address":"10.0.0.1"},"Service":{"ID":"web1","Service":"web","Tags":["v1"],"Address":"","Port":8080,"Meta":{"weight":"5"}}},{"Node":{"Node":"node2","Address":"10.0.0.2"},"Service":{"ID":"web2","Service":"web","Tags":["v1"],"Address":"10.0.1.2","Port":8080,"Meta":null}},{"Node":{"Node":"node3","Address":"::1"},"Service":{"ID":"web3","Service":"web","Tags":["v1"],"Address":"","Port":0}}]"#;
+    const CHANGED_SERVICE_ENTRIES: &str = r#"[{"Node":{"Node":"node1","Address":"10.0.0.1"},"Service":{"ID":"web1","Service":"web","Address":"","Port":8080}}]"#;
+
+    fn get_addrs(backends: &BTreeSet<Backend>) -> Vec<String> {
+        backends.iter().map(|item| item.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn test_consul_service() {
+        let service = ConsulService::try_from(
+            "127.0.0.1:8500/web?tag=v1&tag=blue&dc=dc1&token=abc weight=3 backup",
+        )
+        .unwrap();
+        assert_eq!(
+            ConsulService {
+                server: "http://127.0.0.1:8500".to_string(),
+                name: "web".to_string(),
+                tags: vec!["v1".to_string(), "blue".to_string()],
+                dc: "dc1".to_string(),
+                passing: true,
+                token: "abc".to_string(),
+                wait: DEFAULT_WAIT.to_string(),
+                weight: 3,
+                backup: true,
+            },
+            service
+        );
+        assert_eq!(
+            "http://127.0.0.1:8500/v1/health/service/web?tag=v1&tag=blue&dc=dc1&passing=true",
+            service.get_url(0)
+        );
+        assert_eq!(
+            "http://127.0.0.1:8500/v1/health/service/web?tag=v1&tag=blue&dc=dc1&passing=true&index=10&wait=5m",
+            service.get_url(10)
+        );
+
+        let service = ConsulService::try_from(
+            "https://consul.pingap.io/web?passing=false&wait=1m",
+        )
+        .unwrap();
+        assert_eq!("https://consul.pingap.io", service.server);
+        assert_eq!(
+            "https://consul.pingap.io/v1/health/service/web?index=1&wait=1m",
+            service.get_url(1)
+        );
+
+        assert_eq!(
+            "consul discovery: invalid service: 127.0.0.1:8500",
+            ConsulService::try_from("127.0.0.1:8500")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_to_backends() {
+        let entries: Vec<ConsulServiceEntry> =
+            serde_json::from_str(SERVICE_ENTRIES).unwrap();
+        let service = ConsulService::try_from("127.0.0.1:8500/web").unwrap();
+        let backends = to_backends(&entries, &service, false, false).await;
+        assert_eq!(
+            vec![
+                ("10.0.0.1:8080".to_string(), 5),
+                ("10.0.1.2:8080".to_string(), 1),
+                ("[::1]:80".to_string(), 1),
+            ],
+            backends
+                .iter()
+                .map(|item| (item.addr.to_string(), item.weight))
+                .collect::<Vec<_>>()
+        );
+
+        let backends = to_backends(&entries, &service, true, true).await;
+        assert_eq!(2, backends.len());
+    }
+
+    #[tokio::test]
+    async fn test_consul_discover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let size = stream.read(&mut buf).await.unwrap_or_default();
+                    let req = String::from_utf8_lossy(&buf[..size]).to_string();
+                    let (body, index) = if req.contains("index=2") {
+                        // blocking query without change
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        (CHANGED_SERVICE_ENTRIES, 2)
+                    } else if req.contains("index=1") {
+                        (CHANGED_SERVICE_ENTRIES, 2)
+                    } else {
+                        (SERVICE_ENTRIES, 1)
+                    };
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Consul-Index: {index}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(resp.as_bytes()).await;
+                });
+            }
+        });
+        let notified = Arc::new(AtomicBool::new(false));
+        let value = notified.clone();
+        let consul = Consul::new(&[format!("{addr}/web?tag=v1")], false, true)
+            .unwrap()
+            .with_update_callback(Some(Arc::new(move || {
+                value.store(true, Ordering::Relaxed);
+            })));
+        let (backends, _) = consul.discover().await.unwrap();
+        assert_eq!(
+            vec!["10.0.0.1:8080", "10.0.1.2:8080"],
+            get_addrs(&backends)
+        );
+
+        // the blocking query returns the changed members,
+        // and the upstream is notified
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(true, notified.load(Ordering::Relaxed));
+        let (backends, _) = consul.discover().await.unwrap();
+        assert_eq!(vec!["10.0.0.1:8080"], get_addrs(&backends));
+    }
+}
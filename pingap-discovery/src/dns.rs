@@ -12,28 +12,50 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{format_addrs, Addr, Error, Result};
+use super::{
+    format_addrs, new_backend, new_shared_attrs_backends, Addr, Error, Result,
+};
 use super::{Discovery, DNS_DISCOVERY, LOG_CATEGORY};
 use async_trait::async_trait;
-use hickory_resolver::config::{
-    LookupIpStrategy, ResolverConfig, ResolverOpts,
-};
+use hickory_resolver::config::LookupIpStrategy;
 use hickory_resolver::lookup_ip::LookupIp;
 use hickory_resolver::name_server::TokioConnectionProvider;
 use hickory_resolver::system_conf::read_system_conf;
-use hickory_resolver::AsyncResolver;
-use http::Extensions;
+use hickory_resolver::{AsyncResolver, TokioAsyncResolver};
 use pingap_core::NotificationSender;
 use pingap_core::{NotificationData, NotificationLevel};
 use pingora::lb::discovery::ServiceDiscovery;
 use pingora::lb::{Backend, Backends};
-use pingora::protocols::l4::socket::SocketAddr;
 use std::collections::{BTreeSet, HashMap};
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tracing::{debug, error, info};
 
+/// Creates the async resolver with the system DNS configuration
+///
+/// # Arguments
+/// * `ipv4_only` - Whether to only lookup IPv4 addresses
+///
+/// # Returns
+/// * `Result<TokioAsyncResolver>` - The async resolver
+pub(crate) fn new_resolver(ipv4_only: bool) -> Result<TokioAsyncResolver> {
+    let (config, mut options) =
+        read_system_conf().map_err(|e| Error::Resolve { source: e })?;
+
+    options.ip_strategy = if ipv4_only {
+        LookupIpStrategy::Ipv4Only
+    } else {
+        LookupIpStrategy::Ipv4AndIpv6
+    };
+
+    Ok(AsyncResolver::new(
+        config,
+        options,
+        TokioConnectionProvider::default(),
+    ))
+}
+
 /// DNS service discovery implementation
 struct Dns {
     ipv4_only: bool,
@@ -57,7 +79,7 @@ impl Dns {
     /// # Returns
     /// * `Result<Self>` - New DNS discovery instance
     fn new(addrs: &[String], tls: bool, ipv4_only: bool) -> Result<Self> {
-        let hosts = format_addrs(addrs, tls);
+        let hosts = format_addrs(addrs, tls)?;
         Ok(Self {
             hosts,
             ipv4_only,
@@ -79,36 +101,17 @@ impl Dns {
         self
     }
 
-    /// Reads system DNS resolver configuration
-    ///
-    /// # Returns
-    /// * `Result<(ResolverConfig, ResolverOpts)>` - Resolver configuration and options
-    fn read_system_conf(&self) -> Result<(ResolverConfig, ResolverOpts)> {
-        let (config, mut options) =
-            read_system_conf().map_err(|e| Error::Resolve { source: e })?;
-
-        options.ip_strategy = if self.ipv4_only {
-            LookupIpStrategy::Ipv4Only
-        } else {
-            LookupIpStrategy::Ipv4AndIpv6
-        };
-
-        Ok((config, options))
-    }
-
     /// Performs DNS lookups for configured hosts using tokio runtime
     ///
     /// # Returns
     /// * `Result<(Vec<LookupIp>, Vec<String>)>` - List of DNS lookup results and unhealthy backends
     async fn tokio_lookup_ip(&self) -> Result<(Vec<LookupIp>, Vec<String>)> {
-        let provider = TokioConnectionProvider::default();
-        let (config, options) = self.read_system_conf()?;
-        let resolver = AsyncResolver::new(config, options, provider);
+        let resolver = new_resolver(self.ipv4_only)?;
 
         let mut lookup_ips = Vec::new();
         let mut failed_hosts = Vec::new();
 
-        for (host, _, _) in self.hosts.iter() {
+        for (host, _, _, _) in self.hosts.iter() {
             match resolver.lookup_ip(host).await {
                 Ok(lookup) => {
                     lookup_ips.push(lookup);
@@ -148,7 +151,7 @@ impl Dns {
 
         let (lookup_ips, failed_hosts) = self.tokio_lookup_ip().await?;
 
-        for ((_, port, weight), lookup_ip) in
+        for ((_, port, weight, backup), lookup_ip) in
             self.hosts.iter().zip(lookup_ips.iter())
         {
             for ip in lookup_ip
@@ -169,10 +172,8 @@ impl Dns {
                         ),
                     })?;
 
-                upstreams.extend(socket_addrs.map(|socket_addr| Backend {
-                    addr: SocketAddr::Inet(socket_addr),
-                    weight: *weight,
-                    ext: Extensions::new(),
+                upstreams.extend(socket_addrs.map(|socket_addr| {
+                    new_backend(socket_addr, *weight, *backup)
                 }));
             }
         }
@@ -263,7 +264,7 @@ impl ServiceDiscovery for Dns {
 pub fn new_dns_discover_backends(discovery: &Discovery) -> Result<Backends> {
     let dns = Dns::new(&discovery.addr, discovery.tls, discovery.ipv4_only)?;
     let backends =
-        Backends::new(Box::new(dns.with_sender(discovery.sender.clone())));
+        new_shared_attrs_backends(dns.with_sender(discovery.sender.clone()));
     Ok(backends)
 }
 
@@ -12,16 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::{
+    new_backend, new_shared_attrs_backends, parse_addr_options, Error, Result,
+};
 use super::{Discovery, DOCKER_DISCOVERY, LOG_CATEGORY};
-use super::{Error, Result};
 use async_trait::async_trait;
 use bollard::container::ListContainersOptions;
 use bollard::secret::ContainerSummary;
-use http::Extensions;
 use pingap_core::{NotificationData, NotificationLevel, NotificationSender};
 use pingora::lb::discovery::ServiceDiscovery;
 use pingora::lb::{Backend, Backends};
-use pingora::protocols::l4::socket::SocketAddr;
 use std::collections::{BTreeSet, HashMap};
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
@@ -31,41 +31,49 @@ use tracing::{debug, error, info};
 /// Container represents a Docker container with service discovery configuration
 /// - label: The container label used for filtering
 /// - weight: Load balancing weight for this container
+/// - backup: Whether the container is a backup backend
 /// - port: The port number to use for the service
 /// - addrs: List of resolved addresses for this container
 #[derive(Debug, Clone)]
 struct Container {
     label: String,
     weight: usize,
+    backup: bool,
     port: u16,
     addrs: Vec<String>,
 }
 
 impl Container {
     /// Creates a new Container instance from an address string
-    /// Format: "label:port weight" or "label weight" or "label"
-    fn new(addr: &str) -> Self {
-        let (weight, label, port) = Self::parse_addr(addr);
-        Self {
+    /// Format: "label:port weight=5 backup" or "label weight" or "label"
+    fn new(addr: &str) -> Result<Self> {
+        let (weight, backup, label, port) = Self::parse_addr(addr)?;
+        Ok(Self {
             label,
             weight,
+            backup,
             port,
             addrs: vec![],
-        }
+        })
     }
 
-    /// Parses an address string into its components: weight, label, and port
-    /// Returns a tuple of (weight, label, port)
-    fn parse_addr(addr: &str) -> (usize, String, u16) {
-        let parts: Vec<_> = addr.split(' ').collect();
-        let weight = parts.get(1).and_then(|w| w.parse().ok()).unwrap_or(1);
+    /// Parses an address string into its components: weight, backup, label, and port
+    /// Returns a tuple of (weight, backup, label, port), or an error if the options are invalid
+    fn parse_addr(addr: &str) -> Result<(usize, bool, String, u16)> {
+        let parts: Vec<_> = addr.split_whitespace().collect();
+        let (weight, backup) = parse_addr_options(
+            parts.get(1..).unwrap_or_default(),
+        )
+        .map_err(|e| Error::Invalid {
+            message: format!("{e}(container:{addr})"),
+        })?;
 
         let (label, port) = parts[0]
             .split_once(':')
             .map(|(l, p)| (l.to_string(), p.parse().unwrap_or(0)))
             .unwrap_or((parts[0].to_string(), 0));
 
-        (weight, label, port)
+        Ok((weight, backup, label, port))
     }
 }
 
@@ -90,8 +98,10 @@ impl Docker {
         let docker = bollard::Docker::connect_with_local_defaults()
             .map_err(|e| Error::Docker { source: e })?;
 
-        let containers =
-            addrs.iter().map(|addr| Container::new(addr)).collect();
+        let containers = addrs
+            .iter()
+            .map(|addr| Container::new(addr))
+            .collect::<Result<_>>()?;
 
         Ok(Self {
             docker,
@@ -216,11 +226,11 @@ impl Docker {
                     if self.ipv4_only && !socket_addr.is_ipv4() {
                         continue;
                     }
-                    backends.push(Backend {
-                        addr: SocketAddr::Inet(socket_addr),
-                        weight: container.weight,
-                        ext: Extensions::new(),
-                    });
+                    backends.push(new_backend(
+                        socket_addr,
+                        container.weight,
+                        container.backup,
+                    ));
                 }
             }
         }
@@ -296,6 +306,6 @@ impl ServiceDiscovery for Docker {
 pub fn new_docker_discover_backends(discovery: &Discovery) -> Result<Backends> {
     let docker = Docker::new(&discovery.addr, discovery.ipv4_only)?;
     let backends =
-        Backends::new(Box::new(docker.with_sender(discovery.sender.clone())));
+        new_shared_attrs_backends(docker.with_sender(discovery.sender.clone()));
     Ok(backends)
 }
@@ -0,0 +1,546 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    format_addrs, new_backend, new_shared_attrs_backends, set_backend_metadata,
+    Error, Result,
+};
+use super::{
+    Discovery, UpdateCallback, UpdateNotifier, FILE_DISCOVERY, LOG_CATEGORY,
+};
+use async_trait::async_trait;
+use pingap_core::{NotificationData, NotificationLevel, NotificationSender};
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+// the interval of checking whether the files are modified
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks if the discovery type is file
+pub fn is_file_discovery(value: &str) -> bool {
+    value == FILE_DISCOVERY
+}
+
+/// The backend of discovery file
+#[derive(Debug, Deserialize, PartialEq)]
+struct FileBackend {
+    /// The address of backend, e.g. "127.0.0.1:3000" or "unix:/run/app.sock"
+    addr: String,
+    weight: Option<usize>,
+    #[serde(default)]
+    backup: bool,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+/// The content of discovery file.
+///
+/// JSON: `{"backends": [{"addr": "127.0.0.1:3000", "weight": 10, "metadata": {"zone": "a"}}]}`
+/// or the array of backends.
+///
+/// TOML:
+/// ```toml
+/// [[backends]]
+/// addr = "127.0.0.1:3000"
+/// weight = 10
+/// metadata = { zone = "a" }
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct FileBackends {
+    #[serde(default)]
+    backends: Vec<FileBackend>,
+}
+
+/// Parses the discovery file, the file with `.toml` extension is parsed
+/// as toml, otherwise it's parsed as json.
+fn parse_file_backends(file: &str, data: &[u8]) -> Result<FileBackends> {
+    let is_toml = Path::new(file)
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("toml"))
+        .unwrap_or_default();
+    if is_toml {
+        let data = std::str::from_utf8(data).map_err(|e| Error::Invalid {
+            message: format!("{file} is not utf8: {e}"),
+        })?;
+        return toml::from_str(data).map_err(|e| Error::Invalid {
+            message: format!("parse {file} fail: {e}"),
+        });
+    }
+    let map_err = |e: serde_json::Error| Error::Invalid {
+        message: format!("parse {file} fail: {e}"),
+    };
+    if data.trim_ascii_start().starts_with(b"[") {
+        let backends = serde_json::from_slice(data).map_err(map_err)?;
+        return Ok(FileBackends { backends });
+    }
+    serde_json::from_slice(data).map_err(map_err)
+}
+
+/// Converts the backends of file to pingora backends, the domain
+/// address is resolved and the metadata is stored in the extensions.
+/// The weight and backup options of the address(e.g. "127.0.0.1:3000 weight=5 backup")
+/// are used if they are not set by the fields.
+async fn to_backends(
+    backends: &FileBackends,
+    tls: bool,
+    ipv4_only: bool,
+) -> Result<BTreeSet<Backend>> {
+    let mut upstreams = BTreeSet::new();
+    for item in backends.backends.iter() {
+        let Some((host, port, weight, backup)) =
+            format_addrs(std::slice::from_ref(&item.addr), tls)?.pop()
+        else {
+            return Err(Error::Invalid {
+                message: format!("invalid address: {}", item.addr),
+            });
+        };
+        let weight = item.weight.unwrap_or(weight);
+        let backup = item.backup || backup;
+        let mut list = vec![];
+        #[cfg(unix)]
+        if let Some(path) = host.strip_prefix(super::UNIX_ADDR_PREFIX) {
+            list.push(super::new_unix_backend(path, weight, backup)?);
+        }
+        if list.is_empty() {
+            let addr = format!("{host}:{port}");
+            tokio::net::lookup_host(&addr)
+                .await
+                .map_err(|e| Error::Io {
+                    source: e,
+                    content: format!("{addr} to socket addr fail"),
+                })?
+                .filter(|socket_addr| !ipv4_only || socket_addr.is_ipv4())
+                .for_each(|socket_addr| {
+                    list.push(new_backend(socket_addr, weight, backup));
+                });
+        }
+        for backend in list {
+            if !item.metadata.is_empty() {
+                set_backend_metadata(&backend, item.metadata.clone());
+            }
+            upstreams.insert(backend);
+        }
+    }
+    Ok(upstreams)
+}
+
+/// The loaded backends of discovery file
+struct FileState {
+    modified: SystemTime,
+    len: u64,
+    backends: BTreeSet<Backend>,
+}
+
+type FileStates = HashMap<String, FileState>;
+
+/// Returns the modified time and length of the file
+async fn get_file_modified(file: &str) -> Option<(SystemTime, u64)> {
+    let meta = tokio::fs::metadata(file).await.ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Polls the files until the discovery is dropped,
+/// the upstream is notified when any file is modified.
+async fn poll_files(
+    states: Weak<RwLock<FileStates>>,
+    files: Vec<String>,
+    notifier: UpdateNotifier,
+) {
+    // the files are compared with the loaded state at first
+    let mut last_modified: Vec<_> = states
+        .upgrade()
+        .and_then(|states| {
+            let states = states.read().ok()?;
+            let modified = files
+                .iter()
+                .map(|file| {
+                    states.get(file).map(|state| (state.modified, state.len))
+                })
+                .collect();
+            Some(modified)
+        })
+        .unwrap_or_default();
+    loop {
+        tokio::time::sleep(FILE_POLL_INTERVAL).await;
+        if states.strong_count() == 0 {
+            info!(
+                category = LOG_CATEGORY,
+                files = files.join(","),
+                "file discovery is dropped, stop polling"
+            );
+            return;
+        }
+        let mut modified = Vec::with_capacity(files.len());
+        for file in files.iter() {
+            modified.push(get_file_modified(file).await);
+        }
+        if modified == last_modified {
+            continue;
+        }
+        debug!(
+            category = LOG_CATEGORY,
+            files = files.join(","),
+            "discovery file is modified"
+        );
+        last_modified = modified;
+        notifier.notify();
+    }
+}
+
+/// File service discovery implementation, the files are polled and
+/// loaded again only if they are modified, the invalid file is ignored
+/// and the previous backends are kept.
+struct File {
+    files: Vec<String>,
+    tls: bool,
+    ipv4_only: bool,
+    sender: Option<Arc<NotificationSender>>,
+    notifier: UpdateNotifier,
+    states: Arc<RwLock<FileStates>>,
+    polling: AtomicBool,
+}
+
+impl File {
+    fn new(addrs: &[String], tls: bool, ipv4_only: bool) -> Self {
+        let files = addrs
+            .iter()
+            .map(|addr| pingap_util::resolve_path(addr.trim()))
+            .collect();
+        Self {
+            files,
+            tls,
+            ipv4_only,
+            sender: None,
+            notifier: UpdateNotifier::default(),
+            states: Arc::new(RwLock::new(HashMap::new())),
+            polling: AtomicBool::new(false),
+        }
+    }
+    /// Sets the notification sender
+    pub fn with_sender(
+        mut self,
+        sender: Option<Arc<NotificationSender>>,
+    ) -> Self {
+        self.sender = sender;
+        self
+    }
+    /// Sets the callback which is called when the files are modified
+    pub fn with_update_callback(
+        mut self,
+        callback: Option<UpdateCallback>,
+    ) -> Self {
+        self.notifier = UpdateNotifier::new(callback);
+        self
+    }
+    /// Loads the file if it is modified, returns whether it is loaded.
+    async fn load_file(&self, file: &str) -> Result<bool> {
+        let map_io_err = |e: std::io::Error| Error::Io {
+            source: e,
+            content: format!("read {file} fail"),
+        };
+        let meta = tokio::fs::metadata(file).await.map_err(map_io_err)?;
+        let modified = meta.modified().map_err(map_io_err)?;
+        let len = meta.len();
+        let unchanged = self
+            .states
+            .read()
+            .map(|states| {
+                states.get(file).is_some_and(|state| {
+                    state.modified == modified && state.len == len
+                })
+            })
+            .unwrap_or_default();
+        if unchanged {
+            return Ok(false);
+        }
+        let data = tokio::fs::read(file).await.map_err(map_io_err)?;
+        let backends = to_backends(
+            &parse_file_backends(file, &data)?,
+            self.tls,
+            self.ipv4_only,
+        )
+        .await?;
+        if let Ok(mut states) = self.states.write() {
+            states.insert(
+                file.to_string(),
+                FileState {
+                    modified,
+                    len,
+                    backends,
+                },
+            );
+        }
+        Ok(true)
+    }
+    /// Loads the modified files and returns the backends of all files
+    async fn run_discover(&self) -> Result<(BTreeSet<Backend>, Vec<String>)> {
+        let mut failed_files = vec![];
+        for file in self.files.iter() {
+            match self.load_file(file).await {
+                Ok(true) => {
+                    info!(
+                        category = LOG_CATEGORY,
+                        file, "discovery file is loaded"
+                    );
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        file,
+                        "load discovery file fail"
+                    );
+                    failed_files.push(format!("{file}: {e}"));
+                },
+            }
+        }
+        let upstreams: BTreeSet<Backend> = self
+            .states
+            .read()
+            .map(|states| {
+                states
+                    .values()
+                    .flat_map(|state| state.backends.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if upstreams.is_empty() && !failed_files.is_empty() {
+            return Err(Error::Invalid {
+                message: failed_files.join("; "),
+            });
+        }
+        Ok((upstreams, failed_files))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for File {
+    async fn discover(
+        &self,
+    ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = SystemTime::now();
+        let files = self.files.join(",");
+        let (upstreams, failed_files) = match self.run_discover().await {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    files,
+                    "file discover fail"
+                );
+                (BTreeSet::new(), vec![e.to_string()])
+            },
+        };
+        // the files are polled after they are loaded at first
+        if self.notifier.enabled()
+            && !self.polling.swap(true, Ordering::Relaxed)
+        {
+            tokio::spawn(poll_files(
+                Arc::downgrade(&self.states),
+                self.files.clone(),
+                self.notifier.clone(),
+            ));
+        }
+        if !failed_files.is_empty() {
+            if let Some(sender) = &self.sender {
+                sender
+                    .notify(NotificationData {
+                        category: "service_discover_fail".to_string(),
+                        level: NotificationLevel::Warn,
+                        message: format!(
+                            "file discovery fail: {}",
+                            failed_files.join("; ")
+                        ),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+            if upstreams.is_empty() {
+                return Err(Error::Invalid {
+                    message: failed_files.join("; "),
+                }
+                .into());
+            }
+        }
+        let addrs: Vec<String> = upstreams
+            .iter()
+            .map(|item| pingap_core::format_socket_addr(&item.addr))
+            .collect();
+        debug!(
+            category = LOG_CATEGORY,
+            files,
+            addrs = addrs.join(","),
+            elapsed =
+                format!("{}ms", now.elapsed().unwrap_or_default().as_millis()),
+            "file discover success"
+        );
+        Ok((upstreams, HashMap::new()))
+    }
+}
+
+/// Creates a new file-based service discovery backend, the addresses
+/// are the paths of discovery files.
+///
+/// # Arguments
+/// * `discovery` - The discovery configuration
+///
+/// # Returns
+/// * `Result<Backends>` - Configured service discovery backend
+pub fn new_file_discover_backends(discovery: &Discovery) -> Result<Backends> {
+    let file = File::new(&discovery.addr, discovery.tls, discovery.ipv4_only);
+    let backends = new_shared_attrs_backends(
+        file.with_sender(discovery.sender.clone())
+            .with_update_callback(discovery.update_callback.clone()),
+    );
+    Ok(backends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_backend_metadata, is_backup_backend};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_file_backends() {
+        let json = parse_file_backends(
+            "backends.json",
+            br#"{"backends": [{"addr": "127.0.0.1:3000", "weight": 10, "metadata": {"zone": "a"}}]}"#,
+        )
+        .unwrap();
+        let toml = parse_file_backends(
+            "backends.toml",
+            br#"[[backends]]
+addr = "127.0.0.1:3000"
+weight = 10
+metadata = { zone = "a" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(json, toml);
+        assert_eq!(
+            FileBackend {
+                addr: "127.0.0.1:3000".to_string(),
+                weight: Some(10),
+                backup: false,
+                metadata: HashMap::from([(
+                    "zone".to_string(),
+                    "a".to_string()
+                )]),
+            },
+            json.backends[0]
+        );
+
+        let backends = parse_file_backends(
+            "backends",
+            br#" [{"addr": "127.0.0.1:3001", "backup": true}]"#,
+        )
+        .unwrap();
+        assert_eq!(1, backends.backends.len());
+        assert_eq!(true, backends.backends[0].backup);
+
+        assert_eq!(
+            true,
+            parse_file_backends("backends.json", b"{")
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("parse backends.json fail")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_discover() {
+        let file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        let path = file.path().to_string_lossy().to_string();
+        std::fs::write(
+            &path,
+            r#"{"backends": [{"addr": "127.0.0.1:3000", "weight": 10, "metadata": {"zone": "a"}}, {"addr": "127.0.0.1", "backup": true}]}"#,
+        )
+        .unwrap();
+        let discovery = File::new(std::slice::from_ref(&path), false, true);
+        let (backends, _) = discovery.discover().await.unwrap();
+        let backends: Vec<_> = backends.into_iter().collect();
+        assert_eq!(2, backends.len());
+        assert_eq!("127.0.0.1:80", backends[0].addr.to_string());
+        assert_eq!(true, is_backup_backend(&backends[0]));
+        assert_eq!("127.0.0.1:3000", backends[1].addr.to_string());
+        assert_eq!(10, backends[1].weight);
+        assert_eq!(
+            Some(&"a".to_string()),
+            get_backend_metadata(&backends[1]).get("zone")
+        );
+
+        // the invalid file is ignored
+        std::fs::write(&path, r#"{"backends": ["#).unwrap();
+        let (backends, _) = discovery.discover().await.unwrap();
+        assert_eq!(2, backends.len());
+
+        // the weight and backup options of the address are used
+        std::fs::write(
+            &path,
+            r#"[{"addr": "127.0.0.1:3001 weight=3 backup"}]"#,
+        )
+        .unwrap();
+        let (backends, _) = discovery.discover().await.unwrap();
+        assert_eq!(
+            vec![("127.0.0.1:3001".to_string(), 3, true)],
+            backends
+                .iter()
+                .map(|item| (
+                    item.addr.to_string(),
+                    item.weight,
+                    is_backup_backend(item)
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_files() {
+        let file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        let path = file.path().to_string_lossy().to_string();
+        std::fs::write(&path, r#"[{"addr": "127.0.0.1:3000"}]"#).unwrap();
+        let notified = Arc::new(AtomicBool::new(false));
+        let value = notified.clone();
+        let discovery = File::new(std::slice::from_ref(&path), false, true)
+            .with_update_callback(Some(Arc::new(move || {
+                value.store(true, Ordering::Relaxed);
+            })));
+        let (backends, _) = discovery.discover().await.unwrap();
+        assert_eq!(1, backends.len());
+
+        // the file isn't modified
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(false, notified.load(Ordering::Relaxed));
+
+        // the upstream is notified after the file is modified
+        std::fs::write(
+            &path,
+            r#"[{"addr": "127.0.0.1:3000"}, {"addr": "127.0.0.1:3001"}]"#,
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(true, notified.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,972 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    new_backend, new_shared_attrs_backends, parse_addr_options, Error, Result,
+};
+use super::{
+    Discovery, UpdateCallback, UpdateNotifier, K8S_DISCOVERY, LOG_CATEGORY,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pingap_core::{NotificationData, NotificationLevel, NotificationSender};
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use pingora::tls::pkey::PKey;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+const SERVICE_ACCOUNT_DIR: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount";
+/// The watch request is closed by api server after the timeout,
+/// then it will be restarted.
+const WATCH_TIMEOUT_SECONDS: u64 = 300;
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn new_k8s_error(message: String) -> Error {
+    Error::Invalid {
+        message: format!("k8s discovery: {message}"),
+    }
+}
+
+/// Checks if the discovery type is k8s
+pub fn is_k8s_discovery(value: &str) -> bool {
+    value == K8S_DISCOVERY
+}
+
+/// The service of k8s discovery.
+/// Format: "namespace/service:port weight=5 backup", the port can be
+/// the name or number of the service port, the first port is used if empty.
+#[derive(Debug, Clone, PartialEq)]
+struct K8sService {
+    namespace: String,
+    name: String,
+    port: String,
+    weight: usize,
+    backup: bool,
+}
+
+impl TryFrom<&str> for K8sService {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let parts: Vec<_> = value.split_whitespace().collect();
+        let Some(service) = parts.first() else {
+            return Err(new_k8s_error("service is empty".to_string()));
+        };
+        let (weight, backup) = parse_addr_options(&parts[1..])?;
+        let (service, port) = service.split_once(':').unwrap_or((service, ""));
+        let (namespace, name) =
+            service.split_once('/').unwrap_or(("default", service));
+        if namespace.is_empty() || name.is_empty() {
+            return Err(new_k8s_error(format!("invalid service: {value}")));
+        }
+        Ok(Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            port: port.to_string(),
+            weight,
+            backup,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KubeConfig {
+    #[serde(default, rename = "current-context")]
+    current_context: String,
+    #[serde(default)]
+    clusters: Vec<NamedKubeCluster>,
+    #[serde(default)]
+    contexts: Vec<NamedKubeContext>,
+    #[serde(default)]
+    users: Vec<NamedKubeUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedKubeCluster {
+    name: String,
+    cluster: KubeCluster,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeCluster {
+    server: String,
+    #[serde(rename = "certificate-authority")]
+    certificate_authority: Option<String>,
+    #[serde(rename = "certificate-authority-data")]
+    certificate_authority_data: Option<String>,
+    #[serde(rename = "insecure-skip-tls-verify")]
+    insecure_skip_tls_verify: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedKubeContext {
+    name: String,
+    context: KubeContext,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeContext {
+    cluster: String,
+    user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedKubeUser {
+    name: String,
+    user: KubeUser,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KubeUser {
+    token: Option<String>,
+    #[serde(rename = "client-certificate")]
+    client_certificate: Option<String>,
+    #[serde(rename = "client-certificate-data")]
+    client_certificate_data: Option<String>,
+    #[serde(rename = "client-key")]
+    client_key: Option<String>,
+    #[serde(rename = "client-key-data")]
+    client_key_data: Option<String>,
+}
+
+/// Reads the data of kubeconfig, it's base64 encoded data or file path
+fn read_kube_data(
+    data: &Option<String>,
+    file: &Option<String>,
+) -> Result<Option<Vec<u8>>> {
+    if let Some(data) = data {
+        let data = STANDARD
+            .decode(data.trim())
+            .map_err(|e| new_k8s_error(e.to_string()))?;
+        return Ok(Some(data));
+    }
+    if let Some(file) = file {
+        let data = std::fs::read(file).map_err(|e| Error::Io {
+            source: e,
+            content: format!("read {file} fail"),
+        })?;
+        return Ok(Some(data));
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListMeta {
+    #[serde(default)]
+    resource_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    name: String,
+    #[serde(default)]
+    resource_version: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EndpointConditions {
+    ready: Option<bool>,
+    terminating: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    #[serde(default)]
+    address_type: String,
+    endpoints: Option<Vec<Endpoint>>,
+    ports: Option<Vec<EndpointPort>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSliceList {
+    metadata: ListMeta,
+    #[serde(default)]
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    object: serde_json::Value,
+}
+
+impl EndpointSlice {
+    /// Converts the ready endpoints of slice to backends, the endpoints
+    /// which are not ready or terminating are ignored.
+    fn to_backends(
+        &self,
+        service: &K8sService,
+        ipv4_only: bool,
+    ) -> Vec<Backend> {
+        if self.address_type == "FQDN" {
+            return vec![];
+        }
+        let port = self.ports.iter().flatten().find(|item| {
+            service.port.is_empty()
+                || item.name.as_deref() == Some(service.port.as_str())
+                || item.port.map(|port| port.to_string()).as_deref()
+                    == Some(service.port.as_str())
+        });
+        let Some(port) = port.and_then(|item| item.port) else {
+            return vec![];
+        };
+        let mut backends = vec![];
+        for endpoint in self.endpoints.iter().flatten() {
+            let conditions = &endpoint.conditions;
+            // nil ready condition should be interpreted as ready
+            if conditions.ready == Some(false)
+                || conditions.terminating == Some(true)
+            {
+                continue;
+            }
+            for addr in endpoint.addresses.iter() {
+                let Ok(ip) = addr.parse::<IpAddr>() else {
+                    continue;
+                };
+                if ipv4_only && !ip.is_ipv4() {
+                    continue;
+                }
+                backends.push(new_backend(
+                    SocketAddr::new(ip, port),
+                    service.weight,
+                    service.backup,
+                ));
+            }
+        }
+        backends
+    }
+}
+
+/// Client of the k8s api server
+#[derive(Debug, Clone)]
+struct K8sClient {
+    server: String,
+    token: Option<String>,
+    // the projected service account token is rotated by kubelet,
+    // so it is read from the file for each request
+    token_file: Option<String>,
+    client: reqwest::Client,
+}
+
+impl K8sClient {
+    /// Creates the client with the in-cluster service account credentials
+    /// if it runs in k8s, otherwise the kubeconfig(`KUBECONFIG` or `~/.kube/config`) is used.
+    fn new() -> Result<Self> {
+        if let (Ok(host), Ok(port)) = (
+            std::env::var("KUBERNETES_SERVICE_HOST"),
+            std::env::var("KUBERNETES_SERVICE_PORT"),
+        ) {
+            return Self::in_cluster(&host, &port);
+        }
+        let file = std::env::var("KUBECONFIG").unwrap_or_else(|_| {
+            let home = std::env::var("HOME").unwrap_or_default();
+            format!("{home}/.kube/config")
+        });
+        Self::from_kubeconfig(&file)
+    }
+    /// Creates the client with the service account token and ca
+    fn in_cluster(host: &str, port: &str) -> Result<Self> {
+        let read = |name: &str| {
+            let file = format!("{SERVICE_ACCOUNT_DIR}/{name}");
+            std::fs::read(&file).map_err(|e| Error::Io {
+                source: e,
+                content: format!("read {file} fail"),
+            })
+        };
+        // check the token is readable
+        read("token")?;
+        let ca = reqwest::Certificate::from_pem(&read("ca.crt")?)
+            .map_err(|e| new_k8s_error(e.to_string()))?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .map_err(|e| new_k8s_error(e.to_string()))?;
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host.to_string()
+        };
+        Ok(Self {
+            server: format!("https://{host}:{port}"),
+            token: None,
+            token_file: Some(format!("{SERVICE_ACCOUNT_DIR}/token")),
+            client,
+        })
+    }
+    /// Creates the client with the cluster and user of current context
+    fn from_kubeconfig(file: &str) -> Result<Self> {
+        let data = std::fs::read(file).map_err(|e| Error::Io {
+            source: e,
+            content: format!("read kubeconfig {file} fail"),
+        })?;
+        let config: KubeConfig = serde_yaml::from_slice(&data)
+            .map_err(|e| new_k8s_error(e.to_string()))?;
+        let context = config
+            .contexts
+            .iter()
+            .find(|item| item.name == config.current_context)
+            .or(config.contexts.first())
+            .map(|item| &item.context)
+            .ok_or_else(|| new_k8s_error("context is not found".to_string()))?;
+        let cluster = config
+            .clusters
+            .iter()
+            .find(|item| item.name == context.cluster)
+            .map(|item| &item.cluster)
+            .ok_or_else(|| {
+                new_k8s_error(format!(
+                    "cluster {} is not found",
+                    context.cluster
+                ))
+            })?;
+        let default_user = KubeUser::default();
+        let user = config
+            .users
+            .iter()
+            .find(|item| Some(&item.name) == context.user.as_ref())
+            .map(|item| &item.user)
+            .unwrap_or(&default_user);
+
+        let map_err = |e: reqwest::Error| new_k8s_error(e.to_string());
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(
+                cluster.insecure_skip_tls_verify.unwrap_or_default(),
+            );
+        if let Some(ca) = read_kube_data(
+            &cluster.certificate_authority_data,
+            &cluster.certificate_authority,
+        )? {
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_pem(&ca).map_err(map_err)?,
+            );
+        }
+        if let (Some(cert), Some(key)) = (
+            read_kube_data(
+                &user.client_certificate_data,
+                &user.client_certificate,
+            )?,
+            read_kube_data(&user.client_key_data, &user.client_key)?,
+        ) {
+            // the identity only supports pkcs8 key
+            let key = PKey::private_key_from_pem(&key)
+                .and_then(|key| key.private_key_to_pem_pkcs8())
+                .map_err(|e| new_k8s_error(e.to_string()))?;
+            builder = builder.identity(
+                reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                    .map_err(map_err)?,
+            );
+        }
+        Ok(Self {
+            server: cluster.server.trim_end_matches('/').to_string(),
+            token: user.token.clone(),
+            token_file: None,
+            client: builder.build().map_err(map_err)?,
+        })
+    }
+    fn get_endpoint_slices_url(&self, service: &K8sService) -> String {
+        format!(
+            "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.server, service.namespace, service.name
+        )
+    }
+    /// Returns the bearer token, the token file is read again if it is set
+    async fn get_token(&self) -> Result<Option<String>> {
+        let Some(file) = &self.token_file else {
+            return Ok(self.token.clone());
+        };
+        let token = tokio::fs::read(file).await.map_err(|e| Error::Io {
+            source: e,
+            content: format!("read {file} fail"),
+        })?;
+        Ok(Some(String::from_utf8_lossy(&token).trim().to_string()))
+    }
+    async fn get(
+        &self,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<reqwest::Response> {
+        let mut req = self.client.get(url).timeout(timeout);
+        if let Some(token) = self.get_token().await? {
+            req = req.bearer_auth(token);
+        }
+        let resp =
+            req.send().await.map_err(|e| new_k8s_error(e.to_string()))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(new_k8s_error(format!(
+                "request {url} fail, status: {status}, body: {body}"
+            )));
+        }
+        Ok(resp)
+    }
+    /// Lists the endpoint slices of the service
+    async fn list_endpoint_slices(
+        &self,
+        service: &K8sService,
+    ) -> Result<EndpointSliceList> {
+        let url = self.get_endpoint_slices_url(service);
+        self.get(&url, REQUEST_TIMEOUT)
+            .await?
+            .json::<EndpointSliceList>()
+            .await
+            .map_err(|e| new_k8s_error(e.to_string()))
+    }
+    /// Watches the endpoint slices of the service from the resource version
+    async fn watch_endpoint_slices(
+        &self,
+        service: &K8sService,
+        resource_version: &str,
+    ) -> Result<reqwest::Response> {
+        let url = format!(
+            "{}&watch=true&allowWatchBookmarks=true&resourceVersion={resource_version}&timeoutSeconds={WATCH_TIMEOUT_SECONDS}",
+            self.get_endpoint_slices_url(service)
+        );
+        self.get(&url, Duration::from_secs(WATCH_TIMEOUT_SECONDS + 30))
+            .await
+    }
+}
+
+// The backends of endpoint slices, the key is (service index, slice name)
+type SliceBackends = HashMap<(usize, String), Vec<Backend>>;
+
+/// K8s service discovery implementation, the endpoint slices of services
+/// are listed at first and then watched, the backends are updated
+/// incrementally by the watch events.
+struct K8s {
+    services: Vec<K8sService>,
+    client: K8sClient,
+    ipv4_only: bool,
+    sender: Option<Arc<NotificationSender>>,
+    notifier: UpdateNotifier,
+    slices: Arc<RwLock<SliceBackends>>,
+    watching: Vec<AtomicBool>,
+}
+
+/// Replaces the slices of the service with the list result
+fn set_service_slices(
+    slices: &RwLock<SliceBackends>,
+    index: usize,
+    service: &K8sService,
+    list: &EndpointSliceList,
+    ipv4_only: bool,
+) {
+    if let Ok(mut slices) = slices.write() {
+        slices.retain(|(i, _), _| *i != index);
+        for item in list.items.iter() {
+            slices.insert(
+                (index, item.metadata.name.clone()),
+                item.to_backends(service, ipv4_only),
+            );
+        }
+    }
+}
+
+/// Applies the watch event to the slices, returns the resource version
+/// of the event object. The bookmark event only updates the resource version.
+fn apply_watch_event(
+    slices: &RwLock<SliceBackends>,
+    index: usize,
+    service: &K8sService,
+    event: WatchEvent,
+    ipv4_only: bool,
+) -> Result<String> {
+    if event.event_type == "ERROR" {
+        // e.g. 410 gone, the resource version is too old
+        return Err(new_k8s_error(format!("watch error: {}", event.object)));
+    }
+    if event.event_type == "BOOKMARK" {
+        let version = event
+            .object
+            .pointer("/metadata/resourceVersion")
+            .and_then(|value| value.as_str())
+            .unwrap_or_default();
+        return Ok(version.to_string());
+    }
+    let slice: EndpointSlice = serde_json::from_value(event.object)
+        .map_err(|e| new_k8s_error(e.to_string()))?;
+    let key = (index, slice.metadata.name.clone());
+    if let Ok(mut slices) = slices.write() {
+        match event.event_type.as_str() {
+            "ADDED" | "MODIFIED" => {
+                slices.insert(key, slice.to_backends(service, ipv4_only));
+            },
+            "DELETED" => {
+                slices.remove(&key);
+            },
+            _ => {},
+        }
+    }
+    Ok(slice.metadata.resource_version)
+}
+
+/// Watches the endpoint slices of the service until the discovery is dropped,
+/// the upstream is notified after the slices are changed.
+async fn watch_service(
+    slices: Weak<RwLock<SliceBackends>>,
+    client: K8sClient,
+    index: usize,
+    service: K8sService,
+    mut resource_version: String,
+    ipv4_only: bool,
+    notifier: UpdateNotifier,
+) {
+    let service_name = format!("{}/{}", service.namespace, service.name);
+    loop {
+        if slices.strong_count() == 0 {
+            info!(
+                category = LOG_CATEGORY,
+                service = service_name,
+                "k8s discovery is dropped, stop watching"
+            );
+            return;
+        }
+        let result = async {
+            if resource_version.is_empty() {
+                let list = client.list_endpoint_slices(&service).await?;
+                let Some(slices) = slices.upgrade() else {
+                    return Ok(());
+                };
+                set_service_slices(&slices, index, &service, &list, ipv4_only);
+                resource_version = list.metadata.resource_version;
+                notifier.notify();
+            }
+            let mut resp = client
+                .watch_endpoint_slices(&service, &resource_version)
+                .await?;
+            let mut buf = vec![];
+            while let Some(chunk) = resp
+                .chunk()
+                .await
+                .map_err(|e| new_k8s_error(e.to_string()))?
+            {
+                buf.extend_from_slice(&chunk);
+                while let Some(pos) = buf.iter().position(|v| *v == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=pos).collect();
+                    let Ok(event) = serde_json::from_slice::<WatchEvent>(&line)
+                    else {
+                        continue;
+                    };
+                    debug!(
+                        category = LOG_CATEGORY,
+                        service = service_name,
+                        event = event.event_type,
+                        "k8s watch event"
+                    );
+                    let Some(slices) = slices.upgrade() else {
+                        return Ok(());
+                    };
+                    let changed = event.event_type != "BOOKMARK";
+                    // the service is listed again if the event is error
+                    let version = apply_watch_event(
+                        &slices, index, &service, event, ipv4_only,
+                    )?;
+                    if !version.is_empty() {
+                        resource_version = version;
+                    }
+                    if changed {
+                        notifier.notify();
+                    }
+                }
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            error!(
+                category = LOG_CATEGORY,
+                error = %e,
+                service = service_name,
+                "k8s watch fail"
+            );
+            resource_version = "".to_string();
+            tokio::time::sleep(WATCH_RETRY_DELAY).await;
+        }
+    }
+}
+
+impl K8s {
+    fn new(
+        addrs: &[String],
+        ipv4_only: bool,
+        client: K8sClient,
+    ) -> Result<Self> {
+        let services = addrs
+            .iter()
+            .map(|addr| K8sService::try_from(addr.as_str()))
+            .collect::<Result<Vec<_>>>()?;
+        let watching =
+            services.iter().map(|_| AtomicBool::new(false)).collect();
+        Ok(Self {
+            services,
+            client,
+            ipv4_only,
+            sender: None,
+            notifier: UpdateNotifier::default(),
+            slices: Arc::new(RwLock::new(HashMap::new())),
+            watching,
+        })
+    }
+    /// Sets the notification sender
+    pub fn with_sender(
+        mut self,
+        sender: Option<Arc<NotificationSender>>,
+    ) -> Self {
+        self.sender = sender;
+        self
+    }
+    /// Sets the callback which is called when the endpoint slices are changed
+    pub fn with_update_callback(
+        mut self,
+        callback: Option<UpdateCallback>,
+    ) -> Self {
+        self.notifier = UpdateNotifier::new(callback);
+        self
+    }
+    /// Lists the services which are not watched and starts watching them,
+    /// then returns the backends of all services.
+    async fn run_discover(&self) -> Result<(BTreeSet<Backend>, Vec<String>)> {
+        let mut failed_services = vec![];
+        for (index, service) in self.services.iter().enumerate() {
+            if self.watching[index].load(Ordering::Relaxed) {
+                continue;
+            }
+            match self.client.list_endpoint_slices(service).await {
+                Ok(list) => {
+                    set_service_slices(
+                        &self.slices,
+                        index,
+                        service,
+                        &list,
+                        self.ipv4_only,
+                    );
+                    self.watching[index].store(true, Ordering::Relaxed);
+                    tokio::spawn(watch_service(
+                        Arc::downgrade(&self.slices),
+                        self.client.clone(),
+                        index,
+                        service.clone(),
+                        list.metadata.resource_version,
+                        self.ipv4_only,
+                        self.notifier.clone(),
+                    ));
+                },
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        namespace = service.namespace,
+                        service = service.name,
+                        "k8s list endpoint slices fail"
+                    );
+                    failed_services.push(format!(
+                        "{}/{}: {e}",
+                        service.namespace, service.name
+                    ));
+                },
+            }
+        }
+        // the failed services are listed again after the retry delay
+        if !failed_services.is_empty() {
+            self.notifier.notify_after(WATCH_RETRY_DELAY);
+        }
+        if failed_services.len() == self.services.len() {
+            return Err(new_k8s_error(failed_services.join("; ")));
+        }
+        let upstreams = self
+            .slices
+            .read()
+            .map(|slices| slices.values().flatten().cloned().collect())
+            .unwrap_or_default();
+        Ok((upstreams, failed_services))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for K8s {
+    async fn discover(
+        &self,
+    ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = SystemTime::now();
+        let services: Vec<String> = self
+            .services
+            .iter()
+            .map(|item| format!("{}/{}", item.namespace, item.name))
+            .collect();
+        let (upstreams, failed_services) = match self.run_discover().await {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    services = services.join(","),
+                    "k8s discover fail"
+                );
+                (BTreeSet::new(), vec![e.to_string()])
+            },
+        };
+        if !failed_services.is_empty() {
+            if let Some(sender) = &self.sender {
+                sender
+                    .notify(NotificationData {
+                        category: "service_discover_fail".to_string(),
+                        level: NotificationLevel::Warn,
+                        message: format!(
+                            "k8s discovery fail: {}",
+                            failed_services.join("; ")
+                        ),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+            if upstreams.is_empty() {
+                return Err(new_k8s_error(failed_services.join("; ")).into());
+            }
+        }
+        let addrs: Vec<String> =
+            upstreams.iter().map(|item| item.addr.to_string()).collect();
+        debug!(
+            category = LOG_CATEGORY,
+            services = services.join(","),
+            addrs = addrs.join(","),
+            elapsed =
+                format!("{}ms", now.elapsed().unwrap_or_default().as_millis()),
+            "k8s discover success"
+        );
+        Ok((upstreams, HashMap::new()))
+    }
+}
+
+/// Creates a new k8s service discovery backend, the addresses are
+/// the services in the format "namespace/service:port".
+///
+/// # Arguments
+/// * `discovery` - The discovery configuration
+///
+/// # Returns
+/// * `Result<Backends>` - Configured service discovery backend
+pub fn new_k8s_discover_backends(discovery: &Discovery) -> Result<Backends> {
+    let k8s =
+        K8s::new(&discovery.addr, discovery.ipv4_only, K8sClient::new()?)?;
+    let backends = new_shared_attrs_backends(
+        k8s.with_sender(discovery.sender.clone())
+            .with_update_callback(discovery.update_callback.clone()),
+    );
+    Ok(backends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const SLICE_LIST: &str = r#"{"metadata":{"resourceVersion":"10"},"items":[{"metadata":{"name":"web-abc","resourceVersion":"9"},"addressType":"IPv4","ports":[{"name":"http","port":8080}],"endpoints":[{"addresses":["10.0.0.1"],"conditions":{"ready":true}},{"addresses":["10.0.0.2"],"conditions":{"ready":false}},{"addresses":["10.0.0.3"],"conditions":{"ready":true,"terminating":true}},{"addresses":["10.0.0.4"]}]}]}"#;
+    const SLICE_EVENT: &str = r#"{"type":"ADDED","object":{"metadata":{"name":"web-def","resourceVersion":"11"},"addressType":"IPv4","ports":[{"name":"http","port":8080}],"endpoints":[{"addresses":["10.0.0.5"],"conditions":{"ready":true}}]}}"#;
+
+    fn get_addrs(backends: &BTreeSet<Backend>) -> Vec<String> {
+        backends.iter().map(|item| item.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn test_k8s_service() {
+        assert_eq!(
+            K8sService {
+                namespace: "prod".to_string(),
+                name: "web".to_string(),
+                port: "http".to_string(),
+                weight: 5,
+                backup: true,
+            },
+            K8sService::try_from("prod/web:http weight=5 backup").unwrap()
+        );
+        let service = K8sService::try_from("web").unwrap();
+        assert_eq!("default", service.namespace);
+        assert_eq!("", service.port);
+        assert_eq!(
+            "k8s discovery: invalid service: /web",
+            K8sService::try_from("/web").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_endpoint_slice_to_backends() {
+        let list: EndpointSliceList = serde_json::from_str(SLICE_LIST).unwrap();
+        let slice = &list.items[0];
+        let service = K8sService::try_from("default/web:http").unwrap();
+        let backends: BTreeSet<_> =
+            slice.to_backends(&service, false).into_iter().collect();
+        assert_eq!(
+            vec!["10.0.0.1:8080", "10.0.0.4:8080"],
+            get_addrs(&backends)
+        );
+
+        let service = K8sService::try_from("default/web:8080").unwrap();
+        assert_eq!(2, slice.to_backends(&service, false).len());
+        let service = K8sService::try_from("default/web:grpc").unwrap();
+        assert_eq!(0, slice.to_backends(&service, false).len());
+    }
+
+    #[test]
+    fn test_from_kubeconfig() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"apiVersion: v1
+clusters:
+- cluster:
+    server: https://127.0.0.1:6443/
+    insecure-skip-tls-verify: true
+  name: local
+contexts:
+- context:
+    cluster: local
+    user: admin
+  name: local
+current-context: local
+users:
+- name: admin
+  user:
+    token: pingap
+"#,
+        )
+        .unwrap();
+        let client =
+            K8sClient::from_kubeconfig(&file.path().to_string_lossy()).unwrap();
+        assert_eq!("https://127.0.0.1:6443", client.server);
+        assert_eq!(Some("pingap".to_string()), client.token);
+    }
+
+    #[tokio::test]
+    async fn test_get_token() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "token-a\n").unwrap();
+        let client = K8sClient {
+            server: "https://127.0.0.1:6443".to_string(),
+            token: None,
+            token_file: Some(file.path().to_string_lossy().to_string()),
+            client: reqwest::Client::new(),
+        };
+        assert_eq!(
+            Some("token-a".to_string()),
+            client.get_token().await.unwrap()
+        );
+
+        // the rotated token is read
+        std::fs::write(file.path(), "token-b").unwrap();
+        assert_eq!(
+            Some("token-b".to_string()),
+            client.get_token().await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_apply_watch_event() {
+        let slices = RwLock::new(SliceBackends::new());
+        let service = K8sService::try_from("default/web:http").unwrap();
+        let event: WatchEvent = serde_json::from_str(SLICE_EVENT).unwrap();
+        assert_eq!(
+            "11",
+            apply_watch_event(&slices, 0, &service, event, false).unwrap()
+        );
+        assert_eq!(1, slices.read().unwrap().len());
+
+        // the object of bookmark only has the resource version
+        let event: WatchEvent = serde_json::from_str(
+            r#"{"type":"BOOKMARK","object":{"kind":"EndpointSlice","apiVersion":"discovery.k8s.io/v1","metadata":{"resourceVersion":"12"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            "12",
+            apply_watch_event(&slices, 0, &service, event, false).unwrap()
+        );
+        assert_eq!(1, slices.read().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_k8s_discover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let size = stream.read(&mut buf).await.unwrap_or_default();
+                    let req = String::from_utf8_lossy(&buf[..size]).to_string();
+                    let body = if req.contains("watch=true") {
+                        format!("{SLICE_EVENT}\n")
+                    } else {
+                        SLICE_LIST.to_string()
+                    };
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(resp.as_bytes()).await;
+                });
+            }
+        });
+        let client = K8sClient {
+            server,
+            token: Some("pingap".to_string()),
+            token_file: None,
+            client: reqwest::Client::new(),
+        };
+        let k8s =
+            K8s::new(&["default/web:http".to_string()], true, client).unwrap();
+        let (backends, _) = k8s.discover().await.unwrap();
+        assert_eq!(
+            vec!["10.0.0.1:8080", "10.0.0.4:8080"],
+            get_addrs(&backends)
+        );
+
+        // the watch event is applied
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (backends, _) = k8s.discover().await.unwrap();
+        assert_eq!(
+            vec!["10.0.0.1:8080", "10.0.0.4:8080", "10.0.0.5:8080"],
+            get_addrs(&backends)
+        );
+    }
+}
@@ -12,10 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use hickory_resolver::error::ResolveError;
+use http::Extensions;
 use pingap_core::NotificationSender;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use pingora::protocols::l4::socket::SocketAddr;
 use snafu::Snafu;
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub static LOG_CATEGORY: &str = "discovery";
 
@@ -45,58 +53,346 @@ impl From<Error> for pingora::BError {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub(crate) type Addr = (String, String, usize);
+pub(crate) type Addr = (String, String, usize, bool);
+
+/// Attributes stored in the extensions of backend
+#[derive(Clone, Debug, Default, PartialEq)]
+struct BackendAttrs {
+    backup: bool,
+    metadata: HashMap<String, String>,
+}
+
+// The attributes are shared by the backends of the same address, pingora
+// ignores the extensions when comparing backends, so the backends are not
+// replaced if only the attributes are changed. The shared attributes are
+// updated in place, then the stored backends get the new attributes.
+#[derive(Clone, Debug, Default)]
+struct SharedBackendAttrs(Arc<ArcSwap<BackendAttrs>>);
+
+/// Creates a new backend, the backup flag is stored in its extensions.
+///
+/// # Arguments
+///
+/// * `addr` - The socket address of the backend
+/// * `weight` - The weight of the backend
+/// * `backup` - Whether the backend only receives traffic when all primary backends are unhealthy
+pub fn new_backend(
+    addr: std::net::SocketAddr,
+    weight: usize,
+    backup: bool,
+) -> Backend {
+    build_backend(SocketAddr::Inet(addr), weight, backup)
+}
+
+// Creates the backend of the address, the backup flag is
+// stored in the extensions of backend
+fn build_backend(addr: SocketAddr, weight: usize, backup: bool) -> Backend {
+    let mut ext = Extensions::new();
+    ext.insert(SharedBackendAttrs(Arc::new(ArcSwap::from_pointee(
+        BackendAttrs {
+            backup,
+            ..Default::default()
+        },
+    ))));
+    Backend { addr, weight, ext }
+}
+
+// Returns the attributes of backend
+fn get_backend_attrs(backend: &Backend) -> Arc<BackendAttrs> {
+    backend
+        .ext
+        .get::<SharedBackendAttrs>()
+        .map(|attrs| attrs.0.load_full())
+        .unwrap_or_default()
+}
+
+/// Service discovery which shares the attributes of the backends with the
+/// same address between discoveries, so the change of attributes
+/// (e.g. backup, metadata) takes effect even if the address and weight are not changed.
+struct SharedAttrsDiscovery {
+    discovery: Box<dyn ServiceDiscovery + Send + Sync>,
+    attrs: Mutex<HashMap<SocketAddr, SharedBackendAttrs>>,
+}
+
+#[async_trait]
+impl ServiceDiscovery for SharedAttrsDiscovery {
+    async fn discover(
+        &self,
+    ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let (backends, enablement) = self.discovery.discover().await?;
+        let Ok(mut shared) = self.attrs.lock() else {
+            return Ok((backends, enablement));
+        };
+        let mut current = HashMap::with_capacity(backends.len());
+        let backends = backends
+            .into_iter()
+            .map(|mut backend| {
+                let attrs = current
+                    .get(&backend.addr)
+                    .cloned()
+                    .or_else(|| shared.remove(&backend.addr))
+                    .unwrap_or_default();
+                attrs.0.store(get_backend_attrs(&backend));
+                backend.ext.insert(attrs.clone());
+                current.insert(backend.addr.clone(), attrs);
+                backend
+            })
+            .collect();
+        *shared = current;
+        Ok((backends, enablement))
+    }
+}
+
+/// Creates the backends of the dynamic discovery, the attributes of the
+/// discovered backends are updated even if the backends are not changed.
+pub(crate) fn new_shared_attrs_backends<D>(discovery: D) -> Backends
+where
+    D: ServiceDiscovery + Send + Sync + 'static,
+{
+    Backends::new(Box::new(SharedAttrsDiscovery {
+        discovery: Box::new(discovery),
+        attrs: Mutex::new(HashMap::new()),
+    }))
+}
+
+/// Prefix of the unix domain socket address, e.g. "unix:/run/app.sock"
+pub const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// Returns whether the address is a unix domain socket address
+#[inline]
+pub fn is_unix_addr(addr: &str) -> bool {
+    addr.starts_with(UNIX_ADDR_PREFIX)
+}
+
+/// Creates a new backend of the unix domain socket
+///
+/// # Arguments
+///
+/// * `path` - The path of the unix domain socket
+/// * `weight` - The weight of the backend
+/// * `backup` - Whether the backend only receives traffic when all primary backends are unhealthy
+#[cfg(unix)]
+pub fn new_unix_backend(
+    path: &str,
+    weight: usize,
+    backup: bool,
+) -> Result<Backend> {
+    let addr =
+        std::os::unix::net::SocketAddr::from_pathname(path).map_err(|e| {
+            Error::Io {
+                source: e,
+                content: format!("{path} to unix socket addr fail"),
+            }
+        })?;
+    Ok(build_backend(SocketAddr::Unix(addr), weight, backup))
+}
+
+/// Returns whether the backend is a backup backend
+#[inline]
+pub fn is_backup_backend(backend: &Backend) -> bool {
+    backend
+        .ext
+        .get::<SharedBackendAttrs>()
+        .map(|attrs| attrs.0.load().backup)
+        .unwrap_or_default()
+}
+
+/// Sets the metadata of the backend, it's set by the file discovery
+pub(crate) fn set_backend_metadata(
+    backend: &Backend,
+    metadata: HashMap<String, String>,
+) {
+    if let Some(attrs) = backend.ext.get::<SharedBackendAttrs>() {
+        attrs.0.rcu(|value| BackendAttrs {
+            metadata: metadata.clone(),
+            ..BackendAttrs::clone(value)
+        });
+    }
+}
+
+/// Returns the metadata of the backend
+#[inline]
+pub fn get_backend_metadata(backend: &Backend) -> HashMap<String, String> {
+    get_backend_attrs(backend).metadata.clone()
+}
+
+/// Parses the options after the address, the following formats are supported:
+/// * "10" - weight 10 (legacy format)
+/// * "weight=10" or "weight 10" - weight 10
+/// * "backup" - backup backend
+///
+/// # Returns
+///
+/// Returns a tuple containing (weight, backup), weight defaults to 1
+pub fn parse_addr_options(options: &[&str]) -> Result<(usize, bool)> {
+    let parse_weight = |value: &str| {
+        value.parse::<usize>().map_err(|_| Error::Invalid {
+            message: format!("invalid weight: {value}"),
+        })
+    };
+    let mut weight = 1;
+    let mut backup = false;
+    let mut iter = options.iter().filter(|item| !item.is_empty());
+    while let Some(option) = iter.next() {
+        match *option {
+            "backup" => backup = true,
+            "weight" => {
+                let Some(value) = iter.next() else {
+                    return Err(Error::Invalid {
+                        message: "weight value is missing".to_string(),
+                    });
+                };
+                weight = parse_weight(value)?;
+            },
+            _ => {
+                if let Some(value) = option.strip_prefix("weight=") {
+                    weight = parse_weight(value)?;
+                } else if option.as_bytes()[0].is_ascii_digit() {
+                    weight = parse_weight(option)?;
+                } else {
+                    return Err(Error::Invalid {
+                        message: format!("invalid address option: {option}"),
+                    });
+                }
+            },
+        }
+    }
+    Ok((weight, backup))
+}
 
 /// Formats a list of address strings into a vector of structured address tuples.
 ///
 /// # Arguments
 ///
-/// * `addrs` - A slice of strings containing addresses in the format "host:port weight" or "host:port" or "host"
+/// * `addrs` - A slice of strings containing addresses in the format "host:port weight=5 backup" or "host:port" or "host",
+///   the unix domain socket address("unix:/run/app.sock") is kept with an empty port
 /// * `tls` - A boolean indicating whether to use TLS default port (443) or HTTP default port (80)
 ///
 /// # Returns
 ///
-/// Returns a vector of tuples containing (host, port, weight), where:
+/// Returns a vector of tuples containing (host, port, weight, backup), or an
+/// error if the options of an address are invalid, where:
 /// * host is the hostname or IP address
 /// * port is either specified in the address or defaults to 443/80 based on TLS setting
 /// * weight is either specified after the address or defaults to 1
-pub(crate) fn format_addrs(addrs: &[String], tls: bool) -> Vec<Addr> {
+/// * backup is whether the address is marked as backup
+pub(crate) fn format_addrs(addrs: &[String], tls: bool) -> Result<Vec<Addr>> {
     let mut new_addrs = vec![];
     for addr in addrs.iter() {
-        // get the weight of address
-        let arr: Vec<_> = addr.split(' ').collect();
-        let weight = if arr.len() == 2 {
-            arr[1].parse::<usize>().unwrap_or(1)
-        } else {
-            1
+        // get the weight and backup option of address
+        let arr: Vec<_> = addr.split_whitespace().collect();
+        let Some(host_port) = arr.first() else {
+            continue;
         };
+        let (weight, backup) =
+            parse_addr_options(&arr[1..]).map_err(|e| Error::Invalid {
+                message: format!("{e}(addr:{addr})"),
+            })?;
+        // the unix domain socket address has no port
+        if is_unix_addr(host_port) {
+            new_addrs.push((
+                host_port.to_string(),
+                "".to_string(),
+                weight,
+                backup,
+            ));
+            continue;
+        }
         // split ip and port
         // the port will use default value if none
-        if let Some((host, port)) = arr[0].split_once(':') {
-            new_addrs.push((host.to_string(), port.to_string(), weight));
+        if let Some((host, port)) = host_port.split_once(':') {
+            new_addrs.push((
+                host.to_string(),
+                port.to_string(),
+                weight,
+                backup,
+            ));
         } else {
             let port = if tls {
                 "443".to_string()
             } else {
                 "80".to_string()
             };
-            new_addrs.push((arr[0].to_string(), port, weight));
+            new_addrs.push((host_port.to_string(), port, weight, backup));
         }
     }
-    new_addrs
+    Ok(new_addrs)
 }
 
+pub const CONSUL_DISCOVERY: &str = "consul";
 pub const DNS_DISCOVERY: &str = "dns";
 pub const DOCKER_DISCOVERY: &str = "docker";
+pub const FILE_DISCOVERY: &str = "file";
+pub const K8S_DISCOVERY: &str = "k8s";
+pub const SRV_DISCOVERY: &str = "srv";
 pub const STATIC_DISCOVERY: &str = "static";
 pub const TRANSPARENT_DISCOVERY: &str = "transparent";
 
+/// Callback of the discovery when its backends are changed, the upstream
+/// updates the backends at once instead of waiting for the update frequency.
+pub type UpdateCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// Notifier of the backends changes, the delayed notifications
+/// are merged into the earliest one.
+#[derive(Clone, Default)]
+pub(crate) struct UpdateNotifier {
+    callback: Option<UpdateCallback>,
+    scheduled: Arc<Mutex<Option<Instant>>>,
+}
+
+impl UpdateNotifier {
+    pub(crate) fn new(callback: Option<UpdateCallback>) -> Self {
+        Self {
+            callback,
+            scheduled: Arc::new(Mutex::new(None)),
+        }
+    }
+    /// Returns whether the callback is set
+    pub(crate) fn enabled(&self) -> bool {
+        self.callback.is_some()
+    }
+    /// Notifies the backends are changed
+    pub(crate) fn notify(&self) {
+        if let Some(callback) = &self.callback {
+            callback();
+        }
+    }
+    /// Notifies after the delay, e.g. the ttl is expired or the failed
+    /// discovery should be retried. It's ignored if an earlier
+    /// notification is scheduled.
+    pub(crate) fn notify_after(&self, delay: Duration) {
+        let Some(callback) = self.callback.clone() else {
+            return;
+        };
+        let deadline = Instant::now() + delay;
+        if let Ok(mut scheduled) = self.scheduled.lock() {
+            if scheduled.is_some_and(|value| value <= deadline) {
+                return;
+            }
+            *scheduled = Some(deadline);
+        }
+        let scheduled = self.scheduled.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(deadline.into()).await;
+            if let Ok(mut value) = scheduled.lock() {
+                // it's replaced by an earlier notification
+                if *value != Some(deadline) {
+                    return;
+                }
+                *value = None;
+            }
+            callback();
+        });
+    }
+}
+
 #[derive(Default)]
 pub struct Discovery {
     addr: Vec<String>,
     tls: bool,
     ipv4_only: bool,
     sender: Option<Arc<NotificationSender>>,
+    update_callback: Option<UpdateCallback>,
 }
 
 impl Discovery {
@@ -106,6 +402,7 @@ impl Discovery {
             tls: false,
             ipv4_only: false,
             sender: None,
+            update_callback: None,
         }
     }
     pub fn with_sender(
@@ -123,32 +420,201 @@ impl Discovery {
         self.ipv4_only = ipv4_only;
         self
     }
+    /// Sets the callback which is called when the backends are changed
+    pub fn with_update_callback(
+        mut self,
+        callback: Option<UpdateCallback>,
+    ) -> Self {
+        self.update_callback = callback;
+        self
+    }
 }
 
 mod common;
+mod consul;
 mod dns;
 mod docker;
+mod file;
+mod k8s;
+mod srv;
 pub use common::{is_static_discovery, new_static_discovery};
+pub use consul::{is_consul_discovery, new_consul_discover_backends};
 pub use dns::{is_dns_discovery, new_dns_discover_backends};
 pub use docker::{is_docker_discovery, new_docker_discover_backends};
+pub use file::{is_file_discovery, new_file_discover_backends};
+pub use k8s::{is_k8s_discovery, new_k8s_discover_backends};
+pub use srv::{is_srv_discovery, new_srv_discover_backends};
 
 #[cfg(test)]
 mod tests {
-    use super::format_addrs;
+    use super::{
+        format_addrs, is_backup_backend, new_backend,
+        new_shared_attrs_backends, new_unix_backend, parse_addr_options,
+        UpdateNotifier,
+    };
+    use async_trait::async_trait;
+    use pingora::lb::discovery::ServiceDiscovery;
+    use pingora::lb::Backend;
     use pretty_assertions::assert_eq;
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn test_format_addrs() {
-        let addrs = format_addrs(&["127.0.0.1:8080".to_string()], false);
-        assert_eq!(format!("{:?}", addrs), r#"[("127.0.0.1", "8080", 1)]"#);
+        let addrs =
+            format_addrs(&["127.0.0.1:8080".to_string()], false).unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("127.0.0.1", "8080", 1, false)]"#
+        );
+
+        let addrs = format_addrs(&["127.0.0.1".to_string()], false).unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("127.0.0.1", "80", 1, false)]"#
+        );
+
+        let addrs = format_addrs(&["127.0.0.1".to_string()], true).unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("127.0.0.1", "443", 1, false)]"#
+        );
+
+        let addrs = format_addrs(&["127.0.0.1 10".to_string()], false).unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("127.0.0.1", "80", 10, false)]"#
+        );
+
+        let addrs = format_addrs(
+            &[
+                "127.0.0.1:8080 weight 5".to_string(),
+                "127.0.0.2:8080  weight=3 backup".to_string(),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("127.0.0.1", "8080", 5, false), ("127.0.0.2", "8080", 3, true)]"#
+        );
+
+        let addrs =
+            format_addrs(&["unix:/run/app.sock weight=2".to_string()], false)
+                .unwrap();
+        assert_eq!(
+            format!("{:?}", addrs),
+            r#"[("unix:/run/app.sock", "", 2, false)]"#
+        );
+
+        let result =
+            format_addrs(&["127.0.0.1:8080 weight=a".to_string()], false);
+        assert_eq!(
+            "invalid weight: a(addr:127.0.0.1:8080 weight=a)",
+            result.err().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_addr_options() {
+        assert_eq!((1, false), parse_addr_options(&[]).unwrap());
+        assert_eq!((10, false), parse_addr_options(&["10"]).unwrap());
+        assert_eq!(
+            (5, true),
+            parse_addr_options(&["weight", "5", "backup"]).unwrap()
+        );
+        assert_eq!((1, true), parse_addr_options(&["backup"]).unwrap());
+        assert_eq!((2, false), parse_addr_options(&["weight=2"]).unwrap());
+        assert_eq!(
+            "invalid weight: a",
+            parse_addr_options(&["weight=a"]).err().unwrap().to_string()
+        );
+        assert_eq!(
+            "weight value is missing",
+            parse_addr_options(&["weight"]).err().unwrap().to_string()
+        );
+        assert_eq!(
+            "invalid address option: down",
+            parse_addr_options(&["down"]).err().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_backup_backend() {
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let backend = new_backend(addr, 2, false);
+        assert_eq!(2, backend.weight);
+        assert_eq!(false, is_backup_backend(&backend));
+        assert_eq!(true, is_backup_backend(&new_backend(addr, 1, true)));
+
+        let backend = new_unix_backend("/run/app.sock", 1, true).unwrap();
+        assert_eq!(
+            "unix:/run/app.sock",
+            pingap_core::format_socket_addr(&backend.addr)
+        );
+        assert_eq!(true, is_backup_backend(&backend));
+    }
+
+    struct BackupDiscovery {
+        backup: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ServiceDiscovery for BackupDiscovery {
+        async fn discover(
+            &self,
+        ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+            let backend = new_backend(
+                "127.0.0.1:80".parse().unwrap(),
+                1,
+                self.backup.load(Ordering::Relaxed),
+            );
+            Ok((BTreeSet::from([backend]), HashMap::new()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_attrs_backends() {
+        let backup = Arc::new(AtomicBool::new(false));
+        let backends = new_shared_attrs_backends(BackupDiscovery {
+            backup: backup.clone(),
+        });
+        backends.update(|_| {}).await.unwrap();
+        let backend = backends.get_backend().first().cloned().unwrap();
+        assert_eq!(false, is_backup_backend(&backend));
+
+        // only the backup flag is changed, the stored backend is not
+        // replaced but its attributes are updated
+        backup.store(true, Ordering::Relaxed);
+        let updated = AtomicBool::new(false);
+        backends
+            .update(|_| updated.store(true, Ordering::Relaxed))
+            .await
+            .unwrap();
+        assert_eq!(false, updated.load(Ordering::Relaxed));
+        assert_eq!(true, is_backup_backend(&backend));
+    }
 
-        let addrs = format_addrs(&["127.0.0.1".to_string()], false);
-        assert_eq!(format!("{:?}", addrs), r#"[("127.0.0.1", "80", 1)]"#);
+    #[tokio::test]
+    async fn test_update_notifier() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let value = count.clone();
+        let notifier = UpdateNotifier::new(Some(Arc::new(move || {
+            value.fetch_add(1, Ordering::Relaxed);
+        })));
+        notifier.notify();
+        assert_eq!(1, count.load(Ordering::Relaxed));
 
-        let addrs = format_addrs(&["127.0.0.1".to_string()], true);
-        assert_eq!(format!("{:?}", addrs), r#"[("127.0.0.1", "443", 1)]"#);
+        // the delayed notifications are merged into the earliest one
+        notifier.notify_after(Duration::from_millis(100));
+        notifier.notify_after(Duration::from_millis(50));
+        notifier.notify_after(Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(2, count.load(Ordering::Relaxed));
 
-        let addrs = format_addrs(&["127.0.0.1 10".to_string()], false);
-        assert_eq!(format!("{:?}", addrs), r#"[("127.0.0.1", "80", 10)]"#);
+        // no callback
+        UpdateNotifier::default().notify();
     }
 }
@@ -0,0 +1,491 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::dns::new_resolver;
+use super::{
+    new_backend, new_shared_attrs_backends, parse_addr_options, Error, Result,
+};
+use super::{
+    Discovery, UpdateCallback, UpdateNotifier, LOG_CATEGORY, SRV_DISCOVERY,
+};
+use async_trait::async_trait;
+use hickory_resolver::TokioAsyncResolver;
+use pingap_core::{NotificationData, NotificationLevel, NotificationSender};
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::{Backend, Backends};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info};
+
+// the min interval of resolving, avoid resolving too often for zero ttl
+const MIN_TTL: Duration = Duration::from_secs(1);
+// the delay of resolving the failed records again
+const RESOLVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Checks if the discovery type is SRV
+pub fn is_srv_discovery(value: &str) -> bool {
+    value == SRV_DISCOVERY
+}
+
+/// The SRV record name of discovery.
+/// Format: "_http._tcp.service.example weight=5 backup", the weight
+/// is only used for the target whose SRV weight is 0.
+#[derive(Debug, Clone, PartialEq)]
+struct SrvName {
+    name: String,
+    weight: usize,
+    backup: bool,
+}
+
+impl TryFrom<&str> for SrvName {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let parts: Vec<_> = value.split_whitespace().collect();
+        let Some(name) = parts.first() else {
+            return Err(Error::Invalid {
+                message: "srv name is empty".to_string(),
+            });
+        };
+        let (weight, backup) = parse_addr_options(&parts[1..])?;
+        Ok(Self {
+            name: name.to_string(),
+            weight,
+            backup,
+        })
+    }
+}
+
+/// The resolved target of SRV record
+#[derive(Debug, Clone)]
+struct SrvTarget {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    ips: Vec<IpAddr>,
+}
+
+/// Converts the SRV targets to backends, the targets with the lowest
+/// priority are primary and the others are backup, the SRV weight is
+/// used as the backend weight.
+fn to_backends(
+    srv: &SrvName,
+    targets: &[SrvTarget],
+    ipv4_only: bool,
+) -> Vec<Backend> {
+    let Some(min_priority) = targets.iter().map(|item| item.priority).min()
+    else {
+        return vec![];
+    };
+    let mut backends = vec![];
+    for target in targets.iter() {
+        let weight = if target.weight == 0 {
+            srv.weight
+        } else {
+            target.weight as usize
+        };
+        let backup = srv.backup || target.priority > min_priority;
+        for ip in target.ips.iter() {
+            if ipv4_only && !ip.is_ipv4() {
+                continue;
+            }
+            backends.push(new_backend(
+                SocketAddr::new(*ip, target.port),
+                weight,
+                backup,
+            ));
+        }
+    }
+    backends
+}
+
+/// The resolved backends of SRV record, it is valid until the min ttl
+/// of the SRV and address records.
+struct SrvCache {
+    valid_until: Instant,
+    backends: Vec<Backend>,
+}
+
+/// SRV service discovery implementation, the records are resolved
+/// again only after their ttl is expired, the upstream is notified
+/// to discover again when the earliest ttl is expired.
+struct Srv {
+    ipv4_only: bool,
+    names: Vec<SrvName>,
+    sender: Option<Arc<NotificationSender>>,
+    notifier: UpdateNotifier,
+    cache: RwLock<HashMap<String, SrvCache>>,
+}
+
+impl Srv {
+    fn new(addrs: &[String], ipv4_only: bool) -> Result<Self> {
+        let names = addrs
+            .iter()
+            .map(|addr| SrvName::try_from(addr.as_str()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            ipv4_only,
+            names,
+            sender: None,
+            notifier: UpdateNotifier::default(),
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+    /// Sets the notification sender
+    pub fn with_sender(
+        mut self,
+        sender: Option<Arc<NotificationSender>>,
+    ) -> Self {
+        self.sender = sender;
+        self
+    }
+    /// Sets the callback which is called when the ttl of records is expired
+    pub fn with_update_callback(
+        mut self,
+        callback: Option<UpdateCallback>,
+    ) -> Self {
+        self.notifier = UpdateNotifier::new(callback);
+        self
+    }
+    /// Looks up the SRV record and the addresses of its targets,
+    /// returns the targets and the time until which they are valid.
+    async fn lookup_srv(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+    ) -> Result<(Vec<SrvTarget>, Instant)> {
+        let lookup = resolver
+            .srv_lookup(name)
+            .await
+            .map_err(|e| Error::Resolve { source: e })?;
+        let mut valid_until = lookup.as_lookup().valid_until();
+        let mut targets = vec![];
+        for record in lookup.iter() {
+            let target = record.target().to_string();
+            let ips = match resolver.lookup_ip(target.as_str()).await {
+                Ok(lookup_ip) => {
+                    valid_until = valid_until.min(lookup_ip.valid_until());
+                    lookup_ip.iter().collect()
+                },
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name,
+                        target,
+                        "srv target lookup failed"
+                    );
+                    continue;
+                },
+            };
+            targets.push(SrvTarget {
+                priority: record.priority(),
+                weight: record.weight(),
+                port: record.port(),
+                ips,
+            });
+        }
+        if targets.is_empty() {
+            return Err(Error::Invalid {
+                message: format!("srv {name} has no available target"),
+            });
+        }
+        Ok((targets, valid_until.max(Instant::now() + MIN_TTL)))
+    }
+    /// Resolves the SRV records whose ttl is expired, the expired cache
+    /// is still used if resolving fails.
+    async fn run_discover(&self) -> Result<(BTreeSet<Backend>, Vec<String>)> {
+        let now = Instant::now();
+        let expired_names: Vec<_> = {
+            let cache = self.cache.read().map_err(|e| Error::Invalid {
+                message: e.to_string(),
+            })?;
+            self.names
+                .iter()
+                .filter(|srv| {
+                    cache
+                        .get(&srv.name)
+                        .map(|item| item.valid_until <= now)
+                        .unwrap_or(true)
+                })
+                .collect()
+        };
+        let mut failed_names = vec![];
+        if !expired_names.is_empty() {
+            let resolver = new_resolver(self.ipv4_only)?;
+            for srv in expired_names {
+                match self.lookup_srv(&resolver, &srv.name).await {
+                    Ok((targets, valid_until)) => {
+                        let backends =
+                            to_backends(srv, &targets, self.ipv4_only);
+                        debug!(
+                            category = LOG_CATEGORY,
+                            name = srv.name,
+                            ttl = format!(
+                                "{}s",
+                                valid_until
+                                    .saturating_duration_since(now)
+                                    .as_secs()
+                            ),
+                            "srv lookup success"
+                        );
+                        if let Ok(mut cache) = self.cache.write() {
+                            cache.insert(
+                                srv.name.clone(),
+                                SrvCache {
+                                    valid_until,
+                                    backends,
+                                },
+                            );
+                        }
+                    },
+                    Err(e) => {
+                        error!(
+                            category = LOG_CATEGORY,
+                            error = %e,
+                            name = srv.name,
+                            "srv lookup failed"
+                        );
+                        failed_names.push(srv.name.clone());
+                    },
+                }
+            }
+        }
+        let (upstreams, valid_until): (BTreeSet<Backend>, Option<Instant>) =
+            self.cache
+                .read()
+                .map(|cache| {
+                    (
+                        cache
+                            .values()
+                            .flat_map(|item| item.backends.iter().cloned())
+                            .collect(),
+                        cache.values().map(|item| item.valid_until).min(),
+                    )
+                })
+                .unwrap_or_default();
+        // discover again when the earliest ttl is expired,
+        // or the failed records should be resolved again
+        let mut next_resolve = valid_until;
+        if !failed_names.is_empty() {
+            let retry_at = Instant::now() + RESOLVE_RETRY_DELAY;
+            next_resolve = Some(
+                next_resolve.map_or(retry_at, |value| value.min(retry_at)),
+            );
+        }
+        if let Some(value) = next_resolve {
+            self.notifier
+                .notify_after(value.saturating_duration_since(Instant::now()));
+        }
+        if upstreams.is_empty() {
+            return Err(Error::Invalid {
+                message: "resolve srv failed".to_string(),
+            });
+        }
+        Ok((upstreams, failed_names))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Srv {
+    async fn discover(
+        &self,
+    ) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = SystemTime::now();
+        let names: Vec<String> =
+            self.names.iter().map(|item| item.name.clone()).collect();
+        match self.run_discover().await {
+            Ok((upstreams, failed_names)) => {
+                let addrs: Vec<String> = upstreams
+                    .iter()
+                    .map(|item| item.addr.to_string())
+                    .collect();
+                debug!(
+                    category = LOG_CATEGORY,
+                    names = names.join(","),
+                    addrs = addrs.join(","),
+                    elapsed = format!(
+                        "{}ms",
+                        now.elapsed().unwrap_or_default().as_millis()
+                    ),
+                    "srv discover success"
+                );
+                if !failed_names.is_empty() {
+                    if let Some(sender) = &self.sender {
+                        sender
+                            .notify(NotificationData {
+                                category: "service_discover_fail".to_string(),
+                                level: NotificationLevel::Warn,
+                                message: format!(
+                                    "srv discovery resolve failed: {:?}",
+                                    failed_names
+                                ),
+                                ..Default::default()
+                            })
+                            .await;
+                    }
+                }
+                Ok((upstreams, HashMap::new()))
+            },
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    names = names.join(","),
+                    elapsed = format!(
+                        "{}ms",
+                        now.elapsed().unwrap_or_default().as_millis()
+                    ),
+                    "srv discover fail"
+                );
+                if let Some(sender) = &self.sender {
+                    sender
+                        .notify(NotificationData {
+                            category: "service_discover_fail".to_string(),
+                            level: NotificationLevel::Warn,
+                            message: format!(
+                                "srv discovery {:?}, error: {e}",
+                                names
+                            ),
+                            ..Default::default()
+                        })
+                        .await;
+                }
+                Err(e.into())
+            },
+        }
+    }
+}
+
+/// Creates a new SRV-based service discovery backend
+///
+/// # Arguments
+/// * `discovery` - The discovery configuration
+///
+/// # Returns
+/// * `Result<Backends>` - Configured service discovery backend
+pub fn new_srv_discover_backends(discovery: &Discovery) -> Result<Backends> {
+    let srv = Srv::new(&discovery.addr, discovery.ipv4_only)?;
+    info!(
+        category = LOG_CATEGORY,
+        names = discovery.addr.join(","),
+        "new srv discovery"
+    );
+    let backends = new_shared_attrs_backends(
+        srv.with_sender(discovery.sender.clone())
+            .with_update_callback(discovery.update_callback.clone()),
+    );
+    Ok(backends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::is_backup_backend;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_srv_name() {
+        assert_eq!(
+            SrvName {
+                name: "_http._tcp.pingap.io".to_string(),
+                weight: 3,
+                backup: true,
+            },
+            SrvName::try_from("_http._tcp.pingap.io weight=3 backup").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_backends() {
+        let srv = SrvName::try_from("_http._tcp.pingap.io").unwrap();
+        let targets = vec![
+            SrvTarget {
+                priority: 10,
+                weight: 60,
+                port: 8080,
+                ips: vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+            },
+            SrvTarget {
+                priority: 10,
+                weight: 0,
+                port: 8081,
+                ips: vec!["10.0.0.2".parse().unwrap()],
+            },
+            SrvTarget {
+                priority: 20,
+                weight: 5,
+                port: 9000,
+                ips: vec!["10.0.0.3".parse().unwrap()],
+            },
+        ];
+        let backends = to_backends(&srv, &targets, false);
+        assert_eq!(
+            vec![
+                ("10.0.0.1:8080".to_string(), 60, false),
+                ("[::1]:8080".to_string(), 60, false),
+                ("10.0.0.2:8081".to_string(), 1, false),
+                ("10.0.0.3:9000".to_string(), 5, true),
+            ],
+            backends
+                .iter()
+                .map(|item| (
+                    item.addr.to_string(),
+                    item.weight,
+                    is_backup_backend(item)
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(3, to_backends(&srv, &targets, true).len());
+
+        let srv = SrvName::try_from("_http._tcp.pingap.io backup").unwrap();
+        assert_eq!(
+            true,
+            to_backends(&srv, &targets, true)
+                .iter()
+                .all(is_backup_backend)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify_after_ttl() {
+        let notified = Arc::new(AtomicBool::new(false));
+        let value = notified.clone();
+        let srv = Srv::new(&["_http._tcp.pingap.io".to_string()], false)
+            .unwrap()
+            .with_update_callback(Some(Arc::new(move || {
+                value.store(true, Ordering::Relaxed);
+            })));
+        srv.cache.write().unwrap().insert(
+            "_http._tcp.pingap.io".to_string(),
+            SrvCache {
+                valid_until: Instant::now() + Duration::from_millis(100),
+                backends: vec![new_backend(
+                    "10.0.0.1:8080".parse().unwrap(),
+                    1,
+                    false,
+                )],
+            },
+        );
+        // the cache is valid, the upstream is notified after the ttl
+        let (backends, _) = srv.run_discover().await.unwrap();
+        assert_eq!(1, backends.len());
+        assert_eq!(false, notified.load(Ordering::Relaxed));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(true, notified.load(Ordering::Relaxed));
+    }
+}
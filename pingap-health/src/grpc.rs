@@ -18,6 +18,7 @@ use http::uri::InvalidUri;
 use http::Uri;
 use pingora::lb::health_check::{HealthCheck, HealthObserveCallback};
 use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr;
 use std::time::Duration;
 use tonic_health::{
     pb::{health_client::HealthClient, HealthCheckRequest},
@@ -72,15 +73,45 @@ impl GrpcHealthCheck {
 #[async_trait]
 impl HealthCheck for GrpcHealthCheck {
     async fn check(&self, target: &Backend) -> pingora::Result<()> {
-        let uri = format!("{}://{}", self.scheme, target.addr);
+        let uri = match &target.addr {
+            // the authority of unix domain socket is only a placeholder
+            #[cfg(unix)]
+            SocketAddr::Unix(_) => format!("{}://localhost", self.scheme),
+            _ => format!("{}://{}", self.scheme, target.addr),
+        };
 
-        let conn = tonic::transport::Endpoint::from_shared(uri)
+        let endpoint = tonic::transport::Endpoint::from_shared(uri)
             .map_err(|e| new_internal_error(500, e.to_string()))?
             .origin(self.origin.clone())
-            .connect_timeout(self.connection_timeout)
-            .connect()
-            .await
-            .map_err(|e| new_internal_error(500, e.to_string()))?;
+            .connect_timeout(self.connection_timeout);
+        let conn = match &target.addr {
+            #[cfg(unix)]
+            SocketAddr::Unix(addr) => {
+                let path = addr
+                    .as_pathname()
+                    .map(|path| path.to_path_buf())
+                    .ok_or_else(|| {
+                        new_internal_error(
+                            500,
+                            format!("unix socket {addr:?} has no path"),
+                        )
+                    })?;
+                endpoint
+                    .connect_with_connector(tower::service_fn(move |_: Uri| {
+                        let path = path.clone();
+                        async move {
+                            let stream =
+                                tokio::net::UnixStream::connect(path).await?;
+                            Ok::<_, std::io::Error>(
+                                hyper_util::rt::TokioIo::new(stream),
+                            )
+                        }
+                    }))
+                    .await
+            },
+            _ => endpoint.connect().await,
+        }
+        .map_err(|e| new_internal_error(500, e.to_string()))?;
         let resp = HealthClient::new(conn)
             .check(HealthCheckRequest {
                 service: self.service.clone(),
@@ -129,7 +160,7 @@ mod tests {
     fn test_grpc_health_check_conf() {
         let grpc_check: HealthCheckConf = "grpc://upstreamname/ping?connection_timeout=3s&success=2&failure=1&check_frequency=10s&from=nginx&reuse&tls&service=grpc".try_into().unwrap();
         assert_eq!(
-            r###"HealthCheckConf { schema: Grpc, host: "upstreamname", path: "/ping?from=nginx", connection_timeout: 3s, read_timeout: 3s, check_frequency: 10s, reuse_connection: true, consecutive_success: 2, consecutive_failure: 1, service: "grpc", tls: true, parallel_check: false }"###,
+            r###"HealthCheckConf { schema: Grpc, host: "upstreamname", path: "/ping?from=nginx", connection_timeout: 3s, read_timeout: 3s, check_frequency: 10s, reuse_connection: true, consecutive_success: 2, consecutive_failure: 1, service: "grpc", tls: true, parallel_check: false, method: "", expected_statuses: [], body_contains: "", body_regex: None, headers: [] }"###,
             format!("{grpc_check:?}")
         );
         let grpc_check = GrpcHealthCheck::new("", &grpc_check, None).unwrap();
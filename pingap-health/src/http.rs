@@ -13,37 +13,178 @@
 // limitations under the License.
 
 use super::{
-    update_peer_options, Error, HealthCheckSchema, DEFAULT_CHECK_FREQUENCY,
-    DEFAULT_CONNECTION_TIMEOUT, DEFAULT_CONSECUTIVE_FAILURE,
-    DEFAULT_CONSECUTIVE_SUCCESS, DEFAULT_READ_TIMEOUT, LOG_CATEGORY,
+    new_internal_error, update_peer_options, ClientTls, Error,
+    HealthCheckSchema, DEFAULT_CHECK_FREQUENCY, DEFAULT_CONNECTION_TIMEOUT,
+    DEFAULT_CONSECUTIVE_FAILURE, DEFAULT_CONSECUTIVE_SUCCESS,
+    DEFAULT_READ_TIMEOUT, LOG_CATEGORY,
 };
+use async_trait::async_trait;
 use humantime::parse_duration;
+use pingora::connectors::http::Connector as HttpConnector;
 use pingora::http::RequestHeader;
-use pingora::lb::health_check::{HealthObserveCallback, HttpHealthCheck};
+use pingora::lb::health_check::{HealthCheck, HealthObserveCallback};
+use pingora::lb::Backend;
+use pingora::upstreams::peer::{HttpPeer, Peer};
+use regex::Regex;
 use std::time::Duration;
 use tracing::error;
 use url::Url;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+// the max size of response body for matching
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Http health check, the response status should be one of the expected
+/// statuses(default 200), and the body should match the substring or
+/// regex if they are set.
+pub struct HttpHealthCheck {
+    /// Number of successful checks to flip from unhealthy to healthy.
+    pub consecutive_success: usize,
+    /// Number of failed checks to flip from healthy to unhealthy.
+    pub consecutive_failure: usize,
+    /// The peer template of backend, the address is replaced by the backend
+    pub peer_template: HttpPeer,
+    /// Whether the connection can be reused by the next check
+    pub reuse_connection: bool,
+    /// The request header of health check
+    pub req: RequestHeader,
+    /// A callback that is invoked when the `healthy` status changes for a [Backend].
+    pub health_changed_callback: Option<HealthObserveCallback>,
+    /// Timeout of reading the whole response
+    pub read_timeout: Duration,
+    /// The expected status ranges, e.g. [(200, 299)]
+    pub expected_statuses: Vec<(u16, u16)>,
+    /// The substring which the body should contain
+    pub body_contains: String,
+    /// The regex which the body should match
+    pub body_regex: Option<Regex>,
+    connector: HttpConnector,
+}
+
+impl HttpHealthCheck {
+    /// Validates the status of response
+    fn validate_status(&self, status: u16) -> pingora::Result<()> {
+        let matched = if self.expected_statuses.is_empty() {
+            status == 200
+        } else {
+            self.expected_statuses
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&status))
+        };
+        if !matched {
+            return Err(new_internal_error(
+                500,
+                format!("unexpected status {status}"),
+            ));
+        }
+        Ok(())
+    }
+    /// Validates the body of response
+    fn validate_body(&self, body: &[u8]) -> pingora::Result<()> {
+        let body = String::from_utf8_lossy(body);
+        if !self.body_contains.is_empty() && !body.contains(&self.body_contains)
+        {
+            return Err(new_internal_error(
+                500,
+                format!("body doesn't contain {:?}", self.body_contains),
+            ));
+        }
+        if let Some(re) = &self.body_regex {
+            if !re.is_match(&body) {
+                return Err(new_internal_error(
+                    500,
+                    format!("body doesn't match {:?}", re.as_str()),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HttpHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let mut peer = self.peer_template.clone();
+        peer._address = target.addr.clone();
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+        session
+            .write_request_header(Box::new(self.req.clone()))
+            .await?;
+        session.finish_request_body().await?;
+        let matches_body =
+            !self.body_contains.is_empty() || self.body_regex.is_some();
+        let read_response = async {
+            session.read_response_header().await?;
+            let status = session
+                .response_header()
+                .map(|header| header.status.as_u16())
+                .unwrap_or_default();
+            self.validate_status(status)?;
+            let mut body = vec![];
+            // the body should be read to the end for reusing connection
+            while let Some(chunk) = session.read_response_body().await? {
+                if matches_body && body.len() < MAX_BODY_SIZE {
+                    body.extend_from_slice(&chunk);
+                }
+            }
+            self.validate_body(&body)
+        };
+        tokio::time::timeout(self.read_timeout, read_response)
+            .await
+            .map_err(|_| {
+                new_internal_error(500, "read response timeout".to_string())
+            })??;
+        if self.reuse_connection {
+            let idle_timeout = peer.idle_timeout();
+            self.connector
+                .release_http_session(session, &peer, idle_timeout)
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn health_status_change(&self, target: &Backend, healthy: bool) {
+        if let Some(callback) = &self.health_changed_callback {
+            callback.observe(target, healthy).await;
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+}
+
 pub(crate) fn new_http_health_check(
     name: &str,
     conf: &HealthCheckConf,
+    client_tls: &ClientTls,
     health_changed_callback: Option<HealthObserveCallback>,
 ) -> HttpHealthCheck {
-    let mut check = HttpHealthCheck::new(
-        &conf.host,
-        conf.schema == HealthCheckSchema::Https,
-    );
-    check.peer_template.options =
-        update_peer_options(conf, check.peer_template.options.clone());
-
-    check.consecutive_success = conf.consecutive_success;
-    check.consecutive_failure = conf.consecutive_failure;
-    check.reuse_connection = conf.reuse_connection;
-    check.health_changed_callback = health_changed_callback;
-    // create http get request
-    match RequestHeader::build("GET", conf.path.as_bytes(), None) {
+    let tls = conf.schema == HealthCheckSchema::Https;
+    let sni = if tls {
+        conf.host.clone()
+    } else {
+        "".to_string()
+    };
+    let mut peer_template = HttpPeer::new("0.0.0.0:1", tls, sni);
+    peer_template.options =
+        update_peer_options(conf, peer_template.options.clone());
+    // the https health check uses the same client certificate as the proxy
+    peer_template.client_cert_key = client_tls.cert_key.clone();
+    peer_template.options.ca = client_tls.ca.clone();
+
+    let method = if conf.method.is_empty() {
+        "GET"
+    } else {
+        &conf.method
+    };
+    // create http request
+    let req = match RequestHeader::build(method, conf.path.as_bytes(), None) {
         Ok(mut req) => {
             // 忽略append header fail
             if let Err(e) = req.append_header("Host", &conf.host) {
@@ -55,16 +196,79 @@ pub(crate) fn new_http_health_check(
                     "http health check append host fail"
                 );
             }
-            check.req = req;
+            // the custom header replaces the same name header(e.g. Host)
+            for (key, value) in conf.headers.iter() {
+                if let Err(e) = req.insert_header(key.clone(), value) {
+                    error!(
+                        category = LOG_CATEGORY,
+                        name,
+                        error = e.to_string(),
+                        header = key,
+                        "http health check insert header fail"
+                    );
+                }
+            }
+            req
+        },
+        Err(e) => {
+            error!(
+                category = LOG_CATEGORY,
+                error = e.to_string(),
+                "http health check fail"
+            );
+            RequestHeader::build("GET", b"/", None)
+                .expect("build default request should not fail")
         },
-        Err(e) => error!(
-            category = LOG_CATEGORY,
-            error = e.to_string(),
-            "http health check fail"
-        ),
     };
 
-    check
+    HttpHealthCheck {
+        consecutive_success: conf.consecutive_success,
+        consecutive_failure: conf.consecutive_failure,
+        peer_template,
+        reuse_connection: conf.reuse_connection,
+        req,
+        health_changed_callback,
+        read_timeout: conf.read_timeout,
+        expected_statuses: conf.expected_statuses.clone(),
+        body_contains: conf.body_contains.clone(),
+        body_regex: conf.body_regex.clone(),
+        connector: HttpConnector::new(None),
+    }
+}
+
+/// Parses the expected statuses, e.g. "200,204,300-399" or "2xx"
+fn parse_status_ranges(value: &str) -> Result<Vec<(u16, u16)>> {
+    let new_error = || Error::Invalid {
+        message: format!("invalid status: {value}"),
+    };
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|status| (100..=599).contains(status))
+            .ok_or_else(new_error)
+    };
+    let mut ranges = vec![];
+    for item in value.split(',').map(|item| item.trim()) {
+        if item.is_empty() {
+            continue;
+        }
+        let range = if let Some(prefix) = item.strip_suffix("xx") {
+            let start = parse(&format!("{prefix}00"))?;
+            (start, start + 99)
+        } else if let Some((start, end)) = item.split_once('-') {
+            (parse(start)?, parse(end)?)
+        } else {
+            let status = parse(item)?;
+            (status, status)
+        };
+        if range.0 > range.1 {
+            return Err(new_error());
+        }
+        ranges.push(range);
+    }
+    Ok(ranges)
 }
 
 #[derive(Debug, Default)]
@@ -81,6 +285,11 @@ pub struct HealthCheckConf {
     pub service: String,
     pub tls: bool,
     pub parallel_check: bool,
+    pub method: String,
+    pub expected_statuses: Vec<(u16, u16)>,
+    pub body_contains: String,
+    pub body_regex: Option<Regex>,
+    pub headers: Vec<(String, String)>,
 }
 
 impl TryFrom<&str> for HealthCheckConf {
@@ -101,6 +310,11 @@ impl TryFrom<&str> for HealthCheckConf {
         let mut tls = false;
         let mut parallel_check = false;
         let mut service = "".to_string();
+        let mut method = "".to_string();
+        let mut expected_statuses = vec![];
+        let mut body_contains = "".to_string();
+        let mut body_regex = None;
+        let mut headers = vec![];
         // HttpHealthCheck
         for (key, value) in value.query_pairs().into_iter() {
             match key.as_ref() {
@@ -141,6 +355,38 @@ impl TryFrom<&str> for HealthCheckConf {
                 "parallel" => {
                     parallel_check = true;
                 },
+                "method" => {
+                    method = value.to_uppercase();
+                    http::Method::from_bytes(method.as_bytes()).map_err(
+                        |e| Error::Invalid {
+                            message: format!("invalid method {value}: {e}"),
+                        },
+                    )?;
+                },
+                "status" => {
+                    expected_statuses.extend(parse_status_ranges(&value)?);
+                },
+                "body" => {
+                    body_contains = value.to_string();
+                },
+                "body_regex" => {
+                    body_regex = Some(Regex::new(&value).map_err(|e| {
+                        Error::Invalid {
+                            message: format!("invalid body regex: {e}"),
+                        }
+                    })?);
+                },
+                "header" => {
+                    let Some((name, value)) = value.split_once(':') else {
+                        return Err(Error::Invalid {
+                            message: format!("invalid header: {value}"),
+                        });
+                    };
+                    headers.push((
+                        name.trim().to_string(),
+                        value.trim().to_string(),
+                    ));
+                },
                 _ => {
                     if value.is_empty() {
                         query_list.push(key.to_string());
@@ -177,6 +423,11 @@ impl TryFrom<&str> for HealthCheckConf {
             tls,
             service,
             parallel_check,
+            method,
+            expected_statuses,
+            body_contains,
+            body_regex,
+            headers,
         })
     }
 }
@@ -191,10 +442,11 @@ mod tests {
     fn test_http_health_check_conf() {
         let http_check: HealthCheckConf = "https://upstreamname/ping?connection_timeout=3s&read_timeout=1s&success=2&failure=1&check_frequency=10s&from=nginx&reuse&tls&service=grpc".try_into().unwrap();
         assert_eq!(
-            r###"HealthCheckConf { schema: Https, host: "upstreamname", path: "/ping?from=nginx", connection_timeout: 3s, read_timeout: 1s, check_frequency: 10s, reuse_connection: true, consecutive_success: 2, consecutive_failure: 1, service: "grpc", tls: true, parallel_check: false }"###,
+            r###"HealthCheckConf { schema: Https, host: "upstreamname", path: "/ping?from=nginx", connection_timeout: 3s, read_timeout: 1s, check_frequency: 10s, reuse_connection: true, consecutive_success: 2, consecutive_failure: 1, service: "grpc", tls: true, parallel_check: false, method: "", expected_statuses: [], body_contains: "", body_regex: None, headers: [] }"###,
             format!("{http_check:?}")
         );
-        let http_check = new_http_health_check("", &http_check, None);
+        let http_check =
+            new_http_health_check("", &http_check, &ClientTls::default(), None);
         assert_eq!(1, http_check.consecutive_failure);
         assert_eq!(2, http_check.consecutive_success);
         assert_eq!(true, http_check.reuse_connection);
@@ -207,4 +459,146 @@ mod tests {
             http_check.peer_template.options.read_timeout.unwrap()
         );
     }
+
+    #[test]
+    fn test_parse_status_ranges() {
+        assert_eq!(
+            vec![(200, 200), (204, 204), (300, 399), (200, 299)],
+            parse_status_ranges("200, 204,300-399,2xx").unwrap()
+        );
+        assert_eq!(
+            "Invalid health check: invalid status: 600",
+            parse_status_ranges("600").err().unwrap().to_string()
+        );
+        assert_eq!(
+            "Invalid health check: invalid status: 399-300",
+            parse_status_ranges("399-300").err().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_http_health_check_matcher() {
+        let conf: HealthCheckConf = r#"http://upstreamname/health?method=head&status=200,3xx&body="status":"UP"&body_regex=^\{.*\}$&header=Host:pingap.io&header=Authorization:Bearer%20abc"#
+            .try_into()
+            .unwrap();
+        assert_eq!("HEAD", conf.method);
+        assert_eq!(vec![(200, 200), (300, 399)], conf.expected_statuses);
+        assert_eq!("/health", conf.path);
+        assert_eq!(
+            vec![
+                ("Host".to_string(), "pingap.io".to_string()),
+                ("Authorization".to_string(), "Bearer abc".to_string())
+            ],
+            conf.headers
+        );
+        let check =
+            new_http_health_check("", &conf, &ClientTls::default(), None);
+        assert_eq!("HEAD", check.req.method.as_str());
+        assert_eq!(
+            "pingap.io",
+            check.req.headers.get("Host").unwrap().to_str().unwrap()
+        );
+        assert_eq!(
+            "Bearer abc",
+            check
+                .req
+                .headers
+                .get("Authorization")
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+
+        assert_eq!(true, check.validate_status(200).is_ok());
+        assert_eq!(true, check.validate_status(302).is_ok());
+        assert_eq!(true, check.validate_status(204).is_err());
+        assert_eq!(true, check.validate_body(br#"{"status":"UP"}"#).is_ok());
+        assert_eq!(true, check.validate_body(br#"{"status":"DOWN"}"#).is_err());
+        assert_eq!(true, check.validate_body(br#""status":"UP""#).is_err());
+
+        assert_eq!(
+            true,
+            HealthCheckConf::try_from("http://upstreamname/health?method=a b")
+                .is_err()
+        );
+        assert_eq!(
+            "Invalid health check: invalid header: abc",
+            HealthCheckConf::try_from("http://upstreamname/health?header=abc")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let size = stream.read(&mut buf).await.unwrap_or_default();
+                    let req = String::from_utf8_lossy(&buf[..size]).to_string();
+                    let body = if req.contains("token: pingap") {
+                        r#"{"status":"UP"}"#
+                    } else {
+                        r#"{"status":"DOWN"}"#
+                    };
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(resp.as_bytes()).await;
+                });
+            }
+        });
+        let backend = Backend::new(&addr.to_string()).unwrap();
+
+        let conf: HealthCheckConf =
+            r#"http://upstreamname/ping?body="status":"UP"&header=token:pingap"#
+                .try_into()
+                .unwrap();
+        let check =
+            new_http_health_check("", &conf, &ClientTls::default(), None);
+        assert_eq!(true, check.check(&backend).await.is_ok());
+
+        let conf: HealthCheckConf =
+            r#"http://upstreamname/ping?body="status":"UP""#
+                .try_into()
+                .unwrap();
+        let check =
+            new_http_health_check("", &conf, &ClientTls::default(), None);
+        assert_eq!(
+            true,
+            check
+                .check(&backend)
+                .await
+                .err()
+                .unwrap()
+                .to_string()
+                .contains(r#"body doesn't contain "\"status\":\"UP\"""#)
+        );
+
+        let conf: HealthCheckConf =
+            "http://upstreamname/ping?status=204".try_into().unwrap();
+        let check =
+            new_http_health_check("", &conf, &ClientTls::default(), None);
+        assert_eq!(
+            true,
+            check
+                .check(&backend)
+                .await
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("unexpected status 200")
+        );
+    }
 }
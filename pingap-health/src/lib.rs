@@ -12,12 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use async_trait::async_trait;
 use humantime::format_duration;
+use once_cell::sync::Lazy;
 use pingora::lb::health_check::{
     HealthCheck, HealthObserveCallback, TcpHealthCheck,
 };
+use pingora::lb::Backend;
+use pingora::protocols::tls::CaType;
 use pingora::upstreams::peer::PeerOptions;
+use pingora::utils::tls::CertKey;
 use snafu::Snafu;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use strum::EnumString;
 use tracing::info;
@@ -25,8 +32,10 @@ static LOG_CATEGORY: &str = "health";
 
 mod grpc;
 mod http;
+mod udp;
 pub use grpc::GrpcHealthCheck;
-pub use http::HealthCheckConf;
+pub use http::{HealthCheckConf, HttpHealthCheck};
+pub use udp::UdpHealthCheck;
 
 /// Creates a new internal error
 fn new_internal_error(status: u16, message: String) -> pingora::BError {
@@ -37,6 +46,15 @@ fn new_internal_error(status: u16, message: String) -> pingora::BError {
     )
 }
 
+/// Client certificate and trusted CA of the tls connection to upstream
+#[derive(Clone, Default)]
+pub struct ClientTls {
+    /// Client certificate and key of mTLS
+    pub cert_key: Option<Arc<CertKey>>,
+    /// Trusted CA certificates to verify upstream
+    pub ca: Option<Arc<CaType>>,
+}
+
 // Add constants for default values
 const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
 const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(3);
@@ -53,6 +71,8 @@ pub enum Error {
     },
     #[snafu(display("Invalid health check schema: {schema}, {message}"))]
     InvalidSchema { schema: String, message: String },
+    #[snafu(display("Invalid health check: {message}"))]
+    Invalid { message: String },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -88,9 +108,64 @@ fn new_tcp_health_check(
     check
 }
 
+// The failure reasons of the latest health check, the key is
+// upstream name and the value is the map of backend address and reason
+static HEALTH_CHECK_FAILURES: Lazy<
+    RwLock<HashMap<String, HashMap<String, String>>>,
+> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Returns the failure reasons of the upstream's backends,
+/// the key is the backend address.
+pub fn get_health_check_failures(name: &str) -> HashMap<String, String> {
+    HEALTH_CHECK_FAILURES
+        .read()
+        .ok()
+        .and_then(|failures| failures.get(name).cloned())
+        .unwrap_or_default()
+}
+
+/// Health check which records the failure reason of each backend
+struct RecordedHealthCheck {
+    name: String,
+    check: Box<dyn HealthCheck + Send + Sync + 'static>,
+}
+
+#[async_trait]
+impl HealthCheck for RecordedHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let result = self.check.check(target).await;
+        if let Ok(mut failures) = HEALTH_CHECK_FAILURES.write() {
+            let addr = target.addr.to_string();
+            match &result {
+                Ok(()) => {
+                    if let Some(reasons) = failures.get_mut(&self.name) {
+                        reasons.remove(&addr);
+                    }
+                },
+                Err(e) => {
+                    failures
+                        .entry(self.name.clone())
+                        .or_default()
+                        .insert(addr, e.to_string().trim().to_string());
+                },
+            }
+        }
+        result
+    }
+
+    async fn health_status_change(&self, target: &Backend, healthy: bool) {
+        self.check.health_status_change(target, healthy).await;
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.check.health_threshold(success)
+    }
+}
+
 pub fn new_health_check(
     name: &str,
     health_check: &str,
+    client_tls: &ClientTls,
     health_changed_callback: Option<HealthObserveCallback>,
 ) -> Result<(
     HealthCheckConf,
@@ -139,6 +214,7 @@ pub fn new_health_check(
                 Box::new(http::new_http_health_check(
                     name,
                     &health_check_conf,
+                    client_tls,
                     health_changed_callback,
                 ))
             },
@@ -150,6 +226,11 @@ pub fn new_health_check(
                 )?;
                 Box::new(check)
             },
+            HealthCheckSchema::Udp => Box::new(UdpHealthCheck::new(
+                name,
+                &health_check_conf,
+                health_changed_callback,
+            )),
             _ => Box::new(new_tcp_health_check(
                 name,
                 &health_check_conf,
@@ -157,6 +238,14 @@ pub fn new_health_check(
             )),
         }
     };
+    // the failures of previous health check are cleared
+    if let Ok(mut failures) = HEALTH_CHECK_FAILURES.write() {
+        failures.remove(name);
+    }
+    let hc = Box::new(RecordedHealthCheck {
+        name: name.to_string(),
+        check: hc,
+    });
     Ok((health_check_conf, hc))
 }
 
@@ -168,6 +257,7 @@ pub enum HealthCheckSchema {
     Http,
     Https,
     Grpc,
+    Udp,
 }
 
 #[cfg(test)]
@@ -183,7 +273,7 @@ mod tests {
                 .try_into()
                 .unwrap();
         assert_eq!(
-            r###"HealthCheckConf { schema: Tcp, host: "upstreamname", path: "", connection_timeout: 3s, read_timeout: 3s, check_frequency: 10s, reuse_connection: false, consecutive_success: 2, consecutive_failure: 1, service: "", tls: false, parallel_check: false }"###,
+            r###"HealthCheckConf { schema: Tcp, host: "upstreamname", path: "", connection_timeout: 3s, read_timeout: 3s, check_frequency: 10s, reuse_connection: false, consecutive_success: 2, consecutive_failure: 1, service: "", tls: false, parallel_check: false, method: "", expected_statuses: [], body_contains: "", body_regex: None, headers: [] }"###,
             format!("{tcp_check:?}")
         );
         let tcp_check = new_tcp_health_check("", &tcp_check, None);
@@ -196,10 +286,35 @@ mod tests {
     }
     #[test]
     fn test_new_health_check() {
-        let (conf, _) = new_health_check("upstreamname", "https://upstreamname/ping?connection_timeout=3s&read_timeout=1s&success=2&failure=1&check_frequency=10s&from=nginx&reuse", None).unwrap();
+        let (conf, _) = new_health_check("upstreamname", "https://upstreamname/ping?connection_timeout=3s&read_timeout=1s&success=2&failure=1&check_frequency=10s&from=nginx&reuse", &ClientTls::default(), None).unwrap();
         assert_eq!(Duration::from_secs(10), conf.check_frequency);
     }
 
+    #[tokio::test]
+    async fn test_health_check_failures() {
+        let (_, check) = new_health_check(
+            "failures",
+            "tcp://upstreamname?connection_timeout=100ms",
+            &ClientTls::default(),
+            None,
+        )
+        .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let backend = Backend::new(&addr).unwrap();
+        assert_eq!(true, check.check(&backend).await.is_err());
+        assert_eq!(
+            true,
+            get_health_check_failures("failures").contains_key(&addr)
+        );
+
+        let listener = std::net::TcpListener::bind(&addr).unwrap();
+        assert_eq!(true, check.check(&backend).await.is_ok());
+        assert_eq!(true, get_health_check_failures("failures").is_empty());
+        drop(listener);
+    }
+
     #[test]
     fn test_new_internal_error() {
         let err = new_internal_error(500, "test".to_string());
@@ -0,0 +1,138 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{new_internal_error, HealthCheckConf};
+use async_trait::async_trait;
+use pingora::lb::health_check::{HealthCheck, HealthObserveCallback};
+use pingora::lb::Backend;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// Health check of udp backend, an empty datagram is sent to the backend
+/// and the check fails only if the port is unreachable(icmp), because
+/// most udp services(e.g. syslog) don't reply to the unknown datagram.
+pub struct UdpHealthCheck {
+    /// Number of successful checks to flip from unhealthy to healthy.
+    pub consecutive_success: usize,
+    /// Number of failed checks to flip from healthy to unhealthy.
+    pub consecutive_failure: usize,
+    /// A callback that is invoked when the `healthy` status changes for a [Backend].
+    pub health_changed_callback: Option<HealthObserveCallback>,
+    /// Timeout of waiting for the unreachable error
+    pub read_timeout: Duration,
+}
+
+impl UdpHealthCheck {
+    pub fn new(
+        _name: &str,
+        conf: &HealthCheckConf,
+        health_changed_callback: Option<HealthObserveCallback>,
+    ) -> Self {
+        Self {
+            consecutive_success: conf.consecutive_success,
+            consecutive_failure: conf.consecutive_failure,
+            health_changed_callback,
+            read_timeout: conf.read_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for UdpHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let Some(addr) = target.addr.as_inet() else {
+            return Err(new_internal_error(
+                500,
+                format!("{} is not udp address", target.addr),
+            ));
+        };
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| new_internal_error(500, e.to_string()))?;
+        // connected socket receives the icmp port unreachable error
+        socket
+            .connect(addr)
+            .await
+            .map_err(|e| new_internal_error(500, e.to_string()))?;
+        socket
+            .send(&[])
+            .await
+            .map_err(|e| new_internal_error(500, e.to_string()))?;
+        // the unreachable error is only notified as error readiness
+        let ready = tokio::time::timeout(
+            self.read_timeout,
+            socket.ready(Interest::READABLE | Interest::ERROR),
+        )
+        .await;
+        // no reply is fine
+        let Ok(ready) = ready else {
+            return Ok(());
+        };
+        ready.map_err(|e| new_internal_error(500, e.to_string()))?;
+        match socket.take_error() {
+            Ok(Some(e)) if e.kind() == ErrorKind::ConnectionRefused => {
+                Err(new_internal_error(500, format!("{addr} is unreachable")))
+            },
+            Ok(Some(e)) | Err(e) => Err(new_internal_error(500, e.to_string())),
+            Ok(None) => Ok(()),
+        }
+    }
+
+    async fn health_status_change(&self, target: &Backend, healthy: bool) {
+        if let Some(callback) = &self.health_changed_callback {
+            callback.observe(target, healthy).await;
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_udp_health_check() {
+        let conf: HealthCheckConf =
+            "udp://upstreamname?read_timeout=500ms&success=2&failure=1"
+                .try_into()
+                .unwrap();
+        let check = UdpHealthCheck::new("", &conf, None);
+        assert_eq!(2, check.health_threshold(true));
+        assert_eq!(1, check.health_threshold(false));
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let backend = Backend::new(&addr.to_string()).unwrap();
+        assert_eq!(true, check.check(&backend).await.is_ok());
+
+        // the port is closed
+        drop(server);
+        assert_eq!(true, check.check(&backend).await.is_err());
+    }
+}
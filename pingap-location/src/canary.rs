@@ -0,0 +1,266 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Error;
+use pingora::http::RequestHeader;
+use std::sync::Mutex;
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Source of the value to match the upstream rule
+#[derive(Debug)]
+enum RuleSource {
+    Header(String),
+    Cookie(String),
+    Query(String),
+}
+
+// Rule to select the upstream, e.g. "header:X-Canary=1 api-v2"
+#[derive(Debug)]
+struct UpstreamRule {
+    source: RuleSource,
+    // the rule matches if the value exists when it's none
+    value: Option<String>,
+    upstream: String,
+}
+
+impl UpstreamRule {
+    // Parses the rule from "<header|cookie|query>:<name>[=<value>] <upstream>"
+    fn new(rule: &str) -> Result<Self> {
+        let invalid = || Error::Invalid {
+            message: format!("upstream rule({rule}) is invalid"),
+        };
+        let mut arr = rule.split_whitespace();
+        let (Some(matcher), Some(upstream), None) =
+            (arr.next(), arr.next(), arr.next())
+        else {
+            return Err(invalid());
+        };
+        let (category, matcher) =
+            matcher.split_once(':').ok_or_else(invalid)?;
+        let (name, value) = match matcher.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (matcher, None),
+        };
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let source = match category {
+            "header" => RuleSource::Header(name.to_string()),
+            "cookie" => RuleSource::Cookie(name.to_string()),
+            "query" => RuleSource::Query(name.to_string()),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            source,
+            value,
+            upstream: upstream.to_string(),
+        })
+    }
+    fn matched(&self, req_header: &RequestHeader) -> bool {
+        let value = match &self.source {
+            RuleSource::Header(name) => {
+                pingap_core::get_req_header_value(req_header, name)
+            },
+            RuleSource::Cookie(name) => {
+                pingap_core::get_cookie_value(req_header, name)
+            },
+            RuleSource::Query(name) => {
+                pingap_core::get_query_value(req_header, name)
+            },
+        };
+        match (&self.value, value) {
+            (Some(expected), Some(value)) => expected == value,
+            (None, Some(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Upstreams of the location for canary release, the request is sent to
+/// the upstream of the first matched rule, otherwise the upstream is
+/// selected by smooth weighted round-robin.
+#[derive(Debug)]
+pub struct CanaryUpstreams {
+    upstreams: Vec<(String, i64)>,
+    total_weight: i64,
+    current_weights: Mutex<Vec<i64>>,
+    rules: Vec<UpstreamRule>,
+}
+
+impl CanaryUpstreams {
+    /// Creates the canary upstreams from "<upstream> [weight]" list and
+    /// "<header|cookie|query>:<name>[=<value>] <upstream>" rules,
+    /// returns None if neither upstreams nor rules are configured.
+    pub fn new(upstreams: &[String], rules: &[String]) -> Result<Option<Self>> {
+        if upstreams.is_empty() && rules.is_empty() {
+            return Ok(None);
+        }
+        let mut weighted_upstreams = Vec::with_capacity(upstreams.len());
+        for item in upstreams.iter() {
+            let mut arr = item.split_whitespace();
+            let Some(name) = arr.next() else {
+                continue;
+            };
+            let weight = match arr.next() {
+                Some(value) => {
+                    let value = value
+                        .trim_start_matches("weight")
+                        .trim_start_matches('=');
+                    let value = if value.is_empty() {
+                        arr.next().unwrap_or_default()
+                    } else {
+                        value
+                    };
+                    value.parse::<i64>().map_err(|_| Error::Invalid {
+                        message: format!(
+                            "weight of upstream({item}) is invalid"
+                        ),
+                    })?
+                },
+                None => 1,
+            };
+            weighted_upstreams.push((name.to_string(), weight.max(0)));
+        }
+        let total_weight = weighted_upstreams.iter().map(|(_, w)| w).sum();
+        if !weighted_upstreams.is_empty() && total_weight == 0 {
+            return Err(Error::Invalid {
+                message: "total weight of upstreams should be greater than 0"
+                    .to_string(),
+            });
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| UpstreamRule::new(rule))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Self {
+            current_weights: Mutex::new(vec![0; weighted_upstreams.len()]),
+            upstreams: weighted_upstreams,
+            total_weight,
+            rules,
+        }))
+    }
+    /// Selects the upstream of the request,
+    /// returns None if no rule matches and no weighted upstream.
+    pub fn select(&self, req_header: &RequestHeader) -> Option<&str> {
+        if let Some(rule) =
+            self.rules.iter().find(|rule| rule.matched(req_header))
+        {
+            return Some(&rule.upstream);
+        }
+        let first = &self.upstreams.first()?.0;
+        let Ok(mut current_weights) = self.current_weights.lock() else {
+            return Some(first);
+        };
+        let mut index = 0;
+        for (i, (_, weight)) in self.upstreams.iter().enumerate() {
+            current_weights[i] += weight;
+            if current_weights[i] > current_weights[index] {
+                index = i;
+            }
+        }
+        current_weights[index] -= self.total_weight;
+        Some(&self.upstreams[index].0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_upstream_rule() {
+        let mut req_header =
+            RequestHeader::build("GET", b"/users?canary=1", None).unwrap();
+        req_header.insert_header("X-Canary", "1").unwrap();
+        req_header.insert_header("Cookie", "uid=1; beta=2").unwrap();
+
+        for (rule, matched) in [
+            ("header:X-Canary=1 api-v2", true),
+            ("header:X-Canary=2 api-v2", false),
+            ("header:X-Canary api-v2", true),
+            ("header:X-Beta api-v2", false),
+            ("cookie:beta=2 api-v2", true),
+            ("cookie:beta=1 api-v2", false),
+            ("query:canary=1 api-v2", true),
+            ("query:canary api-v2", true),
+            ("query:beta api-v2", false),
+        ] {
+            let rule = UpstreamRule::new(rule).unwrap();
+            assert_eq!(matched, rule.matched(&req_header), "{rule:?}");
+            assert_eq!("api-v2", rule.upstream);
+        }
+
+        for rule in ["header:X-Canary=1", "ip:1.1.1.1 api-v2", "header: api-v2"]
+        {
+            assert_eq!(
+                format!("Invalid error upstream rule({rule}) is invalid"),
+                UpstreamRule::new(rule).unwrap_err().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_canary_upstreams() {
+        assert_eq!(true, CanaryUpstreams::new(&[], &[]).unwrap().is_none());
+        assert_eq!(
+            "Invalid error weight of upstream(api-v1 a) is invalid",
+            CanaryUpstreams::new(&["api-v1 a".to_string()], &[])
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Invalid error total weight of upstreams should be greater than 0",
+            CanaryUpstreams::new(&["api-v1 0".to_string()], &[])
+                .unwrap_err()
+                .to_string()
+        );
+
+        let upstreams = CanaryUpstreams::new(
+            &["api-v1 weight=3".to_string(), "api-v2 1".to_string()],
+            &["header:X-Canary=1 api-v2".to_string()],
+        )
+        .unwrap()
+        .unwrap();
+        let req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        let selected: Vec<&str> = (0..8)
+            .map(|_| upstreams.select(&req_header).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "api-v1", "api-v1", "api-v2", "api-v1", "api-v1", "api-v1",
+                "api-v2", "api-v1"
+            ],
+            selected
+        );
+
+        let mut req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        req_header.insert_header("X-Canary", "1").unwrap();
+        for _ in 0..4 {
+            assert_eq!(Some("api-v2"), upstreams.select(&req_header));
+        }
+
+        // only rules
+        let upstreams = CanaryUpstreams::new(
+            &[],
+            &["header:X-Canary=1 api-v2".to_string()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(Some("api-v2"), upstreams.select(&req_header));
+        let req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        assert_eq!(None, upstreams.select(&req_header));
+    }
+}
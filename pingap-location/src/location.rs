@@ -12,12 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::canary::CanaryUpstreams;
 use super::regex::RegexCapture;
 use ahash::AHashMap;
 use arc_swap::ArcSwap;
 use once_cell::sync::Lazy;
 use pingap_config::LocationConf;
-use pingap_core::{convert_headers, HttpHeader};
+use pingap_core::{
+    convert_headers, HedgePolicy, HttpHeader, RetryPolicy,
+    DEFAULT_HEDGE_BUDGET, DEFAULT_RETRY_BUDGET,
+};
 use pingora::http::RequestHeader;
 use regex::Regex;
 use snafu::{ResultExt, Snafu};
@@ -183,6 +187,10 @@ pub struct Location {
     /// Target upstream server where requests will be proxied to
     pub upstream: String,
 
+    /// Weighted upstreams and rules for canary release,
+    /// it takes precedence over the upstream
+    canary: Option<CanaryUpstreams>,
+
     /// Original path pattern string used for matching requests
     path: String,
 
@@ -232,6 +240,25 @@ pub struct Location {
     /// Whether to automatically add standard reverse proxy headers like:
     /// X-Forwarded-For, X-Real-IP, X-Forwarded-Proto, etc.
     pub enable_reverse_proxy_headers: bool,
+
+    /// Retry policy of the request to upstream,
+    /// it takes precedence over the retry policy of upstream
+    pub retry: Option<Arc<RetryPolicy>>,
+
+    /// Hedge policy of the idempotent request without body
+    pub hedge: Option<Arc<HedgePolicy>>,
+
+    /// Upstream to send a copy of the requests to
+    mirror_upstream: Option<String>,
+
+    /// Percentage of the requests to mirror
+    mirror_percentage: u64,
+
+    /// Maximum size of the request body buffered for mirroring
+    pub mirror_body_limit: usize,
+
+    /// Number of the requests sampled for mirroring
+    mirror_count: AtomicU64,
 }
 
 /// Formats a vector of header strings into internal HttpHeader representation.
@@ -305,6 +332,29 @@ impl Location {
 
         let path = conf.path.clone().unwrap_or_default();
 
+        let canary = CanaryUpstreams::new(
+            &conf.upstreams.clone().unwrap_or_default(),
+            &conf.upstream_rules.clone().unwrap_or_default(),
+        )?;
+
+        let retry =
+            conf.retry_attempts.filter(|value| *value > 0).map(|value| {
+                Arc::new(RetryPolicy::new(
+                    value,
+                    &conf.retry_on.clone().unwrap_or_default(),
+                    conf.retry_idempotent_only.unwrap_or(true),
+                    conf.retry_budget.unwrap_or(DEFAULT_RETRY_BUDGET),
+                ))
+            });
+
+        let hedge = HedgePolicy::new(
+            conf.hedge_delay,
+            conf.hedge_percentile,
+            conf.hedge_max_attempts.unwrap_or(1),
+            conf.hedge_budget.unwrap_or(DEFAULT_HEDGE_BUDGET),
+        )
+        .map(Arc::new);
+
         let location = Location {
             name: name.to_string(),
             key,
@@ -312,6 +362,7 @@ impl Location {
             path,
             hosts,
             upstream,
+            canary,
             reg_rewrite,
             plugins: conf.plugins.clone(),
             accepted: AtomicU64::new(0),
@@ -327,6 +378,19 @@ impl Location {
             enable_reverse_proxy_headers: conf
                 .enable_reverse_proxy_headers
                 .unwrap_or_default(),
+            retry,
+            hedge,
+            mirror_upstream: conf
+                .mirror_upstream
+                .clone()
+                .filter(|value| !value.is_empty()),
+            mirror_percentage: conf.mirror_percentage.unwrap_or(100).min(100)
+                as u64,
+            mirror_body_limit: conf
+                .mirror_body_limit
+                .map(|value| value.as_u64() as usize)
+                .unwrap_or(64 * 1000),
+            mirror_count: AtomicU64::new(0),
         };
         debug!(
             category = LOG_CATEGORY,
@@ -337,6 +401,31 @@ impl Location {
         Ok(location)
     }
 
+    /// Returns the upstream of the request, it's selected from the weighted
+    /// upstreams or rules for canary release if configured,
+    /// otherwise it's the upstream of location.
+    pub fn select_upstream(&self, req_header: &RequestHeader) -> &str {
+        self.canary
+            .as_ref()
+            .and_then(|canary| canary.select(req_header))
+            .unwrap_or(&self.upstream)
+    }
+
+    /// Returns the mirror upstream if the request should be mirrored,
+    /// the requests are sampled evenly by the mirror percentage.
+    pub fn sample_mirror(&self) -> Option<&str> {
+        let upstream = self.mirror_upstream.as_deref()?;
+        let percentage = self.mirror_percentage;
+        if percentage < 100 {
+            let count = self.mirror_count.fetch_add(1, Ordering::Relaxed);
+            // mirror the request when the sampled count reaches the next integer
+            if (count + 1) * percentage / 100 == count * percentage / 100 {
+                return None;
+            }
+        }
+        Some(upstream)
+    }
+
     /// Returns whether gRPC-Web protocol support is enabled for this location
     /// When enabled, the proxy will handle gRPC-Web requests and convert them to regular gRPC
     #[inline]
@@ -868,4 +957,80 @@ mod tests {
                 .to_string()
         );
     }
+
+    #[test]
+    fn test_select_upstream() {
+        let req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        let lo = Location::new(
+            "lo",
+            &LocationConf {
+                upstream: Some("charts".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!("charts", lo.select_upstream(&req_header));
+
+        let lo = Location::new(
+            "lo",
+            &LocationConf {
+                upstreams: Some(vec![
+                    "api-v1 95".to_string(),
+                    "api-v2 5".to_string(),
+                ]),
+                upstream_rules: Some(vec!["query:canary api-v2".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let count = (0..100)
+            .filter(|_| lo.select_upstream(&req_header) == "api-v2")
+            .count();
+        assert_eq!(5, count);
+        let req_header =
+            RequestHeader::build("GET", b"/?canary=1", None).unwrap();
+        assert_eq!("api-v2", lo.select_upstream(&req_header));
+
+        let result = Location::new(
+            "lo",
+            &LocationConf {
+                upstreams: Some(vec!["api-v1".to_string()]),
+                upstream_rules: Some(vec!["api-v2".to_string()]),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            "Invalid error upstream rule(api-v2) is invalid",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_sample_mirror() {
+        let lo = Location::new("lo", &LocationConf::default()).unwrap();
+        assert_eq!(true, lo.sample_mirror().is_none());
+
+        let lo = Location::new(
+            "lo",
+            &LocationConf {
+                mirror_upstream: Some("charts".to_string()),
+                mirror_percentage: Some(10),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(64_000, lo.mirror_body_limit);
+        let count = (0..100).filter(|_| lo.sample_mirror().is_some()).count();
+        assert_eq!(10, count);
+
+        let lo = Location::new(
+            "lo",
+            &LocationConf {
+                mirror_upstream: Some("charts".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(Some("charts"), lo.sample_mirror());
+    }
 }
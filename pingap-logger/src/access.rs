@@ -119,6 +119,7 @@ static COMMON: &str =
     r###"{remote} "{method} {uri} {proto}" {status} {size_human}""###;
 static SHORT: &str = r###"{remote} {method} {uri} {proto} {status} {size_human} - {latency}ms"###;
 static TINY: &str = r###"{method} {uri} {status} {size_human} - {latency}ms"###;
+static STREAM: &str = r###"{remote} {:tls_server_name} {:upstream_addr} {payload_size_human} {size_human} - {latency}ms"###;
 
 impl From<&str> for Parser {
     fn from(value: &str) -> Self {
@@ -127,6 +128,7 @@ impl From<&str> for Parser {
             "common" => COMMON,
             "short" => SHORT,
             "tiny" => TINY,
+            "stream" => STREAM,
             _ => value,
         };
         let reg = Regex::new(r"(\{[a-zA-Z_<>\-~:$]+*\})").unwrap();
@@ -473,6 +475,95 @@ impl Parser {
             };
         }
 
+        std::string::String::from_utf8(buf.into()).unwrap_or_default()
+    }
+    // Formats a log entry of the stream connection, the tags of http request
+    // are ignored, the payload size is received from client and the size is sent to client.
+    pub fn format_stream(&self, ctx: &Ctx, sent: usize) -> String {
+        let mut buf = BytesMut::with_capacity(self.capacity);
+        let now = if self.needs_timestamp {
+            Some(Utc::now())
+        } else {
+            None
+        };
+        let now_ms = now.map(|n| n.timestamp_millis() as u64);
+
+        for tag in self.tags.iter() {
+            match tag.category {
+                TagCategory::Fill => {
+                    if let Some(data) = &tag.data {
+                        buf.extend_from_slice(data.as_bytes());
+                    }
+                },
+                TagCategory::Remote => {
+                    if let Some(addr) = &ctx.remote_addr {
+                        buf.extend_from_slice(addr.as_bytes());
+                    }
+                },
+                TagCategory::ClientIp => {
+                    if let Some(client_ip) = &ctx.client_ip {
+                        buf.extend_from_slice(client_ip.as_bytes());
+                    }
+                },
+                TagCategory::When => {
+                    if let Some(now) = &now {
+                        buf.extend_from_slice(
+                            now.with_timezone(&Local).to_rfc3339().as_bytes(),
+                        );
+                    }
+                },
+                TagCategory::WhenUtcIso => {
+                    if let Some(now) = &now {
+                        buf.extend_from_slice(now.to_rfc3339().as_bytes());
+                    }
+                },
+                TagCategory::WhenUnix => {
+                    if let Some(now_ms) = now_ms {
+                        buf.extend_from_slice(
+                            itoa::Buffer::new().format(now_ms).as_bytes(),
+                        );
+                    }
+                },
+                TagCategory::Size => {
+                    buf.extend_from_slice(
+                        itoa::Buffer::new().format(sent).as_bytes(),
+                    );
+                },
+                TagCategory::SizeHuman => {
+                    buf = format_byte_size(buf, sent);
+                },
+                TagCategory::Latency => {
+                    if let Some(now_ms) = now_ms {
+                        let ms = now_ms - ctx.created_at;
+                        buf.extend_from_slice(
+                            itoa::Buffer::new().format(ms).as_bytes(),
+                        );
+                    }
+                },
+                TagCategory::LatencyHuman => {
+                    if let Some(now_ms) = now_ms {
+                        let ms = now_ms - ctx.created_at;
+                        buf = format_duration(buf, ms);
+                    }
+                },
+                TagCategory::PayloadSize => {
+                    buf.extend_from_slice(
+                        itoa::Buffer::new().format(ctx.payload_size).as_bytes(),
+                    );
+                },
+                TagCategory::PayloadSizeHuman => {
+                    buf = format_byte_size(buf, ctx.payload_size);
+                },
+                TagCategory::Context => {
+                    if let Some(key) = &tag.data {
+                        buf = ctx.append_value(buf, key.as_str());
+                    }
+                },
+                // the tags of http request and response
+                _ => {},
+            };
+        }
+
         std::string::String::from_utf8(buf.into()).unwrap_or_default()
     }
 }
@@ -732,4 +823,31 @@ mod tests {
         let log = p.format(&session, &ctx);
         assert_eq!(true, log.len() == 13);
     }
+
+    #[test]
+    fn test_stream_logger() {
+        let p: Parser = "stream".into();
+        let ctx = Ctx {
+            remote_addr: Some("10.1.1.1".to_string()),
+            tls_server_name: Some("pg.example.com".to_string()),
+            upstream_address: "192.168.1.1:5432".to_string(),
+            payload_size: 1024,
+            ..Default::default()
+        };
+        let log = p.format_stream(&ctx, 2048);
+        assert_eq!(
+            true,
+            log.starts_with(
+                "10.1.1.1 pg.example.com 192.168.1.1:5432 1KB 2KB - "
+            )
+        );
+
+        let p: Parser = "{method} {client_ip} {size} {payload_size}".into();
+        let ctx = Ctx {
+            client_ip: Some("10.1.1.1".to_string()),
+            payload_size: 10,
+            ..Default::default()
+        };
+        assert_eq!(" 10.1.1.1 20 10", p.format_stream(&ctx, 20));
+    }
 }
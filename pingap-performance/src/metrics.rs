@@ -18,6 +18,7 @@ use pingap_core::SimpleServiceTaskFuture;
 use pingap_location::get_locations_processing;
 use pingap_upstream::{
     get_upstream_healthy_status, get_upstreams_processing_connected,
+    CircuitBreakerState,
 };
 use tracing::info;
 
@@ -96,7 +97,19 @@ pub fn new_performance_metrics_log_service() -> (String, SimpleServiceTaskFuture
                 let upstreams_healthy_status = get_upstream_healthy_status()
                     .iter()
                     .map(|(name, status)| {
-                        format!("{name}:{}/{}", status.healthy, status.total)
+                        let value = format!(
+                            "{name}:{}/{}",
+                            status.healthy, status.total
+                        );
+                        // append the circuit breaker state if it's not closed
+                        match status.circuit_breaker {
+                            Some(state)
+                                if state != CircuitBreakerState::Closed =>
+                            {
+                                format!("{value}({state})")
+                            },
+                            _ => value,
+                        }
                     })
                     .collect::<Vec<String>>()
                     .join(", ");
@@ -18,6 +18,7 @@ use pingap_cache::{CACHE_READING_TIME, CACHE_WRITING_TIME};
 use pingap_core::Error as ServiceError;
 use pingap_core::SimpleServiceTaskFuture;
 use pingap_core::{get_hostname, Ctx};
+use pingap_upstream::get_upstreams_circuit_breaker_state;
 use pingora::proxy::Session;
 use prometheus::core::Collector;
 use prometheus::{
@@ -113,6 +114,54 @@ pub struct Prometheus {
     /// Histogram of upstream response times in seconds, labeled by upstream
     upstream_response_time: Box<HistogramVec>,
 
+    /// Circuit breaker state of upstream(0: closed, 1: open, 2: half-open), labeled by upstream
+    upstream_circuit_breaker_state: Box<IntGaugeVec>,
+
+    /// Count of hedged requests to upstream, labeled by upstream
+    upstream_hedged_requests: Box<IntCounterVec>,
+
+    /// Count of responses from the hedged requests, labeled by upstream
+    upstream_hedge_wins: Box<IntCounterVec>,
+
+    /// Count of mirror requests by response code, labeled by upstream and code
+    mirror_requests: Box<IntCounterVec>,
+
+    /// Histogram of mirror request response times in seconds, labeled by upstream
+    mirror_response_time: Box<HistogramVec>,
+
+    /// Total number of stream connections, labeled by upstream
+    stream_connections_total: Box<IntCounterVec>,
+
+    /// Current number of active stream connections, labeled by upstream
+    stream_connections_current: Box<IntGaugeVec>,
+
+    /// Total bytes of stream connections received from clients, labeled by upstream
+    stream_received_bytes: Box<IntCounterVec>,
+
+    /// Total bytes of stream connections sent to clients, labeled by upstream
+    stream_sent_bytes: Box<IntCounterVec>,
+
+    /// Histogram of stream connection durations in seconds, labeled by upstream
+    stream_connection_time: Box<HistogramVec>,
+
+    /// Total number of udp sessions
+    udp_sessions_total: Box<IntCounter>,
+
+    /// Current number of active udp sessions
+    udp_sessions_current: Box<IntGauge>,
+
+    /// Total datagrams received from clients
+    udp_received_packets: Box<IntCounter>,
+
+    /// Total datagrams sent to clients
+    udp_sent_packets: Box<IntCounter>,
+
+    /// Total bytes of datagrams received from clients
+    udp_received_bytes: Box<IntCounter>,
+
+    /// Total bytes of datagrams sent to clients
+    udp_sent_bytes: Box<IntCounter>,
+
     /// Histogram of cache lookup times in seconds
     cache_lookup_time: Box<Histogram>,
 
@@ -144,6 +193,19 @@ pub struct Prometheus {
 /// Milliseconds to seconds conversion factor
 const SECOND: f64 = 1000.0;
 
+/// Returns the label of response code category(2xx, 3xx, etc.)
+#[inline]
+fn get_code_label(code: u16) -> &'static str {
+    match code {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "unknown",
+    }
+}
+
 impl Prometheus {
     /// Records metrics at the start of request processing.
     ///
@@ -199,14 +261,7 @@ impl Prometheus {
         let sent = sent_bytes as f64 / 1024.0;
 
         // http response code
-        let code_label = match code {
-            100..=199 => "1xx",
-            200..=299 => "2xx",
-            300..=399 => "3xx",
-            400..=499 => "4xx",
-            500..=599 => "5xx",
-            _ => "unknown",
-        };
+        let code_label = get_code_label(code);
         let mut labels_list = Vec::with_capacity(2);
         labels_list.push([""]);
         if !location.is_empty() {
@@ -299,6 +354,16 @@ impl Prometheus {
                     .with_label_values(upstream_labels)
                     .observe(upstream_response_time as f64 / SECOND);
             }
+            if ctx.upstream_hedged_requests > 0 {
+                self.upstream_hedged_requests
+                    .with_label_values(upstream_labels)
+                    .inc_by(ctx.upstream_hedged_requests as u64);
+            }
+            if ctx.upstream_hedge_won {
+                self.upstream_hedge_wins
+                    .with_label_values(upstream_labels)
+                    .inc();
+            }
         }
 
         // cache stats
@@ -323,18 +388,119 @@ impl Prometheus {
         }
     }
 
+    /// Records metrics of the mirror request.
+    ///
+    /// # Arguments
+    /// * `upstream` - The mirror upstream
+    /// * `status` - Response status code, None if the mirror request fails
+    /// * `response_time` - Response time of the mirror request in milliseconds
+    pub fn mirror(
+        &self,
+        upstream: &str,
+        status: Option<u16>,
+        response_time: u64,
+    ) {
+        let code_label = status.map_or("error", get_code_label);
+        self.mirror_requests
+            .with_label_values(&[upstream, code_label])
+            .inc();
+        self.mirror_response_time
+            .with_label_values(&[upstream])
+            .observe(response_time as f64 / SECOND);
+    }
+
+    /// Records metrics at the start of the stream connection.
+    ///
+    /// # Arguments
+    /// * `upstream` - The upstream of the stream connection
+    pub fn stream_before(&self, upstream: &str) {
+        self.stream_connections_total
+            .with_label_values(&[upstream])
+            .inc();
+        self.stream_connections_current
+            .with_label_values(&[upstream])
+            .inc();
+    }
+
+    /// Records metrics when the stream connection is closed.
+    ///
+    /// # Arguments
+    /// * `upstream` - The upstream of the stream connection
+    /// * `received` - Bytes received from the client
+    /// * `sent` - Bytes sent to the client
+    /// * `connection_time` - Duration of the connection in milliseconds
+    pub fn stream_after(
+        &self,
+        upstream: &str,
+        received: u64,
+        sent: u64,
+        connection_time: u64,
+    ) {
+        let labels = &[upstream];
+        self.stream_connections_current
+            .with_label_values(labels)
+            .dec();
+        self.stream_received_bytes
+            .with_label_values(labels)
+            .inc_by(received);
+        self.stream_sent_bytes
+            .with_label_values(labels)
+            .inc_by(sent);
+        self.stream_connection_time
+            .with_label_values(labels)
+            .observe(connection_time as f64 / SECOND);
+    }
+
+    /// Records metrics when the udp session is created, the udp metrics
+    /// are only labeled by the server.
+    pub fn udp_session_opened(&self) {
+        self.udp_sessions_total.inc();
+        self.udp_sessions_current.inc();
+    }
+
+    /// Records metrics when the udp session is closed.
+    pub fn udp_session_closed(&self) {
+        self.udp_sessions_current.dec();
+    }
+
+    /// Records the datagram received from the client.
+    ///
+    /// # Arguments
+    /// * `size` - Bytes of the datagram
+    pub fn udp_received(&self, size: u64) {
+        self.udp_received_packets.inc();
+        self.udp_received_bytes.inc_by(size);
+    }
+
+    /// Records the datagram sent to the client.
+    ///
+    /// # Arguments
+    /// * `size` - Bytes of the datagram
+    pub fn udp_sent(&self, size: u64) {
+        self.udp_sent_packets.inc();
+        self.udp_sent_bytes.inc_by(size);
+    }
+
     /// Collects all registered metrics and updates system resource gauges.
     ///
     /// Updates the following system metrics before collection:
     /// - Memory usage in MB
     /// - Open file descriptor count
     /// - IPv4 and IPv6 TCP connection counts
+    /// - Circuit breaker state of upstreams
     fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
         let info = get_process_system_info();
         self.memory.set(info.memory_mb as i64);
         self.fd_count.set(info.fd_count as i64);
         self.tcp_count.set(info.tcp_count as i64);
         self.tcp6_count.set(info.tcp6_count as i64);
+        // reset to remove the upstreams which are deleted
+        self.upstream_circuit_breaker_state.reset();
+        for (name, state) in get_upstreams_circuit_breaker_state() {
+            self.upstream_circuit_breaker_state
+                .with_label_values(&[name.as_str()])
+                .set(state.value());
+        }
         self.r.gather()
     }
 
@@ -682,6 +848,98 @@ pub fn new_prometheus(server: &str) -> Result<Prometheus> {
         &["upstream"],
         &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0],
     )?);
+    let upstream_circuit_breaker_state = Box::new(new_int_gauge_vec(
+        server,
+        "pingap_upstream_circuit_breaker_state",
+        "pingap circuit breaker state of upstream(0: closed, 1: open, 2: half-open)",
+        &["upstream"],
+    )?);
+    let upstream_hedged_requests = Box::new(new_int_counter_vec(
+        server,
+        "pingap_upstream_hedged_requests",
+        "pingap hedged requests to upstream",
+        &["upstream"],
+    )?);
+    let upstream_hedge_wins = Box::new(new_int_counter_vec(
+        server,
+        "pingap_upstream_hedge_wins",
+        "pingap responses from the hedged requests of upstream",
+        &["upstream"],
+    )?);
+    let mirror_requests = Box::new(new_int_counter_vec(
+        server,
+        "pingap_mirror_requests",
+        "pingap total mirror requests by code",
+        &["upstream", "code"],
+    )?);
+    let mirror_response_time = Box::new(new_histogram_vec(
+        server,
+        "pingap_mirror_response_time",
+        "pingap mirror response time(second)",
+        &["upstream"],
+        &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+    )?);
+    let stream_connections_total = Box::new(new_int_counter_vec(
+        server,
+        "pingap_stream_connections_total",
+        "pingap total stream connections",
+        &["upstream"],
+    )?);
+    let stream_connections_current = Box::new(new_int_gauge_vec(
+        server,
+        "pingap_stream_connections_current",
+        "pingap current stream connections",
+        &["upstream"],
+    )?);
+    let stream_received_bytes = Box::new(new_int_counter_vec(
+        server,
+        "pingap_stream_received_bytes",
+        "pingap stream received from clients(bytes)",
+        &["upstream"],
+    )?);
+    let stream_sent_bytes = Box::new(new_int_counter_vec(
+        server,
+        "pingap_stream_sent_bytes",
+        "pingap stream sent to clients(bytes)",
+        &["upstream"],
+    )?);
+    let stream_connection_time = Box::new(new_histogram_vec(
+        server,
+        "pingap_stream_connection_time",
+        "pingap stream connection time(second)",
+        &["upstream"],
+        &[0.1, 1.0, 10.0, 60.0, 300.0, 3600.0],
+    )?);
+    let udp_sessions_total = Box::new(new_int_counter(
+        server,
+        "pingap_udp_sessions_total",
+        "pingap total udp sessions",
+    )?);
+    let udp_sessions_current = Box::new(new_int_gauge(
+        server,
+        "pingap_udp_sessions_current",
+        "pingap current udp sessions",
+    )?);
+    let udp_received_packets = Box::new(new_int_counter(
+        server,
+        "pingap_udp_received_packets",
+        "pingap udp datagrams received from clients",
+    )?);
+    let udp_sent_packets = Box::new(new_int_counter(
+        server,
+        "pingap_udp_sent_packets",
+        "pingap udp datagrams sent to clients",
+    )?);
+    let udp_received_bytes = Box::new(new_int_counter(
+        server,
+        "pingap_udp_received_bytes",
+        "pingap udp received from clients(bytes)",
+    )?);
+    let udp_sent_bytes = Box::new(new_int_counter(
+        server,
+        "pingap_udp_sent_bytes",
+        "pingap udp sent to clients(bytes)",
+    )?);
     let cache_lookup_time = Box::new(new_histogram(
         server,
         "pingap_cache_lookup_time",
@@ -750,6 +1008,22 @@ pub fn new_prometheus(server: &str) -> Result<Prometheus> {
         upstream_reuses.clone(),
         upstream_processing_time.clone(),
         upstream_response_time.clone(),
+        upstream_circuit_breaker_state.clone(),
+        upstream_hedged_requests.clone(),
+        upstream_hedge_wins.clone(),
+        mirror_requests.clone(),
+        mirror_response_time.clone(),
+        stream_connections_total.clone(),
+        stream_connections_current.clone(),
+        stream_received_bytes.clone(),
+        stream_sent_bytes.clone(),
+        stream_connection_time.clone(),
+        udp_sessions_total.clone(),
+        udp_sessions_current.clone(),
+        udp_received_packets.clone(),
+        udp_sent_packets.clone(),
+        udp_received_bytes.clone(),
+        udp_sent_bytes.clone(),
         cache_lookup_time.clone(),
         cache_lock_time.clone(),
         cache_reading.clone(),
@@ -787,6 +1061,22 @@ pub fn new_prometheus(server: &str) -> Result<Prometheus> {
         upstream_reuses,
         upstream_processing_time,
         upstream_response_time,
+        upstream_circuit_breaker_state,
+        upstream_hedged_requests,
+        upstream_hedge_wins,
+        mirror_requests,
+        mirror_response_time,
+        stream_connections_total,
+        stream_connections_current,
+        stream_received_bytes,
+        stream_sent_bytes,
+        stream_connection_time,
+        udp_sessions_total,
+        udp_sessions_current,
+        udp_received_packets,
+        udp_sent_packets,
+        udp_received_bytes,
+        udp_sent_bytes,
         cache_lookup_time,
         cache_lock_time,
         cache_reading,
@@ -843,6 +1133,8 @@ mod tests {
                 upstream_reused: true,
                 upstream_processing_time: Some(10),
                 upstream_response_time: Some(5),
+                upstream_hedged_requests: 1,
+                upstream_hedge_won: true,
                 cache_lookup_time: Some(11),
                 cache_lock_time: Some(12),
                 compression_stat: Some(CompressionStat {
@@ -855,7 +1147,15 @@ mod tests {
                 ..Default::default()
             },
         );
+        p.mirror("charts", Some(200), 10);
+        p.mirror("charts", None, 1000);
+        p.stream_before("postgres");
+        p.stream_after("postgres", 1024, 2048, 1500);
+        p.udp_session_opened();
+        p.udp_received(64);
+        p.udp_sent(128);
+        p.udp_session_closed();
         let buf = p.metrics().unwrap();
-        assert_eq!(225, std::str::from_utf8(&buf).unwrap().split('\n').count());
+        assert_eq!(288, std::str::from_utf8(&buf).unwrap().split('\n').count());
     }
 }
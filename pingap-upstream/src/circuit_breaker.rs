@@ -0,0 +1,333 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// State of the circuit breaker
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitBreakerState {
+    /// Requests pass through, the error rate is tracked
+    #[default]
+    Closed,
+    /// Requests are rejected until the cool-down is over
+    Open,
+    /// Limited probe requests pass through to check the upstream
+    HalfOpen,
+}
+
+impl CircuitBreakerState {
+    /// Returns the numeric value of the state, used as metric value
+    #[inline]
+    pub fn value(&self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+impl fmt::Display for CircuitBreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        };
+        write!(f, "{value}")
+    }
+}
+
+/// Circuit breaker parameters
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerParams {
+    /// Error rate(percentage) to open the circuit
+    pub error_rate: u32,
+    /// Minimum number of requests in the window before the error rate is checked
+    pub min_requests: u32,
+    /// Rolling window of the error rate
+    pub window: Duration,
+    /// Cool-down time before the open circuit becomes half-open
+    pub open_time: Duration,
+    /// Number of successful probe requests to close the half-open circuit
+    pub half_open_requests: u32,
+    /// Response status code when the circuit is open
+    pub status: u16,
+}
+
+#[derive(Debug, Default)]
+struct CircuitBreakerInner {
+    state: CircuitBreakerState,
+    // timestamp(ms) of the last state change
+    changed_at: u64,
+    // start timestamp(ms) of the current window
+    window_start: u64,
+    requests: u64,
+    failures: u64,
+    // counters of the previous window, they are weighted
+    // by the overlap with the rolling window
+    prev_requests: u64,
+    prev_failures: u64,
+    // probe requests of half-open state
+    probes: u32,
+    successes: u32,
+}
+
+impl CircuitBreakerInner {
+    fn change_state(&mut self, state: CircuitBreakerState, now: u64) {
+        self.state = state;
+        self.changed_at = now;
+        self.window_start = now;
+        self.requests = 0;
+        self.failures = 0;
+        self.prev_requests = 0;
+        self.prev_failures = 0;
+        self.probes = 0;
+        self.successes = 0;
+    }
+    fn roll(&mut self, now: u64, window: u64) {
+        let elapsed = now.saturating_sub(self.window_start);
+        if elapsed < window {
+            return;
+        }
+        if elapsed < 2 * window {
+            self.prev_requests = self.requests;
+            self.prev_failures = self.failures;
+            self.window_start += window;
+        } else {
+            self.prev_requests = 0;
+            self.prev_failures = 0;
+            self.window_start = now;
+        }
+        self.requests = 0;
+        self.failures = 0;
+    }
+    // Returns the weighted requests and failures of the rolling window
+    fn get_counts(&self, now: u64, window: u64) -> (f64, f64) {
+        let elapsed = now.saturating_sub(self.window_start).min(window);
+        let weight = 1.0 - elapsed as f64 / window as f64;
+        (
+            self.requests as f64 + self.prev_requests as f64 * weight,
+            self.failures as f64 + self.prev_failures as f64 * weight,
+        )
+    }
+}
+
+/// Circuit breaker of an upstream, the circuit opens when the error rate
+/// of the rolling window passes the threshold, after the cool-down time
+/// it becomes half-open and lets the probe requests through, the circuit
+/// is closed if all probe requests succeed, otherwise it opens again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    params: CircuitBreakerParams,
+    inner: Mutex<CircuitBreakerInner>,
+}
+
+impl CircuitBreaker {
+    /// Creates a new circuit breaker in closed state
+    pub fn new(params: CircuitBreakerParams) -> Self {
+        Self {
+            params,
+            inner: Mutex::new(CircuitBreakerInner::default()),
+        }
+    }
+    /// Returns the parameters of the circuit breaker
+    #[inline]
+    pub fn params(&self) -> &CircuitBreakerParams {
+        &self.params
+    }
+    /// Returns the current state of the circuit breaker
+    pub fn state(&self) -> CircuitBreakerState {
+        self.inner
+            .lock()
+            .map(|inner| inner.state)
+            .unwrap_or_default()
+    }
+    /// Acquires the permission of a request, returns whether the request
+    /// is allowed and the new state if the state changes.
+    pub fn acquire(&self, now: u64) -> (bool, Option<CircuitBreakerState>) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return (true, None);
+        };
+        let open_time = self.params.open_time.as_millis() as u64;
+        match inner.state {
+            CircuitBreakerState::Closed => (true, None),
+            CircuitBreakerState::Open => {
+                if now < inner.changed_at + open_time {
+                    return (false, None);
+                }
+                inner.change_state(CircuitBreakerState::HalfOpen, now);
+                inner.probes = 1;
+                (true, Some(CircuitBreakerState::HalfOpen))
+            },
+            CircuitBreakerState::HalfOpen => {
+                if inner.probes < self.params.half_open_requests {
+                    inner.probes += 1;
+                    return (true, None);
+                }
+                // the results of probe requests are lost(e.g. client closed),
+                // start a new round of probes
+                if now >= inner.changed_at + open_time {
+                    inner.change_state(CircuitBreakerState::HalfOpen, now);
+                    inner.probes = 1;
+                    return (true, None);
+                }
+                (false, None)
+            },
+        }
+    }
+    /// Records the result of a request, returns the new state
+    /// if the state changes.
+    pub fn on_result(
+        &self,
+        now: u64,
+        success: bool,
+    ) -> Option<CircuitBreakerState> {
+        let mut inner = self.inner.lock().ok()?;
+        match inner.state {
+            CircuitBreakerState::Closed => {
+                let window = (self.params.window.as_millis() as u64).max(1);
+                inner.roll(now, window);
+                inner.requests += 1;
+                if !success {
+                    inner.failures += 1;
+                }
+                let (requests, failures) = inner.get_counts(now, window);
+                if requests >= self.params.min_requests.max(1) as f64
+                    && failures * 100.0
+                        >= self.params.error_rate as f64 * requests
+                {
+                    inner.change_state(CircuitBreakerState::Open, now);
+                    return Some(CircuitBreakerState::Open);
+                }
+                None
+            },
+            CircuitBreakerState::HalfOpen => {
+                if !success {
+                    inner.change_state(CircuitBreakerState::Open, now);
+                    return Some(CircuitBreakerState::Open);
+                }
+                inner.successes += 1;
+                if inner.successes >= self.params.half_open_requests {
+                    inner.change_state(CircuitBreakerState::Closed, now);
+                    return Some(CircuitBreakerState::Closed);
+                }
+                None
+            },
+            // the results of requests before the circuit opens are ignored
+            CircuitBreakerState::Open => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn new_circuit_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerParams {
+            error_rate: 50,
+            min_requests: 4,
+            window: Duration::from_secs(10),
+            open_time: Duration::from_secs(30),
+            half_open_requests: 2,
+            status: 503,
+        })
+    }
+
+    #[test]
+    fn test_circuit_breaker_state() {
+        assert_eq!("closed", CircuitBreakerState::Closed.to_string());
+        assert_eq!("half_open", CircuitBreakerState::HalfOpen.to_string());
+        assert_eq!(1, CircuitBreakerState::Open.value());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cb = new_circuit_breaker();
+        assert_eq!((true, None), cb.acquire(1000));
+        // less than min requests
+        assert_eq!(None, cb.on_result(1000, false));
+        assert_eq!(None, cb.on_result(1000, false));
+        assert_eq!(None, cb.on_result(1000, true));
+        assert_eq!(Some(CircuitBreakerState::Open), cb.on_result(1000, false));
+        assert_eq!(CircuitBreakerState::Open, cb.state());
+        assert_eq!((false, None), cb.acquire(30_999));
+
+        // half-open after cool-down
+        assert_eq!(
+            (true, Some(CircuitBreakerState::HalfOpen)),
+            cb.acquire(31_000)
+        );
+        assert_eq!((true, None), cb.acquire(31_000));
+        // only limited probe requests
+        assert_eq!((false, None), cb.acquire(31_000));
+        assert_eq!(None, cb.on_result(31_100, true));
+        assert_eq!(
+            Some(CircuitBreakerState::Closed),
+            cb.on_result(31_100, true)
+        );
+        assert_eq!((true, None), cb.acquire(31_100));
+
+        // open again if the probe request fails
+        for _ in 0..4 {
+            cb.on_result(32_000, false);
+        }
+        assert_eq!(CircuitBreakerState::Open, cb.state());
+        assert_eq!(
+            (true, Some(CircuitBreakerState::HalfOpen)),
+            cb.acquire(62_000)
+        );
+        assert_eq!(
+            Some(CircuitBreakerState::Open),
+            cb.on_result(62_000, false)
+        );
+        assert_eq!((false, None), cb.acquire(62_000));
+    }
+
+    #[test]
+    fn test_circuit_breaker_rolling_window() {
+        let cb = new_circuit_breaker();
+        cb.on_result(0, false);
+        cb.on_result(0, false);
+        cb.on_result(0, true);
+        // the failures of previous window are weighted
+        assert_eq!(None, cb.on_result(19_000, true));
+        assert_eq!(CircuitBreakerState::Closed, cb.state());
+        // the failures of expired windows are dropped
+        cb.on_result(40_000, false);
+        cb.on_result(40_000, true);
+        cb.on_result(40_000, true);
+        assert_eq!(None, cb.on_result(40_000, true));
+        assert_eq!(CircuitBreakerState::Closed, cb.state());
+    }
+
+    #[test]
+    fn test_circuit_breaker_lost_probes() {
+        let cb = new_circuit_breaker();
+        for _ in 0..4 {
+            cb.on_result(0, false);
+        }
+        cb.acquire(30_000);
+        cb.acquire(30_000);
+        assert_eq!((false, None), cb.acquire(59_999));
+        assert_eq!((true, None), cb.acquire(60_000));
+        assert_eq!(CircuitBreakerState::HalfOpen, cb.state());
+    }
+}
@@ -12,6 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod circuit_breaker;
+mod proxy_protocol;
+mod stats;
+mod sticky;
 mod upstream;
 
+pub use circuit_breaker::CircuitBreakerState;
 pub use upstream::*;
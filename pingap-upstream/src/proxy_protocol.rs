@@ -0,0 +1,213 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use pingap_core::{
+    new_proxy_protocol_header, ProxyProtocolAddrs, ProxyProtocolVersion,
+};
+use pingora::connectors::L4Connect;
+use pingora::protocols::l4::ext::{
+    set_recv_buf, set_tcp_fastopen_connect, set_tcp_keepalive, TcpKeepalive,
+};
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::l4::stream::Stream;
+use pingora::{Error, ErrorType, OrErr};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpSocket;
+
+// Random keys of the connection group hash, so the collision
+// of the original addresses can't be forged by the client
+static GROUP_KEY_HASHER: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+/// Returns the group key of the upstream connections with the PROXY header,
+/// the connection is only reused by the same original addresses.
+pub(crate) fn get_proxy_protocol_group_key(addrs: &ProxyProtocolAddrs) -> u64 {
+    GROUP_KEY_HASHER.hash_one(addrs)
+}
+
+/// Tcp options of the upstream connection, they are applied by the connector
+/// because pingora's connect is bypassed by the custom connector.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TcpConnectOptions {
+    pub connection_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    pub tcp_recv_buf: Option<usize>,
+    pub tcp_fast_open: bool,
+}
+
+/// Connector of upstream which sends the PROXY protocol header
+/// with the original addresses after the connection is established,
+/// no header is sent if the version is not set.
+#[derive(Debug)]
+pub(crate) struct ProxyProtocolConnector {
+    header: Vec<u8>,
+    options: TcpConnectOptions,
+}
+
+impl ProxyProtocolConnector {
+    pub fn new(
+        version: Option<ProxyProtocolVersion>,
+        addrs: Option<&ProxyProtocolAddrs>,
+        options: TcpConnectOptions,
+    ) -> Self {
+        let header = version
+            .map(|version| new_proxy_protocol_header(version, addrs))
+            .unwrap_or_default();
+        Self { header, options }
+    }
+    async fn send_header<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> pingora::Result<()> {
+        if self.header.is_empty() {
+            return Ok(());
+        }
+        stream
+            .write_all(&self.header)
+            .await
+            .or_err(ErrorType::WriteError, "send proxy protocol header fail")
+    }
+    async fn connect_addr(&self, addr: &SocketAddr) -> pingora::Result<Stream> {
+        match addr {
+            SocketAddr::Inet(addr) => {
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()
+                } else {
+                    TcpSocket::new_v6()
+                }
+                .or_err(ErrorType::SocketError, "create tcp socket fail")?;
+                #[cfg(unix)]
+                let raw = socket.as_raw_fd();
+                #[cfg(windows)]
+                let raw = socket.as_raw_socket();
+                if self.options.tcp_fast_open {
+                    set_tcp_fastopen_connect(raw)?;
+                }
+                if let Some(recv_buf) = self.options.tcp_recv_buf {
+                    set_recv_buf(raw, recv_buf)?;
+                }
+                let mut stream = socket
+                    .connect(*addr)
+                    .await
+                    .or_err(ErrorType::ConnectError, "connect upstream fail")?;
+                if let Some(tcp_keepalive) = &self.options.tcp_keepalive {
+                    set_tcp_keepalive(&stream, tcp_keepalive)?;
+                }
+                stream
+                    .set_nodelay(true)
+                    .or_err(ErrorType::ConnectError, "set tcp nodelay fail")?;
+                self.send_header(&mut stream).await?;
+                Ok(stream.into())
+            },
+            #[cfg(unix)]
+            SocketAddr::Unix(addr) => {
+                let path = addr.as_pathname().ok_or_else(|| {
+                    Error::explain(
+                        ErrorType::ConnectError,
+                        "unix socket path is empty",
+                    )
+                })?;
+                let mut stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .or_err(ErrorType::ConnectError, "connect upstream fail")?;
+                self.send_header(&mut stream).await?;
+                Ok(stream.into())
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl L4Connect for ProxyProtocolConnector {
+    async fn connect(&self, addr: &SocketAddr) -> pingora::Result<Stream> {
+        let Some(timeout) = self.options.connection_timeout else {
+            return self.connect_addr(addr).await;
+        };
+        tokio::time::timeout(timeout, self.connect_addr(addr))
+            .await
+            .or_err(ErrorType::ConnectTimedout, "connect upstream timeout")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_proxy_protocol_connector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 43];
+            stream.read_exact(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf).to_string()
+        });
+        let connector = ProxyProtocolConnector::new(
+            Some(ProxyProtocolVersion::V1),
+            Some(&ProxyProtocolAddrs {
+                source: "192.168.1.1:56324".parse().unwrap(),
+                destination: "10.0.0.1:443".parse().unwrap(),
+            }),
+            TcpConnectOptions {
+                connection_timeout: Some(Duration::from_secs(3)),
+                tcp_keepalive: Some(TcpKeepalive {
+                    idle: Duration::from_secs(60),
+                    interval: Duration::from_secs(10),
+                    count: 3,
+                    #[cfg(target_os = "linux")]
+                    user_timeout: Duration::from_secs(0),
+                }),
+                tcp_recv_buf: Some(64 * 1024),
+                tcp_fast_open: false,
+            },
+        );
+        connector.connect(&SocketAddr::Inet(addr)).await.unwrap();
+        assert_eq!(
+            "PROXY TCP4 192.168.1.1 10.0.0.1 56324 443\r\n",
+            server.await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_proxy_protocol_group_key() {
+        let addrs = ProxyProtocolAddrs {
+            source: "192.168.1.1:56324".parse().unwrap(),
+            destination: "10.0.0.1:443".parse().unwrap(),
+        };
+        assert_eq!(
+            get_proxy_protocol_group_key(&addrs),
+            get_proxy_protocol_group_key(&addrs.clone())
+        );
+        let other = ProxyProtocolAddrs {
+            source: "192.168.1.1:56325".parse().unwrap(),
+            ..addrs
+        };
+        assert_ne!(
+            get_proxy_protocol_group_key(&addrs),
+            get_proxy_protocol_group_key(&other)
+        );
+    }
+}
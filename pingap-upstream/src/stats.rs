@@ -0,0 +1,411 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ahash::AHashMap;
+use arc_swap::ArcSwap;
+use pingap_core::format_socket_addr;
+use pingora::lb::Backend;
+use std::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+// The decay window of the ewma response time, a sample older than
+// this window has only about 37% (1/e) influence on the average.
+const EWMA_DECAY_WINDOW_MS: f64 = 10_000.0;
+
+// The minimum ramp-up factor of slow start, so that the backend
+// still receives a little traffic at the beginning of slow start
+const SLOW_START_MIN_FACTOR: f64 = 0.05;
+
+/// Passive health check(outlier detection) parameters
+#[derive(Debug, Clone)]
+pub struct OutlierDetection {
+    /// Number of consecutive failures before the backend is ejected
+    pub consecutive_failures: u32,
+    /// Base ejection time
+    pub ejection_time: Duration,
+    /// Maximum ejection time
+    pub max_ejection_time: Duration,
+}
+
+impl OutlierDetection {
+    // Ejection time grows exponentially with the number of ejections
+    fn get_ejection_time(&self, ejections: u32) -> Duration {
+        self.ejection_time
+            .saturating_mul(1 << ejections.min(16))
+            .min(self.max_ejection_time)
+    }
+}
+
+/// Runtime statistics of a single backend
+#[derive(Debug, Default)]
+pub struct BackendStat {
+    /// Number of requests currently in flight to the backend
+    processing: AtomicI32,
+    /// Exponentially weighted moving average of response time (f64 bits, ms)
+    ewma: AtomicU64,
+    /// Timestamp (ms) of the last ewma update
+    updated_at: AtomicU64,
+    /// Number of consecutive failures
+    failures: AtomicU32,
+    /// Number of times the backend has been ejected
+    ejections: AtomicU32,
+    /// Timestamp (ms) until which the backend is ejected
+    ejected_until: AtomicU64,
+    /// Whether the backend has been ejected and not yet recovered
+    ejected: AtomicBool,
+    /// Timestamp (ms) when the slow start of the backend begins
+    slow_start_at: AtomicU64,
+    /// Number of requests evaluated during slow start
+    slow_start_requests: AtomicU64,
+}
+
+impl BackendStat {
+    /// Returns the number of requests in flight to the backend
+    #[inline]
+    pub fn processing(&self) -> i32 {
+        self.processing.load(Ordering::Relaxed)
+    }
+    /// Returns the ewma response time of the backend in milliseconds
+    #[inline]
+    pub fn ewma(&self) -> f64 {
+        f64::from_bits(self.ewma.load(Ordering::Relaxed))
+    }
+    /// Returns whether the backend is ejected at the time
+    #[inline]
+    pub fn is_ejected(&self, now: u64) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > now
+    }
+    /// Records a failure of the backend, returns the ejection time
+    /// if the consecutive failures reach the threshold.
+    /// The failures of an ejected backend are not counted, so the
+    /// backend is not ejected again as soon as the ejection expires.
+    pub fn on_failure(
+        &self,
+        now: u64,
+        outlier: &OutlierDetection,
+    ) -> Option<Duration> {
+        if self.is_ejected(now) {
+            return None;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < outlier.consecutive_failures {
+            return None;
+        }
+        self.failures.store(0, Ordering::Relaxed);
+        let ejections = self.ejections.fetch_add(1, Ordering::Relaxed);
+        let ejection_time = outlier.get_ejection_time(ejections);
+        self.ejected_until
+            .store(now + ejection_time.as_millis() as u64, Ordering::Relaxed);
+        self.ejected.store(true, Ordering::Relaxed);
+        Some(ejection_time)
+    }
+    /// Records a success of the backend, returns true if
+    /// the ejected backend recovers.
+    pub fn on_success(&self, now: u64, outlier: &OutlierDetection) -> bool {
+        self.failures.store(0, Ordering::Relaxed);
+        if self.is_ejected(now) {
+            return false;
+        }
+        // reset the ejection count if the backend is stable for a while
+        let ejected_until = self.ejected_until.load(Ordering::Relaxed);
+        if ejected_until > 0
+            && now
+                > ejected_until + outlier.max_ejection_time.as_millis() as u64
+        {
+            self.ejections.store(0, Ordering::Relaxed);
+        }
+        self.ejected.swap(false, Ordering::Relaxed)
+    }
+    /// Returns the ramp-up factor(0-1] of slow start, the effective
+    /// weight of the backend grows linearly during the slow start window.
+    pub fn slow_start_factor(&self, now: u64, slow_start: Duration) -> f64 {
+        let started_at = self.slow_start_at.load(Ordering::Relaxed);
+        let window = slow_start.as_millis() as u64;
+        let elapsed = now.saturating_sub(started_at);
+        if started_at == 0 || window == 0 || elapsed >= window {
+            return 1.0;
+        }
+        (elapsed as f64 / window as f64).max(SLOW_START_MIN_FACTOR)
+    }
+    // Returns whether the request is accepted by the backend in slow start,
+    // the ratio of accepted requests is the same as the ramp-up factor.
+    fn slow_start_accept(&self, factor: f64) -> bool {
+        if factor >= 1.0 {
+            return true;
+        }
+        let count = self.slow_start_requests.fetch_add(1, Ordering::Relaxed);
+        (count as f64 * factor).floor() != ((count + 1) as f64 * factor).floor()
+    }
+    fn observe(&self, response_time: u64, now: u64) {
+        let last = self.updated_at.swap(now, Ordering::Relaxed);
+        let sample = response_time as f64;
+        let value = if last == 0 {
+            sample
+        } else {
+            // time based decay, the longer since the last sample,
+            // the less weight the previous average has
+            let elapsed = now.saturating_sub(last) as f64;
+            let w = (-elapsed / EWMA_DECAY_WINDOW_MS).exp();
+            self.ewma() * w + sample * (1.0 - w)
+        };
+        self.ewma.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Per backend statistics of an upstream, used by the least connection
+/// and ewma load balancing algorithms and the passive health check.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    stats: ArcSwap<AHashMap<String, Arc<BackendStat>>>,
+    // rotating offset used to break ties between backends
+    offset: AtomicUsize,
+}
+
+impl BackendStats {
+    /// Returns the statistics of the backend, creates it if not exists
+    pub fn get(&self, addr: &str) -> Arc<BackendStat> {
+        if let Some(stat) = self.stats.load().get(addr) {
+            return stat.clone();
+        }
+        self.stats.rcu(|stats| {
+            let mut stats = AHashMap::clone(stats);
+            stats.entry(addr.to_string()).or_default();
+            stats
+        });
+        self.stats.load().get(addr).cloned().unwrap_or_default()
+    }
+    /// Returns whether the backend is ejected by the passive health check
+    #[inline]
+    pub fn is_ejected(&self, addr: &str, now: u64) -> bool {
+        self.stats
+            .load()
+            .get(addr)
+            .map(|stat| stat.is_ejected(now))
+            .unwrap_or_default()
+    }
+    /// Starts the slow start of the backend, e.g. the backend becomes
+    /// healthy or is newly discovered.
+    pub fn start_slow_start(&self, addr: &str, now: u64) {
+        let stat = self.get(addr);
+        stat.slow_start_requests.store(0, Ordering::Relaxed);
+        stat.slow_start_at.store(now, Ordering::Relaxed);
+    }
+    /// Returns whether the request is accepted by the backend,
+    /// the backend in slow start only accepts part of the requests.
+    pub fn slow_start_accept(
+        &self,
+        addr: &str,
+        now: u64,
+        slow_start: Duration,
+    ) -> bool {
+        let Some(stat) = self.stats.load().get(addr).cloned() else {
+            return true;
+        };
+        stat.slow_start_accept(stat.slow_start_factor(now, slow_start))
+    }
+    /// Increases the in flight count of the backend
+    pub fn on_selected(&self, addr: &str) {
+        self.get(addr).processing.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Decreases the in flight count of the backend and
+    /// records the response time if it exists.
+    pub fn on_completed(&self, addr: &str, response_time: Option<u64>) {
+        let Some(stat) = self.stats.load().get(addr).cloned() else {
+            return;
+        };
+        // avoid negative count
+        let _ = stat.processing.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |value| (value > 0).then(|| value - 1),
+        );
+        if let Some(response_time) = response_time {
+            stat.observe(response_time, pingap_util::now_ms());
+        }
+    }
+    /// Selects the backend with the lowest score from the candidates,
+    /// ties are broken by a rotating offset so that the traffic is
+    /// spread when the backends are idle.
+    pub fn select<'a, I, F>(&self, backends: I, score: F) -> Option<Backend>
+    where
+        I: ExactSizeIterator<Item = &'a Backend> + Clone,
+        F: Fn(&Backend, &BackendStat) -> f64,
+    {
+        let count = backends.len();
+        if count == 0 {
+            return None;
+        }
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed) % count;
+        let mut selected: Option<(&Backend, f64)> = None;
+        for backend in backends.cycle().skip(offset).take(count) {
+            let value =
+                score(backend, &self.get(&format_socket_addr(&backend.addr)));
+            if selected.is_none_or(|(_, current)| value < current) {
+                selected = Some((backend, value));
+            }
+        }
+        selected.map(|(backend, _)| backend.clone())
+    }
+}
+
+/// Score of least connection, the in flight count weighted by backend weight
+#[inline]
+pub fn least_conn_score(backend: &Backend, stat: &BackendStat) -> f64 {
+    (stat.processing() as f64 + 1.0) / backend.weight.max(1) as f64
+}
+
+/// Score of ewma, the ewma response time multiplied by in flight count
+/// and weighted by backend weight
+#[inline]
+pub fn ewma_score(backend: &Backend, stat: &BackendStat) -> f64 {
+    stat.ewma() * (stat.processing() as f64 + 1.0)
+        / backend.weight.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_backend_stat() {
+        let stat = BackendStat::default();
+        stat.observe(100, 1000);
+        assert_eq!(100.0, stat.ewma());
+        // same time, the previous value is kept
+        stat.observe(300, 1000);
+        assert_eq!(100.0, stat.ewma());
+        stat.observe(300, 11_000);
+        assert_eq!(226, stat.ewma() as u64);
+    }
+
+    #[test]
+    fn test_outlier_detection() {
+        let outlier = OutlierDetection {
+            consecutive_failures: 2,
+            ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(30),
+        };
+        let stat = BackendStat::default();
+        assert_eq!(None, stat.on_failure(1000, &outlier));
+        // success resets the consecutive failures
+        assert_eq!(false, stat.on_success(1000, &outlier));
+        assert_eq!(None, stat.on_failure(1000, &outlier));
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            stat.on_failure(1000, &outlier)
+        );
+        assert_eq!(true, stat.is_ejected(10_999));
+        // the failures during ejection are not counted
+        assert_eq!(None, stat.on_failure(10_999, &outlier));
+        assert_eq!(None, stat.on_failure(10_999, &outlier));
+        assert_eq!(0, stat.failures.load(Ordering::Relaxed));
+        assert_eq!(false, stat.on_success(10_999, &outlier));
+
+        // ejected again, the ejection time grows
+        assert_eq!(None, stat.on_failure(11_000, &outlier));
+        assert_eq!(
+            Some(Duration::from_secs(20)),
+            stat.on_failure(11_000, &outlier)
+        );
+        assert_eq!(None, stat.on_failure(31_000, &outlier));
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            stat.on_failure(31_000, &outlier)
+        );
+        assert_eq!(true, stat.on_success(61_000, &outlier));
+        assert_eq!(false, stat.is_ejected(61_000));
+        // the ejection count is reset after stable for a while
+        assert_eq!(false, stat.on_success(100_000, &outlier));
+        assert_eq!(0, stat.ejections.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let slow_start = Duration::from_secs(10);
+        let stats = BackendStats::default();
+        let addr = "127.0.0.1:5000";
+        // not in slow start
+        assert_eq!(true, stats.slow_start_accept(addr, 1000, slow_start));
+        assert_eq!(1.0, stats.get(addr).slow_start_factor(1000, slow_start));
+
+        stats.start_slow_start(addr, 1000);
+        let stat = stats.get(addr);
+        assert_eq!(
+            SLOW_START_MIN_FACTOR,
+            stat.slow_start_factor(1000, slow_start)
+        );
+        assert_eq!(0.5, stat.slow_start_factor(6000, slow_start));
+        assert_eq!(1.0, stat.slow_start_factor(11_000, slow_start));
+
+        let accepted = (0..100)
+            .filter(|_| stats.slow_start_accept(addr, 3000, slow_start))
+            .count();
+        assert_eq!(20, accepted);
+        let accepted = (0..100)
+            .filter(|_| stats.slow_start_accept(addr, 11_000, slow_start))
+            .count();
+        assert_eq!(100, accepted);
+    }
+
+    #[test]
+    fn test_least_conn_select() {
+        let stats = BackendStats::default();
+        let backends = [
+            Backend::new("127.0.0.1:5000").unwrap(),
+            Backend::new("127.0.0.1:5001").unwrap(),
+        ];
+        let first = stats.select(backends.iter(), least_conn_score).unwrap();
+        stats.on_selected(&first.addr.to_string());
+        let second = stats.select(backends.iter(), least_conn_score).unwrap();
+        assert_ne!(first, second);
+        stats.on_selected(&second.addr.to_string());
+
+        stats.on_completed(&first.addr.to_string(), Some(10));
+        for _ in 0..3 {
+            assert_eq!(
+                first,
+                stats.select(backends.iter(), least_conn_score).unwrap()
+            );
+        }
+        // the count should not be negative
+        stats.on_completed(&first.addr.to_string(), None);
+        assert_eq!(0, stats.get(&first.addr.to_string()).processing());
+    }
+
+    #[test]
+    fn test_ewma_select() {
+        let stats = BackendStats::default();
+        let backends = [
+            Backend::new("127.0.0.1:5000").unwrap(),
+            Backend::new("127.0.0.1:5001").unwrap(),
+        ];
+        let slow = backends[0].addr.to_string();
+        let fast = backends[1].addr.to_string();
+        stats.on_selected(&slow);
+        stats.on_completed(&slow, Some(200));
+        stats.on_selected(&fast);
+        stats.on_completed(&fast, Some(20));
+        for _ in 0..3 {
+            assert_eq!(
+                backends[1],
+                stats.select(backends.iter(), ewma_score).unwrap()
+            );
+        }
+        assert_eq!(true, stats.select([].iter(), ewma_score).is_none());
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use derive_more::Debug;
+use pingap_config::UpstreamConf;
+use pingap_core::format_socket_addr;
+use pingora::lb::Backend;
+use std::time::Duration;
+
+/// Affinity cookie issued by the proxy, its value identifies the backend
+/// which serves the first request, so the later requests of the client
+/// are sent to the same backend.
+#[derive(Debug)]
+pub struct StickyCookie {
+    upstream: String,
+    name: String,
+    ttl: Option<Duration>,
+    path: String,
+    attributes: Option<String>,
+    // the backend address is encrypted with the secret if set,
+    // otherwise the hash of the address is used
+    #[debug(skip)]
+    secret: Option<String>,
+}
+
+impl StickyCookie {
+    /// Creates a sticky cookie from the upstream config,
+    /// returns None if the cookie name is not set.
+    pub fn new(upstream: &str, conf: &UpstreamConf) -> Option<Self> {
+        let name = conf
+            .sticky_cookie
+            .as_ref()
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())?;
+        Some(Self {
+            upstream: upstream.to_string(),
+            name: name.to_string(),
+            ttl: conf.sticky_cookie_ttl,
+            path: conf
+                .sticky_cookie_path
+                .clone()
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| "/".to_string()),
+            attributes: conf
+                .sticky_cookie_attributes
+                .as_ref()
+                .map(|value| value.trim().trim_matches(';').trim().to_string())
+                .filter(|value| !value.is_empty()),
+            secret: conf
+                .sticky_cookie_secret
+                .clone()
+                .filter(|value| !value.is_empty()),
+        })
+    }
+    /// Returns the name of the cookie
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Returns the cookie value of the backend address
+    pub fn encode(&self, address: &str) -> String {
+        if let Some(secret) = &self.secret {
+            if let Ok(value) = pingap_util::aes_encrypt(secret, address) {
+                return value;
+            }
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(self.upstream.as_bytes());
+        hasher.update(b":");
+        hasher.update(address.as_bytes());
+        format!("{:08x}", hasher.finalize())
+    }
+    /// Finds the backend of the cookie value
+    pub fn find_backend<'a>(
+        &self,
+        value: &str,
+        mut backends: impl Iterator<Item = &'a Backend>,
+    ) -> Option<&'a Backend> {
+        if value.is_empty() {
+            return None;
+        }
+        if let Some(secret) = &self.secret {
+            let address = pingap_util::aes_decrypt(secret, value).ok()?;
+            return backends
+                .find(|backend| format_socket_addr(&backend.addr) == address);
+        }
+        backends.find(|backend| {
+            self.encode(&format_socket_addr(&backend.addr)) == value
+        })
+    }
+    /// Returns the set-cookie header value of the backend address
+    pub fn new_set_cookie(&self, address: &str) -> String {
+        let mut cookie = format!(
+            "{}={}; Path={}",
+            self.name,
+            self.encode(address),
+            self.path
+        );
+        if let Some(ttl) = self.ttl {
+            cookie.push_str(&format!("; Max-Age={}", ttl.as_secs()));
+        }
+        if let Some(attributes) = &self.attributes {
+            cookie.push_str("; ");
+            cookie.push_str(attributes);
+        }
+        cookie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_sticky_cookie() {
+        assert_eq!(
+            true,
+            StickyCookie::new("test", &UpstreamConf::default()).is_none()
+        );
+
+        let backends = [
+            Backend::new("127.0.0.1:3000").unwrap(),
+            Backend::new("127.0.0.1:3001").unwrap(),
+        ];
+        let sticky = StickyCookie::new(
+            "test",
+            &UpstreamConf {
+                sticky_cookie: Some("pingap_sticky".to_string()),
+                sticky_cookie_ttl: Some(Duration::from_secs(3600)),
+                sticky_cookie_attributes: Some(
+                    "HttpOnly; SameSite=Lax;".to_string(),
+                ),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!("pingap_sticky", sticky.name());
+        let value = sticky.encode("127.0.0.1:3001");
+        assert_eq!(8, value.len());
+        assert_eq!(
+            format!(
+                "pingap_sticky={value}; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax"
+            ),
+            sticky.new_set_cookie("127.0.0.1:3001")
+        );
+        assert_eq!(
+            "127.0.0.1:3001",
+            sticky
+                .find_backend(&value, backends.iter())
+                .unwrap()
+                .addr
+                .to_string()
+        );
+        assert_eq!(true, sticky.find_backend("abc", backends.iter()).is_none());
+
+        let sticky = StickyCookie::new(
+            "test",
+            &UpstreamConf {
+                sticky_cookie: Some("pingap_sticky".to_string()),
+                sticky_cookie_path: Some("/api".to_string()),
+                sticky_cookie_secret: Some("secret".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let value = sticky.encode("127.0.0.1:3000");
+        assert_eq!(
+            "127.0.0.1:3000",
+            pingap_util::aes_decrypt("secret", &value).unwrap()
+        );
+        assert_eq!(
+            format!("pingap_sticky={value}; Path=/api"),
+            sticky.new_set_cookie("127.0.0.1:3000")
+        );
+        assert_eq!(
+            "127.0.0.1:3000",
+            sticky
+                .find_backend(&value, backends.iter())
+                .unwrap()
+                .addr
+                .to_string()
+        );
+        assert_eq!(
+            true,
+            sticky
+                .find_backend(&sticky.encode("127.0.0.1:4000"), backends.iter())
+                .is_none()
+        );
+    }
+}
@@ -17,7 +17,7 @@ use crossbeam_channel::Sender;
 use pingap_acme::new_lets_encrypt_service;
 use pingap_cache::new_storage_clear_service;
 use pingap_certificate::{
-    new_certificate_validity_service,
+    new_certificate_validity_service, new_ocsp_stapling_service,
     new_self_signed_certificate_validity_service,
 };
 use pingap_config::{get_config_storage, ETCD_PROTOCOL};
@@ -36,7 +36,7 @@ use process::{
     get_admin_addr, get_start_time, new_auto_restart_service,
     new_observer_service, set_admin_addr,
 };
-use proxy::{Server, ServerConf};
+use proxy::{Server, ServerConf, StreamServer, UdpServer, UdpServerService};
 use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::OsString;
@@ -420,7 +420,11 @@ fn run() -> Result<(), Box<dyn Error>> {
         process::set_restart_process_command(cmd);
     }
 
-    try_init_upstreams(&conf.upstreams, webhook::get_webhook_sender())?;
+    try_init_upstreams(
+        &conf.upstreams,
+        &conf.certificates,
+        webhook::get_webhook_sender(),
+    )?;
     try_init_locations(&conf.locations)?;
     proxy::try_init_server_locations(&conf.servers, &conf.locations)?;
     let certificates = conf.certificates.clone();
@@ -483,7 +487,7 @@ fn run() -> Result<(), Box<dyn Error>> {
     }
 
     let mut server_conf_list: Vec<ServerConf> =
-        proxy::parse_from_conf(conf.clone());
+        proxy::parse_from_conf(conf.clone())?;
 
     if let Some(addr) = &get_admin_addr() {
         let (server_conf, _, plugin_conf) = plugin::parse_admin_plugin(addr)?;
@@ -525,6 +529,10 @@ fn run() -> Result<(), Box<dyn Error>> {
         new_certificate_validity_service(webhook::get_webhook_sender()),
         new_self_signed_certificate_validity_service(),
         new_performance_metrics_log_service(),
+        new_ocsp_stapling_service(
+            conf.basic.ocsp_cache_directory.clone(),
+            webhook::get_webhook_sender(),
+        ),
     ];
     if let Some(task) = new_storage_clear_service() {
         simple_tasks.push(task);
@@ -573,6 +581,34 @@ fn run() -> Result<(), Box<dyn Error>> {
     }
 
     for server_conf in server_conf_list.iter() {
+        if server_conf.stream {
+            let ss = StreamServer::new(server_conf)?;
+            if let Some(service) = ss.get_prometheus_push_service() {
+                simple_tasks.push(service);
+            }
+            let services = ss.run()?;
+            my_server.add_service(services.stream);
+            if let Some(service) = services.prometheus_pull {
+                my_server.add_services(vec![service]);
+            }
+            continue;
+        }
+        if server_conf.udp {
+            let us = UdpServer::new(server_conf)?;
+            if let Some(service) = us.get_prometheus_push_service() {
+                simple_tasks.push(service);
+            }
+            if let Some(service) = us.get_prometheus_pull_service() {
+                my_server.add_services(vec![service]);
+            }
+            let mut service = background_service(
+                &format!("udp:{}", server_conf.name),
+                UdpServerService::from(us),
+            );
+            service.threads = server_conf.threads;
+            my_server.add_service(service);
+            continue;
+        }
         let listen_80_port = server_conf.addr.ends_with(":80");
         let mut ps = Server::new(server_conf)?;
         if enabled_lets_encrypt && listen_80_port {
@@ -583,6 +619,9 @@ fn run() -> Result<(), Box<dyn Error>> {
         }
         let services = ps.run(&my_server.configuration)?;
         my_server.add_service(services.lb);
+        if let Some(service) = services.proxy_protocol {
+            my_server.add_service(service);
+        }
     }
 
     if args.autorestart || args.autoreload {
@@ -110,6 +110,18 @@ async fn diff_and_update_config(
         }
         if !exists_acme {
             hot_reload_config.certificates = new_config.certificates.clone();
+            // the ca certificate of client verification is loaded when
+            // the server is started, so it can't be reloaded.
+            for server in current_config.servers.values() {
+                let Some(ca) = &server.tls_client_ca else {
+                    continue;
+                };
+                if let Some(cert) = current_config.certificates.get(ca) {
+                    hot_reload_config
+                        .certificates
+                        .insert(ca.clone(), cert.clone());
+                }
+            }
         }
 
         // new_config.certificates
@@ -123,6 +135,8 @@ async fn diff_and_update_config(
                     if !exists_acme {
                         should_reload_certificate = true;
                     }
+                    // the client certificate of upstream may be modified
+                    should_reload_upstream = true;
                 },
                 _ => {},
             };
@@ -138,6 +152,7 @@ async fn diff_and_update_config(
         if should_reload_upstream {
             match try_update_upstreams(
                 &new_config.upstreams,
+                &new_config.certificates,
                 get_webhook_sender(),
             )
             .await
@@ -0,0 +1,281 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use http::Version;
+use pingap_core::{format_socket_addr, HedgePolicy};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::protocols::http::client::HttpSession;
+use pingora::upstreams::peer::{HttpPeer, Peer};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::sleep_until;
+
+/// Response of the fastest attempt, only the response header is read
+pub struct HedgeResponse {
+    /// Client session of upstream, the response body is not read yet
+    pub session: HttpSession,
+    pub peer: HttpPeer,
+    /// Whether the connection is reused
+    pub reused: bool,
+    /// Timestamp(ms) when connecting to upstream
+    pub connect_at: u64,
+    /// Timestamp(ms) when the request is sent to upstream
+    pub sent_at: u64,
+    /// Whether the response is from the hedged request
+    pub hedged: bool,
+}
+
+/// Result of the hedged attempts
+#[derive(Default)]
+pub struct HedgeResult {
+    /// Response of the fastest attempt, none if all attempts fail
+    pub response: Option<HedgeResponse>,
+    /// Number of hedged requests sent
+    pub hedged_requests: u32,
+    /// Addresses of the attempts that fail
+    pub failed_addresses: Vec<String>,
+    /// Addresses of the attempts that are cancelled by the fastest one
+    pub cancelled_addresses: Vec<String>,
+}
+
+/// Sends the request to the peer and reads the response header.
+async fn send_request(
+    connector: Arc<Connector>,
+    peer: HttpPeer,
+    mut req_header: RequestHeader,
+    hedged: bool,
+) -> (HttpPeer, pingora::Result<HedgeResponse>) {
+    let result = async {
+        let connect_at = pingap_util::now_ms();
+        let (mut session, reused) = connector.get_http_session(&peer).await?;
+        if let Some(timeout) = peer.options.read_timeout {
+            session.set_read_timeout(timeout);
+        }
+        if let Some(timeout) = peer.options.write_timeout {
+            session.set_write_timeout(timeout);
+        }
+        // the request of http2 downstream is sent to http1 upstream
+        if matches!(session, HttpSession::H1(_))
+            && req_header.version == Version::HTTP_2
+        {
+            req_header.set_version(Version::HTTP_11);
+        }
+        let sent_at = pingap_util::now_ms();
+        session.write_request_header(Box::new(req_header)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        Ok(HedgeResponse {
+            session,
+            peer: peer.clone(),
+            reused,
+            connect_at,
+            sent_at,
+            hedged,
+        })
+    }
+    .await;
+    (peer, result)
+}
+
+/// Sends the request to the first peer, if the response header is not
+/// received within the delay of hedge policy, another request is sent to
+/// the peer selected by `next_peer` (the addresses of sent attempts are
+/// excluded), the fastest response wins and the other attempts are cancelled.
+/// The connector should be created with the connector options of the server,
+/// the same as the connector of the normal proxy.
+pub async fn send_hedged_request<F>(
+    connector: Arc<Connector>,
+    first: HttpPeer,
+    req_header: &RequestHeader,
+    policy: &HedgePolicy,
+    mut next_peer: F,
+) -> HedgeResult
+where
+    F: FnMut(&[String]) -> Option<HttpPeer>,
+{
+    let mut result = HedgeResult::default();
+    let mut addresses = vec![format_socket_addr(first.address())];
+    let mut pending = JoinSet::new();
+    pending.spawn(send_request(
+        connector.clone(),
+        first,
+        req_header.clone(),
+        false,
+    ));
+    let delay = policy.delay();
+    let mut deadline = delay.map(|value| tokio::time::Instant::now() + value);
+    // addresses of the attempts in flight
+    let mut in_flight = addresses.clone();
+
+    loop {
+        let timer = async move {
+            match deadline {
+                Some(value) => sleep_until(value).await,
+                None => std::future::pending::<()>().await,
+            }
+        };
+        tokio::select! {
+            joined = pending.join_next() => {
+                let Some(Ok((peer, response))) = joined else {
+                    break;
+                };
+                let address = format_socket_addr(peer.address());
+                in_flight.retain(|item| item != &address);
+                match response {
+                    Ok(response) => {
+                        result.response = Some(response);
+                        break;
+                    },
+                    Err(_) => {
+                        result.failed_addresses.push(address);
+                        // fallback to the normal proxy if all attempts fail
+                        if pending.is_empty() {
+                            break;
+                        }
+                    },
+                }
+            },
+            _ = timer => {
+                deadline = None;
+                if result.hedged_requests >= policy.max_attempts()
+                    || !policy.acquire()
+                {
+                    continue;
+                }
+                let Some(peer) = next_peer(&addresses) else {
+                    continue;
+                };
+                let address = format_socket_addr(peer.address());
+                addresses.push(address.clone());
+                in_flight.push(address);
+                result.hedged_requests += 1;
+                pending.spawn(send_request(
+                    connector.clone(),
+                    peer,
+                    req_header.clone(),
+                    true,
+                ));
+                deadline =
+                    delay.map(|value| tokio::time::Instant::now() + value);
+            },
+        }
+    }
+    // the attempts in flight are aborted when the join set is dropped
+    result.cancelled_addresses = in_flight;
+    result
+}
+
+/// Releases the client session of the finished response to the pool
+pub async fn release_hedge_session(
+    connector: &Connector,
+    session: HttpSession,
+    peer: &HttpPeer,
+) {
+    connector
+        .release_http_session(session, peer, peer.options.idle_timeout)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn new_server(delay: Duration, status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    tokio::time::sleep(delay).await;
+                    let resp = format!(
+                        "HTTP/1.1 {status}\r\nContent-Length: 2\r\n\r\nok"
+                    );
+                    let _ = stream.write_all(resp.as_bytes()).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_send_hedged_request() {
+        let slow = new_server(Duration::from_secs(3), "200 OK").await;
+        let fast = new_server(Duration::from_millis(0), "201 Created").await;
+        let policy =
+            HedgePolicy::new(Some(Duration::from_millis(50)), None, 1, 0)
+                .unwrap();
+        let mut req_header =
+            RequestHeader::build("GET", b"/users", None).unwrap();
+        req_header.insert_header("Host", "github.com").unwrap();
+
+        let connector = Arc::new(Connector::new(None));
+        let mut excluded = vec![];
+        let result = send_hedged_request(
+            connector.clone(),
+            HttpPeer::new(slow.clone(), false, "".to_string()),
+            &req_header,
+            &policy,
+            |addresses| {
+                excluded = addresses.to_vec();
+                Some(HttpPeer::new(fast.clone(), false, "".to_string()))
+            },
+        )
+        .await;
+        assert_eq!(vec![slow.clone()], excluded);
+        assert_eq!(1, result.hedged_requests);
+        assert_eq!(vec![slow.clone()], result.cancelled_addresses);
+        let response = result.response.unwrap();
+        assert_eq!(true, response.hedged);
+        assert_eq!(fast, response.peer.address().to_string());
+        assert_eq!(
+            201,
+            response.session.response_header().unwrap().status.as_u16()
+        );
+
+        // the first request responds within the delay
+        let result = send_hedged_request(
+            connector.clone(),
+            HttpPeer::new(fast.clone(), false, "".to_string()),
+            &req_header,
+            &policy,
+            |_| Some(HttpPeer::new(slow.clone(), false, "".to_string())),
+        )
+        .await;
+        assert_eq!(0, result.hedged_requests);
+        assert_eq!(true, result.cancelled_addresses.is_empty());
+        assert_eq!(false, result.response.unwrap().hedged);
+
+        // all attempts fail
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let result = send_hedged_request(
+            connector,
+            HttpPeer::new(closed.clone(), false, "".to_string()),
+            &req_header,
+            &policy,
+            |_| None,
+        )
+        .await;
+        assert_eq!(true, result.response.is_none());
+        assert_eq!(vec![closed], result.failed_addresses);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use http::header;
+use once_cell::sync::Lazy;
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::upstreams::peer::HttpPeer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Connector of mirror requests, the connections are reused
+static MIRROR_CONNECTOR: Lazy<Connector> = Lazy::new(|| Connector::new(None));
+
+// Default read/write timeout of mirror request
+const MIRROR_DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Maximum number of the mirror requests in flight
+const MIRROR_MAX_CONCURRENCY: usize = 512;
+
+// Limits the mirror requests in flight, so a slow mirror upstream
+// can't pile up the background tasks and buffered bodies
+static MIRROR_PERMITS: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new(MIRROR_MAX_CONCURRENCY)));
+
+/// Acquires a permit of mirror request, returns `None` if too many
+/// mirror requests are in flight and the request should not be mirrored.
+/// The permit should be held until the mirror request is done.
+pub fn try_acquire_mirror_permit() -> Option<OwnedSemaphorePermit> {
+    MIRROR_PERMITS.clone().try_acquire_owned().ok()
+}
+
+/// Sends the copy of the request to the mirror peer,
+/// the response body is discarded and the connection is released to the pool.
+///
+/// # Returns
+/// * `Ok(u16)` - The response status code of the mirror request
+pub async fn send_mirror_request(
+    peer: &HttpPeer,
+    mut req_header: RequestHeader,
+    body: Option<Bytes>,
+) -> pingora::Result<u16> {
+    let body = body.unwrap_or_default();
+    // the body is fully buffered, so send it with content-length
+    if !body.is_empty()
+        || req_header.headers.contains_key(header::CONTENT_LENGTH)
+        || req_header.headers.contains_key(header::TRANSFER_ENCODING)
+    {
+        req_header.remove_header(&header::TRANSFER_ENCODING);
+        req_header
+            .insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    }
+
+    let (mut session, _) = MIRROR_CONNECTOR.get_http_session(peer).await?;
+    session.set_read_timeout(
+        peer.options.read_timeout.unwrap_or(MIRROR_DEFAULT_TIMEOUT),
+    );
+    session.set_write_timeout(
+        peer.options.write_timeout.unwrap_or(MIRROR_DEFAULT_TIMEOUT),
+    );
+    session.write_request_header(Box::new(req_header)).await?;
+    if !body.is_empty() {
+        session.write_request_body(body, true).await?;
+    }
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let status = session
+        .response_header()
+        .map(|header| header.status.as_u16())
+        .unwrap_or_default();
+    // drain the response body, so the connection can be reused
+    while session.read_response_body().await?.is_some() {}
+    MIRROR_CONNECTOR
+        .release_http_session(session, peer, peer.options.idle_timeout)
+        .await;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_send_mirror_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data = vec![];
+            let mut buf = [0; 1024];
+            while !String::from_utf8_lossy(&data).ends_with("pingap") {
+                let size = stream.read(&mut buf).await.unwrap();
+                if size == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..size]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+                )
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&data).to_string()
+        });
+
+        let mut req_header =
+            RequestHeader::build("POST", b"/users", None).unwrap();
+        req_header.insert_header("Host", "github.com").unwrap();
+        req_header
+            .insert_header("Transfer-Encoding", "chunked")
+            .unwrap();
+        let peer = HttpPeer::new(addr, false, "".to_string());
+        let status = send_mirror_request(
+            &peer,
+            req_header,
+            Some(Bytes::from_static(b"pingap")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(201, status);
+
+        let data = server.await.unwrap().to_lowercase();
+        assert_eq!(true, data.starts_with("post /users http/1.1\r\n"));
+        assert_eq!(true, data.contains("content-length: 6\r\n"));
+        assert_eq!(false, data.contains("transfer-encoding"));
+    }
+
+    #[test]
+    fn test_try_acquire_mirror_permit() {
+        let permits: Vec<_> = (0..MIRROR_MAX_CONCURRENCY)
+            .map_while(|_| try_acquire_mirror_permit())
+            .collect();
+        assert_eq!(MIRROR_MAX_CONCURRENCY, permits.len());
+        assert_eq!(true, try_acquire_mirror_permit().is_none());
+        drop(permits);
+        assert_eq!(true, try_acquire_mirror_permit().is_some());
+    }
+}
@@ -12,8 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod hedge;
+mod mirror;
+mod proxy_protocol;
 mod server;
 mod server_conf;
+mod stream;
+mod udp;
 
 pub static LOG_CATEGORY: &str = "proxy";
 
@@ -21,3 +26,5 @@ pub static LOG_CATEGORY: &str = "proxy";
 #[allow(unused_imports)]
 pub use server::*;
 pub use server_conf::{parse_from_conf, ServerConf};
+pub use stream::StreamServer;
+pub use udp::{UdpServer, UdpServerService};
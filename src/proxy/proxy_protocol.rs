@@ -0,0 +1,406 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::LOG_CATEGORY;
+use async_trait::async_trait;
+use pingap_core::{
+    add_proxy_protocol_connection, parse_proxy_protocol,
+    remove_proxy_protocol_connection, ProxyProtocolAddrs, ProxyProtocolHeader,
+};
+use pingap_util::IpRules;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
+
+// Timeout of reading the PROXY header from the new connection
+const PROXY_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Backlog of the PROXY protocol listener
+const LISTENER_BACKLOG: u32 = 65535;
+
+// Reserves a loopback address for the internal listener of http proxy.
+// On linux the socket is kept bound(not listening) with SO_REUSEADDR,
+// so the port can't be allocated by others while pingora still can
+// listen on it(pingora sets SO_REUSEADDR as well).
+fn reserve_internal_addr() -> io::Result<(Option<TcpSocket>, SocketAddr)> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+    let addr = socket.local_addr()?;
+    // the other platforms don't allow binding the same address
+    // without SO_REUSEPORT, the port is released
+    if cfg!(target_os = "linux") {
+        Ok((Some(socket), addr))
+    } else {
+        Ok((None, addr))
+    }
+}
+
+// Binds the listen address of PROXY protocol, SO_REUSEPORT is set so
+// the new process of graceful upgrade can bind the address before the
+// old one exits.
+fn bind_listener(addr: &str) -> io::Result<TcpSocket> {
+    let Some(addr) = addr.to_socket_addrs()?.next() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid listen address {addr}"),
+        ));
+    };
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket)
+}
+
+// Listener of PROXY protocol, it's bound when the service is created
+struct RelayListener {
+    addr: String,
+    socket: TcpSocket,
+    internal_addr: SocketAddr,
+}
+
+// Original addresses of the relayed connection,
+// they are removed when the connection is closed
+struct RelayedConnection(SocketAddr);
+
+impl RelayedConnection {
+    fn new(relay_addr: SocketAddr, addrs: ProxyProtocolAddrs) -> Self {
+        add_proxy_protocol_connection(relay_addr, addrs);
+        Self(relay_addr)
+    }
+}
+
+impl Drop for RelayedConnection {
+    fn drop(&mut self) {
+        remove_proxy_protocol_connection(&self.0);
+    }
+}
+
+// Reads the data until the PROXY header is parsed or missing
+async fn read_proxy_header<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> io::Result<ProxyProtocolHeader>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut data = [0; 256];
+    loop {
+        match parse_proxy_protocol(buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            ProxyProtocolHeader::Incomplete => {},
+            header => return Ok(header),
+        }
+        let size = stream.read(&mut data).await?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&data[..size]);
+    }
+}
+
+/// Reads the PROXY header at the beginning of the accepted connection,
+/// the addresses are none if the header is missing or has no addresses.
+/// The data read after the header is returned, it should be processed
+/// before the data read from the connection later.
+pub async fn accept_proxy_header<S>(
+    stream: &mut S,
+) -> io::Result<(Option<ProxyProtocolAddrs>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![];
+    let header = tokio::time::timeout(
+        PROXY_HEADER_READ_TIMEOUT,
+        read_proxy_header(stream, &mut buf),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut addrs = None;
+    if let ProxyProtocolHeader::Parsed {
+        addrs: original,
+        size,
+    } = header
+    {
+        addrs = original;
+        buf.drain(..size);
+    }
+    Ok((addrs, buf))
+}
+
+// Relays the connection to the internal listener, the PROXY header
+// is only parsed if the connection is from the trusted source.
+async fn relay(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    trusted: bool,
+    internal_addr: SocketAddr,
+) -> io::Result<()> {
+    let mut addrs = ProxyProtocolAddrs {
+        source: peer_addr,
+        destination: stream.local_addr()?,
+    };
+    let mut buf = vec![];
+    if trusted {
+        let (original, data) = accept_proxy_header(&mut stream).await?;
+        if let Some(original) = original {
+            addrs = original;
+        }
+        buf = data;
+    }
+    let mut upstream = TcpStream::connect(internal_addr).await?;
+    upstream.set_nodelay(true)?;
+    let _connection = RelayedConnection::new(upstream.local_addr()?, addrs);
+    if !buf.is_empty() {
+        upstream.write_all(&buf).await?;
+    }
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+/// Listener of PROXY protocol v1/v2, the header is parsed and the connection
+/// is relayed to the internal listener of http proxy service, the original
+/// addresses are registered by the local address of the relayed connection.
+/// It's only used by http proxy service, because its listeners can't read
+/// the header before the tls handshake, the stream server reads the header
+/// in its accept path by `accept_proxy_header`.
+pub struct ProxyProtocolService {
+    name: String,
+    // the bound listeners, they are taken when the service starts
+    listeners: Mutex<Vec<RelayListener>>,
+    // the reserved internal addresses, kept until the service is dropped
+    _reserved: Vec<TcpSocket>,
+    // only the header of trusted sources is parsed
+    trusted_cidrs: IpRules,
+}
+
+impl ProxyProtocolService {
+    /// Creates a new PROXY protocol listener service,
+    /// no source is trusted if the trusted cidrs are empty.
+    pub fn new(name: &str, trusted_cidrs: &[String]) -> Self {
+        Self {
+            name: name.to_string(),
+            listeners: Mutex::new(vec![]),
+            _reserved: vec![],
+            trusted_cidrs: IpRules::new(&trusted_cidrs.to_vec()),
+        }
+    }
+    /// Binds the listen address and reserves the internal address of
+    /// http proxy service for it, returns the internal address.
+    pub fn add_listener(&mut self, addr: &str) -> io::Result<SocketAddr> {
+        let socket = bind_listener(addr).map_err(|e| {
+            io::Error::new(e.kind(), format!("bind {addr} fail: {e}"))
+        })?;
+        let (reserved, internal_addr) = reserve_internal_addr()?;
+        if let Some(reserved) = reserved {
+            self._reserved.push(reserved);
+        }
+        self.listeners
+            .get_mut()
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .push(RelayListener {
+                addr: addr.to_string(),
+                socket,
+                internal_addr,
+            });
+        Ok(internal_addr)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ProxyProtocolService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut accept_tasks = JoinSet::new();
+        let listeners = self
+            .listeners
+            .lock()
+            .map(|mut listeners| std::mem::take(&mut *listeners))
+            .unwrap_or_default();
+        for RelayListener {
+            addr,
+            socket,
+            internal_addr,
+        } in listeners
+        {
+            let listener = match socket.listen(LISTENER_BACKLOG) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        addr,
+                        "proxy protocol listener listen fail"
+                    );
+                    continue;
+                },
+            };
+            info!(
+                category = LOG_CATEGORY,
+                name = self.name,
+                addr,
+                internal_addr = internal_addr.to_string(),
+                "proxy protocol listener is listening"
+            );
+            let name = self.name.clone();
+            let trusted_cidrs = self.trusted_cidrs.clone();
+            accept_tasks.spawn(async move {
+                loop {
+                    let (stream, peer_addr) = match listener.accept().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            error!(
+                                category = LOG_CATEGORY,
+                                error = %e,
+                                name,
+                                "proxy protocol listener accept fail"
+                            );
+                            continue;
+                        },
+                    };
+                    let trusted = trusted_cidrs
+                        .is_match(&peer_addr.ip().to_string())
+                        .unwrap_or_default();
+                    let name = name.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            relay(stream, peer_addr, trusted, internal_addr)
+                                .await
+                        {
+                            debug!(
+                                category = LOG_CATEGORY,
+                                error = %e,
+                                name,
+                                peer_addr = peer_addr.to_string(),
+                                "proxy protocol relay fail"
+                            );
+                        }
+                    });
+                }
+            });
+        }
+        let _ = shutdown.changed().await;
+        // the accept loops are aborted when the join set is dropped,
+        // the relayed connections are closed by the http proxy service
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingap_core::get_proxy_protocol_connection;
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_relay() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal_addr = internal.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, relay_addr) = internal.accept().await.unwrap();
+            let mut buf = [0; 14];
+            stream.read_exact(&mut buf).await.unwrap();
+            let addrs = get_proxy_protocol_connection(&relay_addr);
+            stream.write_all(b"ok").await.unwrap();
+            (String::from_utf8_lossy(&buf).to_string(), addrs)
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            relay(stream, peer_addr, true, internal_addr).await
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1",
+            )
+            .await
+            .unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ok", &buf);
+
+        let (data, addrs) = server.await.unwrap();
+        assert_eq!("GET / HTTP/1.1", data);
+        assert_eq!(
+            Some(ProxyProtocolAddrs {
+                source: "192.168.1.1:56324".parse().unwrap(),
+                destination: "10.0.0.1:443".parse().unwrap(),
+            }),
+            addrs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_proxy_header() {
+        let mut data: &[u8] =
+            b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1";
+        let (addrs, buf) = accept_proxy_header(&mut data).await.unwrap();
+        assert_eq!(
+            Some(ProxyProtocolAddrs {
+                source: "192.168.1.1:56324".parse().unwrap(),
+                destination: "10.0.0.1:443".parse().unwrap(),
+            }),
+            addrs
+        );
+        assert_eq!(b"GET / HTTP/1.1", buf.as_slice());
+
+        // the data is kept if the header is missing
+        let mut data: &[u8] = b"GET / HTTP/1.1";
+        let (addrs, buf) = accept_proxy_header(&mut data).await.unwrap();
+        assert_eq!(None, addrs);
+        assert_eq!(b"GET / HTTP/1.1", buf.as_slice());
+
+        let mut data: &[u8] = b"PROXY TCP4 192.168.1.1";
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            accept_proxy_header(&mut data).await.unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_add_listener() {
+        let mut service = ProxyProtocolService::new("test", &[]);
+        let internal_addr = service.add_listener("127.0.0.1:0").unwrap();
+        assert_eq!(true, internal_addr.ip().is_loopback());
+        assert_eq!(1, service.listeners.lock().unwrap().len());
+        // the port of internal address is reserved
+        #[cfg(target_os = "linux")]
+        {
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.set_reuseaddr(false).unwrap();
+            assert_eq!(true, socket.bind(internal_addr).is_err());
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert_eq!(true, service.add_listener(&addr).is_err());
+    }
+}
@@ -12,22 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::hedge::{release_hedge_session, send_hedged_request};
+use super::mirror::{send_mirror_request, try_acquire_mirror_permit};
+use super::proxy_protocol::ProxyProtocolService;
 use super::{ServerConf, LOG_CATEGORY};
 use crate::plugin::{get_plugin, ADMIN_SERVER_PLUGIN};
 use ahash::AHashMap;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use http::StatusCode;
 use http::{HeaderName, HeaderValue};
+use http::{Method, StatusCode};
 use once_cell::sync::Lazy;
 use pingap_acme::handle_lets_encrypt;
-use pingap_certificate::{GlobalCertificate, TlsSettingParams};
+use pingap_certificate::{
+    get_client_certificate_info, GlobalCertificate, TlsSettingParams,
+};
 use pingap_config::get_config_storage;
 #[cfg(feature = "full")]
 use pingap_core::OtelTracer;
 use pingap_core::SimpleServiceTaskFuture;
 use pingap_core::{convert_header_value, convert_headers, HttpHeader};
+use pingap_core::{format_socket_addr, RetryCondition, RetryPolicy};
 use pingap_core::{get_cache_key, CompressionStat, Ctx, PluginStep};
 use pingap_core::{HttpResponse, HTTP_HEADER_NAME_X_REQUEST_ID};
 use pingap_location::{get_location, Location};
@@ -54,6 +60,8 @@ use pingora::cache::filters::resp_cacheable;
 use pingora::cache::{
     CacheKey, CacheMetaDefaults, NoCacheReason, RespCacheable,
 };
+use pingora::connectors::http::Connector;
+use pingora::connectors::ConnectorOptions;
 use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::listeners::TcpSocketOptions;
 use pingora::modules::http::compression::{
@@ -67,6 +75,7 @@ use pingora::protocols::TimingDigest;
 use pingora::proxy::{http_proxy_service, FailToProxy, HttpProxy};
 use pingora::proxy::{ProxyHttp, Session};
 use pingora::server::configuration;
+use pingora::services::background::{background_service, GenBackgroundService};
 use pingora::services::listening::Service;
 use pingora::upstreams::peer::{HttpPeer, Peer};
 use scopeguard::defer;
@@ -180,6 +189,12 @@ pub struct Server {
     /// Maximum TLS protocol version to accept
     tls_max_version: Option<String>,
 
+    /// CA certificates to verify the client certificate
+    tls_client_ca: Option<Vec<u8>>,
+
+    /// Whether the client certificate is required
+    tls_client_auth_required: bool,
+
     /// Whether HTTP/2 protocol is enabled
     enabled_h2: bool,
 
@@ -212,10 +227,22 @@ pub struct Server {
 
     /// Whether to enable server-timing header
     enable_server_timing: bool,
+
+    /// Whether to accept PROXY protocol header on the listeners
+    proxy_protocol: bool,
+
+    /// Trusted source ips or cidrs of PROXY protocol header
+    proxy_protocol_trusted_cidrs: Vec<String>,
+
+    /// Connector of hedged requests, it's created with the connector
+    /// options of server when the server runs
+    hedge_connector: Option<Arc<Connector>>,
 }
 
 pub struct ServerServices {
     pub lb: Service<HttpProxy<Server>>,
+    /// Listener service of PROXY protocol, it relays the connections to `lb`
+    pub proxy_protocol: Option<GenBackgroundService<ProxyProtocolService>>,
 }
 
 const META_DEFAULTS: CacheMetaDefaults =
@@ -275,6 +302,8 @@ impl Server {
             tls_ciphersuites: conf.tls_ciphersuites.clone(),
             tls_min_version: conf.tls_min_version.clone(),
             tls_max_version: conf.tls_max_version.clone(),
+            tls_client_ca: conf.tls_client_ca.clone(),
+            tls_client_auth_required: conf.tls_client_auth_required,
             threads: conf.threads,
             lets_encrypt_enabled: false,
             global_certificates: conf.global_certificates,
@@ -289,6 +318,11 @@ impl Server {
             prometheus,
             enable_server_timing: conf.enable_server_timing,
             modules: conf.modules.clone(),
+            proxy_protocol: conf.proxy_protocol,
+            proxy_protocol_trusted_cidrs: conf
+                .proxy_protocol_trusted_cidrs
+                .clone(),
+            hedge_connector: None,
         };
         Ok(s)
     }
@@ -338,9 +372,13 @@ impl Server {
     /// - Initializes HTTP/2 support
     /// - Configures thread pool
     pub fn run(
-        self,
+        mut self,
         conf: &Arc<configuration::ServerConf>,
     ) -> Result<ServerServices> {
+        // the hedged requests use the same connector options as the proxy
+        self.hedge_connector = Some(Arc::new(Connector::new(Some(
+            ConnectorOptions::from_server_conf(conf),
+        ))));
         let addr = self.addr.clone();
         let tcp_socket_options = self.tcp_socket_options.clone();
 
@@ -352,6 +390,15 @@ impl Server {
         }
 
         let is_tls = dynamic_cert.is_some();
+        // the client certificate can't be verified without tls
+        if self.tls_client_ca.is_some() && !is_tls {
+            return Err(Error::Common {
+                category: "tls_client_ca".to_string(),
+                message: format!(
+                    "tls client ca is set but tls is not enabled(server:{name})"
+                ),
+            });
+        }
 
         let enabled_h2 = self.enabled_h2;
         let threads = if let Some(threads) = self.threads {
@@ -380,6 +427,18 @@ impl Server {
         let cipher_suites = self.tls_ciphersuites.clone();
         let tls_min_version = self.tls_min_version.clone();
         let tls_max_version = self.tls_max_version.clone();
+        let tls_client_ca = self.tls_client_ca.clone();
+        let tls_client_auth_required = self.tls_client_auth_required;
+        // the connections are accepted by the PROXY protocol listener
+        // and relayed to the internal listeners of http proxy service
+        let mut proxy_protocol_service = if self.proxy_protocol {
+            Some(ProxyProtocolService::new(
+                &name,
+                &self.proxy_protocol_trusted_cidrs,
+            ))
+        } else {
+            None
+        };
         let mut lb = http_proxy_service(conf, self);
         // use h2c if not tls and enable http2
         if !is_tls && enabled_h2 {
@@ -392,6 +451,18 @@ impl Server {
         lb.threads = threads;
         // support listen multi address
         for addr in addr.split(',') {
+            let internal_addr;
+            let addr = if let Some(service) = &mut proxy_protocol_service {
+                let value =
+                    service.add_listener(addr).map_err(|e| Error::Common {
+                        category: "proxy_protocol".to_string(),
+                        message: e.to_string(),
+                    })?;
+                internal_addr = value.to_string();
+                internal_addr.as_str()
+            } else {
+                addr
+            };
             // tls
             if let Some(dynamic_cert) = &dynamic_cert {
                 let tls_settings = dynamic_cert
@@ -402,6 +473,8 @@ impl Server {
                         cipher_suites: cipher_suites.clone(),
                         tls_min_version: tls_min_version.clone(),
                         tls_max_version: tls_max_version.clone(),
+                        client_ca: tls_client_ca.clone(),
+                        client_auth_required: tls_client_auth_required,
                     })
                     .map_err(|e| Error::Common {
                         category: "tls".to_string(),
@@ -418,7 +491,13 @@ impl Server {
                 lb.add_tcp(addr);
             }
         }
-        Ok(ServerServices { lb })
+        let proxy_protocol = proxy_protocol_service.map(|service| {
+            let mut service =
+                background_service(&format!("proxy protocol:{name}"), service);
+            service.threads = threads;
+            service
+        });
+        Ok(ServerServices { lb, proxy_protocol })
     }
     /// Handles requests to the admin interface.
     /// Processes admin-specific plugins and returns response if handled.
@@ -456,6 +535,8 @@ struct DigestDetail {
     tls_version: Option<String>,
     /// TLS cipher suite in use if using HTTPS
     tls_cipher: Option<String>,
+    /// Sha256 digest of the client certificate if it's verified
+    tls_client_cert_digest: Option<Vec<u8>>,
 }
 
 /// Extracts timing and TLS information from connection digest.
@@ -500,6 +581,11 @@ fn get_digest_detail(digest: &Digest) -> DigestDetail {
         tls_established: get_established(digest.timing_digest.last()),
         tls_version: Some(ssl_digest.version.to_string()),
         tls_cipher: Some(ssl_digest.cipher.to_string()),
+        tls_client_cert_digest: if ssl_digest.cert_digest.is_empty() {
+            None
+        } else {
+            Some(ssl_digest.cert_digest.clone())
+        },
     }
 }
 
@@ -650,6 +736,215 @@ impl Server {
         }
         Ok(())
     }
+
+    /// Sends the copy of the request to the mirror upstream in background,
+    /// the mirror request doesn't affect the response of client.
+    pub fn mirror_request(&self, session: &Session, ctx: &mut Ctx) {
+        let Some(name) = ctx.mirror_upstream.take() else {
+            return;
+        };
+        let body = ctx.mirror_body.take().map(|body| body.freeze());
+        // the mirror request is dropped if too many are in flight
+        let Some(permit) = try_acquire_mirror_permit() else {
+            debug!(
+                category = LOG_CATEGORY,
+                upstream = name,
+                "too many mirror requests, the request is not mirrored"
+            );
+            return;
+        };
+        let Some(up) = get_upstream(&name) else {
+            return;
+        };
+        let Some(peer) = up.new_http_peer(session, &ctx.client_ip, &[]) else {
+            up.completed();
+            return;
+        };
+        let req_header = session.req_header().clone();
+        #[cfg(feature = "full")]
+        let prometheus = self.prometheus.clone();
+        tokio::spawn(async move {
+            let now = Instant::now();
+            let address = format_socket_addr(peer.address());
+            let result = send_mirror_request(&peer, req_header, body).await;
+            drop(permit);
+            let elapsed = now.elapsed().as_millis() as u64;
+            up.completed();
+            up.backend_completed(&address, result.is_ok().then_some(elapsed));
+            match &result {
+                Ok(status) => debug!(
+                    category = LOG_CATEGORY,
+                    upstream = up.name,
+                    address,
+                    status,
+                    elapsed,
+                    "mirror request is done"
+                ),
+                Err(e) => debug!(
+                    category = LOG_CATEGORY,
+                    upstream = up.name,
+                    address,
+                    error = %e,
+                    elapsed,
+                    "mirror request fail"
+                ),
+            };
+            #[cfg(feature = "full")]
+            if let Some(prom) = prometheus {
+                prom.mirror(&up.name, result.ok(), elapsed);
+            }
+        });
+    }
+
+    /// Proxies the idempotent request without body by the hedge policy of location,
+    /// the fastest response of the attempts is written to downstream.
+    ///
+    /// # Returns
+    /// * `Ok(true)` - The response is written to downstream
+    /// * `Ok(false)` - The request should be proxied normally, e.g. all attempts fail
+    pub async fn proxy_hedged_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut Ctx,
+    ) -> pingora::Result<bool> {
+        let Some(location) = get_location(&ctx.location) else {
+            return Ok(false);
+        };
+        let Some(policy) = location.hedge.clone() else {
+            return Ok(false);
+        };
+        let Some(connector) = self.hedge_connector.clone() else {
+            return Ok(false);
+        };
+        let method = &session.req_header().method;
+        if (method != Method::GET && method != Method::HEAD)
+            || session.cache.enabled()
+            || session.is_upgrade_req()
+            || !session.is_body_empty()
+        {
+            return Ok(false);
+        }
+        let upstream = location.select_upstream(session.req_header());
+        let Some(up) = get_upstream_with_variables(upstream, ctx) else {
+            return Ok(false);
+        };
+        // the upstream of normal proxy is the same as the hedged request
+        ctx.upstream = up.name.clone();
+        if let Some(status) = up.circuit_breaker_acquire() {
+            return Err(pingap_core::new_internal_error(
+                status,
+                format!("Circuit breaker of upstream {} is open", up.name),
+            ));
+        }
+        // the normal proxy doesn't acquire again if the hedged request falls back
+        ctx.upstream_circuit_breaker_acquired = true;
+        let Some(peer) = up.new_http_peer(session, &ctx.client_ip, &[]) else {
+            up.completed();
+            return Ok(false);
+        };
+        policy.on_request();
+        let mut req_header = session.req_header().clone();
+        self.upstream_request_filter(session, &mut req_header, ctx)
+            .await?;
+
+        let client_ip = ctx.client_ip.clone();
+        let result = send_hedged_request(
+            connector.clone(),
+            peer,
+            &req_header,
+            &policy,
+            |excluded| up.new_http_peer(session, &client_ip, excluded),
+        )
+        .await;
+        ctx.upstream_hedged_requests += result.hedged_requests;
+        for address in result.cancelled_addresses.iter() {
+            up.completed();
+            up.backend_completed(address, None);
+        }
+        for address in result.failed_addresses {
+            up.completed();
+            up.backend_completed(&address, None);
+            up.record_backend_result(&address, false);
+            ctx.upstream_failed_addresses
+                .get_or_insert_with(Vec::new)
+                .push(address);
+        }
+        let Some(mut resp) = result.response else {
+            return Ok(false);
+        };
+        ctx.upstream_address = format_socket_addr(resp.peer.address());
+        let Some(header) = resp.session.response_header() else {
+            return Ok(false);
+        };
+        let mut header = Box::new(header.clone());
+        ctx.upstream_hedge_won = resp.hedged;
+        ctx.upstream_connect_time = Some(resp.connect_at);
+        set_upstream_connected(ctx, resp.reused, resp.session.digest());
+        // the start time of processing, it's calculated in upstream response filter
+        ctx.upstream_processing_time = Some(resp.sent_at);
+
+        if let Err(e) = self.upstream_response_filter(session, &mut header, ctx)
+        {
+            // the status is retryable, the backend is released
+            // and the request is retried by the normal proxy
+            if e.retry() {
+                ctx.upstream_connect_time = None;
+                ctx.upstream_processing_time = None;
+                return Ok(false);
+            }
+            return Err(e);
+        }
+        self.response_filter(session, &mut header, ctx).await?;
+        session.write_response_header(header, false).await?;
+        // the response header is written, so the error of body can't be
+        // responded to downstream, the connection is closed instead
+        let result = async {
+            loop {
+                let mut body = resp.session.read_response_body().await?;
+                let end_of_stream =
+                    body.is_none() || resp.session.response_done();
+                self.upstream_response_body_filter(
+                    session,
+                    &mut body,
+                    end_of_stream,
+                    ctx,
+                )?;
+                self.response_body_filter(
+                    session,
+                    &mut body,
+                    end_of_stream,
+                    ctx,
+                )?;
+                session.write_response_body(body, end_of_stream).await?;
+                if end_of_stream {
+                    break;
+                }
+            }
+            session.finish_body().await
+        }
+        .await;
+        match result {
+            Ok(_) => {
+                release_hedge_session(&connector, resp.session, &resp.peer)
+                    .await;
+            },
+            Err(e) => {
+                error!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    upstream = up.name,
+                    address = ctx.upstream_address,
+                    "proxy hedged response body fail"
+                );
+                if e.esource() == &pingora::ErrorSource::Upstream {
+                    up.record_backend_result(&ctx.upstream_address, false);
+                }
+                session.as_mut().set_keepalive(None);
+                resp.session.shutdown().await;
+            },
+        }
+        Ok(true)
+    }
 }
 
 #[inline]
@@ -668,6 +963,87 @@ fn get_upstream_with_variables(
     }
 }
 
+/// Records the connection details of upstream, the connect and processing
+/// time are converted from the start timestamp to the latency.
+fn set_upstream_connected(
+    ctx: &mut Ctx,
+    reused: bool,
+    digest: Option<&Digest>,
+) {
+    if let Some(digest) = digest {
+        let detail = get_digest_detail(digest);
+        if !reused {
+            let upstream_connect_time =
+                ctx.upstream_connect_time.unwrap_or_default();
+            if upstream_connect_time > 0
+                && detail.tcp_established > upstream_connect_time
+            {
+                ctx.upstream_tcp_connect_time =
+                    Some(detail.tcp_established - upstream_connect_time);
+            }
+            if detail.tls_established > detail.tcp_established {
+                ctx.upstream_tls_handshake_time =
+                    Some(detail.tls_established - detail.tcp_established);
+            }
+        }
+        ctx.upstream_connection_time = Some(detail.connection_time);
+    }
+
+    ctx.upstream_reused = reused;
+    ctx.upstream_connect_time =
+        pingap_util::get_latency(&ctx.upstream_connect_time);
+    ctx.upstream_processing_time =
+        pingap_util::get_latency(&ctx.upstream_processing_time);
+}
+
+/// Gets the retry policy of the request,
+/// the policy of location takes precedence over the policy of upstream.
+fn get_retry_policy(ctx: &Ctx) -> Option<Arc<RetryPolicy>> {
+    if let Some(policy) =
+        get_location(&ctx.location).and_then(|location| location.retry.clone())
+    {
+        return Some(policy);
+    }
+    get_upstream_with_variables(&ctx.upstream, ctx)
+        .and_then(|up| up.retry_policy())
+}
+
+/// Records the retry of the request, the failed backend
+/// is excluded when selecting the backend for the next attempt.
+fn add_upstream_retry(ctx: &mut Ctx) {
+    ctx.upstream_retries += 1;
+    if !ctx.upstream_address.is_empty() {
+        ctx.upstream_failed_addresses
+            .get_or_insert_with(Vec::new)
+            .push(ctx.upstream_address.clone());
+    }
+}
+
+/// Decides whether the failed request to upstream can be retried by the retry policy,
+/// the failure of the backend is recorded for the passive health check if retried,
+/// because `fail_to_proxy` is only called for the final failure.
+fn retry_upstream_error(
+    session: &Session,
+    ctx: &mut Ctx,
+    e: &pingora::Error,
+    connecting: bool,
+) -> Option<bool> {
+    let policy = get_retry_policy(ctx)?;
+    let condition = RetryCondition::from_error(e, connecting)?;
+    let retry = policy.can_retry(
+        &session.req_header().method,
+        ctx.upstream_retries,
+        condition,
+    );
+    if retry {
+        if let Some(up) = get_upstream_with_variables(&ctx.upstream, ctx) {
+            up.record_backend_result(&ctx.upstream_address, false);
+        }
+        add_upstream_retry(ctx);
+    }
+    Some(retry)
+}
+
 #[async_trait]
 impl ProxyHttp for Server {
     type CTX = Ctx;
@@ -728,22 +1104,53 @@ impl ProxyHttp for Server {
             }
             ctx.tls_cipher = digest_detail.tls_cipher;
             ctx.tls_version = digest_detail.tls_version;
+            // the client certificate is verified in the tls handshake
+            if let Some(cert_digest) = digest_detail.tls_client_cert_digest {
+                ctx.tls_client_fingerprint = Some(hex::encode(cert_digest));
+            }
         };
+        // the ssl of connection isn't exposed by http2 session,
+        // so only the fingerprint of client certificate is set for it
+        if let Some(cert) = session
+            .stream()
+            .and_then(|stream| stream.get_ssl())
+            .and_then(|ssl| ssl.peer_certificate())
+        {
+            let info = get_client_certificate_info(&cert);
+            ctx.tls_client_subject = Some(info.subject);
+            ctx.tls_client_san = Some(info.san);
+        }
         accept_request();
 
         ctx.processing = self.processing.fetch_add(1, Ordering::Relaxed) + 1;
         ctx.accepted = self.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        // the internal listener only serves the connections relayed by the
+        // PROXY protocol listener, so the trusted check can't be bypassed
+        // by connecting to the internal address directly
+        if self.proxy_protocol
+            && session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .and_then(pingap_core::get_proxy_protocol_connection)
+                .is_none()
+        {
+            return Err(pingap_core::new_internal_error(
+                403,
+                "Connection is not relayed by proxy protocol listener"
+                    .to_string(),
+            ));
+        }
         if let Some((remote_addr, remote_port)) =
             pingap_core::get_remote_addr(session)
         {
             ctx.remote_addr = Some(remote_addr);
             ctx.remote_port = Some(remote_port);
         }
-        if let Some(addr) =
-            session.server_addr().and_then(|addr| addr.as_inet())
+        if let Some((server_addr, server_port)) =
+            pingap_core::get_server_addr(session)
         {
-            ctx.server_addr = Some(addr.ip().to_string());
-            ctx.server_port = Some(addr.port());
+            ctx.server_addr = Some(server_addr);
+            ctx.server_port = Some(server_port);
         }
 
         let header = session.req_header();
@@ -937,6 +1344,10 @@ impl ProxyHttp for Server {
         if done {
             return Ok(true);
         }
+        // the request is mirrored after its body is received
+        if let Some(upstream) = location.sample_mirror() {
+            ctx.mirror_upstream = Some(upstream.to_string());
+        }
 
         Ok(false)
     }
@@ -967,6 +1378,9 @@ impl ProxyHttp for Server {
                 return Ok(false);
             }
         }
+        if self.proxy_hedged_request(session, ctx).await? {
+            return Ok(false);
+        }
         Ok(true)
     }
 
@@ -982,13 +1396,49 @@ impl ProxyHttp for Server {
         let mut location_name = "unknown".to_string();
         let peer = if let Some(location) = get_location(&ctx.location) {
             location_name.clone_from(&location.name);
-            if let Some(up) =
-                get_upstream_with_variables(&location.upstream, ctx)
-            {
+            // the retry attempts use the upstream of the first attempt
+            let upstream = if ctx.upstream.is_empty() {
+                location.select_upstream(session.req_header()).to_string()
+            } else {
+                ctx.upstream.clone()
+            };
+            if let Some(up) = get_upstream_with_variables(&upstream, ctx) {
+                // reject the request immediately if the circuit is open,
+                // it has been acquired by the hedged request which falls back
+                let status = if std::mem::take(
+                    &mut ctx.upstream_circuit_breaker_acquired,
+                ) {
+                    None
+                } else {
+                    up.circuit_breaker_acquire()
+                };
+                if let Some(status) = status {
+                    return Err(pingap_core::new_internal_error(
+                        status,
+                        format!(
+                            "Circuit breaker of upstream {} is open",
+                            up.name
+                        ),
+                    ));
+                }
+                // release the backend selected by the previous attempt
+                if !ctx.upstream_address.is_empty() {
+                    if let Some(prev) =
+                        get_upstream_with_variables(&ctx.upstream, ctx)
+                    {
+                        prev.completed();
+                        prev.backend_completed(&ctx.upstream_address, None);
+                    }
+                } else if let Some(policy) =
+                    location.retry.clone().or_else(|| up.retry_policy())
+                {
+                    // the first attempt of the request
+                    policy.on_request();
+                }
                 ctx.upstream_connected = up.connected();
                 #[cfg(feature = "full")]
                 if let Some(tracer) = &ctx.otel_tracer {
-                    let name = format!("upstream.{}", &location.upstream);
+                    let name = format!("upstream.{}", &up.name);
                     let mut span = tracer.new_upstream_span(&name);
                     span.set_attribute(KeyValue::new(
                         "upstream.connected",
@@ -996,9 +1446,17 @@ impl ProxyHttp for Server {
                     ));
                     ctx.upstream_span = Some(span);
                 }
-                let peer =
-                    up.new_http_peer(session, &ctx.client_ip).inspect(|peer| {
-                        ctx.upstream_address = peer.address().to_string();
+                let peer = up
+                    .new_http_peer(
+                        session,
+                        &ctx.client_ip,
+                        ctx.upstream_failed_addresses
+                            .as_deref()
+                            .unwrap_or_default(),
+                    )
+                    .inspect(|peer| {
+                        ctx.upstream_address =
+                            format_socket_addr(peer.address());
                     });
                 ctx.upstream = up.name.clone();
                 peer
@@ -1020,6 +1478,43 @@ impl ProxyHttp for Server {
 
         Ok(Box::new(peer))
     }
+    /// Called when fail to connect to upstream.
+    /// Decides whether to retry another backend by the retry policy.
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        if let Some(retry) = retry_upstream_error(session, ctx, &e, true) {
+            e.set_retry(retry);
+        }
+        e
+    }
+    /// Called when there is an error after connection is established to upstream.
+    /// Decides whether to retry another backend by the retry policy,
+    /// the request can't be retried if its body buffer is truncated.
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        let truncated = session.as_ref().retry_buffer_truncated();
+        e.retry.decide_reuse(client_reused && !truncated);
+        // retry of reused connection or retryable status
+        if e.retry() || truncated {
+            return e;
+        }
+        if let Some(true) = retry_upstream_error(session, ctx, &e, false) {
+            e.set_retry(true);
+        }
+        e
+    }
     /// Called when connection is established to upstream.
     /// Records timing metrics and TLS details.
     async fn connected_to_upstream(
@@ -1037,31 +1532,7 @@ impl ProxyHttp for Server {
     {
         debug!(category = LOG_CATEGORY, "--> connected to upstream");
         defer!(debug!(category = LOG_CATEGORY, "<-- connected to upstream"););
-        if let Some(digest) = digest {
-            let detail = get_digest_detail(digest);
-            if !reused {
-                let upstream_connect_time =
-                    ctx.upstream_connect_time.unwrap_or_default();
-                if upstream_connect_time > 0
-                    && detail.tcp_established > upstream_connect_time
-                {
-                    ctx.upstream_tcp_connect_time =
-                        Some(detail.tcp_established - upstream_connect_time);
-                }
-                if detail.tls_established > detail.tcp_established {
-                    ctx.upstream_tls_handshake_time =
-                        Some(detail.tls_established - detail.tcp_established);
-                }
-            }
-            ctx.upstream_connection_time = Some(detail.connection_time);
-        }
-
-        ctx.upstream_reused = reused;
-        ctx.upstream_connect_time =
-            pingap_util::get_latency(&ctx.upstream_connect_time);
-        ctx.upstream_processing_time =
-            pingap_util::get_latency(&ctx.upstream_processing_time);
-
+        set_upstream_connected(ctx, reused, digest);
         Ok(())
     }
     /// Filters upstream request before sending.
@@ -1082,11 +1553,12 @@ impl ProxyHttp for Server {
     }
     /// Filters request body chunks before sending upstream.
     /// Tracks payload size and enforces size limits.
+    /// Buffers the body for the mirror request.
     async fn request_body_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         body: &mut Option<Bytes>,
-        _end_of_stream: bool,
+        end_of_stream: bool,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()>
     where
@@ -1100,8 +1572,22 @@ impl ProxyHttp for Server {
                 location.client_body_size_limit(ctx.payload_size).map_err(
                     |e| pingap_core::new_internal_error(413, e.to_string()),
                 )?;
+                // the request with body larger than the limit isn't mirrored
+                if ctx.mirror_upstream.is_some() {
+                    if ctx.payload_size > location.mirror_body_limit {
+                        ctx.mirror_upstream = None;
+                        ctx.mirror_body = None;
+                    } else {
+                        ctx.mirror_body
+                            .get_or_insert_with(BytesMut::new)
+                            .extend_from_slice(buf);
+                    }
+                }
             }
         }
+        if end_of_stream {
+            self.mirror_request(session, ctx);
+        }
         Ok(())
     }
     /// Generates cache keys for request caching.
@@ -1267,7 +1753,7 @@ impl ProxyHttp for Server {
 
     fn upstream_response_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
@@ -1292,6 +1778,42 @@ impl ProxyHttp for Server {
             }
         }
 
+        // passive health check of the backend
+        if let Some(up) = get_upstream_with_variables(&ctx.upstream, ctx) {
+            up.record_backend_result(
+                &ctx.upstream_address,
+                !upstream_response.status.is_server_error(),
+            );
+        }
+        // retry another backend if the status is retryable,
+        // the response is discarded before writing to downstream
+        if let Some(policy) = get_retry_policy(ctx) {
+            let code = upstream_response.status.as_u16();
+            if policy.is_retry_status(code)
+                && !session.as_ref().retry_buffer_truncated()
+                && policy.can_retry(
+                    &session.req_header().method,
+                    ctx.upstream_retries,
+                    RetryCondition::Status(code),
+                )
+            {
+                add_upstream_retry(ctx);
+                let mut e = pingora::Error::new_up(pingora::HTTPStatus(code));
+                e.set_retry(true);
+                return Err(e);
+            }
+        }
+        // issue the affinity cookie of the backend for sticky session
+        if let Some(set_cookie) =
+            get_upstream_with_variables(&ctx.upstream, ctx).and_then(|up| {
+                up.get_sticky_set_cookie(
+                    session.req_header(),
+                    &ctx.upstream_address,
+                )
+            })
+        {
+            let _ = upstream_response.append_header("Set-Cookie", set_cookie);
+        }
         if ctx.status.is_none() {
             ctx.status = Some(upstream_response.status);
             ctx.upstream_response_time =
@@ -1303,6 +1825,14 @@ impl ProxyHttp for Server {
         }
         ctx.upstream_processing_time =
             pingap_util::get_latency(&ctx.upstream_processing_time);
+        // every response of the location is sampled for the hedge delay
+        if let Some(policy) = get_location(&ctx.location)
+            .and_then(|location| location.hedge.clone())
+        {
+            if let Some(latency) = ctx.upstream_processing_time {
+                policy.observe(latency);
+            }
+        }
         Ok(())
     }
 
@@ -1425,6 +1955,12 @@ impl ProxyHttp for Server {
                 | pingora::ErrorSource::Unset => 500,
             },
         };
+        // connection failure or timeout of the backend
+        if e.esource() == &pingora::ErrorSource::Upstream {
+            if let Some(up) = get_upstream_with_variables(&ctx.upstream, ctx) {
+                up.record_backend_result(&ctx.upstream_address, false);
+            }
+        }
         let mut resp = match code {
             502 => error_resp::HTTP_502_RESPONSE.clone(),
             400 => error_resp::HTTP_400_RESPONSE.clone(),
@@ -1512,6 +2048,10 @@ impl ProxyHttp for Server {
         defer!(debug!(category = LOG_CATEGORY, "<-- logging"););
         end_request();
         self.processing.fetch_sub(1, Ordering::Relaxed);
+        // the request body is not passed to upstream, e.g. cache hit
+        if session.is_body_done() {
+            self.mirror_request(session, ctx);
+        }
         if let Some(location) = get_location(&ctx.location) {
             location.sub_processing();
         }
@@ -1519,6 +2059,17 @@ impl ProxyHttp for Server {
         if let Some(upstream) = get_upstream_with_variables(&ctx.upstream, ctx)
         {
             ctx.upstream_processing = Some(upstream.completed());
+            if !ctx.upstream_address.is_empty() {
+                let response_time =
+                    ctx.get_upstream_processing_time().map(|processing_time| {
+                        processing_time
+                            + ctx
+                                .get_upstream_response_time()
+                                .unwrap_or_default()
+                    });
+                upstream
+                    .backend_completed(&ctx.upstream_address, response_time);
+            }
         }
         if ctx.status.is_none() {
             if let Some(header) = session.response_written() {
@@ -1691,11 +2242,16 @@ category = "config"
 value = 'proxy_set_headers = ["name:value"]'
         "###;
         let pingap_conf = PingapConf::new(toml_data.as_ref(), false).unwrap();
-        try_init_upstreams(&pingap_conf.upstreams, None).unwrap();
+        try_init_upstreams(
+            &pingap_conf.upstreams,
+            &pingap_conf.certificates,
+            None,
+        )
+        .unwrap();
         try_init_locations(&pingap_conf.locations).unwrap();
         try_init_server_locations(&pingap_conf.servers, &pingap_conf.locations)
             .unwrap();
-        let confs = parse_from_conf(pingap_conf);
+        let confs = parse_from_conf(pingap_conf).unwrap();
         Server::new(&confs[0]).unwrap()
     }
 
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 // use pingap_config::PingapConf;
+use super::Error;
 use pingora::protocols::l4::ext::TcpKeepalive;
 use std::fmt;
 #[cfg(target_os = "linux")]
@@ -76,6 +77,13 @@ pub struct ServerConf {
     // False means the server is using http protocol
     pub global_certificates: bool,
 
+    // CA certificates in PEM format to verify the client certificate
+    // None means the client certificate is not requested
+    pub tls_client_ca: Option<Vec<u8>>,
+
+    // Whether the client certificate is required, otherwise it's optional
+    pub tls_client_auth_required: bool,
+
     // Whether HTTP/2 protocol support is enabled for this server
     // The http protocol is using h2c
     pub enabled_h2: bool,
@@ -94,6 +102,35 @@ pub struct ServerConf {
 
     // Whether to enable server-timing header
     pub enable_server_timing: bool,
+
+    // Whether to accept PROXY protocol header on the listeners
+    pub proxy_protocol: bool,
+
+    // Trusted source ips or cidrs of PROXY protocol header
+    // Empty means all sources are trusted
+    pub proxy_protocol_trusted_cidrs: Vec<String>,
+
+    // Whether it's a stream(L4) server
+    pub stream: bool,
+
+    // Default upstream of the stream server
+    pub stream_upstream: Option<String>,
+
+    // Upstreams selected by the SNI of tls connection, (server name, upstream)
+    pub stream_sni_upstreams: Vec<(String, String)>,
+
+    // Listen address and path of prometheus pull metrics of the stream or
+    // udp server
+    pub stream_prometheus_pull: Option<(String, String)>,
+
+    // Whether it's a udp server, the `stream_upstream` is used as its upstream
+    pub udp: bool,
+
+    // Idle timeout of the udp session
+    pub udp_idle_timeout: Option<Duration>,
+
+    // Maximum number of the udp sessions
+    pub udp_max_sessions: Option<usize>,
 }
 
 impl fmt::Display for ServerConf {
@@ -118,6 +155,13 @@ impl fmt::Display for ServerConf {
         }
         write!(f, "threads: {:?}, ", self.threads)?;
         write!(f, "global_certificates: {}, ", self.global_certificates)?;
+        if self.tls_client_ca.is_some() {
+            write!(
+                f,
+                "tls_client_auth_required: {}, ",
+                self.tls_client_auth_required
+            )?;
+        }
         write!(f, "enabled_h2: {}, ", self.enabled_h2)?;
         write!(f, "tcp_keepalive: {:?}, ", self.tcp_keepalive)?;
         write!(f, "tcp_fastopen: {:?}, ", self.tcp_fastopen)?;
@@ -131,13 +175,41 @@ impl fmt::Display for ServerConf {
             write!(f, "modules: {:?}, ", modules)?;
         }
         write!(f, "enable_server_timing: {}, ", self.enable_server_timing)?;
+        if self.proxy_protocol {
+            write!(
+                f,
+                "proxy_protocol_trusted_cidrs: {:?}, ",
+                self.proxy_protocol_trusted_cidrs
+            )?;
+        }
+        if self.udp {
+            write!(f, "stream_upstream: {:?}, ", self.stream_upstream)?;
+            write!(f, "udp_idle_timeout: {:?}, ", self.udp_idle_timeout)?;
+            write!(f, "udp_max_sessions: {:?}, ", self.udp_max_sessions)?;
+        }
+        if self.stream {
+            write!(f, "stream_upstream: {:?}, ", self.stream_upstream)?;
+            write!(
+                f,
+                "stream_sni_upstreams: {:?}, ",
+                self.stream_sni_upstreams
+            )?;
+            write!(
+                f,
+                "stream_prometheus_pull: {:?}, ",
+                self.stream_prometheus_pull
+            )?;
+        }
         write!(f, "error_template: {} }}", self.error_template)?;
         Ok(())
     }
 }
 
-// Conversion implementation from PingapConf to Vec<ServerConf>
-pub fn parse_from_conf(conf: pingap_config::PingapConf) -> Vec<ServerConf> {
+// Conversion implementation from PingapConf to Vec<ServerConf>,
+// it fails if the ca certificate to verify client certificate can't be loaded
+pub fn parse_from_conf(
+    conf: pingap_config::PingapConf,
+) -> Result<Vec<ServerConf>, Error> {
     let mut upstreams = vec![];
     for (name, item) in conf.upstreams {
         upstreams.push((name, item));
@@ -173,6 +245,41 @@ pub fn parse_from_conf(conf: pingap_config::PingapConf) -> Vec<ServerConf> {
             None
         };
 
+        // Load the ca certificate to verify client certificate, the client
+        // certificate verification must not be disabled by the invalid ca
+        let tls_client_ca = if let Some(ca) = &item.tls_client_ca {
+            let new_error = |message: String| Error::Common {
+                category: "tls_client_ca".to_string(),
+                message: format!("{message}(server:{name})"),
+            };
+            let tls_cert = conf
+                .certificates
+                .get(ca)
+                .and_then(|cert| cert.tls_cert.clone())
+                .unwrap_or_default();
+            if tls_cert.is_empty() {
+                return Err(new_error(format!(
+                    "ca certificate({ca}) is not found"
+                )));
+            }
+            let buf = pingap_util::convert_pem(&tls_cert).map_err(|e| {
+                new_error(format!("load ca certificate({ca}) fail, {e}"))
+            })?;
+            if buf.is_empty() {
+                return Err(new_error(format!(
+                    "ca certificate({ca}) is empty"
+                )));
+            }
+            Some(buf)
+        } else {
+            None
+        };
+
+        let stream = item.is_stream();
+        let udp = item.is_udp();
+        let stream_sni_upstreams = item.get_stream_sni_upstreams();
+        let stream_prometheus_pull = item.get_stream_prometheus_pull();
+
         // Create server configuration with all settings
         servers.push(ServerConf {
             name,
@@ -186,6 +293,9 @@ pub fn parse_from_conf(conf: pingap_config::PingapConf) -> Vec<ServerConf> {
             locations: item.locations.unwrap_or_default(),
             threads: item.threads,
             global_certificates: item.global_certificates.unwrap_or_default(),
+            tls_client_ca,
+            tls_client_auth_required: item.tls_client_auth.as_deref()
+                != Some("optional"),
             enabled_h2: item.enabled_h2.unwrap_or_default(),
             tcp_keepalive,
             tcp_fastopen: item.tcp_fastopen,
@@ -193,20 +303,68 @@ pub fn parse_from_conf(conf: pingap_config::PingapConf) -> Vec<ServerConf> {
             otlp_exporter: item.otlp_exporter.clone(),
             modules: item.modules.clone(),
             enable_server_timing: item.enable_server_timing.unwrap_or_default(),
+            proxy_protocol: item.proxy_protocol.unwrap_or_default(),
+            proxy_protocol_trusted_cidrs: item
+                .proxy_protocol_trusted_cidrs
+                .unwrap_or_default(),
+            stream,
+            stream_upstream: item.stream_upstream,
+            stream_sni_upstreams,
+            stream_prometheus_pull,
+            udp,
+            udp_idle_timeout: item.udp_idle_timeout,
+            udp_max_sessions: item.udp_max_sessions,
             error_template,
         });
     }
 
-    servers
+    Ok(servers)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ServerConf;
+    use super::{parse_from_conf, ServerConf};
+    use pingap_config::PingapConf;
     use pingora::protocols::l4::ext::TcpKeepalive;
     use pretty_assertions::assert_eq;
     use std::time::Duration;
 
+    #[test]
+    fn test_parse_tls_client_ca() {
+        let mut conf = PingapConf::default();
+        conf.servers.insert(
+            "pingap".to_string(),
+            pingap_config::ServerConf {
+                tls_client_ca: Some("ca".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            "Common error, category: tls_client_ca, ca certificate(ca) is not found(server:pingap)",
+            parse_from_conf(conf.clone()).err().unwrap().to_string()
+        );
+
+        conf.certificates.insert(
+            "ca".to_string(),
+            pingap_config::CertificateConf {
+                tls_cert: Some("/not/exists/ca.pem".to_string()),
+                is_ca: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            parse_from_conf(conf.clone())
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("load ca certificate(ca) fail")
+        );
+
+        conf.servers.get_mut("pingap").unwrap().tls_client_ca = None;
+        assert_eq!(1, parse_from_conf(conf).unwrap().len());
+    }
+
     #[test]
     fn test_server_conf() {
         let conf = ServerConf {
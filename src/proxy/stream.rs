@@ -0,0 +1,909 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::proxy_protocol::accept_proxy_header;
+use super::server::Error;
+use super::{ServerConf, LOG_CATEGORY};
+use async_trait::async_trait;
+use http::Method;
+use pingap_core::{
+    format_socket_addr, Ctx, ProxyProtocolAddrs, RetryCondition,
+    SimpleServiceTaskFuture,
+};
+use pingap_logger::Parser;
+#[cfg(feature = "full")]
+use pingap_performance::{
+    new_prometheus, new_prometheus_push_service, Prometheus,
+};
+use pingap_upstream::{get_upstream, Upstream};
+use pingap_util::IpRules;
+#[cfg(feature = "full")]
+use pingora::apps::http_app::{HttpServer, ServeHttp};
+use pingora::apps::ServerApp;
+use pingora::listeners::TcpSocketOptions;
+#[cfg(feature = "full")]
+use pingora::protocols::http::ServerSession;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use pingora::services::listening::Service;
+use pingora::services::Service as ServiceTrait;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, error, info};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Timeout of reading the tls client hello for sni routing
+const CLIENT_HELLO_READ_TIMEOUT: Duration = Duration::from_secs(10);
+// Max size of tls record
+const MAX_TLS_RECORD_SIZE: usize = 16 * 1024 + 5;
+// The connection is closed if no data is transferred within
+// the interval after the server begins to shut down
+const SHUTDOWN_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes transferred of the client connection
+#[derive(Debug, Default)]
+struct TransferStats {
+    /// Bytes received from client
+    received: AtomicU64,
+    /// Bytes sent to client
+    sent: AtomicU64,
+    /// Whether the io error is from the client connection
+    client_error: AtomicBool,
+}
+
+impl TransferStats {
+    fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+    fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+    fn transferred(&self) -> u64 {
+        self.received() + self.sent()
+    }
+}
+
+// Counts the bytes of the client connection, so they are still reported
+// if the connection is closed with error.
+struct CountingStream<'a, S> {
+    inner: S,
+    stats: &'a TransferStats,
+}
+
+impl<S> CountingStream<'_, S> {
+    fn record<T>(&self, result: &Poll<io::Result<T>>) {
+        if let Poll::Ready(Err(_)) = result {
+            self.stats.client_error.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let size = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.record(&result);
+        let size = (buf.filled().len() - size) as u64;
+        self.stats.received.fetch_add(size, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.record(&result);
+        if let Poll::Ready(Ok(size)) = result {
+            self.stats.sent.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        result
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_flush(cx);
+        self.record(&result);
+        result
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.record(&result);
+        result
+    }
+}
+
+// Copies the data between client and upstream until one of them is closed.
+// If the server begins to shut down, the connection is closed
+// once it's idle, so the long-lived connection can't block the shutdown.
+async fn transfer<A, B>(
+    client: &mut A,
+    upstream: &mut B,
+    stats: &TransferStats,
+    shutdown: &ShutdownWatch,
+) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut shutdown = shutdown.clone();
+    {
+        let copy = tokio::io::copy_bidirectional(client, upstream);
+        tokio::pin!(copy);
+        tokio::select! {
+            result = &mut copy => return result.map(|_| ()),
+            Ok(_) = shutdown.changed() => {},
+        }
+        loop {
+            let transferred = stats.transferred();
+            tokio::select! {
+                result = &mut copy => return result.map(|_| ()),
+                _ = tokio::time::sleep(SHUTDOWN_IDLE_TIMEOUT) => {
+                    if stats.transferred() == transferred {
+                        break;
+                    }
+                },
+            }
+        }
+    }
+    // close the connections gracefully
+    let _ = client.shutdown().await;
+    let _ = upstream.shutdown().await;
+    Ok(())
+}
+
+/// Http app of prometheus pull metrics of the stream server
+#[cfg(feature = "full")]
+struct PrometheusPullApp {
+    path: String,
+    prometheus: Arc<Prometheus>,
+}
+
+#[cfg(feature = "full")]
+#[async_trait]
+impl ServeHttp for PrometheusPullApp {
+    async fn response(
+        &self,
+        session: &mut ServerSession,
+    ) -> http::Response<Vec<u8>> {
+        let (status, body) = if session.req_header().uri.path() != self.path {
+            (404, b"Not Found".to_vec())
+        } else {
+            match self.prometheus.metrics() {
+                Ok(body) => (200, body),
+                Err(e) => (500, e.to_string().into_bytes()),
+            }
+        };
+        http::Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap_or_default()
+    }
+}
+
+/// Creates the http service of prometheus pull metrics, it listens on
+/// its own address as the stream and udp server are not http server.
+#[cfg(feature = "full")]
+pub(super) fn new_prometheus_pull_service(
+    name: &str,
+    addr: &str,
+    path: String,
+    prometheus: Arc<Prometheus>,
+) -> Box<dyn ServiceTrait> {
+    let mut service = Service::new(
+        format!("metrics:{name}"),
+        HttpServer::new_app(PrometheusPullApp { path, prometheus }),
+    );
+    service.threads = Some(1);
+    service.add_tcp(addr);
+    Box::new(service)
+}
+
+/// Result of parsing the server name from tls client hello
+#[derive(Debug, PartialEq)]
+enum ClientHelloServerName {
+    /// More data is required
+    Incomplete,
+    /// It's not tls or the client hello has no server name
+    Missing,
+    /// Server name indication of the client hello
+    Found(String),
+}
+
+// Reads the big endian number of the bytes
+fn read_number(buf: &[u8], offset: usize, size: usize) -> Option<usize> {
+    let data = buf.get(offset..offset + size)?;
+    Some(data.iter().fold(0, |acc, v| (acc << 8) | *v as usize))
+}
+
+// Parses the server name of the tls client hello, the client hello
+// is only parsed if it's in the first tls record.
+fn parse_client_hello_server_name(buf: &[u8]) -> ClientHelloServerName {
+    // record header: content type(1), version(2), length(2)
+    if buf.len() < 5 {
+        return ClientHelloServerName::Incomplete;
+    }
+    // handshake record
+    if buf[0] != 0x16 {
+        return ClientHelloServerName::Missing;
+    }
+    let record_size = read_number(buf, 3, 2).unwrap_or_default();
+    if buf.len() < 5 + record_size {
+        return ClientHelloServerName::Incomplete;
+    }
+    let record = &buf[5..5 + record_size];
+    parse_server_name(record)
+        .map(ClientHelloServerName::Found)
+        .unwrap_or(ClientHelloServerName::Missing)
+}
+
+// Parses the server name extension of the client hello handshake
+fn parse_server_name(record: &[u8]) -> Option<String> {
+    // handshake type: client hello
+    if *record.first()? != 0x01 {
+        return None;
+    }
+    // handshake type(1), length(3), version(2), random(32)
+    let mut offset = 38;
+    // session id
+    offset += 1 + read_number(record, offset, 1)?;
+    // cipher suites
+    offset += 2 + read_number(record, offset, 2)?;
+    // compression methods
+    offset += 1 + read_number(record, offset, 1)?;
+    let extensions_end = offset + 2 + read_number(record, offset, 2)?;
+    offset += 2;
+    while offset + 4 <= extensions_end {
+        let extension_type = read_number(record, offset, 2)?;
+        let extension_size = read_number(record, offset + 2, 2)?;
+        offset += 4;
+        // server name extension
+        if extension_type == 0 {
+            // server name list length(2), name type(1)
+            let name_type = read_number(record, offset + 2, 1)?;
+            if name_type != 0 {
+                return None;
+            }
+            let name_size = read_number(record, offset + 3, 2)?;
+            let name = record.get(offset + 5..offset + 5 + name_size)?;
+            return std::str::from_utf8(name)
+                .ok()
+                .map(|name| name.to_lowercase());
+        }
+        offset += extension_size;
+    }
+    None
+}
+
+// Returns the upstream of the server name, the exact match is prior to
+// the wildcard match(e.g. *.example.com)
+fn match_sni_upstream<'a>(
+    sni_upstreams: &'a [(String, String)],
+    server_name: &str,
+) -> Option<&'a str> {
+    if let Some((_, upstream)) =
+        sni_upstreams.iter().find(|(name, _)| name == server_name)
+    {
+        return Some(upstream);
+    }
+    sni_upstreams
+        .iter()
+        .find(|(name, _)| {
+            name.strip_prefix('*').is_some_and(|suffix| {
+                suffix.starts_with('.') && server_name.ends_with(suffix)
+            })
+        })
+        .map(|(_, upstream)| upstream.as_str())
+}
+
+/// Stream server proxies the tcp connections to upstream, or routes the tls
+/// connections by SNI without terminating them.
+pub struct StreamServer {
+    /// Server name identifier
+    name: String,
+
+    /// Comma-separated list of listening addresses
+    addr: String,
+
+    /// Number of worker threads
+    threads: Option<usize>,
+
+    /// Default upstream of the connections
+    upstream: Option<String>,
+
+    /// Upstreams selected by the SNI of tls connection
+    sni_upstreams: Vec<(String, String)>,
+
+    /// Access log formatter
+    log_parser: Option<Parser>,
+
+    /// TCP socket configuration options
+    tcp_socket_options: Option<TcpSocketOptions>,
+
+    /// Trusted source ips or cidrs of PROXY protocol header,
+    /// none if PROXY protocol is not accepted on the listeners
+    proxy_protocol_trusted_cidrs: Option<IpRules>,
+
+    /// Number of connections currently being proxied
+    processing: AtomicI32,
+
+    /// Total number of accepted connections
+    accepted: AtomicU64,
+
+    /// Whether to push metrics to remote Prometheus pushgateway
+    prometheus_push_mode: bool,
+
+    /// Listen address and path of Prometheus pull metrics
+    #[cfg(feature = "full")]
+    prometheus_pull: Option<(String, String)>,
+
+    /// Prometheus push gateway URL
+    #[cfg(feature = "full")]
+    prometheus_metrics: String,
+
+    /// Prometheus metrics registry when metrics collection is enabled
+    #[cfg(feature = "full")]
+    prometheus: Option<Arc<Prometheus>>,
+}
+
+pub struct StreamServerServices {
+    pub stream: Service<StreamServer>,
+    /// Http service of Prometheus pull metrics
+    pub prometheus_pull: Option<Box<dyn ServiceTrait>>,
+}
+
+impl StreamServer {
+    /// Creates a new stream server, the prometheus metrics are pushed to
+    /// the push gateway or pulled from the listen address of metrics.
+    pub fn new(conf: &ServerConf) -> Result<Self> {
+        debug!(
+            category = LOG_CATEGORY,
+            config = conf.to_string(),
+            "new stream server"
+        );
+        let log_parser = conf
+            .access_log
+            .as_ref()
+            .filter(|value| !value.is_empty())
+            .map(|value| Parser::from(value.as_str()));
+        let tcp_socket_options =
+            if conf.tcp_fastopen.is_some() || conf.tcp_keepalive.is_some() {
+                let mut opts = TcpSocketOptions::default();
+                opts.tcp_fastopen = conf.tcp_fastopen;
+                opts.tcp_keepalive.clone_from(&conf.tcp_keepalive);
+                Some(opts)
+            } else {
+                None
+            };
+        let prometheus_metrics =
+            conf.prometheus_metrics.clone().unwrap_or_default();
+        let prometheus_push_mode = prometheus_metrics.contains("://");
+        #[cfg(feature = "full")]
+        let prometheus = if prometheus_push_mode
+            || conf.stream_prometheus_pull.is_some()
+        {
+            let p = new_prometheus(&conf.name).map_err(|e| Error::Common {
+                category: "prometheus".to_string(),
+                message: e.to_string(),
+            })?;
+            Some(Arc::new(p))
+        } else {
+            None
+        };
+        Ok(Self {
+            name: conf.name.clone(),
+            addr: conf.addr.clone(),
+            threads: conf.threads,
+            upstream: conf.stream_upstream.clone(),
+            sni_upstreams: conf.stream_sni_upstreams.clone(),
+            log_parser,
+            tcp_socket_options,
+            proxy_protocol_trusted_cidrs: conf
+                .proxy_protocol
+                .then(|| IpRules::new(&conf.proxy_protocol_trusted_cidrs)),
+            processing: AtomicI32::new(0),
+            accepted: AtomicU64::new(0),
+            prometheus_push_mode,
+            #[cfg(feature = "full")]
+            prometheus_pull: conf.stream_prometheus_pull.clone(),
+            #[cfg(feature = "full")]
+            prometheus_metrics,
+            #[cfg(feature = "full")]
+            prometheus,
+        })
+    }
+    /// Get the prometheus push service if push mode is configured.
+    pub fn get_prometheus_push_service(
+        &self,
+    ) -> Option<(String, SimpleServiceTaskFuture)> {
+        if !self.prometheus_push_mode {
+            return None;
+        }
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "full")] {
+                let prometheus = self.prometheus.as_ref()?;
+                match new_prometheus_push_service(
+                    &self.name,
+                    &self.prometheus_metrics,
+                    prometheus.clone(),
+                ) {
+                    Ok(service) => Some(service),
+                    Err(e) => {
+                        error!(
+                            category = LOG_CATEGORY,
+                            error = %e,
+                            name = self.name,
+                            "new prometheus push service fail"
+                        );
+                        None
+                    },
+                }
+            } else {
+               None
+            }
+        }
+    }
+    /// Starts the stream server with the tcp listeners
+    pub fn run(self) -> Result<StreamServerServices> {
+        let name = self.name.clone();
+        let addr = self.addr.clone();
+        let threads = self.threads;
+        let tcp_socket_options = self.tcp_socket_options.clone();
+        info!(
+            category = LOG_CATEGORY,
+            name,
+            addr,
+            threads,
+            upstream = self.upstream,
+            sni_upstreams = format!("{:?}", self.sni_upstreams),
+            "stream server is listening"
+        );
+        #[cfg(feature = "full")]
+        let prometheus_pull = self
+            .prometheus_pull
+            .clone()
+            .zip(self.prometheus.clone())
+            .map(|((addr, path), prometheus)| {
+                new_prometheus_pull_service(
+                    &format!("stream:{name}"),
+                    &addr,
+                    path,
+                    prometheus,
+                )
+            });
+        #[cfg(not(feature = "full"))]
+        let prometheus_pull = None;
+        let mut stream = Service::new(format!("stream:{name}"), self);
+        stream.threads = threads;
+        for addr in addr.split(',') {
+            if let Some(opt) = &tcp_socket_options {
+                stream.add_tcp_with_settings(addr, opt.clone());
+            } else {
+                stream.add_tcp(addr);
+            }
+        }
+        Ok(StreamServerServices {
+            stream,
+            prometheus_pull,
+        })
+    }
+    // Reads the client hello and returns the upstream of its server name,
+    // the data read from client (after the initial data) is returned
+    // for sending to upstream.
+    async fn read_sni_upstream(
+        &self,
+        session: &mut Stream,
+        ctx: &mut Ctx,
+        mut buf: Vec<u8>,
+    ) -> std::io::Result<(Option<String>, Vec<u8>)> {
+        buf.reserve(1024);
+        let mut data = [0; 1024];
+        let server_name = loop {
+            match parse_client_hello_server_name(&buf) {
+                ClientHelloServerName::Incomplete => {},
+                ClientHelloServerName::Missing => break None,
+                ClientHelloServerName::Found(name) => break Some(name),
+            }
+            if buf.len() >= MAX_TLS_RECORD_SIZE {
+                break None;
+            }
+            let size = tokio::time::timeout(
+                CLIENT_HELLO_READ_TIMEOUT,
+                session.read(&mut data),
+            )
+            .await
+            .map_err(|_| {
+                std::io::Error::from(std::io::ErrorKind::TimedOut)
+            })??;
+            if size == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            buf.extend_from_slice(&data[..size]);
+        };
+        let upstream = server_name
+            .as_deref()
+            .and_then(|name| match_sni_upstream(&self.sni_upstreams, name))
+            .map(|upstream| upstream.to_string())
+            .or_else(|| self.upstream.clone());
+        ctx.tls_server_name = server_name;
+        Ok((upstream, buf))
+    }
+    // Connects to the backend of upstream, the other backends are
+    // tried if it fails and the retry policy of upstream allows.
+    async fn connect_upstream(
+        &self,
+        upstream: &Upstream,
+        ctx: &mut Ctx,
+        addrs: Option<&ProxyProtocolAddrs>,
+    ) -> Option<(L4Stream, String)> {
+        let client_ip = ctx.client_ip.clone().unwrap_or_default();
+        let retry_policy = upstream.retry_policy();
+        if let Some(policy) = &retry_policy {
+            policy.on_request();
+        }
+        let mut excluded = vec![];
+        loop {
+            let Some(backend) =
+                upstream.select_stream_backend(&client_ip, &excluded)
+            else {
+                error!(
+                    category = LOG_CATEGORY,
+                    name = self.name,
+                    upstream = upstream.name,
+                    "no available stream upstream backend"
+                );
+                return None;
+            };
+            let address = format_socket_addr(&backend.addr);
+            match upstream.connect_stream_backend(&backend, addrs).await {
+                Ok(stream) => return Some((stream, address)),
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        upstream = upstream.name,
+                        address,
+                        "connect stream upstream fail"
+                    );
+                    upstream.completed();
+                    upstream.record_backend_result(&address, false);
+                    upstream.backend_completed(&address, None);
+                    let condition =
+                        if e.etype() == &pingora::ErrorType::ConnectTimedout {
+                            RetryCondition::Timeout
+                        } else {
+                            RetryCondition::ConnectError
+                        };
+                    // nothing is sent to upstream before the connection
+                    // is established, so it's safe to retry as GET request
+                    let retry = retry_policy.as_ref().is_some_and(|policy| {
+                        policy.can_retry(
+                            &Method::GET,
+                            ctx.upstream_retries,
+                            condition,
+                        )
+                    });
+                    if !retry {
+                        return None;
+                    }
+                    ctx.upstream_retries += 1;
+                    excluded.push(address);
+                },
+            }
+        }
+    }
+    // Proxies the connection to the upstream, the initial data read from
+    // client is sent to upstream first, returns the bytes sent to client
+    async fn proxy(
+        &self,
+        mut session: Stream,
+        ctx: &mut Ctx,
+        addrs: Option<ProxyProtocolAddrs>,
+        buf: Vec<u8>,
+        shutdown: &ShutdownWatch,
+    ) -> usize {
+        let (upstream_name, buf) = if self.sni_upstreams.is_empty() {
+            (self.upstream.clone(), buf)
+        } else {
+            match self.read_sni_upstream(&mut session, ctx, buf).await {
+                Ok(value) => value,
+                Err(e) => {
+                    debug!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        "read tls client hello fail"
+                    );
+                    return 0;
+                },
+            }
+        };
+        ctx.payload_size = buf.len();
+        let Some(upstream) = upstream_name.as_deref().and_then(get_upstream)
+        else {
+            error!(
+                category = LOG_CATEGORY,
+                name = self.name,
+                server_name = ctx.tls_server_name,
+                "stream upstream is not found"
+            );
+            return 0;
+        };
+        ctx.upstream.clone_from(&upstream.name);
+        if upstream.circuit_breaker_acquire().is_some() {
+            debug!(
+                category = LOG_CATEGORY,
+                name = self.name,
+                upstream = upstream.name,
+                "stream connection is rejected by circuit breaker"
+            );
+            return 0;
+        }
+
+        let Some((mut upstream_stream, address)) =
+            self.connect_upstream(&upstream, ctx, addrs.as_ref()).await
+        else {
+            return 0;
+        };
+        ctx.upstream_address.clone_from(&address);
+        ctx.upstream_connect_time =
+            Some(pingap_util::now_ms().saturating_sub(ctx.created_at));
+        #[cfg(feature = "full")]
+        if let Some(prom) = &self.prometheus {
+            prom.stream_before(&upstream.name);
+        }
+
+        let stats = TransferStats::default();
+        let mut result = Ok(());
+        if !buf.is_empty() {
+            result = upstream_stream.write_all(&buf).await;
+        }
+        if result.is_ok() {
+            let mut client = CountingStream {
+                inner: &mut session,
+                stats: &stats,
+            };
+            result =
+                transfer(&mut client, &mut upstream_stream, &stats, shutdown)
+                    .await;
+        }
+        // the error of client connection is not the failure of backend
+        let success =
+            result.is_ok() || stats.client_error.load(Ordering::Relaxed);
+        if let Err(e) = &result {
+            debug!(
+                category = LOG_CATEGORY,
+                error = %e,
+                name = self.name,
+                upstream = upstream.name,
+                "stream connection is closed with error"
+            );
+        }
+        let sent = stats.sent();
+        ctx.payload_size += stats.received() as usize;
+        upstream.stream_disconnected();
+        upstream.completed();
+        upstream.record_backend_result(&address, success);
+        upstream.backend_completed(&address, None);
+        #[cfg(feature = "full")]
+        if let Some(prom) = &self.prometheus {
+            prom.stream_after(
+                &upstream.name,
+                ctx.payload_size as u64,
+                sent,
+                pingap_util::now_ms().saturating_sub(ctx.created_at),
+            );
+        }
+        sent as usize
+    }
+}
+
+#[async_trait]
+impl ServerApp for StreamServer {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut session: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let mut ctx = Ctx::new();
+        ctx.accepted = self.accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        ctx.processing = self.processing.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut addrs = session.get_socket_digest().and_then(|digest| {
+            Some(ProxyProtocolAddrs {
+                source: *digest.peer_addr()?.as_inet()?,
+                destination: *digest.local_addr()?.as_inet()?,
+            })
+        });
+        // the PROXY header is only parsed if the connection is from
+        // the trusted source, the original addresses of it are used
+        let mut buf = vec![];
+        let trusted = self
+            .proxy_protocol_trusted_cidrs
+            .as_ref()
+            .zip(addrs.as_ref())
+            .is_some_and(|(trusted_cidrs, addrs)| {
+                trusted_cidrs
+                    .is_match(&addrs.source.ip().to_string())
+                    .unwrap_or_default()
+            });
+        if trusted {
+            match accept_proxy_header(&mut session).await {
+                Ok((original, data)) => {
+                    if original.is_some() {
+                        addrs = original;
+                    }
+                    buf = data;
+                },
+                Err(e) => {
+                    debug!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        "read proxy protocol header fail"
+                    );
+                    self.processing.fetch_sub(1, Ordering::Relaxed);
+                    return None;
+                },
+            }
+        }
+        if let Some(addrs) = &addrs {
+            ctx.remote_addr = Some(addrs.source.ip().to_string());
+            ctx.remote_port = Some(addrs.source.port());
+            ctx.client_ip = Some(addrs.source.ip().to_string());
+            ctx.server_addr = Some(addrs.destination.ip().to_string());
+            ctx.server_port = Some(addrs.destination.port());
+        }
+
+        let sent = self.proxy(session, &mut ctx, addrs, buf, shutdown).await;
+
+        self.processing.fetch_sub(1, Ordering::Relaxed);
+        if let Some(p) = &self.log_parser {
+            info!("{}", p.format_stream(&ctx, sent));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // client hello of `openssl s_client -servername pingap.io`(truncated extensions)
+    fn new_client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut server_name_ext = vec![0x00, 0x00];
+        let list_size = name.len() + 3;
+        server_name_ext.extend((list_size as u16 + 2).to_be_bytes());
+        server_name_ext.extend((list_size as u16).to_be_bytes());
+        server_name_ext.push(0x00);
+        server_name_ext.extend((name.len() as u16).to_be_bytes());
+        server_name_ext.extend(name);
+        // supported versions extension before server name
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend(server_name_ext);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        // session id
+        body.push(0x00);
+        // cipher suites
+        body.extend([0x00, 0x02, 0x13, 0x01]);
+        // compression methods
+        body.extend([0x01, 0x00]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello_server_name() {
+        let data = new_client_hello("Pingap.io");
+        assert_eq!(
+            ClientHelloServerName::Found("pingap.io".to_string()),
+            parse_client_hello_server_name(&data)
+        );
+        assert_eq!(
+            ClientHelloServerName::Incomplete,
+            parse_client_hello_server_name(&data[..20])
+        );
+        assert_eq!(
+            ClientHelloServerName::Missing,
+            parse_client_hello_server_name(b"GET / HTTP/1.1\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let (mut client, client_side) = tokio::io::duplex(1024);
+        let (mut upstream, mut upstream_side) = tokio::io::duplex(1024);
+        let (tx, shutdown) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move {
+            let stats = TransferStats::default();
+            let mut client_side = CountingStream {
+                inner: client_side,
+                stats: &stats,
+            };
+            let result = transfer(
+                &mut client_side,
+                &mut upstream_side,
+                &stats,
+                &shutdown,
+            )
+            .await;
+            (result.is_ok(), stats.received(), stats.sent())
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+        upstream.write_all(b"pingap").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"pingap", &buf);
+
+        // the idle connection is closed after shutdown
+        tx.send(true).unwrap();
+        assert_eq!((true, 4, 6), task.await.unwrap());
+        assert_eq!(0, client.read(&mut buf).await.unwrap());
+    }
+
+    #[test]
+    fn test_match_sni_upstream() {
+        let sni_upstreams = vec![
+            ("pg.example.com".to_string(), "pg".to_string()),
+            ("*.example.com".to_string(), "web".to_string()),
+        ];
+        assert_eq!(
+            Some("pg"),
+            match_sni_upstream(&sni_upstreams, "pg.example.com")
+        );
+        assert_eq!(
+            Some("web"),
+            match_sni_upstream(&sni_upstreams, "api.example.com")
+        );
+        assert_eq!(None, match_sni_upstream(&sni_upstreams, "example.com"));
+        assert_eq!(None, match_sni_upstream(&sni_upstreams, "pingap.io"));
+    }
+}
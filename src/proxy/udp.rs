@@ -0,0 +1,607 @@
+// Copyright 2024-2025 Tree xie.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::server::Error;
+#[cfg(feature = "full")]
+use super::stream::new_prometheus_pull_service;
+use super::{ServerConf, LOG_CATEGORY};
+use ahash::AHashMap;
+use async_trait::async_trait;
+use pingap_core::{format_socket_addr, Ctx, SimpleServiceTaskFuture};
+use pingap_logger::Parser;
+#[cfg(feature = "full")]
+use pingap_performance::{
+    new_prometheus, new_prometheus_push_service, Prometheus,
+};
+use pingap_upstream::{get_upstream, Upstream};
+use pingap_util::now_ms;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::services::Service as ServiceTrait;
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Default idle timeout of the udp session
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Default max number of the udp sessions
+const DEFAULT_MAX_SESSIONS: usize = 10_000;
+// Max size of udp datagram
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+thread_local! {
+    // Receive buffer of the backend datagrams, it's shared by the sessions
+    // of the thread instead of being allocated for each session
+    static RECV_BUF: RefCell<Vec<u8>> = RefCell::new(vec![0; MAX_DATAGRAM_SIZE]);
+}
+
+/// Session of the client address, the datagrams of the client are
+/// forwarded to the same backend until the session is idle.
+struct UdpSession {
+    /// Socket connected to the backend
+    socket: UdpSocket,
+    /// Non-blocking clone of the socket for sending the datagrams, the
+    /// tokio socket isn't writable until it's polled by the reactor
+    sender: std::net::UdpSocket,
+    /// Address of the backend
+    address: String,
+    /// Timestamp(ms) of the last forwarded datagram
+    last_active: AtomicU64,
+    /// Bytes received from the client
+    received: AtomicU64,
+    /// Bytes sent to the client
+    sent: AtomicU64,
+    /// Whether the backend refused the datagram or the socket failed
+    failed: AtomicBool,
+}
+
+type UdpSessions = Arc<Mutex<AHashMap<SocketAddr, Arc<UdpSession>>>>;
+
+/// Udp server forwards the datagrams to the upstream, the backend is
+/// selected for each client address(session affinity) and the session
+/// is closed after the idle timeout.
+pub struct UdpServer {
+    /// Server name identifier
+    name: String,
+
+    /// Comma-separated list of listening addresses
+    addr: String,
+
+    /// Upstream of the datagrams
+    upstream: String,
+
+    /// Idle timeout of the session
+    idle_timeout: Duration,
+
+    /// Max number of the sessions, the datagrams of new client are dropped
+    /// if it's exceeded
+    max_sessions: usize,
+
+    /// Access log formatter, it's logged when the session is closed
+    log_parser: Option<Parser>,
+
+    /// Whether to push metrics to remote Prometheus pushgateway
+    prometheus_push_mode: bool,
+
+    /// Listen address and path of Prometheus pull metrics
+    #[cfg(feature = "full")]
+    prometheus_pull: Option<(String, String)>,
+
+    /// Prometheus push gateway URL
+    #[cfg(feature = "full")]
+    prometheus_metrics: String,
+
+    /// Prometheus metrics registry when metrics collection is enabled
+    #[cfg(feature = "full")]
+    prometheus: Option<Arc<Prometheus>>,
+}
+
+impl UdpServer {
+    /// Creates a new udp server, the prometheus metrics are pushed to
+    /// the push gateway or pulled from the listen address of metrics.
+    pub fn new(conf: &ServerConf) -> Result<Self> {
+        debug!(
+            category = LOG_CATEGORY,
+            config = conf.to_string(),
+            "new udp server"
+        );
+        let log_parser = conf
+            .access_log
+            .as_ref()
+            .filter(|value| !value.is_empty())
+            .map(|value| Parser::from(value.as_str()));
+        let prometheus_metrics =
+            conf.prometheus_metrics.clone().unwrap_or_default();
+        let prometheus_push_mode = prometheus_metrics.contains("://");
+        #[cfg(feature = "full")]
+        let prometheus = if prometheus_push_mode
+            || conf.stream_prometheus_pull.is_some()
+        {
+            let p = new_prometheus(&conf.name).map_err(|e| Error::Common {
+                category: "prometheus".to_string(),
+                message: e.to_string(),
+            })?;
+            Some(Arc::new(p))
+        } else {
+            None
+        };
+        Ok(Self {
+            name: conf.name.clone(),
+            addr: conf.addr.clone(),
+            upstream: conf.stream_upstream.clone().unwrap_or_default(),
+            idle_timeout: conf.udp_idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            max_sessions: conf.udp_max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS),
+            log_parser,
+            prometheus_push_mode,
+            #[cfg(feature = "full")]
+            prometheus_pull: conf.stream_prometheus_pull.clone(),
+            #[cfg(feature = "full")]
+            prometheus_metrics,
+            #[cfg(feature = "full")]
+            prometheus,
+        })
+    }
+    /// Get the prometheus push service if push mode is configured.
+    pub fn get_prometheus_push_service(
+        &self,
+    ) -> Option<(String, SimpleServiceTaskFuture)> {
+        if !self.prometheus_push_mode {
+            return None;
+        }
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "full")] {
+                let prometheus = self.prometheus.as_ref()?;
+                match new_prometheus_push_service(
+                    &self.name,
+                    &self.prometheus_metrics,
+                    prometheus.clone(),
+                ) {
+                    Ok(service) => Some(service),
+                    Err(e) => {
+                        error!(
+                            category = LOG_CATEGORY,
+                            error = %e,
+                            name = self.name,
+                            "new prometheus push service fail"
+                        );
+                        None
+                    },
+                }
+            } else {
+               None
+            }
+        }
+    }
+    /// Get the http service of prometheus pull metrics if the listen
+    /// address of metrics is configured.
+    pub fn get_prometheus_pull_service(&self) -> Option<Box<dyn ServiceTrait>> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "full")] {
+                let (addr, path) = self.prometheus_pull.clone()?;
+                let prometheus = self.prometheus.clone()?;
+                Some(new_prometheus_pull_service(
+                    &format!("udp:{}", self.name),
+                    &addr,
+                    path,
+                    prometheus,
+                ))
+            } else {
+               None
+            }
+        }
+    }
+    // Creates the session of the client, the backend is selected
+    // and connected by a new udp socket. The bind and connect of udp
+    // socket don't wait for the network, so the std socket is used
+    // and the receive loop is not blocked.
+    fn new_session(
+        &self,
+        upstream: &Upstream,
+        client_addr: &SocketAddr,
+    ) -> io::Result<UdpSession> {
+        let backend = upstream
+            .select_stream_backend(&client_addr.ip().to_string(), &[])
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no available udp upstream backend",
+                )
+            })?;
+        let address = format_socket_addr(&backend.addr);
+        let result = (|| {
+            let addr = backend.addr.as_inet().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{address} is not udp address"),
+                )
+            })?;
+            let bind_addr = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = std::net::UdpSocket::bind(bind_addr)?;
+            socket.connect(addr)?;
+            socket.set_nonblocking(true)?;
+            let sender = socket.try_clone()?;
+            Ok((UdpSocket::from_std(socket)?, sender))
+        })();
+        let (socket, sender) = match result {
+            Ok(value) => value,
+            Err(e) => {
+                upstream.completed();
+                upstream.record_backend_result(&address, false);
+                upstream.backend_completed(&address, None);
+                return Err(e);
+            },
+        };
+        Ok(UdpSession {
+            socket,
+            sender,
+            address,
+            last_active: AtomicU64::new(now_ms()),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+        })
+    }
+    // Forwards the datagrams of backend to the client until the session is idle,
+    // then the session is removed. The session is treated as failure of the
+    // backend only if the datagram is refused or the socket fails, as the
+    // one-way protocol(e.g. syslog) doesn't reply.
+    async fn relay_backend(
+        self: Arc<Self>,
+        upstream: Arc<Upstream>,
+        listener: Arc<UdpSocket>,
+        sessions: UdpSessions,
+        client_addr: SocketAddr,
+        session: Arc<UdpSession>,
+    ) {
+        let created_at = now_ms();
+        let idle_timeout = self.idle_timeout.as_millis() as u64;
+        loop {
+            let last_active = session.last_active.load(Ordering::Relaxed);
+            let elapsed = now_ms().saturating_sub(last_active);
+            if elapsed >= idle_timeout {
+                break;
+            }
+            let timeout = Duration::from_millis(idle_timeout - elapsed);
+            match tokio::time::timeout(timeout, session.socket.readable()).await
+            {
+                // check the last active time again,
+                // the datagrams of client may be forwarded
+                Err(_) => continue,
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => {
+                    debug!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        address = session.address,
+                        "udp upstream receive fail"
+                    );
+                    session.failed.store(true, Ordering::Relaxed);
+                    break;
+                },
+            };
+            let result = RECV_BUF.with_borrow_mut(|buf| {
+                let size = session.socket.try_recv(buf)?;
+                Ok::<_, io::Error>(buf[..size].to_vec())
+            });
+            let data = match result {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    debug!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        address = session.address,
+                        "udp upstream receive fail"
+                    );
+                    session.failed.store(true, Ordering::Relaxed);
+                    break;
+                },
+            };
+            let size = data.len();
+            session.last_active.store(now_ms(), Ordering::Relaxed);
+            if let Err(e) = listener.send_to(&data, client_addr).await {
+                debug!(
+                    category = LOG_CATEGORY,
+                    error = %e,
+                    name = self.name,
+                    client_addr = client_addr.to_string(),
+                    "udp send to client fail"
+                );
+                continue;
+            }
+            session.sent.fetch_add(size as u64, Ordering::Relaxed);
+            #[cfg(feature = "full")]
+            if let Some(prom) = &self.prometheus {
+                prom.udp_sent(size as u64);
+            }
+        }
+        if let Ok(mut sessions) = sessions.lock() {
+            sessions.remove(&client_addr);
+        }
+        upstream.completed();
+        upstream.record_backend_result(
+            &session.address,
+            !session.failed.load(Ordering::Relaxed),
+        );
+        upstream.backend_completed(&session.address, None);
+        #[cfg(feature = "full")]
+        if let Some(prom) = &self.prometheus {
+            prom.udp_session_closed();
+        }
+        if let Some(p) = &self.log_parser {
+            let mut ctx = Ctx::new();
+            ctx.created_at = created_at;
+            ctx.remote_addr = Some(client_addr.ip().to_string());
+            ctx.remote_port = Some(client_addr.port());
+            ctx.client_ip = Some(client_addr.ip().to_string());
+            ctx.upstream.clone_from(&upstream.name);
+            ctx.upstream_address.clone_from(&session.address);
+            ctx.payload_size =
+                session.received.load(Ordering::Relaxed) as usize;
+            let sent = session.sent.load(Ordering::Relaxed) as usize;
+            info!("{}", p.format_stream(&ctx, sent));
+        }
+    }
+    // Forwards the datagram of client to the backend of its session,
+    // the session is created if not exists. It doesn't wait, the datagram
+    // is dropped if it can't be sent immediately.
+    fn forward(
+        self: &Arc<Self>,
+        upstream: &Arc<Upstream>,
+        listener: &Arc<UdpSocket>,
+        sessions: &UdpSessions,
+        client_addr: SocketAddr,
+        data: &[u8],
+    ) {
+        let found = sessions
+            .lock()
+            .ok()
+            .and_then(|sessions| sessions.get(&client_addr).cloned());
+        let session = if let Some(session) = found {
+            session
+        } else {
+            let count = sessions.lock().map(|sessions| sessions.len());
+            if count.unwrap_or_default() >= self.max_sessions {
+                debug!(
+                    category = LOG_CATEGORY,
+                    name = self.name,
+                    client_addr = client_addr.to_string(),
+                    max_sessions = self.max_sessions,
+                    "udp sessions exceed the limit, the datagram is dropped"
+                );
+                return;
+            }
+            let session = match self.new_session(upstream, &client_addr) {
+                Ok(session) => Arc::new(session),
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        upstream = upstream.name,
+                        "new udp session fail"
+                    );
+                    return;
+                },
+            };
+            if let Ok(mut sessions) = sessions.lock() {
+                sessions.insert(client_addr, session.clone());
+            }
+            #[cfg(feature = "full")]
+            if let Some(prom) = &self.prometheus {
+                prom.udp_session_opened();
+            }
+            tokio::spawn(self.clone().relay_backend(
+                upstream.clone(),
+                listener.clone(),
+                sessions.clone(),
+                client_addr,
+                session.clone(),
+            ));
+            session
+        };
+        session.last_active.store(now_ms(), Ordering::Relaxed);
+        if let Err(e) = session.sender.send(data) {
+            debug!(
+                category = LOG_CATEGORY,
+                error = %e,
+                name = self.name,
+                address = session.address,
+                "udp send to upstream fail"
+            );
+            // the datagram is dropped if the socket buffer is full
+            if e.kind() != io::ErrorKind::WouldBlock {
+                session.failed.store(true, Ordering::Relaxed);
+            }
+            return;
+        }
+        session
+            .received
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        #[cfg(feature = "full")]
+        if let Some(prom) = &self.prometheus {
+            prom.udp_received(data.len() as u64);
+        }
+    }
+    // Receives the datagrams of the listener and forwards them to
+    // the backend of the client session.
+    async fn serve(self: Arc<Self>, listener: Arc<UdpSocket>) {
+        let sessions: UdpSessions = Default::default();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, client_addr) = match listener.recv_from(&mut buf).await {
+                Ok(value) => value,
+                Err(e) => {
+                    // the icmp error of the previous datagram sent to client
+                    debug!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.name,
+                        "udp listener receive fail"
+                    );
+                    continue;
+                },
+            };
+            let Some(upstream) = get_upstream(&self.upstream) else {
+                error!(
+                    category = LOG_CATEGORY,
+                    name = self.name,
+                    upstream = self.upstream,
+                    "udp upstream is not found"
+                );
+                continue;
+            };
+            self.forward(
+                &upstream,
+                &listener,
+                &sessions,
+                client_addr,
+                &buf[..size],
+            );
+        }
+    }
+}
+
+/// Background service of the udp server, each listening address is
+/// served by its own receive loop.
+pub struct UdpServerService {
+    server: Arc<UdpServer>,
+}
+
+impl From<UdpServer> for UdpServerService {
+    fn from(server: UdpServer) -> Self {
+        Self {
+            server: Arc::new(server),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UdpServerService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut serve_tasks = JoinSet::new();
+        for addr in self.server.addr.split(',') {
+            let listener = match UdpSocket::bind(addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(
+                        category = LOG_CATEGORY,
+                        error = %e,
+                        name = self.server.name,
+                        addr,
+                        "udp listener bind fail"
+                    );
+                    continue;
+                },
+            };
+            info!(
+                category = LOG_CATEGORY,
+                name = self.server.name,
+                addr,
+                upstream = self.server.upstream,
+                idle_timeout =
+                    humantime::format_duration(self.server.idle_timeout)
+                        .to_string(),
+                "udp server is listening"
+            );
+            serve_tasks.spawn(self.server.clone().serve(Arc::new(listener)));
+        }
+        let _ = shutdown.changed().await;
+        // the receive loops are aborted when the join set is dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingap_config::UpstreamConf;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_udp_forward() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((size, addr)) = backend.recv_from(&mut buf).await {
+                let _ = backend.send_to(&buf[..size], addr).await;
+            }
+        });
+        let upstream = Upstream::new(
+            "udp-echo",
+            &UpstreamConf {
+                addrs: vec![backend_addr.to_string()],
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        upstream.update_backends().await.unwrap();
+        let upstream = Arc::new(upstream);
+
+        let server = Arc::new(
+            UdpServer::new(&ServerConf {
+                name: "udp".to_string(),
+                addr: "127.0.0.1:0".to_string(),
+                stream_upstream: Some("udp-echo".to_string()),
+                udp_idle_timeout: Some(Duration::from_millis(200)),
+                udp_max_sessions: Some(1),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let sessions: UdpSessions = Default::default();
+
+        let mut buf = [0; 1024];
+        for data in [b"ping".as_slice(), b"pingap".as_slice()] {
+            server.forward(&upstream, &listener, &sessions, client_addr, data);
+            let size = client.recv(&mut buf).await.unwrap();
+            assert_eq!(data, &buf[..size]);
+        }
+        // the datagrams of the client are forwarded by the same session
+        assert_eq!(1, sessions.lock().unwrap().len());
+        let session = sessions.lock().unwrap().get(&client_addr).cloned();
+        assert_eq!(10, session.unwrap().received.load(Ordering::Relaxed));
+
+        // the datagram of new client is dropped if the sessions exceed the limit
+        let other_addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        server.forward(&upstream, &listener, &sessions, other_addr, b"ping");
+        assert_eq!(1, sessions.lock().unwrap().len());
+
+        // the session is removed after idle timeout
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(0, sessions.lock().unwrap().len());
+    }
+}
@@ -195,7 +195,10 @@ export default function Basic() {
           "tls_validity",
           "parse_certificate_fail",
           "service_discover_fail",
+          "service_discover_change",
           "upstream_status",
+          "upstream_circuit_breaker",
+          "ocsp_stapling",
         ].sort(),
         true,
       ),
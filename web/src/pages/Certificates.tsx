@@ -120,8 +120,8 @@ export default function Certificates() {
       placeholder: "",
       defaultValue: certificateConfig.acme,
       span: 3,
-      category: ExFormItemCategory.RADIOS,
-      options: newStringOptions(["lets_encrypt"], true, true),
+      category: ExFormItemCategory.INPUT_SELECT,
+      options: newStringOptions(["lets_encrypt", "tls_alpn_01"], true, true),
     },
     {
       name: "is_default",
@@ -155,6 +155,11 @@ export default function Home() {
                     className="relative pl-4 before:content-[''] before:absolute before:left-0 before:top-2 before:w-2 before:h-2 before:rounded-full before:bg-rose-600"
                   >
                     <span className="text-muted-foreground">{backend}</span>
+                    {status.failure_reasons?.[backend] && (
+                      <span className="text-muted-foreground break-all">
+                        {` ${status.failure_reasons[backend]}`}
+                      </span>
+                    )}
                   </li>
                 );
               })}
@@ -89,7 +89,16 @@ export default function Upstreams() {
       span: 3,
       category: ExFormItemCategory.SELECT,
       options: newStringOptions(
-        ["static", "dns", "docker", "transparent"],
+        [
+          "static",
+          "dns",
+          "srv",
+          "docker",
+          "k8s",
+          "consul",
+          "file",
+          "transparent",
+        ],
         true,
         true,
       ),
@@ -5,6 +5,7 @@ interface UpstreamHealthyStatus {
   healthy: number;
   total: number;
   unhealthy_backends: string[];
+  failure_reasons?: Record<string, string>;
 }
 
 interface Basic {